@@ -1,19 +1,121 @@
+mod snapshot;
+
 use find_my_device::device_service_client::DeviceServiceClient;
+use find_my_device::user_service_client::UserServiceClient;
 use find_my_device::{
+    AcknowledgeWipeArg,
     IntroduceMyselfArg,
+    ListLocationsArg,
+    LocationSnapshot,
     SubmitLocationArg,
     Location,
 };
 
+use base64::Engine;
+use clap::Parser;
+use std::path::PathBuf;
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Identity, Uri};
+
 pub mod find_my_device {
     tonic::include_proto!("findmydevice");
 }
 
+#[derive(Debug, Parser)]
+#[command(version, about = "A FindMyX agent")]
+struct Args {
+    /// The URL of the server's gRPC services. Use `https` to connect over TLS.
+    #[arg(long, env = "FMX_SERVER", default_value = "http://127.0.0.1:50051")]
+    server: String,
+
+    /// Trusts only this CA certificate (PEM) to identify the server, instead
+    /// of the CAs trusted by the operating system.
+    #[arg(long, env = "FMX_CA_CERTIFICATE")]
+    ca_certificate: Option<PathBuf>,
+
+    /// A client certificate (PEM) for servers that require one.
+    #[arg(long, env = "FMX_CLIENT_CERTIFICATE", requires = "client_key")]
+    client_certificate: Option<PathBuf>,
+
+    #[arg(long, env = "FMX_CLIENT_KEY", requires = "client_certificate")]
+    client_key: Option<PathBuf>,
+
+    /// The name expected in the server's certificate, if not the host in
+    /// `--server`.
+    #[arg(long, env = "FMX_TLS_DOMAIN_NAME")]
+    tls_domain_name: Option<String>,
+
+    /// The URL of the server's web interface, if not the host in `--server`
+    /// on the web interface's default port.
+    #[arg(long, env = "FMX_WEB_URL")]
+    web_url: Option<String>,
+
+    /// Encrypts locations before submitting them, so that the server cannot
+    /// read them, with a key derived from this device's secret key. Share the
+    /// key only with those who may see this device's location.
+    #[arg(long, env = "FMX_ENCRYPT_LOCATIONS")]
+    encrypt_locations: bool,
+
+    /// Lists the locations recorded for this device after submitting one,
+    /// decrypting them if they were encrypted.
+    #[arg(long)]
+    list_locations: bool,
+}
+
+/// Returns the CA certificates trusted by the operating system, as PEM.
+fn system_roots_pem () -> std::io::Result<Vec<u8>> {
+    let mut pem = String::new();
+    for cert in rustls_native_certs::load_native_certs()? {
+        let encoded = base64::engine::general_purpose::STANDARD.encode(&cert.0);
+        pem.push_str("-----BEGIN CERTIFICATE-----\n");
+        for line in encoded.as_bytes().chunks(64) {
+            pem.push_str(std::str::from_utf8(line).unwrap());
+            pem.push('\n');
+        }
+        pem.push_str("-----END CERTIFICATE-----\n");
+    }
+    Ok(pem.into_bytes())
+}
+
+/// The port the server's web interface listens on by default.
+const DEFAULT_WEB_PORT: u16 = 3030;
+
+/// Returns the URL of the server's web interface, without a trailing slash.
+fn web_url (args: &Args) -> Result<String, Box<dyn std::error::Error>> {
+    if let Some(url) = &args.web_url {
+        return Ok(url.trim_end_matches('/').to_owned());
+    }
+    let server: Uri = args.server.parse()?;
+    let scheme = server.scheme_str().unwrap_or("http");
+    let host = server.host().ok_or("The server's URL has no host.")?;
+    Ok(format!("{}://{}:{}", scheme, host, DEFAULT_WEB_PORT))
+}
+
+async fn connect (args: &Args) -> Result<Channel, Box<dyn std::error::Error>> {
+    let mut endpoint = Channel::from_shared(args.server.clone())?;
+    if endpoint.uri().scheme_str() == Some("https") {
+        let ca = match &args.ca_certificate {
+            Some(path) => std::fs::read(path)?,
+            None => system_roots_pem()?,
+        };
+        let mut tls = ClientTlsConfig::new().ca_certificate(Certificate::from_pem(ca));
+        if let (Some(cert), Some(key)) = (&args.client_certificate, &args.client_key) {
+            tls = tls.identity(Identity::from_pem(std::fs::read(cert)?, std::fs::read(key)?));
+        }
+        if let Some(domain_name) = &args.tls_domain_name {
+            tls = tls.domain_name(domain_name);
+        }
+        endpoint = endpoint.tls_config(tls)?;
+    }
+    Ok(endpoint.connect().await?)
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let mut client = DeviceServiceClient::connect("http://127.0.0.1:50051").await?;
+    let args = Args::parse();
+    let channel = connect(&args).await?;
+    let mut client = DeviceServiceClient::new(channel.clone());
 
-    let token: Vec<u8> = {
+    let (token, location_key) = {
         let request = tonic::Request::new(IntroduceMyselfArg {
             ..Default::default()
         });
@@ -22,25 +124,77 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         if resp.nice_to_meet_you {
             println!("Server said hello.");
         }
-        resp.your_token
+        println!("Secret key: {}", hex::encode(&resp.your_secret_key));
+        let location_key = args.encrypt_locations.then(|| snapshot::derive_key(&resp.your_secret_key));
+        (resp.your_token, location_key)
     };
 
-    println!("http://localhost:3030/locations/{}", hex::encode(&token));
+    // The key goes in the URL's fragment, which the browser does not send to
+    // the server.
+    let viewer = format!("{}/locations/{}", web_url(&args)?, hex::encode(&token));
+    match &location_key {
+        Some(key) => println!("{}#key={}", viewer, hex::encode(key)),
+        None => println!("{}", viewer),
+    };
 
     {
-        let request = tonic::Request::new(SubmitLocationArg {
-            token,
-            emergency: true,
-            location: Some(Location{
-                degrees_latitude: 30.0832,
-                degress_longitude: -81.4028,
-                meters_elevation: 0.0,
-            }),
-            ..Default::default()
+        let location = Some(Location{
+            degrees_latitude: 30.0832,
+            degress_longitude: -81.4028,
+            meters_elevation: 0.0,
         });
-    
+        let arg = match &location_key {
+            Some(key) => SubmitLocationArg {
+                token: token.clone(),
+                emergency: true,
+                encrypted_snapshot: snapshot::seal(key, &LocationSnapshot {
+                    location,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            None => SubmitLocationArg {
+                token: token.clone(),
+                emergency: true,
+                location,
+                ..Default::default()
+            },
+        };
+        let request = tonic::Request::new(arg);
+
         let response = client.submit_location(request).await?;
         println!("RESPONSE={:?}", response);
+        if response.into_inner().remote_wipe {
+            // This agent does not actually wipe anything: it just confirms
+            // that the order was received.
+            println!("Server ordered a remote wipe.");
+            let request = tonic::Request::new(AcknowledgeWipeArg {
+                token: token.clone(),
+            });
+            client.acknowledge_wipe(request).await?;
+        }
+    }
+
+    if args.list_locations {
+        let mut user_client = UserServiceClient::new(channel);
+        let request = tonic::Request::new(ListLocationsArg {
+            token: token.clone(),
+            ..Default::default()
+        });
+        for mut loc in user_client.list_locations(request).await?.into_inner().locations {
+            if !loc.encrypted_snapshot.is_empty() {
+                if let Some(key) = &location_key {
+                    let opened = snapshot::open(key, &loc.encrypted_snapshot)?;
+                    loc = LocationSnapshot {
+                        update_time: loc.update_time,
+                        expected_next_update_time: loc.expected_next_update_time,
+                        emergency: loc.emergency,
+                        ..opened
+                    };
+                }
+            }
+            println!("{:?}", loc);
+        }
     }
 
     Ok(())
@@ -0,0 +1,80 @@
+use crate::find_my_device::LocationSnapshot;
+use aes_gcm::{Aes256Gcm, Key, Nonce, KeyInit};
+use aes_gcm::aead::{Aead, AeadCore, OsRng, Payload};
+use hkdf::Hkdf;
+use prost::Message;
+use sha2::Sha256;
+
+/// The length, in bytes, of a location key.
+pub const KEY_LENGTH: usize = 32;
+
+const NONCE_LENGTH: usize = 12;
+
+const SNAPSHOT_AAD: &[u8] = b"fmx location snapshot";
+
+const LOCATION_KEY_INFO: &[u8] = b"fmx location key";
+
+/// Derives a device's location key from its secret key, which the server only
+/// ever sees a digest of.
+pub fn derive_key (secret_key: &[u8]) -> [u8; KEY_LENGTH] {
+    let mut key = [ 0; KEY_LENGTH ];
+    Hkdf::<Sha256>::new(None, secret_key)
+        .expand(LOCATION_KEY_INFO, &mut key)
+        .expect("a location key is not too long for HKDF");
+    key
+}
+
+/// Encrypts a snapshot for `SubmitLocationArg.encryptedSnapshot`, so that only
+/// holders of `key` can read it.
+pub fn seal (key: &[u8; KEY_LENGTH], snapshot: &LocationSnapshot) -> Vec<u8> {
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = cipher.encrypt(&nonce, Payload { msg: &snapshot.encode_to_vec(), aad: SNAPSHOT_AAD })
+        .expect("encrypting a snapshot does not fail");
+    let mut sealed = nonce.to_vec();
+    sealed.extend(ciphertext);
+    sealed
+}
+
+/// Decrypts a snapshot sealed with `seal`.
+pub fn open (key: &[u8; KEY_LENGTH], sealed: &[u8]) -> Result<LocationSnapshot, Box<dyn std::error::Error>> {
+    if sealed.len() < NONCE_LENGTH {
+        return Err("The encrypted snapshot is truncated.".into());
+    }
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LENGTH);
+    let plaintext = cipher.decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: SNAPSHOT_AAD })
+        .map_err(|_| "The encrypted snapshot could not be decrypted.")?;
+    Ok(LocationSnapshot::decode(plaintext.as_slice())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::find_my_device::Location;
+
+    #[test]
+    fn snapshots_open_only_with_their_key () {
+        let key = [ 3; KEY_LENGTH ];
+        let snapshot = LocationSnapshot {
+            location: Some(Location {
+                degrees_latitude: 30.0832,
+                degress_longitude: -81.4028,
+                meters_elevation: 0.0,
+            }),
+            notes: String::from("Under the pier"),
+            ..Default::default()
+        };
+        let sealed = seal(&key, &snapshot);
+        assert_eq!(open(&key, &sealed).unwrap(), snapshot);
+        assert!(open(&[ 4; KEY_LENGTH ], &sealed).is_err());
+        assert!(open(&key, &sealed[..NONCE_LENGTH - 1]).is_err());
+    }
+
+    #[test]
+    fn each_device_derives_its_own_key () {
+        assert_eq!(derive_key(&[ 1; 16 ]), derive_key(&[ 1; 16 ]));
+        assert_ne!(derive_key(&[ 1; 16 ]), derive_key(&[ 2; 16 ]));
+    }
+
+}
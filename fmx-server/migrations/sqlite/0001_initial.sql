@@ -0,0 +1,101 @@
+-- Times are nanoseconds since the Unix epoch, in UTC, and IDs are unsigned
+-- 64-bit integers stored as signed ones.
+
+-- A location's nearby Wi-Fi networks and Bluetooth devices are encrypted
+-- along with the rest of it, so they have no tables of their own: kept apart,
+-- they would be readable to anyone with the database file.
+CREATE TABLE locations (
+    device_id   BLOB NOT NULL,
+    update_time INTEGER NOT NULL,
+    ciphertext  BLOB NOT NULL,
+    PRIMARY KEY (device_id, update_time)
+) WITHOUT ROWID;
+
+CREATE INDEX locations_by_update_time ON locations (update_time);
+
+CREATE TABLE device_keys (
+    device_id   BLOB PRIMARY KEY,
+    wrapped_key BLOB NOT NULL
+) WITHOUT ROWID;
+
+CREATE TABLE intros (
+    device_id               BLOB PRIMARY KEY,
+    remote_addr             TEXT,
+    registration_key        BLOB NOT NULL,
+    remote_wipe_enabled     INTEGER NOT NULL,
+    can_read_nearby_devices INTEGER NOT NULL
+) WITHOUT ROWID;
+
+CREATE TABLE tokens (
+    digest          BLOB PRIMARY KEY,
+    device_id       BLOB NOT NULL,
+    write_locations INTEGER NOT NULL,
+    read_locations  INTEGER NOT NULL,
+    nearby          INTEGER NOT NULL,
+    wipe            INTEGER NOT NULL,
+    list_tokens     INTEGER NOT NULL,
+    stats           INTEGER NOT NULL,
+    cancel_purge    INTEGER NOT NULL,
+    not_before      INTEGER NOT NULL,
+    not_after       INTEGER
+) WITHOUT ROWID;
+
+CREATE INDEX tokens_by_device ON tokens (device_id);
+
+CREATE TABLE wipe_orders (
+    device_id    BLOB PRIMARY KEY,
+    requested    INTEGER NOT NULL,
+    delivered    INTEGER,
+    acknowledged INTEGER
+) WITHOUT ROWID;
+
+CREATE TABLE excommunications (
+    device_id        BLOB PRIMARY KEY,
+    time             INTEGER NOT NULL,
+    reason           TEXT NOT NULL,
+    by_administrator INTEGER NOT NULL
+) WITHOUT ROWID;
+
+CREATE TABLE purge_orders (
+    id         INTEGER PRIMARY KEY,
+    device_id  BLOB NOT NULL,
+    requested  INTEGER NOT NULL,
+    since      INTEGER,
+    execute_at INTEGER NOT NULL
+);
+
+CREATE INDEX purge_orders_by_device ON purge_orders (device_id, execute_at);
+CREATE INDEX purge_orders_by_execute_at ON purge_orders (execute_at);
+
+CREATE TABLE emergency_purges (
+    id             INTEGER PRIMARY KEY,
+    device_id      BLOB NOT NULL,
+    purge_order_id INTEGER NOT NULL,
+    since          INTEGER,
+    requested      INTEGER NOT NULL,
+    decided        INTEGER,
+    approved       INTEGER NOT NULL
+);
+
+CREATE TABLE audit_log (
+    id        INTEGER PRIMARY KEY,
+    time      INTEGER NOT NULL,
+    action    TEXT NOT NULL,
+    device_id BLOB,
+    detail    TEXT NOT NULL
+);
+
+CREATE TABLE registration_keys (
+    key             BLOB PRIMARY KEY,
+    uses_remaining  INTEGER NOT NULL,
+    created         INTEGER NOT NULL,
+    not_after       INTEGER,
+    write_locations INTEGER NOT NULL,
+    read_locations  INTEGER NOT NULL,
+    nearby          INTEGER NOT NULL,
+    wipe            INTEGER NOT NULL,
+    list_tokens     INTEGER NOT NULL,
+    stats           INTEGER NOT NULL,
+    cancel_purge    INTEGER NOT NULL,
+    note            TEXT NOT NULL
+) WITHOUT ROWID;
@@ -0,0 +1,34 @@
+-- How many locations each device has, and the total length of their
+-- ciphertexts, so that quotas can be checked without reading a device's whole
+-- history. The triggers keep it up to date. A location that replaces another
+-- at the same time must be written as an update, not with INSERT OR REPLACE,
+-- since rows deleted by REPLACE do not fire delete triggers.
+CREATE TABLE location_usage (
+    device_id BLOB PRIMARY KEY,
+    locations INTEGER NOT NULL,
+    bytes     INTEGER NOT NULL
+) WITHOUT ROWID;
+
+INSERT INTO location_usage (device_id, locations, bytes)
+    SELECT device_id, COUNT(*), SUM(LENGTH(ciphertext)) FROM locations GROUP BY device_id;
+
+CREATE TRIGGER location_usage_after_insert AFTER INSERT ON locations BEGIN
+    INSERT INTO location_usage (device_id, locations, bytes)
+        VALUES (new.device_id, 1, LENGTH(new.ciphertext))
+        ON CONFLICT (device_id) DO UPDATE SET
+            locations = locations + 1,
+            bytes = bytes + excluded.bytes;
+END;
+
+CREATE TRIGGER location_usage_after_update AFTER UPDATE OF ciphertext ON locations BEGIN
+    UPDATE location_usage
+        SET bytes = bytes - LENGTH(old.ciphertext) + LENGTH(new.ciphertext)
+        WHERE device_id = new.device_id;
+END;
+
+CREATE TRIGGER location_usage_after_delete AFTER DELETE ON locations BEGIN
+    UPDATE location_usage
+        SET locations = locations - 1, bytes = bytes - LENGTH(old.ciphertext)
+        WHERE device_id = old.device_id;
+    DELETE FROM location_usage WHERE device_id = old.device_id AND locations = 0;
+END;
@@ -0,0 +1,420 @@
+use crate::auth::{Authorized, Authorizer, database_failure};
+use crate::device::default_device_permissions;
+use crate::events::ServerEventQueues;
+use crate::purge::carry_out_purge;
+use crate::grpc::find_my_device::admin_service_server::AdminService;
+use crate::grpc::find_my_device::{
+    AdminExcommunicateArg,
+    ExcommunicateResult,
+    ServerEventType,
+    ListEmergencyPurgesArg,
+    ListEmergencyPurgesResult,
+    DecideEmergencyPurgeArg,
+    DecideEmergencyPurgeResult,
+    EmergencyPurge,
+    ListAuditLogArg,
+    ListAuditLogResult,
+    CreateRegistrationKeyArg,
+    CreateRegistrationKeyResult,
+    ListRegistrationKeysArg,
+    ListRegistrationKeysResult,
+    RevokeRegistrationKeyArg,
+    RevokeRegistrationKeyResult,
+};
+use crate::grpc::find_my_device;
+use crate::storage::{
+    Storage,
+    Excommunication,
+    EmergencyPurgeRequest,
+    AuditRecord,
+    RegistrationKey,
+};
+use crate::utils::{chrono_to_grpc_timestamp, grpc_timestamp_to_chrono};
+use tonic::{Request, Response, Status};
+use std::sync::Arc;
+use log::{info, warn};
+use chrono::prelude::*;
+use rand::RngCore;
+use rand::rngs::OsRng;
+
+/// The number of audit records returned by `ListAuditLog` if no limit is given.
+const DEFAULT_AUDIT_LOG_LIMIT: u32 = 100;
+
+const REGISTRATION_KEY_LENGTH: usize = 16;
+
+fn emergency_purge_to_grpc (request: &EmergencyPurgeRequest) -> EmergencyPurge {
+    EmergencyPurge {
+        request_id: request.id,
+        purge_id: request.purge_order_id,
+        requested: Some(chrono_to_grpc_timestamp(&request.requested)),
+        since: request.since.as_ref().map(chrono_to_grpc_timestamp),
+        decided: request.decided.as_ref().map(chrono_to_grpc_timestamp),
+        approved: request.approved,
+    }
+}
+
+/// `raw_key` is only known when the key has just been created: after that,
+/// only its digest is kept.
+fn registration_key_to_grpc (raw_key: Option<Vec<u8>>, key: &RegistrationKey) -> find_my_device::RegistrationKey {
+    find_my_device::RegistrationKey {
+        registration_key: raw_key.unwrap_or_default(),
+        registration_key_digest: key.digest.clone(),
+        uses_remaining: key.uses_remaining,
+        created: Some(chrono_to_grpc_timestamp(&key.created)),
+        not_after: key.not_after.as_ref().map(chrono_to_grpc_timestamp),
+        device_permissions: Some(key.device_permissions.clone()),
+        note: key.note.clone(),
+    }
+}
+
+#[derive(Clone)]
+pub struct AdminServiceProvider <S: Storage> {
+    pub storage: Arc<S>,
+    pub events: Arc<ServerEventQueues>,
+    pub auth: Authorizer,
+}
+
+#[tonic::async_trait]
+impl <S: Storage + Send + Sync + 'static> AdminService for AdminServiceProvider <S> {
+
+    async fn excommunicate (
+        &self,
+        request: Request<AdminExcommunicateArg>,
+    ) -> Result<Response<ExcommunicateResult>, Status> {
+        Authorized::admin(&request)?;
+        let req = request.into_inner();
+        let storage = self.storage.as_ref();
+        let device_id = if !req.secret_key.is_empty() {
+            self.auth.digests.device_id(&req.secret_key)
+        } else {
+            let digest = self.auth.digests.token(&req.token);
+            match storage.get_token_info(&digest).await.map_err(database_failure)? {
+                Some(token_info) => token_info.device_id,
+                None => return Err(Status::not_found("No such token")),
+            }
+        };
+        if storage.get_intro(&device_id).await.map_err(database_failure)?.is_none() {
+            return Err(Status::not_found("No such device"));
+        }
+        if storage.get_excommunication(&device_id).await.map_err(database_failure)?.is_none() {
+            let record = Excommunication {
+                time: Utc::now(),
+                reason: req.reason,
+                by_administrator: true,
+            };
+            storage.excommunicate(&device_id, &record).await.map_err(database_failure)?;
+            storage.write_audit_record(&AuditRecord {
+                time: record.time,
+                action: String::from("excommunicate"),
+                device_id: Some(device_id.clone()),
+                detail: record.reason.clone(),
+            }).await.map_err(database_failure)?;
+            self.events.raise(&device_id, ServerEventType::Excommunicated);
+            warn!("Device {:?} excommunicated by the administrator: {}", device_id, record.reason);
+        }
+        Ok(Response::new(ExcommunicateResult { excommunicated: true }))
+    }
+
+    async fn list_emergency_purges (
+        &self,
+        request: Request<ListEmergencyPurgesArg>,
+    ) -> Result<Response<ListEmergencyPurgesResult>, Status> {
+        Authorized::admin(&request)?;
+        let req = request.into_inner();
+        let storage = self.storage.as_ref();
+        let requests = storage.list_emergency_purges(req.include_decided).await
+            .map_err(database_failure)?;
+        Ok(Response::new(ListEmergencyPurgesResult {
+            requests: requests.iter().map(emergency_purge_to_grpc).collect(),
+        }))
+    }
+
+    async fn decide_emergency_purge (
+        &self,
+        request: Request<DecideEmergencyPurgeArg>,
+    ) -> Result<Response<DecideEmergencyPurgeResult>, Status> {
+        Authorized::admin(&request)?;
+        let req = request.into_inner();
+        let storage = self.storage.as_ref();
+        let mut emergency = match storage.get_emergency_purge(req.request_id).await.map_err(database_failure)? {
+            Some(e) => e,
+            None => return Err(Status::not_found("No such emergency purge request")),
+        };
+        if emergency.decided.is_some() {
+            return Err(Status::failed_precondition("This request has already been decided."));
+        }
+        if req.approve {
+            // The owner may have cancelled the purge since, or it may have
+            // been carried out already. Either way, whichever of them deletes
+            // the order first decides what becomes of it.
+            let gone = || Status::failed_precondition("The purge this request is for no longer exists.");
+            let order = storage.list_purge_orders(&emergency.device_id).await
+                .map_err(database_failure)?
+                .into_iter()
+                .find(|o| o.id == emergency.purge_order_id)
+                .ok_or_else(gone)?;
+            // This bypasses the usual delay, in place of the delayed purge.
+            if !carry_out_purge(storage, &order).await.map_err(database_failure)? {
+                return Err(gone());
+            }
+        }
+        let now = Utc::now();
+        emergency.decided = Some(now);
+        emergency.approved = req.approve;
+        storage.write_emergency_purge(&emergency).await.map_err(database_failure)?;
+        let action = if req.approve { "approve-emergency-purge" } else { "deny-emergency-purge" };
+        storage.write_audit_record(&AuditRecord {
+            time: now,
+            action: String::from(action),
+            device_id: Some(emergency.device_id.clone()),
+            detail: format!("Request {}: {}", emergency.id, req.note),
+        }).await.map_err(database_failure)?;
+        info!("Emergency purge request {} decided: {}", emergency.id, action);
+        Ok(Response::new(DecideEmergencyPurgeResult { purged: req.approve }))
+    }
+
+    async fn list_audit_log (
+        &self,
+        request: Request<ListAuditLogArg>,
+    ) -> Result<Response<ListAuditLogResult>, Status> {
+        Authorized::admin(&request)?;
+        let req = request.into_inner();
+        let limit = match req.limit {
+            0 => DEFAULT_AUDIT_LOG_LIMIT,
+            l => l,
+        };
+        let storage = self.storage.as_ref();
+        let records = storage.list_audit_records(limit).await.map_err(database_failure)?;
+        Ok(Response::new(ListAuditLogResult {
+            records: records
+                .into_iter()
+                .map(|r| find_my_device::AuditRecord {
+                    time: Some(chrono_to_grpc_timestamp(&r.time)),
+                    action: r.action,
+                    device_id: r.device_id.unwrap_or_default(),
+                    detail: r.detail,
+                })
+                .collect(),
+        }))
+    }
+
+    async fn create_registration_key (
+        &self,
+        request: Request<CreateRegistrationKeyArg>,
+    ) -> Result<Response<CreateRegistrationKeyResult>, Status> {
+        Authorized::admin(&request)?;
+        let req = request.into_inner();
+        let now = Utc::now();
+        let not_after = match req.not_after.as_ref() {
+            Some(t) => Some(grpc_timestamp_to_chrono(t)
+                .ok_or_else(|| Status::invalid_argument("Invalid notAfter."))?),
+            None => None,
+        };
+        if not_after.map(|t| t <= now).unwrap_or(false) {
+            return Err(Status::invalid_argument("notAfter must be in the future."));
+        }
+        let mut raw_key = vec![0; REGISTRATION_KEY_LENGTH];
+        OsRng.fill_bytes(&mut raw_key);
+        let key = RegistrationKey {
+            digest: self.auth.digests.registration_key(&raw_key),
+            uses_remaining: req.uses.max(1),
+            created: now,
+            not_after,
+            device_permissions: req.device_permissions.unwrap_or_else(default_device_permissions),
+            note: req.note,
+        };
+        let storage = self.storage.as_ref();
+        storage.write_registration_key(&key).await.map_err(database_failure)?;
+        storage.write_audit_record(&AuditRecord {
+            time: now,
+            action: String::from("create-registration-key"),
+            device_id: None,
+            detail: format!("{} uses: {}", key.uses_remaining, key.note),
+        }).await.map_err(database_failure)?;
+        info!("Registration key created with {} uses.", key.uses_remaining);
+        Ok(Response::new(CreateRegistrationKeyResult {
+            registration_key: Some(registration_key_to_grpc(Some(raw_key), &key)),
+        }))
+    }
+
+    async fn list_registration_keys (
+        &self,
+        request: Request<ListRegistrationKeysArg>,
+    ) -> Result<Response<ListRegistrationKeysResult>, Status> {
+        Authorized::admin(&request)?;
+        let storage = self.storage.as_ref();
+        let keys = storage.list_registration_keys().await.map_err(database_failure)?;
+        Ok(Response::new(ListRegistrationKeysResult {
+            registration_keys: keys.iter().map(|k| registration_key_to_grpc(None, k)).collect(),
+        }))
+    }
+
+    async fn revoke_registration_key (
+        &self,
+        request: Request<RevokeRegistrationKeyArg>,
+    ) -> Result<Response<RevokeRegistrationKeyResult>, Status> {
+        Authorized::admin(&request)?;
+        let req = request.into_inner();
+        let storage = self.storage.as_ref();
+        let digest = if !req.registration_key.is_empty() {
+            self.auth.digests.registration_key(&req.registration_key)
+        } else {
+            req.registration_key_digest
+        };
+        let revoked = storage.delete_registration_key(&digest).await
+            .map_err(database_failure)?;
+        if revoked {
+            storage.write_audit_record(&AuditRecord {
+                time: Utc::now(),
+                action: String::from("revoke-registration-key"),
+                device_id: None,
+                detail: String::new(),
+            }).await.map_err(database_failure)?;
+            info!("Registration key revoked.");
+        }
+        Ok(Response::new(RevokeRegistrationKeyResult { revoked }))
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Harness, PausingStorage, ADMIN_TOKEN, all, only, code};
+    use crate::config::Config;
+    use crate::grpc::find_my_device::{PurgeLocationArg, CancelPurgeArg, IntroduceMyselfArg, SubmitLocationArg};
+    use crate::storage::LocationsFilter;
+    use tonic::Code;
+
+    #[tokio::test]
+    async fn emergency_purges_cannot_be_approved_once_cancelled () {
+        let h = Harness::new().await;
+        let token = h.valid_token(all()).await;
+        let purge = async || h.user().purge_location(Request::new(PurgeLocationArg {
+            token: token.clone(),
+            emergency: true,
+            ..Default::default()
+        })).await;
+        let decide = async |request_id: u64| h.admin().decide_emergency_purge(Request::new(DecideEmergencyPurgeArg {
+            admin_token: Vec::from(ADMIN_TOKEN),
+            request_id,
+            approve: true,
+            ..Default::default()
+        })).await;
+
+        let cancelled = purge().await.unwrap().into_inner();
+        let pending = h.storage.list_emergency_purges(false).await.unwrap();
+        assert_eq!(pending.len(), 1);
+        let request_id = pending[0].id;
+        h.user().cancel_purge(Request::new(CancelPurgeArg {
+            token: token.clone(),
+            purge_id: cancelled.purge_id,
+        })).await.unwrap();
+        assert!(h.storage.list_emergency_purges(false).await.unwrap().is_empty());
+        let closed = h.storage.get_emergency_purge(request_id).await.unwrap().unwrap();
+        assert!(closed.decided.is_some());
+        assert!(!closed.approved);
+        assert_eq!(decide(request_id).await.unwrap_err().code(), Code::FailedPrecondition);
+
+        // Even if the request was left open, it may not be approved once its
+        // purge order is gone.
+        let order = purge().await.unwrap().into_inner();
+        let request_id = h.storage.list_emergency_purges(false).await.unwrap()[0].id;
+        h.storage.delete_purge_order(order.purge_id).await.unwrap();
+        assert_eq!(decide(request_id).await.unwrap_err().code(), Code::FailedPrecondition);
+        assert!(h.storage.get_emergency_purge(request_id).await.unwrap().unwrap().decided.is_none());
+
+        let order = purge().await.unwrap().into_inner();
+        let request_id = h.storage.list_emergency_purges(false).await.unwrap()
+            .into_iter().find(|e| e.purge_order_id == order.purge_id).unwrap().id;
+        assert!(decide(request_id).await.unwrap().into_inner().purged);
+        assert!(h.storage.list_purge_orders(&h.device_id()).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn purges_cannot_be_cancelled_once_an_approval_has_claimed_them () {
+        let h = Harness::new().await;
+        let token = h.valid_token(all()).await;
+        h.device().submit_location(Request::new(SubmitLocationArg {
+            token: token.clone(),
+            notes: String::from("Home"),
+            ..Default::default()
+        })).await.unwrap();
+        let order = h.user().purge_location(Request::new(PurgeLocationArg {
+            token: token.clone(),
+            emergency: true,
+            ..Default::default()
+        })).await.unwrap().into_inner();
+        let request_id = h.storage.list_emergency_purges(false).await.unwrap()[0].id;
+
+        // The approval is paused after it has claimed the order, but before
+        // it has purged anything, and the owner cancels the purge then.
+        let storage = Arc::new(PausingStorage::new(h.storage.clone(), "purge_location"));
+        let admin = AdminServiceProvider {
+            storage: storage.clone(),
+            events: Arc::new(ServerEventQueues::default()),
+            auth: h.auth.clone(),
+        };
+        let mut request = Request::new(DecideEmergencyPurgeArg {
+            request_id,
+            approve: true,
+            ..Default::default()
+        });
+        request.extensions_mut().insert(Authorized::Admin);
+        let decision = tokio::spawn(async move { admin.decide_emergency_purge(request).await });
+        storage.reached().await;
+        let cancelled = h.user().cancel_purge(Request::new(CancelPurgeArg {
+            token,
+            purge_id: order.purge_id,
+        })).await.unwrap().into_inner();
+        assert_eq!(cancelled.cancelled, 0);
+        storage.resume();
+
+        assert!(decision.await.unwrap().unwrap().into_inner().purged);
+        let filter = LocationsFilter { limit: 100, since: None, until: None };
+        assert!(h.storage.list_locations(&h.device_id(), &filter).await.unwrap().is_empty());
+        assert!(h.storage.get_emergency_purge(request_id).await.unwrap().unwrap().approved);
+    }
+
+    #[tokio::test]
+    async fn closed_registration_requires_a_valid_key () {
+        let h = Harness::with_config(Config {
+            open_registration: false,
+            admin_token: Some(Vec::from(ADMIN_TOKEN)),
+            ..Default::default()
+        }).await;
+        let introduce = async |registration_key: Vec<u8>| h.device().introduce_myself(Request::new(IntroduceMyselfArg {
+            registration_key,
+            ..Default::default()
+        })).await;
+
+        assert_eq!(code(introduce(vec![]).await), Code::Unauthenticated);
+        assert_eq!(code(introduce(vec![ 0xFF; 16 ]).await), Code::Unauthenticated);
+
+        let key = h.admin().create_registration_key(Request::new(CreateRegistrationKeyArg {
+            admin_token: Vec::from(ADMIN_TOKEN),
+            uses: 2,
+            device_permissions: Some(only("write_locations")),
+            ..Default::default()
+        })).await.unwrap().into_inner().registration_key.unwrap();
+        for _ in 0..2 {
+            let intro = introduce(key.registration_key.clone()).await.unwrap().into_inner();
+            let info = h.storage.get_token_info(&h.auth.digests.token(&intro.your_token)).await.unwrap().unwrap();
+            assert_eq!(info.permissions, only("write_locations"));
+        }
+        assert_eq!(code(introduce(key.registration_key.clone()).await), Code::Unauthenticated);
+
+        let single_use = h.admin().create_registration_key(Request::new(CreateRegistrationKeyArg {
+            admin_token: Vec::from(ADMIN_TOKEN),
+            ..Default::default()
+        })).await.unwrap().into_inner().registration_key.unwrap();
+        let revoked = h.admin().revoke_registration_key(Request::new(RevokeRegistrationKeyArg {
+            admin_token: Vec::from(ADMIN_TOKEN),
+            registration_key: single_use.registration_key.clone(),
+            ..Default::default()
+        })).await.unwrap().into_inner();
+        assert!(revoked.revoked);
+        assert_eq!(code(introduce(single_use.registration_key).await), Code::Unauthenticated);
+    }
+
+}
@@ -0,0 +1,546 @@
+//! Archives of everything in a storage, for backups and for moving from one
+//! storage backend to another.
+//!
+//! An archive begins with `MAGIC` and a little-endian `u32` version, and then
+//! consists of records framed as in the journal (see `storage::journal`), each
+//! an `ArchiveEntry`. Each device comes with its tokens, its locations, oldest
+//! first, and its purge orders, and what does not belong to any one device
+//! comes last. The final entry is a `Summary` of the others: how many there
+//! are of each kind, and a SHA-256 digest of all of them.
+//!
+//! Locations and data keys are archived just as they are stored, encrypted
+//! and wrapped by the master key, so an archive is no easier to read than the
+//! storage it came from, and is only of use with the same master key and
+//! digest key.
+use crate::storage::{Storage, DeviceId, LocationsFilter};
+use crate::storage::journal::{frame, Token, HEADER_LENGTH};
+use crate::storage::records::{
+    LocationRecord,
+    IntroRecord,
+    WipeOrderRecord,
+    ExcommunicationRecord,
+    PurgeOrderRecord,
+    EmergencyPurgeRecord,
+    AuditRecordRecord,
+    RegistrationKeyRecord,
+};
+use chrono::Duration;
+use prost::{Message, Oneof};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+const MAGIC: &[u8; 8] = b"FMXARCHV";
+
+/// The version of the archive format written, which must be incremented
+/// whenever the format changes in a way that older readers would misread.
+pub const VERSION: u32 = 1;
+
+/// How many locations are read from storage at a time.
+const LOCATIONS_PAGE: u32 = 1000;
+
+/// Everything stored about a device, other than its tokens, locations and
+/// purge orders.
+#[derive(Clone, PartialEq, Message)]
+pub struct Device {
+    #[prost(bytes = "vec", tag = "1")]
+    pub device_id: Vec<u8>,
+    #[prost(message, optional, tag = "2")]
+    pub intro: Option<IntroRecord>,
+    #[prost(bytes = "vec", optional, tag = "3")]
+    pub wrapped_key: Option<Vec<u8>>,
+    #[prost(message, optional, tag = "4")]
+    pub wipe_order: Option<WipeOrderRecord>,
+    #[prost(message, optional, tag = "5")]
+    pub excommunication: Option<ExcommunicationRecord>,
+}
+
+/// How many entries of each kind an archive holds, and a SHA-256 digest of
+/// them, in order.
+#[derive(Clone, PartialEq, Message)]
+pub struct Summary {
+    #[prost(uint64, tag = "1")]
+    pub devices: u64,
+    #[prost(uint64, tag = "2")]
+    pub tokens: u64,
+    #[prost(uint64, tag = "3")]
+    pub locations: u64,
+    #[prost(uint64, tag = "4")]
+    pub purge_orders: u64,
+    #[prost(uint64, tag = "5")]
+    pub emergency_purges: u64,
+    #[prost(uint64, tag = "6")]
+    pub audit_records: u64,
+    #[prost(uint64, tag = "7")]
+    pub registration_keys: u64,
+    #[prost(bytes = "vec", tag = "8")]
+    pub sha256: Vec<u8>,
+}
+
+impl fmt::Display for Summary {
+
+    fn fmt (&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} devices, {} tokens, {} locations, {} purge orders, {} emergency purges, {} audit records and {} registration keys",
+            self.devices,
+            self.tokens,
+            self.locations,
+            self.purge_orders,
+            self.emergency_purges,
+            self.audit_records,
+            self.registration_keys,
+        )
+    }
+
+}
+
+#[derive(Clone, PartialEq, Oneof)]
+pub enum Entry {
+    #[prost(message, tag = "1")]
+    Device(Device),
+    #[prost(message, tag = "2")]
+    Token(Token),
+
+    /// A location of the device in the last `Device` entry.
+    #[prost(message, tag = "3")]
+    Location(LocationRecord),
+    #[prost(message, tag = "4")]
+    PurgeOrder(PurgeOrderRecord),
+    #[prost(message, tag = "5")]
+    EmergencyPurge(EmergencyPurgeRecord),
+    #[prost(message, tag = "6")]
+    AuditRecord(AuditRecordRecord),
+    #[prost(message, tag = "7")]
+    RegistrationKey(RegistrationKeyRecord),
+    #[prost(message, tag = "8")]
+    Summary(Summary),
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ArchiveEntry {
+    #[prost(oneof = "Entry", tags = "1, 2, 3, 4, 5, 6, 7, 8")]
+    entry: Option<Entry>,
+}
+
+/// Counts `entry` in `summary`, unless it is a summary itself.
+fn count (summary: &mut Summary, entry: &Entry) {
+    let n = match entry {
+        Entry::Device(_) => &mut summary.devices,
+        Entry::Token(_) => &mut summary.tokens,
+        Entry::Location(_) => &mut summary.locations,
+        Entry::PurgeOrder(_) => &mut summary.purge_orders,
+        Entry::EmergencyPurge(_) => &mut summary.emergency_purges,
+        Entry::AuditRecord(_) => &mut summary.audit_records,
+        Entry::RegistrationKey(_) => &mut summary.registration_keys,
+        Entry::Summary(_) => return,
+    };
+    *n += 1;
+}
+
+struct Writer <W: Write> {
+    out: W,
+    hasher: Sha256,
+    summary: Summary,
+}
+
+impl <W: Write> Writer<W> {
+
+    fn new (mut out: W) -> anyhow::Result<Self> {
+        out.write_all(MAGIC)?;
+        out.write_all(&VERSION.to_le_bytes())?;
+        Ok(Writer { out, hasher: Sha256::new(), summary: Summary::default() })
+    }
+
+    fn write_payload (&mut self, entry: Entry) -> anyhow::Result<Vec<u8>> {
+        let payload = ArchiveEntry { entry: Some(entry) }.encode_to_vec();
+        self.out.write_all(&frame(&payload)?)?;
+        Ok(payload)
+    }
+
+    fn write (&mut self, entry: Entry) -> anyhow::Result<()> {
+        count(&mut self.summary, &entry);
+        let payload = self.write_payload(entry)?;
+        self.hasher.update(&payload);
+        Ok(())
+    }
+
+    /// Writes the summary, and returns it along with the output.
+    fn finish (mut self) -> anyhow::Result<(W, Summary)> {
+        let mut summary = self.summary.clone();
+        summary.sha256 = self.hasher.clone().finalize().to_vec();
+        self.write_payload(Entry::Summary(summary.clone()))?;
+        Ok((self.out, summary))
+    }
+
+}
+
+struct Reader <R: Read> {
+    input: R,
+    hasher: Sha256,
+    summary: Summary,
+}
+
+impl <R: Read> Reader<R> {
+
+    fn new (mut input: R) -> anyhow::Result<Self> {
+        let mut header = [ 0; 12 ];
+        input.read_exact(&mut header)
+            .map_err(|_| anyhow::anyhow!("This is not an archive."))?;
+        if &header[..8] != MAGIC {
+            anyhow::bail!("This is not an archive.");
+        }
+        let version = u32::from_le_bytes(header[8..].try_into()?);
+        if version > VERSION {
+            anyhow::bail!("The archive has version {}, but this server only supports up to version {}.", version, VERSION);
+        }
+        Ok(Reader { input, hasher: Sha256::new(), summary: Summary::default() })
+    }
+
+    /// Reads the next entry. The summary at the end is checked against what
+    /// was read before it, and must be followed by nothing else.
+    fn next (&mut self) -> anyhow::Result<Entry> {
+        let truncated = |e: io::Error| match e.kind() {
+            io::ErrorKind::UnexpectedEof => anyhow::anyhow!("The archive is truncated."),
+            _ => e.into(),
+        };
+        let mut header = [ 0; HEADER_LENGTH ];
+        self.input.read_exact(&mut header).map_err(truncated)?;
+        let len = u32::from_le_bytes(header[0..4].try_into()?) as usize;
+        let crc = u32::from_le_bytes(header[4..8].try_into()?);
+        // The length is not checked until the payload is, so the payload is
+        // only given as much space as it turns out to take.
+        let mut payload = Vec::new();
+        (&mut self.input).take(len as u64).read_to_end(&mut payload)?;
+        if payload.len() < len {
+            anyhow::bail!("The archive is truncated.");
+        }
+        if crc32fast::hash(&payload) != crc {
+            anyhow::bail!("The archive is damaged: a record fails its checksum.");
+        }
+        let entry = ArchiveEntry::decode(payload.as_slice())?.entry
+            .ok_or_else(|| anyhow::anyhow!("The archive has an entry of an unknown kind."))?;
+        if let Entry::Summary(summary) = &entry {
+            let mut expected = self.summary.clone();
+            expected.sha256 = self.hasher.clone().finalize().to_vec();
+            if *summary != expected {
+                anyhow::bail!("The archive is damaged: it holds {}, but its summary says {}.", expected, summary);
+            }
+            if self.input.read(&mut [ 0; 1 ])? != 0 {
+                anyhow::bail!("The archive is damaged: something follows its summary.");
+            }
+            return Ok(entry);
+        }
+        count(&mut self.summary, &entry);
+        self.hasher.update(&payload);
+        Ok(entry)
+    }
+
+}
+
+/// Writes everything in `storage` to `out` as an archive.
+async fn dump <S: Storage, W: Write> (storage: &S, out: W) -> anyhow::Result<(W, Summary)> {
+    let mut writer = Writer::new(out)?;
+    for device_id in storage.list_devices().await? {
+        writer.write(Entry::Device(Device {
+            intro: storage.get_intro(&device_id).await?.as_ref().map(IntroRecord::from),
+            wrapped_key: storage.get_device_key(&device_id).await?,
+            wipe_order: storage.get_wipe_order(&device_id).await?.as_ref().map(WipeOrderRecord::from),
+            excommunication: storage.get_excommunication(&device_id).await?.as_ref().map(ExcommunicationRecord::from),
+            device_id: device_id.clone(),
+        }))?;
+        let mut tokens = storage.list_tokens(&device_id).await?;
+        tokens.sort_by(|a, b| a.0.cmp(&b.0));
+        for (digest, entry) in tokens.iter() {
+            writer.write(Entry::Token(Token { digest: digest.clone(), entry: Some(entry.into()) }))?;
+        }
+        let mut filter = LocationsFilter {
+            limit: LOCATIONS_PAGE,
+            since: None,
+            until: None,
+        };
+        loop {
+            let locs = storage.list_locations(&device_id, &filter).await?;
+            for loc in locs.iter() {
+                writer.write(Entry::Location(loc.into()))?;
+            }
+            match locs.last() {
+                Some(last) if locs.len() == LOCATIONS_PAGE as usize => {
+                    filter.since = Some(last.update_time + Duration::nanoseconds(1));
+                },
+                _ => break,
+            };
+        }
+        let mut orders = storage.list_purge_orders(&device_id).await?;
+        orders.sort_by_key(|o| o.id);
+        for order in orders.iter() {
+            writer.write(Entry::PurgeOrder(order.into()))?;
+        }
+    }
+    let mut requests = storage.list_emergency_purges(true).await?;
+    requests.sort_by_key(|r| r.id);
+    for request in requests.iter() {
+        writer.write(Entry::EmergencyPurge(request.into()))?;
+    }
+    for record in storage.list_audit_records(u32::MAX).await?.iter().rev() {
+        writer.write(Entry::AuditRecord(record.into()))?;
+    }
+    let mut keys = storage.list_registration_keys().await?;
+    keys.sort_by(|a, b| a.digest.cmp(&b.digest));
+    for key in keys.iter() {
+        writer.write(Entry::RegistrationKey(key.into()))?;
+    }
+    writer.finish()
+}
+
+/// Writes everything in `storage` to a new archive at `path`.
+pub async fn export <S: Storage> (storage: &S, path: &Path) -> anyhow::Result<Summary> {
+    let file = OpenOptions::new().write(true).create_new(true).open(path)
+        .map_err(|e| anyhow::anyhow!("Could not create {}: {}", path.display(), e))?;
+    let written = async {
+        let (out, summary) = dump(storage, BufWriter::new(file)).await?;
+        out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        anyhow::Ok(summary)
+    }.await;
+    if written.is_err() {
+        let _ = fs::remove_file(path);
+    }
+    written
+}
+
+fn open (path: &Path) -> anyhow::Result<Reader<BufReader<File>>> {
+    let file = File::open(path)
+        .map_err(|e| anyhow::anyhow!("Could not open {}: {}", path.display(), e))?;
+    Reader::new(BufReader::new(file))
+}
+
+/// Reads the archive at `path` into `storage`, which must be empty, and then
+/// checks that `storage` holds exactly what the archive does.
+pub async fn import <S: Storage> (storage: &S, path: &Path) -> anyhow::Result<Summary> {
+    // The whole archive is checked first, so that nothing is imported from a
+    // damaged one.
+    let mut reader = open(path)?;
+    let expected = loop {
+        if let Entry::Summary(summary) = reader.next()? {
+            break summary;
+        }
+    };
+    if !storage.list_devices().await?.is_empty()
+        || !storage.list_registration_keys().await?.is_empty()
+        || !storage.list_emergency_purges(true).await?.is_empty()
+        || !storage.list_audit_records(1).await?.is_empty() {
+        anyhow::bail!("Archives can only be imported into empty storage.");
+    }
+
+    let mut reader = open(path)?;
+    let mut device_id: Option<DeviceId> = None;
+    loop {
+        match reader.next()? {
+            Entry::Device(device) => {
+                if let Some(intro) = device.intro {
+                    storage.restore_intro(&device.device_id, &intro.into()).await?;
+                }
+                if let Some(key) = device.wrapped_key {
+                    storage.insert_device_key(&device.device_id, &key).await?;
+                }
+                if let Some(order) = device.wipe_order {
+                    storage.write_wipe_order(&device.device_id, &order.try_into()?).await?;
+                }
+                if let Some(record) = device.excommunication {
+                    storage.excommunicate(&device.device_id, &record.try_into()?).await?;
+                }
+                device_id = Some(device.device_id);
+            },
+            Entry::Token(token) => {
+                let entry = token.entry.ok_or_else(|| anyhow::anyhow!("The archive has a token without an entry."))?;
+                storage.write_token(&token.digest, &entry.try_into()?).await?;
+            },
+            Entry::Location(loc) => {
+                let device_id = device_id.as_ref()
+                    .ok_or_else(|| anyhow::anyhow!("The archive has a location before any device."))?;
+                storage.write_location(device_id, &loc.try_into()?).await?;
+            },
+            Entry::PurgeOrder(order) => storage.write_purge_order(&order.try_into()?).await?,
+            Entry::EmergencyPurge(request) => storage.write_emergency_purge(&request.try_into()?).await?,
+            Entry::AuditRecord(record) => storage.write_audit_record(&record.try_into()?).await?,
+            Entry::RegistrationKey(key) => storage.write_registration_key(&key.try_into()?).await?,
+            Entry::Summary(_) => break,
+        };
+    }
+
+    let (_, imported) = dump(storage, io::sink()).await?;
+    if imported != expected {
+        anyhow::bail!("After importing, storage holds {}, but the archive holds {}.", imported, expected);
+    }
+    Ok(imported)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grpc::find_my_device::{IntroduceMyselfArg, Permissions};
+    use crate::storage::{
+        IntroInsertion,
+        StoredLocation,
+        WipeOrder,
+        Excommunication,
+        PurgeOrder,
+        EmergencyPurgeRequest,
+        AuditRecord,
+        RegistrationKey,
+    };
+    use crate::storage::memory::MemoryStorage;
+    use crate::storage::redb::RedbStorage;
+    use crate::storage::sqlite::SqliteStorage;
+    use chrono::prelude::*;
+    use std::path::PathBuf;
+
+    fn temp_path (name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("fmx-archive-{}-{}", rand::random::<u64>(), name))
+    }
+
+    fn remove (paths: &[ &Path ]) {
+        for path in paths {
+            for suffix in [ "", "-wal", "-shm" ] {
+                let mut p = path.as_os_str().to_owned();
+                p.push(suffix);
+                let _ = fs::remove_file(p);
+            }
+        }
+    }
+
+    async fn fill (storage: &MemoryStorage) {
+        let now = Utc::now();
+        let (a, b): (DeviceId, DeviceId) = (vec![ 1; 32 ], vec![ 2; 32 ]);
+        storage.write_intro(&IntroInsertion {
+            device_id: &a,
+            token_digest: &vec![ 10; 32 ],
+            permissions: &Permissions { write_locations: true, ..Default::default() },
+            remote_addr: Some("192.0.2.1:1234".parse().unwrap()),
+            registration_key_digest: &vec![],
+            arg: &IntroduceMyselfArg { remote_wipe_enabled: true, ..Default::default() },
+        }).await.unwrap();
+        storage.write_token(&vec![ 11; 32 ], &crate::storage::TokenEntry {
+            device_id: a.clone(),
+            permissions: Permissions { read_locations: true, ..Default::default() },
+            not_before: now,
+            not_after: Some(now + Duration::days(1)),
+        }).await.unwrap();
+        // More than a page of locations.
+        for i in 0..LOCATIONS_PAGE as i64 + 5 {
+            storage.write_location(&a, &StoredLocation {
+                update_time: now + Duration::milliseconds(i),
+                ciphertext: vec![ i as u8; 20 ],
+            }).await.unwrap();
+        }
+        storage.insert_device_key(&a, b"wrapped").await.unwrap();
+        storage.write_wipe_order(&a, &WipeOrder { requested: now, delivered: Some(now), acknowledged: None }).await.unwrap();
+        storage.write_intro(&IntroInsertion {
+            device_id: &b,
+            token_digest: &vec![ 20; 32 ],
+            permissions: &Permissions { write_locations: true, nearby: true, ..Default::default() },
+            remote_addr: None,
+            registration_key_digest: &vec![],
+            arg: &IntroduceMyselfArg::default(),
+        }).await.unwrap();
+        storage.excommunicate(&b, &Excommunication { time: now, reason: String::from("spam"), by_administrator: true }).await.unwrap();
+        storage.write_purge_order(&PurgeOrder {
+            id: u64::MAX,
+            device_id: a.clone(),
+            requested: now,
+            since: Some(now),
+            execute_at: now + Duration::days(1),
+        }).await.unwrap();
+        storage.write_emergency_purge(&EmergencyPurgeRequest {
+            id: 5,
+            device_id: a.clone(),
+            purge_order_id: u64::MAX,
+            since: Some(now),
+            requested: now,
+            decided: None,
+            approved: false,
+        }).await.unwrap();
+        for action in [ "first", "second" ] {
+            storage.write_audit_record(&AuditRecord {
+                time: now,
+                action: String::from(action),
+                device_id: Some(b.clone()),
+                detail: String::new(),
+            }).await.unwrap();
+        }
+        storage.write_registration_key(&RegistrationKey {
+            digest: vec![ 30 ],
+            uses_remaining: 3,
+            created: now,
+            not_after: None,
+            device_permissions: Permissions { write_locations: true, ..Default::default() },
+            note: String::from("family"),
+        }).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn archives_move_everything_between_backends () {
+        let (first, second, redb_path, sqlite_path) = (temp_path("1"), temp_path("2"), temp_path("redb"), temp_path("sqlite"));
+        let memory = MemoryStorage::new();
+        fill(&memory).await;
+        let exported = export(&memory, &first).await.unwrap();
+        assert_eq!(exported.devices, 2);
+        assert_eq!(exported.tokens, 3);
+        assert_eq!(exported.locations, LOCATIONS_PAGE as u64 + 5);
+        assert_eq!(exported.audit_records, 2);
+        assert!(export(&memory, &first).await.is_err(), "An existing archive was overwritten.");
+
+        let redb = RedbStorage::open(&redb_path).unwrap();
+        assert_eq!(import(&redb, &first).await.unwrap(), exported);
+        assert_eq!(export(&redb, &second).await.unwrap(), exported);
+        let sqlite = SqliteStorage::open(&sqlite_path).unwrap();
+        assert_eq!(import(&sqlite, &second).await.unwrap(), exported);
+
+        let token = sqlite.get_token_info(&vec![ 20; 32 ]).await.unwrap().unwrap();
+        assert!(token.permissions.write_locations);
+        assert!(token.permissions.nearby);
+        assert_eq!(sqlite.get_excommunication(&vec![ 2; 32 ]).await.unwrap().unwrap().reason, "spam");
+        assert_eq!(sqlite.get_device_key(&vec![ 1; 32 ]).await.unwrap().unwrap(), b"wrapped");
+        assert_eq!(sqlite.list_audit_records(1).await.unwrap()[0].action, "second");
+        assert!(import(&sqlite, &second).await.is_err(), "An archive was imported into storage that was not empty.");
+        remove(&[ &first, &second, &redb_path, &sqlite_path ]);
+    }
+
+    #[tokio::test]
+    async fn damaged_archives_are_refused () {
+        let path = temp_path("damaged");
+        let memory = MemoryStorage::new();
+        fill(&memory).await;
+        export(&memory, &path).await.unwrap();
+        let intact = fs::read(&path).unwrap();
+
+        let mut damaged = intact.clone();
+        damaged[intact.len() / 2] ^= 1;
+        fs::write(&path, &damaged).unwrap();
+        let target = MemoryStorage::new();
+        assert!(import(&target, &path).await.is_err());
+        assert!(target.list_devices().await.unwrap().is_empty(), "Part of a damaged archive was imported.");
+
+        fs::write(&path, &intact[..intact.len() - 1]).unwrap();
+        assert!(import(&target, &path).await.is_err());
+
+        let mut newer = intact.clone();
+        newer[8..12].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        fs::write(&path, &newer).unwrap();
+        assert!(import(&target, &path).await.unwrap_err().to_string().contains("version"));
+
+        // Even whole records after the summary are refused.
+        let mut extended = intact.clone();
+        extended.extend_from_slice(&intact[12..]);
+        fs::write(&path, &extended).unwrap();
+        assert!(import(&target, &path).await.unwrap_err().to_string().contains("follows its summary"));
+        assert!(target.list_devices().await.unwrap().is_empty());
+
+        fs::write(&path, &intact).unwrap();
+        import(&target, &path).await.unwrap();
+        remove(&[ &path ]);
+    }
+
+}
@@ -0,0 +1,743 @@
+use crate::config::Config;
+use crate::digest::{Digester, constant_time_eq};
+use crate::grpc::find_my_device::Permissions;
+use crate::ratelimit::{RateLimiter, Subject};
+use crate::storage::{Storage, Token, TokenDigest, TokenEntry, SecretKey, DeviceId};
+use tonic::{Request, Status};
+use warp::http::StatusCode;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::Arc;
+use tokio::sync::watch;
+use log::{debug, error};
+use chrono::prelude::*;
+
+/// Every operation that is authorized by a token. The permission that each
+/// one requires is decided here, and only here.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    // DeviceService
+    SubmitLocation,
+    StreamServerEvents,
+    AcknowledgeWipe,
+
+    // UserService
+    ListTokens,
+    PurgeLocation,
+    CancelPurge,
+    Wipe,
+    ListLocations,
+    StreamLocation,
+    RequestLocation,
+    GetStorageInfo,
+    GetDeviceStatus,
+
+    // Web UI
+    ViewLocations,
+}
+
+impl Operation {
+
+    pub fn permitted (&self, permissions: &Permissions) -> bool {
+        match self {
+            Operation::SubmitLocation
+            | Operation::StreamServerEvents
+            | Operation::AcknowledgeWipe
+            | Operation::PurgeLocation => permissions.write_locations,
+            Operation::ListTokens => permissions.list_tokens,
+            Operation::CancelPurge => permissions.cancel_purge,
+            Operation::Wipe => permissions.wipe,
+            Operation::ListLocations
+            | Operation::StreamLocation
+            | Operation::RequestLocation
+            | Operation::ViewLocations => permissions.read_locations,
+            Operation::GetStorageInfo
+            | Operation::GetDeviceStatus => permissions.stats,
+        }
+    }
+
+    /// Whether this operation changes the data stored for a device, which is
+    /// not allowed once a device has been excommunicated.
+    pub fn is_write (&self) -> bool {
+        matches!(self,
+            Operation::SubmitLocation
+            | Operation::AcknowledgeWipe
+            | Operation::PurgeLocation)
+    }
+
+    /// Whether this operation is how a device learns that it has been
+    /// excommunicated, and so must still be allowed once it has been.
+    pub fn tells_excommunication (&self) -> bool {
+        matches!(self,
+            Operation::SubmitLocation
+            | Operation::StreamServerEvents)
+    }
+
+}
+
+/// The credentials that a request must present.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    /// Anyone may make the request, subject to rate limiting.
+    Anyone,
+    /// Anyone may make the request, subject to the stricter rate limiting of
+    /// new devices.
+    Introduction,
+    /// A token with the permission the operation requires.
+    Token(Operation),
+    /// The secret key of a device.
+    SecretKey,
+    /// The secret key of a device if one is given, or otherwise a token with
+    /// the permission the operation requires.
+    TokenOrSecretKey(Operation),
+    /// The administrator token.
+    Admin,
+}
+
+/// The credentials presented with a request. Whichever of these a request
+/// does not carry are left empty.
+#[derive(Debug, Clone, Default)]
+pub struct Credentials {
+    pub token: Token,
+    pub secret_key: SecretKey,
+    pub admin_token: Vec<u8>,
+}
+
+/// What the credentials presented with a request were found to be. This is
+/// added to the extensions of every request that is let through.
+#[derive(Debug, Clone)]
+pub enum Authorized {
+    Anyone,
+    Token(TokenEntry),
+    SecretKey(DeviceId),
+    Admin,
+}
+
+impl Authorized {
+
+    /// Returns what the request was authorized as, which is only missing if
+    /// the service was not wrapped in the authorization layer.
+    pub fn of <T> (request: &Request<T>) -> Result<Authorized, NotAuthorized> {
+        match request.extensions().get::<Authorized>() {
+            Some(a) => Ok(a.clone()),
+            None => {
+                error!("A request reached a service without being authorized.");
+                Err(NotAuthorized)
+            },
+        }
+    }
+
+    /// Returns the entry of the token the request was authorized by.
+    pub fn token <T> (request: &Request<T>) -> Result<TokenEntry, NotAuthorized> {
+        match Authorized::of(request)? {
+            Authorized::Token(t) => Ok(t),
+            a => Err(unexpected(a)),
+        }
+    }
+
+    /// Returns the device whose token or secret key authorized the request.
+    pub fn device <T> (request: &Request<T>) -> Result<DeviceId, NotAuthorized> {
+        match Authorized::of(request)? {
+            Authorized::Token(t) => Ok(t.device_id),
+            Authorized::SecretKey(device_id) => Ok(device_id),
+            a => Err(unexpected(a)),
+        }
+    }
+
+    /// Checks that the request was authorized by the administrator token.
+    pub fn admin <T> (request: &Request<T>) -> Result<(), NotAuthorized> {
+        match Authorized::of(request)? {
+            Authorized::Admin => Ok(()),
+            a => Err(unexpected(a)),
+        }
+    }
+
+}
+
+fn unexpected (authorized: Authorized) -> NotAuthorized {
+    error!("A request was authorized as {:?}, which its service does not accept.", authorized);
+    NotAuthorized
+}
+
+/// A request reached a service without the `Authorized` that it needs, which
+/// would be a mistake in how the service was set up.
+#[derive(Debug)]
+pub struct NotAuthorized;
+
+impl From<NotAuthorized> for Status {
+
+    fn from (_: NotAuthorized) -> Self {
+        Status::internal("Not authorized.")
+    }
+
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    /// The token is missing or unknown.
+    Unauthenticated,
+    NotYetValid,
+    Expired,
+    /// The token is valid, but lacks the permission needed.
+    Forbidden,
+    /// The device has been excommunicated, so its tokens may not be used to
+    /// write anything.
+    Excommunicated,
+    /// The client, token or secret key has made too many requests.
+    RateLimited,
+    /// The client has presented too many invalid credentials.
+    LockedOut,
+    Database(anyhow::Error),
+}
+
+impl fmt::Display for AuthError {
+
+    fn fmt (&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::Unauthenticated => f.write_str("Unauthenticated"),
+            AuthError::NotYetValid => f.write_str("Token is not valid yet"),
+            AuthError::Expired => f.write_str("Token expired"),
+            AuthError::Forbidden => f.write_str("Forbidden"),
+            AuthError::Excommunicated => f.write_str("Excommunicated"),
+            AuthError::RateLimited => f.write_str("Too many requests"),
+            AuthError::LockedOut => f.write_str("Too many invalid attempts. Try again later."),
+            AuthError::Database(_) => f.write_str("Database failure."),
+        }
+    }
+
+}
+
+impl AuthError {
+
+    pub fn http_status (&self) -> StatusCode {
+        match self {
+            AuthError::Unauthenticated
+            | AuthError::NotYetValid
+            | AuthError::Expired => StatusCode::UNAUTHORIZED,
+            AuthError::Forbidden
+            | AuthError::Excommunicated => StatusCode::FORBIDDEN,
+            AuthError::RateLimited
+            | AuthError::LockedOut => StatusCode::TOO_MANY_REQUESTS,
+            AuthError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+}
+
+impl From<AuthError> for Status {
+
+    fn from (e: AuthError) -> Self {
+        match e {
+            AuthError::Unauthenticated
+            | AuthError::NotYetValid
+            | AuthError::Expired => Status::unauthenticated(e.to_string()),
+            AuthError::Forbidden
+            | AuthError::Excommunicated => Status::permission_denied(e.to_string()),
+            AuthError::RateLimited
+            | AuthError::LockedOut => Status::resource_exhausted(e.to_string()),
+            AuthError::Database(e) => database_failure(e),
+        }
+    }
+
+}
+
+pub fn database_failure (e: anyhow::Error) -> Status {
+    error!("Database failure: {:?}", e);
+    Status::internal("Database failure.")
+}
+
+pub fn is_expired (token_info: &TokenEntry) -> bool {
+    token_info.not_after.map(|na| Utc::now() >= na).unwrap_or(false)
+}
+
+/// Resolves the tokens and secret keys presented by clients, and decides
+/// whether they may perform the operation requested. This is used by the
+/// `AuthLayer` that wraps all of the gRPC services and the web UI, so that
+/// these rules live in one place.
+#[derive(Clone)]
+pub struct Authorizer {
+    pub config: Arc<Config>,
+    pub digests: Digester,
+    pub limiter: Arc<RateLimiter>,
+
+    /// Incremented whenever tokens are revoked, so that long-lived streams
+    /// know to check whether the token they were opened with still exists.
+    revocations: Arc<watch::Sender<u64>>,
+}
+
+impl Authorizer {
+
+    pub fn new (config: Arc<Config>) -> Self {
+        let (revocations, _) = watch::channel(0);
+        Authorizer {
+            digests: Digester::new(config.digest_key.clone()),
+            limiter: Arc::new(RateLimiter::new(config.rate_limits.clone())),
+            config,
+            revocations: Arc::new(revocations),
+        }
+    }
+
+    pub fn notify_revoked (&self) {
+        self.revocations.send_modify(|n| *n += 1);
+    }
+
+    pub fn revocations (&self) -> watch::Receiver<u64> {
+        self.revocations.subscribe()
+    }
+
+    /// Checks that `client` is neither locked out nor over its rate limit. The
+    /// `authorize` methods do this first, but requests that are not otherwise
+    /// authorized must call this themselves.
+    pub fn admit (&self, client: Option<IpAddr>) -> Result<(), AuthError> {
+        if let Some(ip) = client {
+            if self.limiter.is_locked_out(&ip) {
+                return Err(AuthError::LockedOut);
+            }
+            if !self.limiter.allow(Subject::Ip(ip)) {
+                return Err(AuthError::RateLimited);
+            }
+        }
+        Ok(())
+    }
+
+    /// Counts an invalid credential presented by `client` towards locking it
+    /// out, and returns the error to reply with.
+    pub fn invalid_attempt (&self, client: Option<IpAddr>) -> AuthError {
+        if let Some(ip) = client {
+            self.limiter.record_invalid_attempt(ip);
+        }
+        AuthError::Unauthenticated
+    }
+
+    /// Checks that `client` may call `IntroduceMyself`, which creates a new
+    /// device, and so is limited more strictly than anything else.
+    pub fn admit_introduction (&self, client: Option<IpAddr>) -> Result<(), AuthError> {
+        self.admit(client)?;
+        match client {
+            Some(ip) if !self.limiter.allow(Subject::Introductions(ip)) => Err(AuthError::RateLimited),
+            _ => Ok(()),
+        }
+    }
+
+    /// The entry used for the testing token, which may only submit locations.
+    fn testing_token_entry (&self) -> TokenEntry {
+        TokenEntry{
+            not_before: DateTime::<Utc>::MIN_UTC,
+            not_after: None,
+            device_id: self.digests.device_id(&self.config.testing_token),
+            permissions: Permissions{
+                write_locations: true,
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Looks up `token` and checks that it is currently valid, that it has the
+    /// permission `operation` requires, and that it is not being used to write
+    /// to an excommunicated device.
+    pub async fn authorize <S: Storage> (
+        &self,
+        storage: &S,
+        operation: Operation,
+        token: &Token,
+        client: Option<IpAddr>,
+    ) -> Result<TokenEntry, AuthError> {
+        self.admit(client)?;
+        if token.is_empty() {
+            return Err(self.invalid_attempt(client));
+        }
+        let digest = self.digests.token(token);
+        let using_test_token = operation == Operation::SubmitLocation
+            && !self.config.testing_token.is_empty()
+            && constant_time_eq(token, &self.config.testing_token);
+        let token_info = if using_test_token {
+            self.testing_token_entry()
+        } else {
+            match storage.get_token_info(&digest).await.map_err(AuthError::Database)? {
+                Some(t) => t,
+                None => return Err(self.invalid_attempt(client)),
+            }
+        };
+        if !self.limiter.allow(Subject::Token(digest)) {
+            return Err(AuthError::RateLimited);
+        }
+        if Utc::now() < token_info.not_before {
+            return Err(AuthError::NotYetValid);
+        }
+        if is_expired(&token_info) {
+            return Err(AuthError::Expired);
+        }
+        if !operation.permitted(&token_info.permissions) {
+            debug!("Token lacks the permission required for {:?}.", operation);
+            return Err(AuthError::Forbidden);
+        }
+        // Excommunication takes away the ability to write, whatever the
+        // token's permissions. The reply to `SubmitLocation` and the
+        // `EXCOMMUNICATED` event tell the device that it has been
+        // excommunicated, so those are left to their handlers.
+        if operation.is_write() && !operation.tells_excommunication()
+            && storage.get_excommunication(&token_info.device_id).await.map_err(AuthError::Database)?.is_some() {
+            return Err(AuthError::Excommunicated);
+        }
+        Ok(token_info)
+    }
+
+    /// Checks that `secret_key` identifies a device known to this server, and
+    /// returns the ID of that device. The holder of a secret key may do
+    /// anything with that device's data.
+    pub async fn authorize_secret_key <S: Storage> (
+        &self,
+        storage: &S,
+        secret_key: &SecretKey,
+        client: Option<IpAddr>,
+    ) -> Result<DeviceId, AuthError> {
+        self.admit(client)?;
+        if secret_key.is_empty() {
+            return Err(self.invalid_attempt(client));
+        }
+        let device_id = self.digests.device_id(secret_key);
+        if storage.get_intro(&device_id).await.map_err(AuthError::Database)?.is_none() {
+            return Err(self.invalid_attempt(client));
+        }
+        if !self.limiter.allow(Subject::SecretKey(device_id.clone())) {
+            return Err(AuthError::RateLimited);
+        }
+        Ok(device_id)
+    }
+
+    /// Checks that `token` is the administrator token configured for this
+    /// server.
+    pub fn authorize_admin (&self, token: &[u8], client: Option<IpAddr>) -> Result<(), AuthError> {
+        self.admit(client)?;
+        let is_admin = match &self.config.admin_token {
+            Some(admin_token) => !token.is_empty() && constant_time_eq(token, admin_token),
+            None => false,
+        };
+        if !is_admin {
+            return Err(self.invalid_attempt(client));
+        }
+        Ok(())
+    }
+
+    /// Checks that `credentials` give the `access` a request requires.
+    pub async fn check <S: Storage> (
+        &self,
+        storage: &S,
+        access: Access,
+        credentials: &Credentials,
+        client: Option<IpAddr>,
+    ) -> Result<Authorized, AuthError> {
+        match access {
+            Access::Anyone => self.admit(client).map(|_| Authorized::Anyone),
+            Access::Introduction => self.admit_introduction(client).map(|_| Authorized::Anyone),
+            Access::SecretKey => self.authorize_secret_key(storage, &credentials.secret_key, client).await
+                .map(Authorized::SecretKey),
+            Access::TokenOrSecretKey(_) if !credentials.secret_key.is_empty() => {
+                self.authorize_secret_key(storage, &credentials.secret_key, client).await
+                    .map(Authorized::SecretKey)
+            },
+            Access::Token(operation) | Access::TokenOrSecretKey(operation) => {
+                self.authorize(storage, operation, &credentials.token, client).await
+                    .map(Authorized::Token)
+            },
+            Access::Admin => self.authorize_admin(&credentials.admin_token, client).map(|_| Authorized::Admin),
+        }
+    }
+
+}
+
+/// Returns `true` if the token with the digest `token` no longer exists. This
+/// is used to end long-lived streams once the token they were opened with is
+/// revoked.
+pub async fn is_revoked <S: Storage> (storage: &S, token: &TokenDigest) -> bool {
+    match storage.get_token_info(token).await {
+        Ok(t) => t.is_none(),
+        Err(e) => {
+            error!("Database failure: {:?}", e);
+            false
+        },
+    }
+}
+
+/// Completes when `token_info` expires, or never if it does not expire. This
+/// is used to end long-lived streams that were opened with the token.
+pub async fn expiry (token_info: &TokenEntry) {
+    match token_info.not_after {
+        Some(na) => tokio::time::sleep((na - Utc::now()).to_std().unwrap_or_default()).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Harness, TESTING_TOKEN, ADMIN_TOKEN, ALL_PERMISSIONS, only, all, token};
+    use crate::gate::{rule, LOCATIONS_PATH};
+    use crate::grpc::find_my_device::*;
+    use crate::storage::Excommunication;
+    use tonic::{Code, Request};
+    use tokio_stream::StreamExt;
+
+    /// The permission that each operation is expected to require.
+    const REQUIRED_PERMISSIONS: [(Operation, &str); 13] = [
+        (Operation::SubmitLocation, "write_locations"),
+        (Operation::StreamServerEvents, "write_locations"),
+        (Operation::AcknowledgeWipe, "write_locations"),
+        (Operation::ListTokens, "list_tokens"),
+        (Operation::PurgeLocation, "write_locations"),
+        (Operation::CancelPurge, "cancel_purge"),
+        (Operation::Wipe, "wipe"),
+        (Operation::ListLocations, "read_locations"),
+        (Operation::StreamLocation, "read_locations"),
+        (Operation::RequestLocation, "read_locations"),
+        (Operation::GetStorageInfo, "stats"),
+        (Operation::GetDeviceStatus, "stats"),
+        (Operation::ViewLocations, "read_locations"),
+    ];
+
+    /// The access that every RPC, and the web UI, is expected to require.
+    const EXPECTED_ACCESS: [(&str, Access); 25] = [
+        ("/findmydevice.DeviceService/SubmitLocation", Access::Token(Operation::SubmitLocation)),
+        ("/findmydevice.DeviceService/StreamServerEvents", Access::Token(Operation::StreamServerEvents)),
+        ("/findmydevice.DeviceService/IntroduceMyself", Access::Introduction),
+        ("/findmydevice.DeviceService/AcknowledgeWipe", Access::Token(Operation::AcknowledgeWipe)),
+        ("/findmydevice.UserService/CreateToken", Access::SecretKey),
+        ("/findmydevice.UserService/RevokeToken", Access::SecretKey),
+        ("/findmydevice.UserService/ListTokens", Access::TokenOrSecretKey(Operation::ListTokens)),
+        ("/findmydevice.UserService/PurgeLocation", Access::Token(Operation::PurgeLocation)),
+        ("/findmydevice.UserService/CancelPurge", Access::Token(Operation::CancelPurge)),
+        ("/findmydevice.UserService/Wipe", Access::Token(Operation::Wipe)),
+        ("/findmydevice.UserService/ListLocations", Access::Token(Operation::ListLocations)),
+        ("/findmydevice.UserService/StreamLocation", Access::Token(Operation::StreamLocation)),
+        ("/findmydevice.UserService/RequestLocation", Access::Token(Operation::RequestLocation)),
+        ("/findmydevice.UserService/GetServerInfo", Access::Anyone),
+        ("/findmydevice.UserService/GetStorageInfo", Access::Token(Operation::GetStorageInfo)),
+        ("/findmydevice.UserService/GetDeviceStatus", Access::TokenOrSecretKey(Operation::GetDeviceStatus)),
+        ("/findmydevice.UserService/Excommunicate", Access::SecretKey),
+        ("/findmydevice.AdminService/Excommunicate", Access::Admin),
+        ("/findmydevice.AdminService/ListEmergencyPurges", Access::Admin),
+        ("/findmydevice.AdminService/DecideEmergencyPurge", Access::Admin),
+        ("/findmydevice.AdminService/ListAuditLog", Access::Admin),
+        ("/findmydevice.AdminService/CreateRegistrationKey", Access::Admin),
+        ("/findmydevice.AdminService/ListRegistrationKeys", Access::Admin),
+        ("/findmydevice.AdminService/RevokeRegistrationKey", Access::Admin),
+        (LOCATIONS_PATH, Access::Token(Operation::ViewLocations)),
+    ];
+
+    /// The paths that may be used with a token, and the operation they perform.
+    fn token_paths () -> impl Iterator<Item = (&'static str, Operation)> {
+        EXPECTED_ACCESS.into_iter().filter_map(|(path, access)| match access {
+            Access::Token(operation) | Access::TokenOrSecretKey(operation) => Some((path, operation)),
+            _ => None,
+        })
+    }
+
+    fn paths_requiring (wanted: fn(Access) -> bool) -> impl Iterator<Item = &'static str> {
+        EXPECTED_ACCESS.into_iter().filter(move |(_, access)| wanted(*access)).map(|(path, _)| path)
+    }
+
+    fn secret_key (secret_key: SecretKey) -> Credentials {
+        Credentials { secret_key, ..Default::default() }
+    }
+
+    fn admin_token (admin_token: Vec<u8>) -> Credentials {
+        Credentials { admin_token, ..Default::default() }
+    }
+
+    #[test]
+    fn every_rpc_has_the_expected_access () {
+        let mut service = "";
+        let mut rpcs = 0;
+        for line in include_str!("../../findmydevice.proto").lines().map(str::trim) {
+            if let Some(name) = line.strip_prefix("service ") {
+                service = name.trim_end_matches('{').trim();
+            } else if let Some(rpc) = line.strip_prefix("rpc ") {
+                let method = rpc.split(|c: char| c.is_whitespace() || c == '(').next().unwrap();
+                let path = format!("/findmydevice.{}/{}", service, method);
+                assert!(EXPECTED_ACCESS.iter().any(|(p, _)| *p == path), "{} is not covered", path);
+                rpcs += 1;
+            }
+        }
+        assert_eq!(rpcs + 1, EXPECTED_ACCESS.len());
+        for (path, access) in EXPECTED_ACCESS.into_iter().filter(|(path, _)| *path != LOCATIONS_PATH) {
+            assert_eq!(rule(path).map(|(a, _)| a), Some(access), "{}", path);
+        }
+        assert!(rule("/findmydevice.UserService/NoSuchMethod").is_none());
+        for (_, operation) in token_paths() {
+            assert!(REQUIRED_PERMISSIONS.iter().any(|(o, _)| *o == operation), "{:?}", operation);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn every_operation_requires_its_permission () {
+        let h = Harness::new().await;
+        for (operation, required) in REQUIRED_PERMISSIONS {
+            let path = token_paths().find(|(_, o)| *o == operation).unwrap().0;
+            for permission in ALL_PERMISSIONS {
+                assert_eq!(operation.permitted(&only(permission)), permission == required);
+                let valid = h.valid_token(only(permission)).await;
+                let code = h.request(path, token(valid)).await;
+                if permission == required {
+                    assert_ne!(code, Code::Unauthenticated, "{} with {}", path, permission);
+                    assert_ne!(code, Code::PermissionDenied, "{} with {}", path, permission);
+                } else {
+                    assert_eq!(code, Code::PermissionDenied, "{} with {}", path, permission);
+                }
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unknown_tokens_are_rejected () {
+        let h = Harness::new().await;
+        for (path, _) in token_paths() {
+            assert_eq!(h.request(path, token(vec![])).await, Code::Unauthenticated, "{}", path);
+            let unknown = Vec::from(rand::random::<[u8; 16]>());
+            assert_eq!(h.request(path, token(unknown)).await, Code::Unauthenticated, "{}", path);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expired_tokens_are_rejected () {
+        let h = Harness::new().await;
+        let now = Utc::now();
+        for (path, _) in token_paths() {
+            let expired = h.token(all(), now - chrono::Duration::hours(2), Some(now - chrono::Duration::hours(1))).await;
+            assert_eq!(h.request(path, token(expired)).await, Code::Unauthenticated, "{}", path);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn tokens_are_rejected_before_they_are_valid () {
+        let h = Harness::new().await;
+        let now = Utc::now();
+        for (path, _) in token_paths() {
+            let early = h.token(all(), now + chrono::Duration::hours(1), None).await;
+            assert_eq!(h.request(path, token(early)).await, Code::Unauthenticated, "{}", path);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn testing_token_may_only_submit_locations () {
+        let h = Harness::new().await;
+        for (path, operation) in token_paths() {
+            let expected = if operation == Operation::SubmitLocation {
+                Code::Ok
+            } else {
+                Code::Unauthenticated
+            };
+            assert_eq!(h.request(path, token(Vec::from(TESTING_TOKEN))).await, expected, "{}", path);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unknown_secret_keys_are_rejected () {
+        let h = Harness::new().await;
+        let token = h.valid_token(all()).await;
+        for path in paths_requiring(|a| matches!(a, Access::SecretKey | Access::TokenOrSecretKey(_))) {
+            assert_eq!(h.request(path, secret_key(vec![])).await, Code::Unauthenticated, "{}", path);
+            let unknown = Vec::from(rand::random::<[u8; 16]>());
+            assert_eq!(h.request(path, secret_key(unknown)).await, Code::Unauthenticated, "{}", path);
+            // Tokens are no substitute for the secret key.
+            assert_eq!(h.request(path, secret_key(token.clone())).await, Code::Unauthenticated, "{}", path);
+
+            // Each is made with a device of its own, since one excommunicates it.
+            let other = Harness::new().await;
+            let code = other.request(path, secret_key(other.secret_key.clone())).await;
+            assert_ne!(code, Code::Unauthenticated, "{}", path);
+            assert_ne!(code, Code::PermissionDenied, "{}", path);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn only_the_admin_token_is_accepted () {
+        let h = Harness::new().await;
+        let token = h.valid_token(all()).await;
+        for path in paths_requiring(|a| a == Access::Admin) {
+            for wrong in [ vec![], vec![ 0xFF; 4 ], Vec::from(TESTING_TOKEN), token.clone(), h.secret_key.clone() ] {
+                assert_eq!(h.request(path, admin_token(wrong)).await, Code::Unauthenticated, "{}", path);
+            }
+            let code = h.request(path, admin_token(Vec::from(ADMIN_TOKEN))).await;
+            assert_ne!(code, Code::Unauthenticated, "{}", path);
+            assert_ne!(code, Code::PermissionDenied, "{}", path);
+        }
+
+        // Nothing is accepted if no administrator token is configured.
+        let h = Harness::with_config(Config {
+            admin_token: None,
+            ..Default::default()
+        }).await;
+        for path in paths_requiring(|a| a == Access::Admin) {
+            assert_eq!(h.request(path, admin_token(vec![])).await, Code::Unauthenticated, "{}", path);
+            assert_eq!(h.request(path, admin_token(Vec::from(ADMIN_TOKEN))).await, Code::Unauthenticated, "{}", path);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn anyone_may_introduce_themselves_and_get_server_info () {
+        let h = Harness::new().await;
+        for path in paths_requiring(|a| matches!(a, Access::Anyone | Access::Introduction)) {
+            assert_eq!(h.request(path, Credentials::default()).await, Code::Ok, "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn excommunicated_devices_may_not_write () {
+        let h = Harness::new().await;
+        let token = h.valid_token(all()).await;
+        h.storage.excommunicate(&h.device_id(), &Excommunication {
+            time: Utc::now(),
+            reason: String::from("Testing"),
+            by_administrator: false,
+        }).await.unwrap();
+        let result = h.device().submit_location(Request::new(SubmitLocationArg {
+            token: token.clone(),
+            ..Default::default()
+        })).await.unwrap().into_inner();
+        assert!(result.excommunicated);
+        assert!(!result.recorded);
+        assert_eq!(h.call(Operation::PurgeLocation, token.clone()).await, Code::PermissionDenied);
+        assert_eq!(h.call(Operation::AcknowledgeWipe, token.clone()).await, Code::PermissionDenied);
+        assert_eq!(h.call(Operation::ListLocations, token).await, Code::Ok);
+    }
+
+    #[tokio::test]
+    async fn excommunicated_devices_are_told_through_their_event_stream () {
+        let h = Harness::new().await;
+        let token = h.valid_token(all()).await;
+        h.user().excommunicate(Request::new(ExcommunicateArg {
+            secret_key: h.secret_key.clone(),
+            reason: String::from("Testing"),
+        })).await.unwrap();
+        let mut events = h.device().stream_server_events(Request::new(StreamServerEventsArg {
+            token,
+        })).await.unwrap().into_inner();
+        let event = events.next().await.unwrap().unwrap();
+        assert_eq!(event.event_type, ServerEventType::Excommunicated as i32);
+    }
+
+    #[tokio::test]
+    async fn excommunicated_devices_still_need_permission_for_their_event_stream () {
+        let h = Harness::new().await;
+        let token = h.valid_token(only("read_locations")).await;
+        h.user().excommunicate(Request::new(ExcommunicateArg {
+            secret_key: h.secret_key.clone(),
+            reason: String::from("Testing"),
+        })).await.unwrap();
+        assert_eq!(h.call(Operation::StreamServerEvents, token).await, Code::PermissionDenied);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn streams_end_when_the_token_expires () {
+        let h = Harness::new().await;
+        let now = Utc::now();
+        let token = h.token(all(), now - chrono::Duration::minutes(1), Some(now + chrono::Duration::seconds(5))).await;
+        let mut stream = h.user().stream_location(Request::new(StreamLocationArg {
+            token,
+        })).await.unwrap().into_inner();
+        let end = stream.next().await.unwrap();
+        assert_eq!(end.unwrap_err().code(), Code::Unauthenticated);
+        assert!(stream.next().await.is_none());
+    }
+
+}
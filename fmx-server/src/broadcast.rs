@@ -0,0 +1,66 @@
+use crate::grpc::find_my_device::LocationSnapshot;
+use crate::storage::DeviceId;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tokio::sync::broadcast;
+
+/// The number of snapshots that may be buffered for each device before the
+/// slowest subscribers to that device start missing snapshots.
+pub const DEFAULT_CHANNEL_CAPACITY: usize = 32;
+
+/// Fans out newly-recorded location snapshots to everyone streaming the
+/// location of the device that submitted them.
+///
+/// Publishing never waits on subscribers: each device gets a bounded broadcast
+/// channel, and a subscriber that falls more than `capacity` snapshots behind
+/// skips the snapshots it missed rather than holding up the device.
+pub struct LocationBroadcaster {
+    capacity: usize,
+    channels: Mutex<HashMap<DeviceId, broadcast::Sender<LocationSnapshot>>>,
+}
+
+impl LocationBroadcaster {
+
+    pub fn new (capacity: usize) -> Self {
+        LocationBroadcaster {
+            capacity,
+            channels: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn subscribe (&self, device_id: &DeviceId) -> broadcast::Receiver<LocationSnapshot> {
+        let mut channels = self.channels.lock().unwrap();
+        match channels.get(device_id.as_slice()) {
+            Some(sender) => sender.subscribe(),
+            None => {
+                let (sender, receiver) = broadcast::channel(self.capacity);
+                channels.insert(device_id.clone(), sender);
+                receiver
+            },
+        }
+    }
+
+    /// Sends `snapshot` to all current subscribers of the device identified by
+    /// `device_id`, returning the number of subscribers that will receive it.
+    pub fn publish (&self, device_id: &DeviceId, snapshot: LocationSnapshot) -> usize {
+        let mut channels = self.channels.lock().unwrap();
+        let sent = match channels.get(device_id.as_slice()) {
+            Some(sender) => sender.send(snapshot).unwrap_or(0),
+            None => return 0,
+        };
+        if sent == 0 {
+            // Nobody is listening anymore, so there is no point keeping the channel.
+            channels.remove(device_id.as_slice());
+        }
+        sent
+    }
+
+}
+
+impl Default for LocationBroadcaster {
+
+    fn default () -> Self {
+        LocationBroadcaster::new(DEFAULT_CHANNEL_CAPACITY)
+    }
+
+}
@@ -1,6 +1,554 @@
+use clap::{Parser, Subcommand, ValueEnum};
+use log::LevelFilter;
+use serde::{Deserialize, Deserializer};
+use serde::de::Error;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tonic::transport::{Certificate, Identity, ServerTlsConfig};
+use tokio_rustls::rustls;
+use rustls_pemfile::Item;
 
-#[derive(Debug, Clone)]
+/// The storage backends that the server can use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    /// Keeps everything in memory. All data is lost when the server stops,
+    /// unless `path` is set, in which case every change is journaled in that
+    /// directory.
+    Memory,
+    /// Keeps everything in a redb database file at `path`.
+    Redb,
+    /// Keeps everything in a SQLite database file at `path`.
+    Sqlite,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub backend: StorageBackend,
+
+    /// Where the backend keeps its data, for backends that use files.
+    pub path: Option<PathBuf>,
+
+    /// How often the memory backend compacts its journal into a snapshot.
+    #[serde(deserialize_with = "duration")]
+    pub snapshot_interval: Duration,
+}
+
+impl Default for StorageConfig {
+
+    fn default () -> Self {
+        StorageConfig {
+            backend: StorageBackend::Memory,
+            path: None,
+            snapshot_interval: Duration::from_secs(60 * 60),
+        }
+    }
+
+}
+
+/// Limits on what clients may ask of the server.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// The most locations that may be returned by a single `ListLocations` call.
+    pub max_locations_per_request: u32,
+
+    /// The longest that `RequestLocation` will wait for the device.
+    #[serde(deserialize_with = "duration")]
+    pub max_request_location_timeout: Duration,
+
+    /// The number of undelivered server events kept for each device.
+    pub event_queue_capacity: usize,
+
+    /// The number of location snapshots buffered for each device's
+    /// `StreamLocation` subscribers.
+    pub location_channel_capacity: usize,
+}
+
+impl Default for Limits {
+
+    fn default () -> Self {
+        Limits {
+            max_locations_per_request: 1000,
+            max_request_location_timeout: Duration::from_secs(300),
+            event_queue_capacity: crate::events::DEFAULT_QUEUE_CAPACITY,
+            location_channel_capacity: crate::broadcast::DEFAULT_CHANNEL_CAPACITY,
+        }
+    }
+
+}
+
+/// What happens when a device's location history reaches its quota.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QuotaPolicy {
+    /// The oldest locations are deleted to make room for new ones.
+    DropOldest,
+    /// New locations are not recorded.
+    Reject,
+    /// Every other location in the older half of the history is deleted, so
+    /// that the history still covers the same period, in less detail.
+    Downsample,
+}
+
+/// Limits on the location history kept for each device.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Quotas {
+    /// The most locations kept for each device. Zero means no limit.
+    pub max_locations_per_device: u64,
+
+    /// The most bytes of locations kept for each device (see
+    /// `StoredLocation::size`). Zero means no limit.
+    pub max_bytes_per_device: u64,
+    pub policy: QuotaPolicy,
+
+    /// How long locations are kept for, if not forever.
+    #[serde(deserialize_with = "optional_duration")]
+    pub retention: Option<Duration>,
+}
+
+impl Default for Quotas {
+
+    fn default () -> Self {
+        Quotas {
+            max_locations_per_device: 10_000,
+            max_bytes_per_device: 10 * 1024 * 1024,
+            policy: QuotaPolicy::DropOldest,
+            retention: None,
+        }
+    }
+
+}
+
+/// A rate: at most `requests` requests in any period of `per`, which may all
+/// come at once. A rate of zero requests is no limit at all.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rate {
+    pub requests: u32,
+    #[serde(deserialize_with = "duration")]
+    pub per: Duration,
+}
+
+impl Rate {
+
+    pub const fn per_minute (requests: u32) -> Self {
+        Rate { requests, per: Duration::from_secs(60) }
+    }
+
+}
+
+/// Rate limits, which apply across the gRPC services and the web interface.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimits {
+    /// Requests of any kind from each IP address.
+    pub per_ip: Rate,
+
+    /// Requests made with each token.
+    pub per_token: Rate,
+
+    /// Requests made with each secret key.
+    pub per_secret_key: Rate,
+
+    /// Calls to `IntroduceMyself` from each IP address, each of which may
+    /// create a new device.
+    pub introductions_per_ip: Rate,
+
+    /// The number of invalid tokens, secret keys or registration keys that an
+    /// IP address may present before it is locked out. Zero disables lockouts.
+    pub max_invalid_attempts: u32,
+
+    /// How long an IP address is locked out for. Invalid attempts are also
+    /// forgotten once this long has passed since the last one.
+    #[serde(deserialize_with = "duration")]
+    pub lockout: Duration,
+}
+
+impl Default for RateLimits {
+
+    fn default () -> Self {
+        RateLimits {
+            per_ip: Rate::per_minute(600),
+            per_token: Rate::per_minute(120),
+            per_secret_key: Rate::per_minute(30),
+            introductions_per_ip: Rate { requests: 10, per: Duration::from_secs(60 * 60) },
+            max_invalid_attempts: 10,
+            lockout: Duration::from_secs(15 * 60),
+        }
+    }
+
+}
+
+/// TLS settings, which apply to both the gRPC services and the web interface.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// A PEM file containing the server's certificate chain.
+    pub certificate: PathBuf,
+
+    /// A PEM file containing the server's private key.
+    pub key: PathBuf,
+
+    /// A PEM file containing the CA certificates that gRPC clients must
+    /// present a certificate from. If absent, client certificates are not
+    /// requested.
+    pub client_ca: Option<PathBuf>,
+
+    /// If set, gRPC clients may still connect without a client certificate
+    /// when `client_ca` is set.
+    #[serde(default)]
+    pub client_auth_optional: bool,
+}
+
+impl TlsConfig {
+
+    pub fn grpc_server_config (&self) -> anyhow::Result<ServerTlsConfig> {
+        let certificate = read_pem(&self.certificate)?;
+        let key = read_pem(&self.key)?;
+        let mut config = ServerTlsConfig::new().identity(Identity::from_pem(certificate, key));
+        if let Some(client_ca) = &self.client_ca {
+            config = config
+                .client_ca_root(Certificate::from_pem(read_pem(client_ca)?))
+                .client_auth_optional(self.client_auth_optional);
+        }
+        Ok(config)
+    }
+
+    /// Client certificates are only requested by the gRPC services, since
+    /// browsers would not have them.
+    pub fn web_server_config (&self) -> anyhow::Result<rustls::ServerConfig> {
+        let certificates = rustls_pemfile::certs(&mut read_pem(&self.certificate)?.as_slice())?
+            .into_iter()
+            .map(rustls::Certificate)
+            .collect();
+        let key = rustls_pemfile::read_all(&mut read_pem(&self.key)?.as_slice())?
+            .into_iter()
+            .find_map(|item| match item {
+                Item::RSAKey(k) | Item::PKCS8Key(k) | Item::ECKey(k) => Some(rustls::PrivateKey(k)),
+                _ => None,
+            })
+            .ok_or_else(|| anyhow::anyhow!("No private key found in {}", self.key.display()))?;
+        let mut config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(certificates, key)?;
+        config.alpn_protocols = vec![ b"h2".to_vec(), b"http/1.1".to_vec() ];
+        Ok(config)
+    }
+
+}
+
+fn read_pem (path: &Path) -> anyhow::Result<Vec<u8>> {
+    std::fs::read(path).map_err(|e| anyhow::anyhow!("Could not read {}: {}", path.display(), e))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    #[serde(deserialize_with = "level_filter")]
+    pub level: LevelFilter,
+
+    /// If set, logs are written to this file instead of to standard output.
+    pub file: Option<PathBuf>,
+}
+
+impl Default for LogConfig {
+
+    fn default () -> Self {
+        LogConfig {
+            level: LevelFilter::Info,
+            file: None,
+        }
+    }
+
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct Config {
+    /// The address on which the gRPC services listen.
+    pub grpc_address: SocketAddr,
+
+    /// The address on which the web interface listens.
+    pub web_address: SocketAddr,
     pub open_registration: bool,
+
+    /// A token that may submit locations to a shared test device without
+    /// registering. It is disabled if empty, which it should be in production.
+    #[serde(deserialize_with = "hex_bytes")]
     pub testing_token: Vec<u8>,
-}
\ No newline at end of file
+
+    /// The token required to use the AdminService. If this is `None`, the
+    /// AdminService rejects every request.
+    #[serde(deserialize_with = "optional_hex_bytes")]
+    pub admin_token: Option<Vec<u8>>,
+
+    /// The key for the digests under which tokens and secret keys are stored
+    /// (see `Digester`). It must stay the same for as long as the stored data
+    /// is kept. If it is empty, a random key is used, which only suits storage
+    /// that does not outlive the server.
+    #[serde(deserialize_with = "hex_bytes")]
+    pub digest_key: Vec<u8>,
+
+    /// The key that wraps the per-device keys with which location history is
+    /// encrypted (see `Vault`). It must be 32 bytes, or empty, in which case a
+    /// random key is used, which only suits storage that does not outlive the
+    /// server.
+    #[serde(deserialize_with = "hex_bytes")]
+    pub master_key: Vec<u8>,
+
+    /// How long purges of location history are delayed, so that a thief cannot
+    /// immediately erase the history of a stolen device.
+    #[serde(deserialize_with = "duration")]
+    pub purge_delay: Duration,
+
+    /// How long tokens created with `CreateToken` last, if the request does
+    /// not say.
+    #[serde(deserialize_with = "duration")]
+    pub default_token_lifetime: Duration,
+    pub storage: StorageConfig,
+    pub limits: Limits,
+    pub quotas: Quotas,
+    pub rate_limits: RateLimits,
+    pub tls: Option<TlsConfig>,
+    pub log: LogConfig,
+}
+
+impl Default for Config {
+
+    fn default () -> Self {
+        Config {
+            grpc_address: SocketAddr::from(([127, 0, 0, 1], 50051)),
+            web_address: SocketAddr::from(([127, 0, 0, 1], 3030)),
+            open_registration: true,
+            testing_token: vec![],
+            admin_token: None,
+            digest_key: vec![],
+            master_key: vec![],
+            purge_delay: Duration::from_secs(60 * 60 * 24),
+            default_token_lifetime: Duration::from_secs(60 * 60 * 24 * 90),
+            storage: StorageConfig::default(),
+            limits: Limits::default(),
+            quotas: Quotas::default(),
+            rate_limits: RateLimits::default(),
+            tls: None,
+            log: LogConfig::default(),
+        }
+    }
+
+}
+
+/// Bytes given in hexadecimal on the command line or in the environment.
+#[derive(Debug, Clone)]
+pub struct HexBytes (pub Vec<u8>);
+
+impl std::str::FromStr for HexBytes {
+    type Err = hex::FromHexError;
+
+    fn from_str (s: &str) -> Result<Self, Self::Err> {
+        hex::decode(s).map(HexBytes)
+    }
+}
+
+/// Command-line arguments. Each option may also be given as the environment
+/// variable shown, and overrides the same setting in the configuration file.
+#[derive(Debug, Parser)]
+#[command(version, about = "The FindMyX server")]
+pub struct Args {
+    /// A TOML configuration file.
+    #[arg(short, long, env = "FMX_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    #[arg(long, env = "FMX_GRPC_ADDRESS")]
+    pub grpc_address: Option<SocketAddr>,
+
+    #[arg(long, env = "FMX_WEB_ADDRESS")]
+    pub web_address: Option<SocketAddr>,
+
+    #[arg(long, env = "FMX_OPEN_REGISTRATION")]
+    pub open_registration: Option<bool>,
+
+    /// In hexadecimal. An empty value disables the testing token.
+    #[arg(long, env = "FMX_TESTING_TOKEN")]
+    pub testing_token: Option<HexBytes>,
+
+    /// In hexadecimal.
+    #[arg(long, env = "FMX_ADMIN_TOKEN")]
+    pub admin_token: Option<HexBytes>,
+
+    /// In hexadecimal.
+    #[arg(long, env = "FMX_DIGEST_KEY")]
+    pub digest_key: Option<HexBytes>,
+
+    /// In hexadecimal.
+    #[arg(long, env = "FMX_MASTER_KEY")]
+    pub master_key: Option<HexBytes>,
+
+    #[arg(long, env = "FMX_STORAGE_BACKEND")]
+    pub storage_backend: Option<StorageBackend>,
+
+    #[arg(long, env = "FMX_STORAGE_PATH")]
+    pub storage_path: Option<PathBuf>,
+
+    #[arg(long, env = "FMX_TLS_CERTIFICATE", requires = "tls_key")]
+    pub tls_certificate: Option<PathBuf>,
+
+    #[arg(long, env = "FMX_TLS_KEY", requires = "tls_certificate")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Requires gRPC clients to present a certificate issued by one of these CAs.
+    #[arg(long, env = "FMX_TLS_CLIENT_CA")]
+    pub tls_client_ca: Option<PathBuf>,
+
+    #[arg(long, env = "FMX_LOG_LEVEL")]
+    pub log_level: Option<LevelFilter>,
+
+    #[arg(long, env = "FMX_LOG_FILE")]
+    pub log_file: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Offline operations on the configured storage. The server must not be
+/// running while these are.
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Writes everything in storage to a new archive file.
+    Export {
+        archive: PathBuf,
+    },
+
+    /// Reads an archive file into empty storage, and then checks that
+    /// everything in the archive arrived intact.
+    Import {
+        archive: PathBuf,
+    },
+}
+
+impl Config {
+
+    /// Reads the configuration file named in `args`, if any, and then applies
+    /// the overrides in `args`.
+    pub fn load (args: Args) -> anyhow::Result<Config> {
+        let mut config: Config = match &args.config {
+            Some(path) => {
+                let text = std::fs::read_to_string(path)
+                    .map_err(|e| anyhow::anyhow!("Could not read {}: {}", path.display(), e))?;
+                toml::from_str(&text)
+                    .map_err(|e| anyhow::anyhow!("Invalid configuration in {}: {}", path.display(), e))?
+            },
+            None => Config::default(),
+        };
+        if let Some(a) = args.grpc_address {
+            config.grpc_address = a;
+        }
+        if let Some(a) = args.web_address {
+            config.web_address = a;
+        }
+        if let Some(r) = args.open_registration {
+            config.open_registration = r;
+        }
+        if let Some(t) = args.testing_token {
+            config.testing_token = t.0;
+        }
+        if let Some(t) = args.admin_token {
+            config.admin_token = Some(t.0);
+        }
+        if let Some(k) = args.digest_key {
+            config.digest_key = k.0;
+        }
+        if let Some(k) = args.master_key {
+            config.master_key = k.0;
+        }
+        if let Some(b) = args.storage_backend {
+            config.storage.backend = b;
+        }
+        if let Some(p) = args.storage_path {
+            config.storage.path = Some(p);
+        }
+        if let (Some(certificate), Some(key)) = (args.tls_certificate, args.tls_key) {
+            config.tls = Some(TlsConfig {
+                certificate,
+                key,
+                client_ca: None,
+                client_auth_optional: false,
+            });
+        }
+        if let Some(client_ca) = args.tls_client_ca {
+            match config.tls.as_mut() {
+                Some(tls) => tls.client_ca = Some(client_ca),
+                None => anyhow::bail!("A TLS client CA was given, but TLS is not configured."),
+            }
+        }
+        if let Some(l) = args.log_level {
+            config.log.level = l;
+        }
+        if let Some(f) = args.log_file {
+            config.log.file = Some(f);
+        }
+        Ok(config)
+    }
+
+}
+
+fn duration <'de, D: Deserializer<'de>> (d: D) -> Result<Duration, D::Error> {
+    let s = String::deserialize(d)?;
+    humantime::parse_duration(&s).map_err(D::Error::custom)
+}
+
+fn optional_duration <'de, D: Deserializer<'de>> (d: D) -> Result<Option<Duration>, D::Error> {
+    duration(d).map(Some)
+}
+
+fn hex_bytes <'de, D: Deserializer<'de>> (d: D) -> Result<Vec<u8>, D::Error> {
+    let s = String::deserialize(d)?;
+    hex::decode(s).map_err(D::Error::custom)
+}
+
+fn optional_hex_bytes <'de, D: Deserializer<'de>> (d: D) -> Result<Option<Vec<u8>>, D::Error> {
+    hex_bytes(d).map(Some)
+}
+
+fn level_filter <'de, D: Deserializer<'de>> (d: D) -> Result<LevelFilter, D::Error> {
+    let s = String::deserialize(d)?;
+    s.parse().map_err(D::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn example_configuration_is_valid () {
+        let config: Config = toml::from_str(include_str!("../config.example.toml")).unwrap();
+        assert!(!config.open_registration);
+        assert!(config.testing_token.is_empty());
+        assert_eq!(config.purge_delay, Duration::from_secs(60 * 60 * 24));
+        assert_eq!(config.log.level, LevelFilter::Info);
+        assert_eq!(config.quotas.retention, Some(Duration::from_secs(60 * 60 * 24 * 365)));
+        assert_eq!(config.rate_limits.introductions_per_ip.per, Duration::from_secs(60 * 60));
+    }
+
+    #[test]
+    fn command_line_overrides_the_file () {
+        let path = std::env::temp_dir().join(format!("fmx-config-{}.toml", rand::random::<u64>()));
+        std::fs::write(&path, "grpc_address = \"0.0.0.0:50051\"\ntesting_token = \"01020304\"\n").unwrap();
+        let args = Args::parse_from([
+            "fmx-server",
+            "--config", path.to_str().unwrap(),
+            "--testing-token", "",
+            "--open-registration", "false",
+        ]);
+        let config = Config::load(args).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(config.grpc_address, "0.0.0.0:50051".parse().unwrap());
+        assert!(config.testing_token.is_empty());
+        assert!(!config.open_registration);
+    }
+
+}
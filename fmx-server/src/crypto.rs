@@ -0,0 +1,255 @@
+use crate::grpc::find_my_device::LocationSnapshot;
+use crate::storage::{Storage, DeviceId, LocationInsertion, LocationsFilter, StoredLocation};
+use crate::utils::grpc_timestamp_to_chrono;
+use chacha20poly1305::{XChaCha20Poly1305, XNonce, Key, KeyInit};
+use chacha20poly1305::aead::{Aead, AeadCore, OsRng, Payload};
+use chrono::prelude::*;
+use prost::Message;
+use log::warn;
+use std::sync::Arc;
+
+/// The length, in bytes, of the master key and of every device's data key.
+pub const KEY_LENGTH: usize = 32;
+
+const NONCE_LENGTH: usize = 24;
+
+/// What is encrypted for each stored location: everything but its time.
+#[derive(Clone, PartialEq, Message)]
+struct SealedLocation {
+    #[prost(message, optional, tag = "1")]
+    snapshot: Option<LocationSnapshot>,
+    #[prost(string, tag = "2")]
+    remote_addr: String,
+}
+
+/// Encrypts `plaintext`, returning the nonce followed by the ciphertext.
+fn seal (cipher: &XChaCha20Poly1305, aad: &[u8], plaintext: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher.encrypt(&nonce, Payload { msg: plaintext, aad })
+        .map_err(|_| anyhow::anyhow!("Encryption failed."))?;
+    let mut sealed = nonce.to_vec();
+    sealed.extend(ciphertext);
+    Ok(sealed)
+}
+
+fn open (cipher: &XChaCha20Poly1305, aad: &[u8], sealed: &[u8]) -> anyhow::Result<Vec<u8>> {
+    if sealed.len() < NONCE_LENGTH {
+        anyhow::bail!("Sealed data is truncated.");
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LENGTH);
+    cipher.decrypt(XNonce::from_slice(nonce), Payload { msg: ciphertext, aad })
+        .map_err(|_| anyhow::anyhow!("Sealed data could not be decrypted."))
+}
+
+/// The additional data for a stored location, which binds its ciphertext to
+/// the device and to the time stored alongside it in the clear.
+fn location_aad (device_id: &DeviceId, update_time: &DateTime<Utc>) -> Vec<u8> {
+    let mut aad = device_id.clone();
+    aad.extend(update_time.to_rfc3339_opts(SecondsFormat::Nanos, true).as_bytes());
+    aad
+}
+
+/// A device's data key, with which its location history is encrypted.
+pub struct DataKey {
+    cipher: XChaCha20Poly1305,
+}
+
+impl DataKey {
+
+    pub fn seal_location (&self, device_id: &DeviceId, location: &LocationInsertion) -> anyhow::Result<StoredLocation> {
+        let sealed = SealedLocation {
+            snapshot: Some(location.to_snapshot()),
+            remote_addr: location.remote_addr.map(|a| a.to_string()).unwrap_or_default(),
+        };
+        let aad = location_aad(device_id, &location.update_time);
+        Ok(StoredLocation {
+            update_time: location.update_time,
+            ciphertext: seal(&self.cipher, &aad, &sealed.encode_to_vec())?,
+        })
+    }
+
+    pub fn open_location (&self, device_id: &DeviceId, stored: &StoredLocation) -> anyhow::Result<LocationInsertion> {
+        let aad = location_aad(device_id, &stored.update_time);
+        let sealed = SealedLocation::decode(open(&self.cipher, &aad, &stored.ciphertext)?.as_slice())?;
+        let snapshot = sealed.snapshot.unwrap_or_default();
+        Ok(LocationInsertion {
+            update_time: stored.update_time,
+            expected_next_update_time: snapshot.expected_next_update_time
+                .as_ref()
+                .and_then(grpc_timestamp_to_chrono),
+            location: snapshot.location,
+            velocity: snapshot.velocity,
+            emergency: snapshot.emergency,
+            notes: snapshot.notes,
+            nearby_wifi_network: snapshot.nearby_wifi_network,
+            nearby_bluetooth_devices: snapshot.nearby_bluetooth_devices,
+            encrypted_snapshot: snapshot.encrypted_snapshot,
+            remote_addr: sealed.remote_addr.parse().ok(),
+        })
+    }
+
+}
+
+/// Envelope encryption of location history at rest.
+///
+/// Each device's history is encrypted with a data key of its own, which is
+/// kept in storage wrapped (encrypted) by the server's master key. Nothing in
+/// storage can be read without the master key, and destroying a device's data
+/// key makes its history unreadable everywhere, including in backups.
+#[derive(Clone)]
+pub struct Vault {
+    master: Arc<XChaCha20Poly1305>,
+}
+
+impl Vault {
+
+    pub fn new (master_key: &[u8]) -> anyhow::Result<Self> {
+        if master_key.len() != KEY_LENGTH {
+            anyhow::bail!("The master key must be {} bytes.", KEY_LENGTH);
+        }
+        Ok(Vault {
+            master: Arc::new(XChaCha20Poly1305::new(Key::from_slice(master_key))),
+        })
+    }
+
+    /// Returns the data key of a device, creating one if it has none.
+    /// If two callers race to create it, both get the one that was stored.
+    pub async fn device_key <S: Storage> (&self, storage: &S, device_id: &DeviceId) -> anyhow::Result<DataKey> {
+        if let Some(key) = self.existing_device_key(storage, device_id).await? {
+            return Ok(key);
+        }
+        let key = XChaCha20Poly1305::generate_key(&mut OsRng);
+        let wrapped = seal(&self.master, device_id, &key)?;
+        let stored = storage.insert_device_key(device_id, &wrapped).await?;
+        let key = open(&self.master, device_id, &stored)?;
+        Ok(DataKey { cipher: XChaCha20Poly1305::new(Key::from_slice(&key)) })
+    }
+
+    /// Returns the data key of a device, or `None` if it has never had one,
+    /// or if it has been destroyed.
+    pub async fn existing_device_key <S: Storage> (&self, storage: &S, device_id: &DeviceId) -> anyhow::Result<Option<DataKey>> {
+        let wrapped = match storage.get_device_key(device_id).await? {
+            Some(w) => w,
+            None => return Ok(None),
+        };
+        let key = open(&self.master, device_id, &wrapped)?;
+        Ok(Some(DataKey { cipher: XChaCha20Poly1305::new(Key::from_slice(&key)) }))
+    }
+
+    /// Lists and decrypts the locations recorded for a device.
+    ///
+    /// A location that was sealed with a key that has since been destroyed
+    /// is skipped. That can happen if all of a device's locations are purged
+    /// while one is being submitted: it is then written after its key is gone.
+    pub async fn list_locations <S: Storage> (
+        &self,
+        storage: &S,
+        device_id: &DeviceId,
+        filter: &LocationsFilter,
+    ) -> anyhow::Result<Vec<LocationSnapshot>> {
+        let key = match self.existing_device_key(storage, device_id).await? {
+            Some(k) => k,
+            None => return Ok(vec![]),
+        };
+        Ok(storage.list_locations(device_id, filter).await?
+            .iter()
+            .filter_map(|stored| match key.open_location(device_id, stored) {
+                Ok(l) => Some(l.to_snapshot()),
+                Err(e) => {
+                    warn!("Skipping the location of {:?} at {}: {}", device_id, stored.update_time.to_rfc3339(), e);
+                    None
+                },
+            })
+            .collect())
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memory::MemoryStorage;
+
+    fn location (notes: &str) -> LocationInsertion {
+        LocationInsertion {
+            update_time: Utc::now(),
+            expected_next_update_time: None,
+            location: None,
+            velocity: None,
+            emergency: true,
+            notes: String::from(notes),
+            nearby_wifi_network: vec![],
+            nearby_bluetooth_devices: vec![],
+            encrypted_snapshot: vec![],
+            remote_addr: Some("192.0.2.1:1234".parse().unwrap()),
+        }
+    }
+
+    #[tokio::test]
+    async fn locations_are_only_readable_with_the_device_key () {
+        let vault = Vault::new(&[ 7; KEY_LENGTH ]).unwrap();
+        let storage = MemoryStorage::new();
+        let device_id: DeviceId = vec![ 1; 32 ];
+        let key = vault.device_key(&storage, &device_id).await.unwrap();
+        let stored = key.seal_location(&device_id, &location("At the lighthouse")).unwrap();
+        assert!(!stored.ciphertext.windows(10).any(|w| w == b"lighthouse"));
+
+        let opened = key.open_location(&device_id, &stored).unwrap();
+        assert_eq!(opened.notes, "At the lighthouse");
+        assert_eq!(opened.remote_addr, Some("192.0.2.1:1234".parse().unwrap()));
+
+        // The ciphertext cannot be moved to another device or another time.
+        assert!(key.open_location(&vec![ 2; 32 ], &stored).is_err());
+        let redated = StoredLocation {
+            update_time: stored.update_time - chrono::Duration::hours(1),
+            ciphertext: stored.ciphertext.clone(),
+        };
+        assert!(key.open_location(&device_id, &redated).is_err());
+
+        // Nor can the wrapped key be unwrapped with another master key.
+        let other = Vault::new(&[ 8; KEY_LENGTH ]).unwrap();
+        assert!(other.existing_device_key(&storage, &device_id).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn purging_all_locations_shreds_the_device_key () {
+        let vault = Vault::new(&[ 7; KEY_LENGTH ]).unwrap();
+        let storage = MemoryStorage::new();
+        let device_id: DeviceId = vec![ 1; 32 ];
+        let key = vault.device_key(&storage, &device_id).await.unwrap();
+        let stored = key.seal_location(&device_id, &location("Home")).unwrap();
+        storage.write_location(&device_id, &stored).await.unwrap();
+        storage.purge_location(&device_id, None).await.unwrap();
+        assert!(vault.existing_device_key(&storage, &device_id).await.unwrap().is_none());
+
+        // A new key is made for anything recorded afterwards, which cannot
+        // open what was recorded before.
+        let new_key = vault.device_key(&storage, &device_id).await.unwrap();
+        assert!(new_key.open_location(&device_id, &stored).is_err());
+    }
+
+    #[tokio::test]
+    async fn locations_sealed_with_a_shredded_key_are_skipped () {
+        let vault = Vault::new(&[ 7; KEY_LENGTH ]).unwrap();
+        let storage = MemoryStorage::new();
+        let device_id: DeviceId = vec![ 1; 32 ];
+
+        // A submission fetches the key, and then everything is purged before
+        // it writes its location.
+        let old_key = vault.device_key(&storage, &device_id).await.unwrap();
+        storage.purge_location(&device_id, None).await.unwrap();
+        let orphan = old_key.seal_location(&device_id, &location("Orphaned")).unwrap();
+        storage.write_location(&device_id, &orphan).await.unwrap();
+
+        let mut later = location("Later");
+        later.update_time = orphan.update_time + chrono::Duration::seconds(1);
+        let new_key = vault.device_key(&storage, &device_id).await.unwrap();
+        storage.write_location(&device_id, &new_key.seal_location(&device_id, &later).unwrap()).await.unwrap();
+
+        let filter = LocationsFilter { limit: 100, since: None, until: None };
+        let listed = vault.list_locations(&storage, &device_id, &filter).await.unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].notes, "Later");
+    }
+
+}
@@ -0,0 +1,323 @@
+use crate::auth::{Authorized, Authorizer, database_failure, expiry, is_revoked};
+use crate::broadcast::LocationBroadcaster;
+use crate::config::Config;
+use crate::crypto::Vault;
+use crate::quota::make_room;
+use crate::events::ServerEventQueues;
+use crate::grpc::find_my_device::device_service_server::DeviceService;
+use crate::grpc::find_my_device::{
+    SubmitLocationArg,
+    SubmitLocationResult,
+    StreamServerEventsArg,
+    ServerEvent,
+    ServerEventType,
+    IntroduceMyselfArg,
+    IntroduceMyselfResult,
+    AcknowledgeWipeArg,
+    AcknowledgeWipeResult,
+    Permissions,
+};
+use crate::storage::{
+    Storage,
+    LocationInsertion,
+    IntroInsertion,
+};
+use crate::utils::{grpc_timestamp_to_chrono, chrono_to_grpc_timestamp};
+use tonic::{Request, Response, Status};
+use tokio_stream::wrappers::ReceiverStream;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use log::{warn, debug, info, trace};
+use chrono::prelude::*;
+
+/// How often a `NOOP` event is sent to a device that is streaming events, so
+/// that dead connections are noticed by both sides.
+const EVENT_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The permissions of the token that a device is given when it introduces
+/// itself, unless its registration key says otherwise.
+pub fn default_device_permissions () -> Permissions {
+    Permissions {
+        list_tokens: false,
+        write_locations: true,
+        read_locations: true, // TODO: Set this to false after testing is done.
+        nearby: true,
+        stats: false,
+        wipe: false,
+        cancel_purge: false,
+    }
+}
+
+#[derive(Clone)]
+pub struct DeviceServiceProvider <S: Storage> {
+    pub storage: Arc<S>,
+    pub config: Arc<Config>,
+    pub locations: Arc<LocationBroadcaster>,
+    pub events: Arc<ServerEventQueues>,
+    pub auth: Authorizer,
+    pub vault: Vault,
+}
+
+#[tonic::async_trait]
+impl <S: Storage + Send + Sync + 'static> DeviceService for DeviceServiceProvider <S> {
+
+    async fn submit_location (
+        &self,
+        request: Request<SubmitLocationArg>,
+    ) -> Result<Response<SubmitLocationResult>, Status> {
+        let maybe_remote_addr = request.remote_addr();
+        let token_info = Authorized::token(&request)?;
+        let req = request.into_inner();
+        let storage = self.storage.as_ref();
+        if storage.get_excommunication(&token_info.device_id).await.map_err(database_failure)?.is_some() {
+            debug!("Rejected location from excommunicated device at {:?}", maybe_remote_addr);
+            return Ok(Response::new(SubmitLocationResult {
+                recorded: false,
+                excommunicated: true,
+                remote_wipe: false,
+                tell_me_what_you_see: false,
+            }));
+        }
+        let in_the_clear = req.location.is_some()
+            || req.velocity.is_some()
+            || !req.notes.is_empty()
+            || !req.nearby_wifi_network.is_empty()
+            || !req.nearby_bluetooth_devices.is_empty();
+        if !req.encrypted_snapshot.is_empty() && in_the_clear {
+            return Err(Status::invalid_argument("An encrypted snapshot must not be accompanied by location data in the clear."));
+        }
+        if req.emergency {
+            warn!("Emergency announced by {:?}", token_info.device_id);
+        }
+        let insertion = LocationInsertion{
+            emergency: req.emergency,
+            update_time: Utc::now(),
+            expected_next_update_time: req.expected_next_update_time
+                .map(|t| grpc_timestamp_to_chrono(&t).unwrap_or(Utc::now())),
+            location: req.location,
+            notes: req.notes,
+            velocity: req.velocity,
+            nearby_bluetooth_devices: req.nearby_bluetooth_devices,
+            nearby_wifi_network: req.nearby_wifi_network,
+            encrypted_snapshot: req.encrypted_snapshot,
+            remote_addr: maybe_remote_addr,
+        };
+        let key = self.vault.device_key(storage, &token_info.device_id).await
+            .map_err(database_failure)?;
+        let stored = key.seal_location(&token_info.device_id, &insertion)
+            .map_err(database_failure)?;
+        // A location that does not fit is still relayed to anyone waiting for
+        // it, and the device is still told of any wipe order.
+        let recorded = make_room(storage, &self.config.quotas, &token_info.device_id, stored.size()).await
+            .map_err(database_failure)?;
+        if recorded {
+            storage.write_location(&token_info.device_id, &stored).await
+                .map_err(database_failure)?;
+            trace!("Inserted location submitted by {:?}", maybe_remote_addr);
+        } else {
+            debug!("Location submitted by {:?} exceeds the device's quota", maybe_remote_addr);
+        }
+        let snapshot = insertion.to_snapshot();
+        let tell_me_what_you_see = self.events.answer_location_request(&token_info.device_id, &snapshot);
+        let subscribers = self.locations.publish(&token_info.device_id, snapshot);
+        trace!("Location update streamed to {} subscribers", subscribers);
+
+        let remote_wipe = match storage.get_wipe_order(&token_info.device_id).await.map_err(database_failure)? {
+            Some(mut order) if order.acknowledged.is_none() => {
+                if order.delivered.is_none() {
+                    order.delivered = Some(Utc::now());
+                    storage.write_wipe_order(&token_info.device_id, &order).await
+                        .map_err(database_failure)?;
+                    info!(
+                        "Delivered remote wipe order requested at {} to {:?}",
+                        order.requested.to_rfc3339(),
+                        maybe_remote_addr,
+                    );
+                }
+                true
+            },
+            _ => false,
+        };
+        Ok(Response::new(SubmitLocationResult {
+            recorded,
+            excommunicated: false,
+            remote_wipe,
+            tell_me_what_you_see,
+        }))
+    }
+
+    async fn introduce_myself (
+        &self,
+        request: Request<IntroduceMyselfArg>,
+    ) -> Result<Response<IntroduceMyselfResult>, Status> {
+        let maybe_remote_addr = request.remote_addr();
+        let client = maybe_remote_addr.map(|a| a.ip());
+        let req = request.into_inner();
+        let storage = self.storage.as_ref();
+        let registration_key_digest = if req.registration_key.is_empty() {
+            vec![]
+        } else {
+            self.auth.digests.registration_key(&req.registration_key)
+        };
+        let permissions = if req.registration_key.is_empty() {
+            if !self.config.open_registration {
+                return Err(Status::unauthenticated("A registration key is required."));
+            }
+            default_device_permissions()
+        } else {
+            match storage.use_registration_key(&registration_key_digest, Utc::now()).await.map_err(database_failure)? {
+                Some(key) => {
+                    debug!("Registration key used by {:?}. {} uses were left.", maybe_remote_addr, key.uses_remaining);
+                    key.device_permissions
+                },
+                None => {
+                    self.auth.invalid_attempt(client);
+                    return Err(Status::unauthenticated("Invalid registration key."));
+                },
+            }
+        };
+        let random_bytes = rand::random::<[u8; 32]>();
+        let secret_key = Vec::from(&random_bytes[0..16]);
+        let token = Vec::from(&random_bytes[16..]);
+        let insertion = IntroInsertion{
+            remote_addr: maybe_remote_addr,
+            device_id: &self.auth.digests.device_id(&secret_key),
+            token_digest: &self.auth.digests.token(&token),
+            permissions: &permissions,
+            registration_key_digest: &registration_key_digest,
+            arg: &req,
+        };
+        match storage.write_intro(&insertion).await {
+            Ok(_) => Ok(Response::new(IntroduceMyselfResult {
+                nice_to_meet_you: true,
+                your_token: token,
+                your_secret_key: secret_key,
+            })),
+            Err(_) => Err(Status::internal("Database failure.")),
+        }
+    }
+
+    async fn acknowledge_wipe (
+        &self,
+        request: Request<AcknowledgeWipeArg>,
+    ) -> Result<Response<AcknowledgeWipeResult>, Status> {
+        let token_info = Authorized::token(&request)?;
+        let storage = self.storage.as_ref();
+        let mut order = match storage.get_wipe_order(&token_info.device_id).await.map_err(database_failure)? {
+            Some(order) => order,
+            None => return Ok(Response::new(AcknowledgeWipeResult { acknowledged: false })),
+        };
+        if order.acknowledged.is_none() {
+            let now = Utc::now();
+            order.delivered.get_or_insert(now);
+            order.acknowledged = Some(now);
+            storage.write_wipe_order(&token_info.device_id, &order).await
+                .map_err(database_failure)?;
+            info!("Device {:?} acknowledged its remote wipe order.", token_info.device_id);
+        }
+        Ok(Response::new(AcknowledgeWipeResult { acknowledged: true }))
+    }
+
+    type StreamServerEventsStream = ReceiverStream<Result<ServerEvent, Status>>;
+
+    async fn stream_server_events (
+        &self,
+        request: Request<StreamServerEventsArg>,
+    ) -> Result<Response<Self::StreamServerEventsStream>, Status> {
+        let token_info = Authorized::token(&request)?;
+        let req = request.into_inner();
+        let events = self.events.clone();
+        let device_id = token_info.device_id.clone();
+        let subscription = events.subscribe(&device_id);
+        let mut revocations = self.auth.revocations();
+        let storage = self.storage.clone();
+        let token = self.auth.digests.token(&req.token);
+        // Events are only taken off of the queue when there is room to send
+        // them, so that as few as possible are lost when the device disconnects.
+        let (tx, rx) = mpsc::channel(1);
+        tokio::spawn(async move {
+            let expired = expiry(&token_info);
+            tokio::pin!(expired);
+            let mut keepalive = tokio::time::interval(EVENT_KEEPALIVE_INTERVAL);
+            let end = loop {
+                let mut pending = events.take(&device_id).into_iter();
+                while let Some(event) = pending.next() {
+                    let tell_me = event.event_type == ServerEventType::TellMeWhatYouSee as i32;
+                    if let Err(mpsc::error::SendError(Ok(event))) = tx.send(Ok(event)).await {
+                        let mut undelivered = vec![ event ];
+                        undelivered.extend(pending);
+                        events.requeue(&device_id, undelivered);
+                        return;
+                    }
+                    if tell_me {
+                        events.location_request_delivered(&device_id);
+                    }
+                }
+                tokio::select! {
+                    _ = &mut expired => break Status::unauthenticated("Token expired"),
+                    _ = tx.closed() => return,
+                    _ = subscription.notified() => {},
+                    _ = revocations.changed() => {
+                        if is_revoked(storage.as_ref(), &token).await {
+                            break Status::unauthenticated("Token revoked");
+                        }
+                    },
+                    _ = keepalive.tick() => {
+                        let noop = ServerEvent {
+                            server_time: Some(chrono_to_grpc_timestamp(&Utc::now())),
+                            event_type: ServerEventType::Noop.into(),
+                        };
+                        if tx.send(Ok(noop)).await.is_err() {
+                            return;
+                        }
+                    },
+                }
+            };
+            debug!("Closing event stream: {}", end.message());
+            let _ = tx.send(Err(end)).await;
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grpc::find_my_device::ListLocationsArg;
+    use crate::testing::{Harness, all};
+    use tonic::Code;
+
+    #[tokio::test]
+    async fn encrypted_snapshots_are_relayed_as_submitted () {
+        let h = Harness::new().await;
+        let token = h.valid_token(all()).await;
+        let sealed = vec![ 0xA5; 64 ];
+        let submit = async |arg: SubmitLocationArg| h.device().submit_location(Request::new(SubmitLocationArg {
+            token: token.clone(),
+            ..arg
+        })).await;
+        let mixed = submit(SubmitLocationArg {
+            encrypted_snapshot: sealed.clone(),
+            notes: String::from("In the clear"),
+            ..Default::default()
+        }).await;
+        assert_eq!(mixed.unwrap_err().code(), Code::InvalidArgument);
+        submit(SubmitLocationArg {
+            encrypted_snapshot: sealed.clone(),
+            emergency: true,
+            ..Default::default()
+        }).await.unwrap();
+
+        let listed = h.user().list_locations(Request::new(ListLocationsArg {
+            token: token.clone(),
+            ..Default::default()
+        })).await.unwrap().into_inner();
+        assert_eq!(listed.locations.len(), 1);
+        assert_eq!(listed.locations[0].encrypted_snapshot, sealed);
+        assert!(listed.locations[0].emergency);
+        assert!(listed.locations[0].location.is_none());
+    }
+
+}
@@ -0,0 +1,148 @@
+use crate::storage::{Token, TokenDigest, SecretKey, DeviceId, RegistrationKeyDigest};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+use std::sync::Arc;
+
+/// Computes the keyed digests under which tokens, secret keys and registration
+/// keys are stored, so that nothing read out of storage can be used to access a
+/// device's data, or to register a new one.
+///
+/// The digests are keyed, so that they cannot be checked against guesses by
+/// anyone who does not also have the server's digest key.
+#[derive(Clone)]
+pub struct Digester {
+    key: Arc<Vec<u8>>,
+}
+
+impl Digester {
+
+    pub fn new (key: Vec<u8>) -> Self {
+        Digester { key: Arc::new(key) }
+    }
+
+    fn digest (&self, domain: &[u8], bytes: &[u8]) -> Vec<u8> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key)
+            .expect("HMAC accepts keys of any length");
+        mac.update(domain);
+        mac.update(bytes);
+        mac.finalize().into_bytes().to_vec()
+    }
+
+    pub fn token (&self, token: &Token) -> TokenDigest {
+        self.digest(b"fmx token\0", token)
+    }
+
+    pub fn device_id (&self, secret_key: &SecretKey) -> DeviceId {
+        self.digest(b"fmx secret key\0", secret_key)
+    }
+
+    pub fn registration_key (&self, key: &[u8]) -> RegistrationKeyDigest {
+        self.digest(b"fmx registration key\0", key)
+    }
+
+}
+
+/// Compares two secrets in constant time.
+pub fn constant_time_eq (a: &[u8], b: &[u8]) -> bool {
+    a.ct_eq(b).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::grpc::find_my_device::{
+        CreateTokenArg,
+        ListTokensArg,
+        RevokeTokenArg,
+        SubmitLocationArg,
+        CreateRegistrationKeyArg,
+        ListRegistrationKeysArg,
+        IntroduceMyselfArg,
+    };
+    use crate::auth::Operation;
+    use crate::storage::Storage;
+    use crate::testing::{Harness, ADMIN_TOKEN, all};
+    use tonic::{Code, Request};
+
+    #[tokio::test]
+    async fn storage_holds_only_digests () {
+        let h = Harness::new().await;
+        let created = h.user().create_token(Request::new(CreateTokenArg {
+            secret_key: h.secret_key.clone(),
+            permissions: Some(all()),
+            ..Default::default()
+        })).await.unwrap().into_inner().token_info.unwrap();
+        assert_eq!(created.token_digest, h.auth.digests.token(&created.token));
+        h.device().submit_location(Request::new(SubmitLocationArg {
+            token: created.token.clone(),
+            ..Default::default()
+        })).await.unwrap();
+        let storage = h.storage.as_ref();
+        assert!(storage.get_token_info(&created.token).await.unwrap().is_none());
+        assert!(storage.get_token_info(&created.token_digest).await.unwrap().is_some());
+        assert!(storage.get_intro(&h.secret_key).await.unwrap().is_none());
+        assert_eq!(storage.get_storage_usage(&h.secret_key).await.unwrap().locations, 0);
+        assert_eq!(storage.get_storage_usage(&h.device_id()).await.unwrap().locations, 1);
+        let tokens = storage.list_tokens(&h.device_id()).await.unwrap();
+        assert!(tokens.iter().any(|(digest, _)| digest == &created.token_digest));
+
+        // Nothing stored to identify a device or a token is a raw secret key
+        // or token, and every token belongs to the device it is stored for.
+        let raw = [ &h.secret_key, &created.token ];
+        let devices = storage.list_devices().await.unwrap();
+        assert_eq!(devices, vec![ h.device_id() ]);
+        for device_id in devices.iter() {
+            assert!(!raw.contains(&device_id));
+            for (digest, entry) in storage.list_tokens(device_id).await.unwrap() {
+                assert!(!raw.contains(&&digest));
+                assert!(!raw.contains(&&entry.device_id));
+                assert_eq!(&entry.device_id, device_id);
+            }
+        }
+
+        // Tokens are listed by digest, and can be revoked by it.
+        let listed = h.user().list_tokens(Request::new(ListTokensArg {
+            secret_key: h.secret_key.clone(),
+            ..Default::default()
+        })).await.unwrap().into_inner();
+        let info = listed.tokens.iter().find(|t| t.token_digest == created.token_digest).unwrap();
+        assert!(info.token.is_empty());
+        let revoked = h.user().revoke_token(Request::new(RevokeTokenArg {
+            secret_key: h.secret_key.clone(),
+            token_digest: created.token_digest,
+            ..Default::default()
+        })).await.unwrap().into_inner();
+        assert!(revoked.revoked);
+        assert_eq!(h.call(Operation::ListLocations, created.token).await, Code::Unauthenticated);
+    }
+
+    #[tokio::test]
+    async fn storage_holds_only_registration_key_digests () {
+        let h = Harness::new().await;
+        let created = h.admin().create_registration_key(Request::new(CreateRegistrationKeyArg {
+            admin_token: Vec::from(ADMIN_TOKEN),
+            uses: 2,
+            ..Default::default()
+        })).await.unwrap().into_inner().registration_key.unwrap();
+        let digest = h.auth.digests.registration_key(&created.registration_key);
+        assert_eq!(created.registration_key_digest, digest);
+        let stored = h.storage.list_registration_keys().await.unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].digest, digest);
+
+        // The key itself is never given out again.
+        let listed = h.admin().list_registration_keys(Request::new(ListRegistrationKeysArg {
+            admin_token: Vec::from(ADMIN_TOKEN),
+        })).await.unwrap().into_inner();
+        assert!(listed.registration_keys[0].registration_key.is_empty());
+        assert_eq!(listed.registration_keys[0].registration_key_digest, digest);
+
+        let intro = h.device().introduce_myself(Request::new(IntroduceMyselfArg {
+            registration_key: created.registration_key,
+            ..Default::default()
+        })).await.unwrap().into_inner();
+        let device_id = h.auth.digests.device_id(&intro.your_secret_key);
+        let stored = h.storage.get_intro(&device_id).await.unwrap().unwrap();
+        assert_eq!(stored.registration_key_digest, digest);
+    }
+}
@@ -0,0 +1,385 @@
+use crate::grpc::find_my_device::{ServerEvent, ServerEventType, LocationSnapshot};
+use crate::storage::DeviceId;
+use crate::utils::chrono_to_grpc_timestamp;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{Notify, oneshot};
+use chrono::prelude::*;
+use log::warn;
+
+/// The number of undelivered events kept for each device. If a device stays
+/// disconnected long enough for more events than this to pile up, the oldest
+/// ones are dropped.
+pub const DEFAULT_QUEUE_CAPACITY: usize = 64;
+
+/// An outstanding request for a device to report what it sees right now.
+#[derive(Default)]
+struct LocationRequest {
+    /// Whether the device has been told about the request. Only snapshots
+    /// submitted after this point are taken as an answer to the request.
+    delivered: bool,
+    waiters: Vec<oneshot::Sender<LocationSnapshot>>,
+}
+
+#[derive(Default)]
+struct DeviceQueue {
+    pending: VecDeque<ServerEvent>,
+    notify: Arc<Notify>,
+    /// The number of event streams open for the device.
+    streams: usize,
+    location_request: Option<LocationRequest>,
+}
+
+impl DeviceQueue {
+
+    fn push (&mut self, capacity: usize, event_type: ServerEventType) {
+        if self.pending.len() >= capacity {
+            warn!("Event queue is full. Dropping the oldest event.");
+            self.pending.pop_front();
+        }
+        self.pending.push_back(ServerEvent {
+            server_time: Some(chrono_to_grpc_timestamp(&Utc::now())),
+            event_type: event_type.into(),
+        });
+        self.notify.notify_one();
+    }
+
+    /// Drops those waiting on the location request who have given up, and
+    /// the request itself, along with the event telling the device of it, if
+    /// no one is left.
+    fn drop_abandoned_request (&mut self) {
+        if let Some(request) = self.location_request.as_mut() {
+            request.waiters.retain(|w| !w.is_closed());
+            if request.waiters.is_empty() {
+                self.location_request = None;
+                self.pending.retain(|e| e.event_type != ServerEventType::TellMeWhatYouSee as i32);
+            }
+        }
+    }
+
+    fn is_idle (&self) -> bool {
+        self.pending.is_empty() && self.streams == 0 && self.location_request.is_none()
+    }
+
+}
+
+/// Forgets the queue of a device once nothing is queued for it and nothing is
+/// waiting on it, so that a queue is not kept for every device that ever
+/// connected.
+fn forget_if_idle (queues: &mut HashMap<DeviceId, DeviceQueue>, device_id: &DeviceId) {
+    if let Some(queue) = queues.get_mut(device_id.as_slice()) {
+        queue.drop_abandoned_request();
+        if queue.is_idle() {
+            queues.remove(device_id.as_slice());
+        }
+    }
+}
+
+/// An open event stream's hold on the queue of its device, through which it
+/// is woken when events are raised. The queue is kept while this is held.
+pub struct Subscription {
+    queues: Arc<ServerEventQueues>,
+    device_id: DeviceId,
+    notify: Arc<Notify>,
+}
+
+impl Subscription {
+
+    /// Waits until an event is raised for the device.
+    pub async fn notified (&self) {
+        self.notify.notified().await
+    }
+
+}
+
+impl Drop for Subscription {
+
+    fn drop (&mut self) {
+        let mut queues = self.queues.queues.lock().unwrap();
+        if let Some(queue) = queues.get_mut(self.device_id.as_slice()) {
+            queue.streams -= 1;
+        }
+        forget_if_idle(&mut queues, &self.device_id);
+    }
+
+}
+
+/// Holds events that the server wants to deliver to devices via
+/// `StreamServerEvents`.
+///
+/// Events raised while a device is not streaming are queued until it connects.
+pub struct ServerEventQueues {
+    capacity: usize,
+    queues: Mutex<HashMap<DeviceId, DeviceQueue>>,
+}
+
+impl ServerEventQueues {
+
+    pub fn new (capacity: usize) -> Self {
+        ServerEventQueues {
+            capacity,
+            queues: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Queues an event of type `event_type` for the device identified by
+    /// `device_id` and wakes up its event stream, if it has one open.
+    pub fn raise (&self, device_id: &DeviceId, event_type: ServerEventType) {
+        let mut queues = self.queues.lock().unwrap();
+        queues.entry(device_id.clone()).or_default().push(self.capacity, event_type);
+    }
+
+    /// Asks a device to report what it sees right now, returning a receiver
+    /// for the first snapshot the device submits after learning of the
+    /// request. Concurrent requests for the same device share one answer.
+    pub fn request_location (&self, device_id: &DeviceId) -> oneshot::Receiver<LocationSnapshot> {
+        let (tx, rx) = oneshot::channel();
+        let mut queues = self.queues.lock().unwrap();
+        let queue = queues.entry(device_id.clone()).or_default();
+        // If everyone waiting on an earlier request gave up, ask again afresh.
+        queue.drop_abandoned_request();
+        if queue.location_request.is_none() {
+            queue.push(self.capacity, ServerEventType::TellMeWhatYouSee);
+        }
+        queue.location_request.get_or_insert_with(Default::default).waiters.push(tx);
+        rx
+    }
+
+    /// Records that a device was told about its outstanding location request
+    /// through its event stream.
+    pub fn location_request_delivered (&self, device_id: &DeviceId) {
+        let mut queues = self.queues.lock().unwrap();
+        if let Some(request) = queues
+            .get_mut(device_id.as_slice())
+            .and_then(|q| q.location_request.as_mut()) {
+            request.delivered = true;
+        }
+    }
+
+    /// Called with each snapshot that a device submits. If the device already
+    /// knew about an outstanding location request, `snapshot` answers it.
+    /// Returns `true` if the device still needs to be told about a request
+    /// that someone is still waiting on.
+    pub fn answer_location_request (&self, device_id: &DeviceId, snapshot: &LocationSnapshot) -> bool {
+        let mut queues = self.queues.lock().unwrap();
+        let queue = match queues.get_mut(device_id.as_slice()) {
+            Some(q) => q,
+            None => return false,
+        };
+        queue.drop_abandoned_request();
+        let tell_me = match queue.location_request.as_mut() {
+            Some(request) if !request.delivered => {
+                request.delivered = true;
+                true
+            },
+            Some(_) => {
+                for waiter in queue.location_request.take().unwrap().waiters {
+                    // The requester may have given up waiting since.
+                    let _ = waiter.send(snapshot.clone());
+                }
+                false
+            },
+            None => false,
+        };
+        forget_if_idle(&mut queues, device_id);
+        tell_me
+    }
+
+    /// Drops whatever no one is waiting on any longer from the queue of a
+    /// device, such as a location request whose requesters all gave up, and
+    /// the queue itself if nothing is left in it.
+    pub fn tidy (&self, device_id: &DeviceId) {
+        forget_if_idle(&mut self.queues.lock().unwrap(), device_id);
+    }
+
+    /// Opens the queue of a device to an event stream, which is woken through
+    /// the returned subscription when new events are raised.
+    pub fn subscribe (self: &Arc<Self>, device_id: &DeviceId) -> Subscription {
+        let mut queues = self.queues.lock().unwrap();
+        let queue = queues.entry(device_id.clone()).or_default();
+        queue.streams += 1;
+        Subscription {
+            queues: self.clone(),
+            device_id: device_id.clone(),
+            notify: queue.notify.clone(),
+        }
+    }
+
+    /// Removes and returns all events queued for a device.
+    pub fn take (&self, device_id: &DeviceId) -> Vec<ServerEvent> {
+        let mut queues = self.queues.lock().unwrap();
+        let taken = match queues.get_mut(device_id.as_slice()) {
+            Some(queue) => queue.pending.drain(..).collect(),
+            None => vec![],
+        };
+        forget_if_idle(&mut queues, device_id);
+        taken
+    }
+
+    /// Puts events that could not be delivered back at the front of the queue.
+    /// If that overfills it, the oldest events are dropped, as when raising.
+    pub fn requeue (&self, device_id: &DeviceId, events: Vec<ServerEvent>) {
+        let mut queues = self.queues.lock().unwrap();
+        let queue = queues.entry(device_id.clone()).or_default();
+        for event in events.into_iter().rev() {
+            queue.pending.push_front(event);
+        }
+        let excess = queue.pending.len().saturating_sub(self.capacity);
+        if excess > 0 {
+            warn!("Event queue is full. Dropping the {} oldest events.", excess);
+            queue.pending.drain(..excess);
+        }
+    }
+
+}
+
+impl Default for ServerEventQueues {
+
+    fn default () -> Self {
+        ServerEventQueues::new(DEFAULT_QUEUE_CAPACITY)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, Limits};
+    use crate::grpc::find_my_device::StreamServerEventsArg;
+    use crate::testing::{Harness, ADMIN_TOKEN, all, settle};
+    use crate::storage::Token;
+    use tonic::Request;
+    use tokio_stream::StreamExt;
+
+    /// Opens an event stream with `token`, reads the events that were waiting
+    /// for it, up to the keepalive that is sent once they are out, and then
+    /// disconnects.
+    async fn connect (h: &Harness, token: &Token) -> Vec<ServerEventType> {
+        let mut stream = h.device().stream_server_events(Request::new(StreamServerEventsArg {
+            token: token.clone(),
+        })).await.unwrap().into_inner();
+        let mut received = vec![];
+        loop {
+            let event = stream.next().await.unwrap().unwrap();
+            match event.event_type() {
+                ServerEventType::Noop => break,
+                t => received.push(t),
+            }
+        }
+        drop(stream);
+        settle().await;
+        received
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn events_raised_while_disconnected_are_delivered_on_connecting () {
+        let h = Harness::new().await;
+        let token = h.valid_token(all()).await;
+        assert!(connect(&h, &token).await.is_empty());
+        h.events.raise(&h.device_id(), ServerEventType::TokenIssued);
+        h.events.raise(&h.device_id(), ServerEventType::Wipe);
+        assert_eq!(connect(&h, &token).await, [ ServerEventType::TokenIssued, ServerEventType::Wipe ]);
+        // Delivered events are not delivered again.
+        assert!(connect(&h, &token).await.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn events_keep_their_order_across_a_dropped_stream () {
+        let h = Harness::new().await;
+        let token = h.valid_token(all()).await;
+        let raised = [ ServerEventType::TokenIssued, ServerEventType::Wipe, ServerEventType::Excommunicated ];
+        let mut stream = h.device().stream_server_events(Request::new(StreamServerEventsArg {
+            token: token.clone(),
+        })).await.unwrap().into_inner();
+        stream.next().await.unwrap().unwrap();
+        // The stream is dropped while the events are being sent. Any that
+        // were already on their way are lost, but the rest are put back.
+        for event_type in raised {
+            h.events.raise(&h.device_id(), event_type);
+        }
+        drop(stream);
+        settle().await;
+        let received = connect(&h, &token).await;
+        assert!(!received.is_empty() && raised.ends_with(&received), "{:?}", received);
+
+        let device_id = h.device_id();
+        let taken = [ ServerEventType::TokenIssued, ServerEventType::Wipe ];
+        for event_type in taken {
+            h.events.raise(&device_id, event_type);
+        }
+        let undelivered = h.events.take(&device_id);
+        h.events.raise(&device_id, ServerEventType::Excommunicated);
+        h.events.requeue(&device_id, undelivered);
+        assert_eq!(connect(&h, &token).await, raised);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn full_queues_drop_the_oldest_events () {
+        let h = Harness::with_config(Config {
+            admin_token: Some(Vec::from(ADMIN_TOKEN)),
+            limits: Limits {
+                event_queue_capacity: 2,
+                ..Default::default()
+            },
+            ..Default::default()
+        }).await;
+        let token = h.valid_token(all()).await;
+        let device_id = h.device_id();
+        h.events.raise(&device_id, ServerEventType::TokenIssued);
+        h.events.raise(&device_id, ServerEventType::Wipe);
+        h.events.raise(&device_id, ServerEventType::Excommunicated);
+        assert_eq!(connect(&h, &token).await, [ ServerEventType::Wipe, ServerEventType::Excommunicated ]);
+
+        // Events put back after a newer one was raised are older than it.
+        h.events.raise(&device_id, ServerEventType::TokenIssued);
+        h.events.raise(&device_id, ServerEventType::Wipe);
+        let undelivered = h.events.take(&device_id);
+        h.events.raise(&device_id, ServerEventType::Excommunicated);
+        h.events.requeue(&device_id, undelivered);
+        assert_eq!(connect(&h, &token).await, [ ServerEventType::Wipe, ServerEventType::Excommunicated ]);
+    }
+
+    #[test]
+    fn abandoned_location_requests_are_dropped () {
+        let events = ServerEventQueues::default();
+        let device_id = vec![ 1; 32 ];
+        drop(events.request_location(&device_id));
+        assert!(!events.answer_location_request(&device_id, &LocationSnapshot::default()));
+        assert!(events.take(&device_id).is_empty());
+
+        // Only those still waiting are answered.
+        drop(events.request_location(&device_id));
+        let mut waiting = events.request_location(&device_id);
+        assert!(events.answer_location_request(&device_id, &LocationSnapshot::default()));
+        assert!(!events.answer_location_request(&device_id, &LocationSnapshot::default()));
+        assert!(waiting.try_recv().is_ok());
+        assert_eq!(events.take(&device_id).len(), 1);
+        assert!(events.queues.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn queues_are_forgotten_once_nothing_is_left_in_them () {
+        let h = Harness::new().await;
+        let token = h.valid_token(all()).await;
+        assert!(connect(&h, &token).await.is_empty());
+        assert!(h.events.queues.lock().unwrap().is_empty());
+        h.events.raise(&h.device_id(), ServerEventType::TokenIssued);
+        assert_eq!(h.events.queues.lock().unwrap().len(), 1);
+        assert_eq!(connect(&h, &token).await, [ ServerEventType::TokenIssued ]);
+        assert!(h.events.queues.lock().unwrap().is_empty());
+
+        // A queue is kept while a stream is open, even if it is empty, so
+        // that the stream is woken by what is raised next.
+        let mut stream = h.device().stream_server_events(Request::new(StreamServerEventsArg {
+            token: token.clone(),
+        })).await.unwrap().into_inner();
+        stream.next().await.unwrap().unwrap();
+        assert_eq!(h.events.queues.lock().unwrap().len(), 1);
+        h.events.raise(&h.device_id(), ServerEventType::Wipe);
+        assert_eq!(stream.next().await.unwrap().unwrap().event_type(), ServerEventType::Wipe);
+        drop(stream);
+        settle().await;
+        assert!(h.events.queues.lock().unwrap().is_empty());
+    }
+
+}
@@ -0,0 +1,348 @@
+//! The layer that authorizes every request made to the gRPC services and the
+//! web UI before it reaches them.
+use crate::auth::{Access, Authorizer, Credentials, Operation};
+use crate::grpc::find_my_device::*;
+use crate::storage::{Storage, Token};
+use tonic::Status;
+use tonic::body::BoxBody;
+use tonic::transport::server::{TcpConnectInfo, TlsConnectInfo};
+use tower::{Layer, Service};
+use hyper::Body;
+use hyper::body::{Bytes, HttpBody};
+use warp::http::{Request, Response, StatusCode};
+use prost::{DecodeError, Message};
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+/// The path under which the web UI shows the locations a token may read.
+pub const LOCATIONS_PATH: &str = "/locations/";
+
+/// The largest request message that will be read. Every request is small, so
+/// this is only reached by clients that mean harm.
+const MAX_MESSAGE_LENGTH: usize = 64 * 1024;
+
+/// The address of a web client, which is added to the extensions of requests
+/// by the web server, since there is no `TcpConnectInfo` for them.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientAddress(pub SocketAddr);
+
+type Decoder = fn(&[u8]) -> Result<Credentials, DecodeError>;
+
+fn token (token: Token) -> Credentials {
+    Credentials { token, ..Default::default() }
+}
+
+fn admin_token (admin_token: Vec<u8>) -> Credentials {
+    Credentials { admin_token, ..Default::default() }
+}
+
+fn nothing (_: &[u8]) -> Result<Credentials, DecodeError> {
+    Ok(Credentials::default())
+}
+
+/// The access that each RPC requires, and how to find the credentials in its
+/// request. Anything not listed here is refused.
+pub fn rule (path: &str) -> Option<(Access, Decoder)> {
+    let rule: (Access, Decoder) = match path {
+        "/findmydevice.DeviceService/SubmitLocation" => (Access::Token(Operation::SubmitLocation),
+            |b| Ok(token(SubmitLocationArg::decode(b)?.token))),
+        "/findmydevice.DeviceService/StreamServerEvents" => (Access::Token(Operation::StreamServerEvents),
+            |b| Ok(token(StreamServerEventsArg::decode(b)?.token))),
+        "/findmydevice.DeviceService/IntroduceMyself" => (Access::Introduction, nothing),
+        "/findmydevice.DeviceService/AcknowledgeWipe" => (Access::Token(Operation::AcknowledgeWipe),
+            |b| Ok(token(AcknowledgeWipeArg::decode(b)?.token))),
+
+        "/findmydevice.UserService/CreateToken" => (Access::SecretKey, |b| Ok(Credentials {
+            secret_key: CreateTokenArg::decode(b)?.secret_key,
+            ..Default::default()
+        })),
+        // The token in this request is the one to revoke, not a credential.
+        "/findmydevice.UserService/RevokeToken" => (Access::SecretKey, |b| Ok(Credentials {
+            secret_key: RevokeTokenArg::decode(b)?.secret_key,
+            ..Default::default()
+        })),
+        "/findmydevice.UserService/ListTokens" => (Access::TokenOrSecretKey(Operation::ListTokens), |b| {
+            let m = ListTokensArg::decode(b)?;
+            Ok(Credentials { token: m.token, secret_key: m.secret_key, ..Default::default() })
+        }),
+        "/findmydevice.UserService/PurgeLocation" => (Access::Token(Operation::PurgeLocation),
+            |b| Ok(token(PurgeLocationArg::decode(b)?.token))),
+        "/findmydevice.UserService/CancelPurge" => (Access::Token(Operation::CancelPurge),
+            |b| Ok(token(CancelPurgeArg::decode(b)?.token))),
+        "/findmydevice.UserService/Wipe" => (Access::Token(Operation::Wipe),
+            |b| Ok(token(WipeArg::decode(b)?.token))),
+        "/findmydevice.UserService/ListLocations" => (Access::Token(Operation::ListLocations),
+            |b| Ok(token(ListLocationsArg::decode(b)?.token))),
+        "/findmydevice.UserService/StreamLocation" => (Access::Token(Operation::StreamLocation),
+            |b| Ok(token(StreamLocationArg::decode(b)?.token))),
+        "/findmydevice.UserService/RequestLocation" => (Access::Token(Operation::RequestLocation),
+            |b| Ok(token(RequestLocationArg::decode(b)?.token))),
+        "/findmydevice.UserService/GetServerInfo" => (Access::Anyone, nothing),
+        "/findmydevice.UserService/GetStorageInfo" => (Access::Token(Operation::GetStorageInfo),
+            |b| Ok(token(GetStorageInfoArg::decode(b)?.token))),
+        "/findmydevice.UserService/GetDeviceStatus" => (Access::TokenOrSecretKey(Operation::GetDeviceStatus), |b| {
+            let m = GetDeviceStatusArg::decode(b)?;
+            Ok(Credentials { token: m.token, secret_key: m.secret_key, ..Default::default() })
+        }),
+        "/findmydevice.UserService/Excommunicate" => (Access::SecretKey, |b| Ok(Credentials {
+            secret_key: ExcommunicateArg::decode(b)?.secret_key,
+            ..Default::default()
+        })),
+
+        // The tokens and secret keys in these requests identify the device
+        // to act on, and are not credentials.
+        "/findmydevice.AdminService/Excommunicate" => (Access::Admin,
+            |b| Ok(admin_token(AdminExcommunicateArg::decode(b)?.admin_token))),
+        "/findmydevice.AdminService/ListEmergencyPurges" => (Access::Admin,
+            |b| Ok(admin_token(ListEmergencyPurgesArg::decode(b)?.admin_token))),
+        "/findmydevice.AdminService/DecideEmergencyPurge" => (Access::Admin,
+            |b| Ok(admin_token(DecideEmergencyPurgeArg::decode(b)?.admin_token))),
+        "/findmydevice.AdminService/ListAuditLog" => (Access::Admin,
+            |b| Ok(admin_token(ListAuditLogArg::decode(b)?.admin_token))),
+        "/findmydevice.AdminService/CreateRegistrationKey" => (Access::Admin,
+            |b| Ok(admin_token(CreateRegistrationKeyArg::decode(b)?.admin_token))),
+        "/findmydevice.AdminService/ListRegistrationKeys" => (Access::Admin,
+            |b| Ok(admin_token(ListRegistrationKeysArg::decode(b)?.admin_token))),
+        "/findmydevice.AdminService/RevokeRegistrationKey" => (Access::Admin,
+            |b| Ok(admin_token(RevokeRegistrationKeyArg::decode(b)?.admin_token))),
+        _ => return None,
+    };
+    Some(rule)
+}
+
+/// Wraps a service, which may be the gRPC services or the web UI, so that
+/// every request is refused unless it presents the credentials `rule` says
+/// it needs. What they were found to be is added to the request's extensions
+/// as an `Authorized`, for the service to use.
+pub struct AuthLayer <S: Storage> {
+    storage: Arc<S>,
+    auth: Authorizer,
+}
+
+impl <S: Storage> AuthLayer <S> {
+
+    pub fn new (storage: Arc<S>, auth: Authorizer) -> Self {
+        AuthLayer { storage, auth }
+    }
+
+}
+
+impl <S: Storage> Clone for AuthLayer <S> {
+
+    fn clone (&self) -> Self {
+        AuthLayer::new(self.storage.clone(), self.auth.clone())
+    }
+
+}
+
+impl <S: Storage, I> Layer<I> for AuthLayer <S> {
+    type Service = AuthService<S, I>;
+
+    fn layer (&self, inner: I) -> Self::Service {
+        AuthService {
+            storage: self.storage.clone(),
+            auth: self.auth.clone(),
+            inner,
+        }
+    }
+}
+
+pub struct AuthService <S: Storage, I> {
+    storage: Arc<S>,
+    auth: Authorizer,
+    inner: I,
+}
+
+impl <S: Storage, I: Clone> Clone for AuthService <S, I> {
+
+    fn clone (&self) -> Self {
+        AuthService {
+            storage: self.storage.clone(),
+            auth: self.auth.clone(),
+            inner: self.inner.clone(),
+        }
+    }
+
+}
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+impl <S, I, B> Service<Request<Body>> for AuthService <S, I>
+where
+    S: Storage + Send + Sync + 'static,
+    I: Service<Request<Body>, Response = Response<B>> + Clone + Send + 'static,
+    I::Future: Send + 'static,
+    B: HttpBody<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    type Response = Response<BoxBody>;
+    type Error = I::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready (&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call (&mut self, request: Request<Body>) -> Self::Future {
+        // The service that was polled ready is the one that must be called.
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let storage = self.storage.clone();
+        let auth = self.auth.clone();
+        Box::pin(async move {
+            let request = match authorize(storage.as_ref(), &auth, request).await {
+                Ok(request) => request,
+                Err(refusal) => return Ok(refusal),
+            };
+            let response = inner.call(request).await?;
+            Ok(response.map(boxed))
+        })
+    }
+}
+
+fn boxed <B> (body: B) -> BoxBody
+where
+    B: HttpBody<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    body.map_err(|e| Status::from_error(e.into())).boxed_unsync()
+}
+
+fn client_address (request: &Request<Body>) -> Option<IpAddr> {
+    let extensions = request.extensions();
+    extensions.get::<TcpConnectInfo>()
+        .and_then(|i| i.remote_addr())
+        .or_else(|| extensions.get::<TlsConnectInfo<TcpConnectInfo>>().and_then(|i| i.get_ref().remote_addr()))
+        .or_else(|| extensions.get::<ClientAddress>().map(|a| a.0))
+        .map(|a| a.ip())
+}
+
+fn is_grpc (request: &Request<Body>) -> bool {
+    request.headers().get("content-type")
+        .map(|t| t.as_bytes().starts_with(b"application/grpc"))
+        .unwrap_or(false)
+}
+
+fn web_refusal (status: StatusCode, message: String) -> Response<BoxBody> {
+    let mut response = Response::new(boxed(Body::from(message)));
+    *response.status_mut() = status;
+    response
+}
+
+/// Returns `request` with what its credentials were found to be added, or the
+/// response to refuse it with.
+async fn authorize <S: Storage> (
+    storage: &S,
+    auth: &Authorizer,
+    request: Request<Body>,
+) -> Result<Request<Body>, Response<BoxBody>> {
+    let client = client_address(&request);
+    if let Some(hex_token) = request.uri().path().strip_prefix(LOCATIONS_PATH) {
+        let credentials = match hex::decode(hex_token) {
+            Ok(t) => token(t),
+            Err(_) => {
+                auth.invalid_attempt(client);
+                return Err(web_refusal(StatusCode::BAD_REQUEST, String::from("Malformed token")));
+            },
+        };
+        let access = Access::Token(Operation::ViewLocations);
+        let authorized = auth.check(storage, access, &credentials, client).await
+            .map_err(|e| web_refusal(e.http_status(), e.to_string()))?;
+        let mut request = request;
+        request.extensions_mut().insert(authorized);
+        return Ok(request);
+    }
+    let (access, decode) = match rule(request.uri().path()) {
+        Some(rule) => rule,
+        None if is_grpc(&request) => return Err(Status::unimplemented("No such method.").to_http()),
+        None => return Err(web_refusal(StatusCode::NOT_FOUND, String::from("Not found"))),
+    };
+    let (mut parts, body) = request.into_parts();
+    let frame = read_frame(body).await.map_err(Status::to_http)?;
+    // The credentials are in the one message that each request consists of,
+    // which is after the compression flag and length that frame it.
+    let credentials = decode(&frame[5..])
+        .map_err(|_| Status::invalid_argument("Malformed request.").to_http())?;
+    let authorized = auth.check(storage, access, &credentials, client).await
+        .map_err(|e| Status::from(e).to_http())?;
+    parts.extensions.insert(authorized);
+    Ok(Request::from_parts(parts, Body::from(frame)))
+}
+
+/// Reads the single message framed in `body`, which is all that any of the
+/// RPCs take.
+async fn read_frame (mut body: Body) -> Result<Vec<u8>, Status> {
+    let mut frame = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| Status::from_error(Box::new(e)))?;
+        if frame.len() + chunk.len() > MAX_MESSAGE_LENGTH + 5 {
+            return Err(Status::resource_exhausted("Request too large."));
+        }
+        frame.extend_from_slice(&chunk);
+    }
+    if frame.len() < 5 {
+        return Err(Status::invalid_argument("Malformed request."));
+    }
+    if frame[0] != 0 {
+        return Err(Status::unimplemented("Compressed requests are not supported."));
+    }
+    let length = u32::from_be_bytes([ frame[1], frame[2], frame[3], frame[4] ]) as usize;
+    if frame.len() - 5 != length {
+        return Err(Status::invalid_argument("Malformed request."));
+    }
+    Ok(frame)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Authorized;
+    use crate::config::Config;
+    use crate::storage::memory::MemoryStorage;
+    use tower::ServiceExt;
+    use std::convert::Infallible;
+
+    /// Sends `frame` to `path` through the layer, and returns the gRPC status
+    /// it was refused with, or `None` if it was let through.
+    async fn send (path: &str, frame: Vec<u8>) -> Option<String> {
+        let layer = AuthLayer::new(Arc::new(MemoryStorage::new()), Authorizer::new(Arc::new(Config::default())));
+        let service = layer.layer(tower::service_fn(|request: Request<Body>| async move {
+            assert!(matches!(request.extensions().get::<Authorized>(), Some(Authorized::Anyone)));
+            Ok::<_, Infallible>(Response::new(Body::empty()))
+        }));
+        let request = Request::post(path)
+            .header("content-type", "application/grpc")
+            .body(Body::from(frame))
+            .unwrap();
+        let response = service.oneshot(request).await.unwrap();
+        response.headers().get("grpc-status").map(|s| s.to_str().unwrap().to_owned())
+    }
+
+    #[tokio::test]
+    async fn malformed_and_unknown_requests_are_refused () {
+        let get_server_info = "/findmydevice.UserService/GetServerInfo";
+        assert_eq!(send(get_server_info, vec![ 0, 0, 0, 0, 0 ]).await, None);
+        assert_eq!(send("/findmydevice.UserService/NoSuchMethod", vec![ 0, 0, 0, 0, 0 ]).await.as_deref(), Some("12"));
+        assert_eq!(send(get_server_info, vec![]).await.as_deref(), Some("3"));
+        assert_eq!(send(get_server_info, vec![ 0, 0, 0, 0, 2, 0 ]).await.as_deref(), Some("3"));
+        assert_eq!(send(get_server_info, vec![ 1, 0, 0, 0, 0 ]).await.as_deref(), Some("12"));
+        let list_locations = "/findmydevice.UserService/ListLocations";
+        assert_eq!(send(list_locations, vec![ 0, 0, 0, 0, 2, 0x0A, 0x05 ]).await.as_deref(), Some("3"));
+        let too_long = vec![ 0; MAX_MESSAGE_LENGTH + 6 ];
+        assert_eq!(send(list_locations, too_long).await.as_deref(), Some("8"));
+    }
+
+    #[tokio::test]
+    async fn unknown_web_pages_are_not_found () {
+        let layer = AuthLayer::new(Arc::new(MemoryStorage::new()), Authorizer::new(Arc::new(Config::default())));
+        let service = layer.layer(tower::service_fn(|_: Request<Body>| async move {
+            Ok::<_, Infallible>(Response::new(Body::empty()))
+        }));
+        let request = Request::get("/passwords").body(Body::empty()).unwrap();
+        assert_eq!(service.oneshot(request).await.unwrap().status(), StatusCode::NOT_FOUND);
+    }
+
+}
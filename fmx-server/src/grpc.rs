@@ -1,3 +1,5 @@
+// Some messages in the protocol are not used by this server yet.
+#[allow(dead_code)]
 pub mod find_my_device {
     tonic::include_proto!("findmydevice");
 }
\ No newline at end of file
@@ -1,12 +1,16 @@
-use log::LevelFilter;
+use crate::config::LogConfig;
+use log4rs::append::Append;
 use log4rs::append::console::ConsoleAppender;
+use log4rs::append::file::FileAppender;
 use log4rs::config::{Appender, Config, Logger, Root};
 
-pub fn get_default_log4rs_config () -> Config {
-    let stdout = ConsoleAppender::builder().build();
-    Config::builder()
-        .appender(Appender::builder().build("stdout", Box::new(stdout)))
-        .logger(Logger::builder().build("fmx", LevelFilter::Info))
-        .build(Root::builder().appender("stdout").build(LevelFilter::Info))
-        .unwrap()
+pub fn get_log4rs_config (log: &LogConfig) -> anyhow::Result<Config> {
+    let appender: Box<dyn Append> = match &log.file {
+        Some(path) => Box::new(FileAppender::builder().build(path)?),
+        None => Box::new(ConsoleAppender::builder().build()),
+    };
+    Ok(Config::builder()
+        .appender(Appender::builder().build("main", appender))
+        .logger(Logger::builder().build("fmx", log.level))
+        .build(Root::builder().appender("main").build(log.level))?)
 }
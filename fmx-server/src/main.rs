@@ -1,180 +1,89 @@
+mod admin;
+mod archive;
+mod auth;
+mod broadcast;
 mod config;
+mod crypto;
+mod device;
+mod digest;
+mod events;
+mod gate;
 mod grpc;
 mod logging;
+mod purge;
+mod quota;
+mod ratelimit;
 mod storage;
+#[cfg(test)]
+mod testing;
+mod user;
 mod utils;
 mod web;
-use logging::get_default_log4rs_config;
-use tonic::{transport::Server, Request, Response, Status};
+use logging::get_log4rs_config;
+use tonic::transport::Server;
 use storage::{
     Storage,
-    LocationInsertion,
-    IntroInsertion,
     LocationsFilter,
-    Token,
-    TokenEntry,
-};
-use config::Config;
-use storage::memory::MemoryStorage;
-use grpc::find_my_device::device_service_server::{DeviceService, DeviceServiceServer};
-use grpc::find_my_device::{
-    SubmitLocationArg,
-    SubmitLocationResult,
-    // StreamServerEventsArg,
-    // ServerEvent,
-    IntroduceMyselfArg,
-    IntroduceMyselfResult,
-    Permissions,
 };
+use broadcast::LocationBroadcaster;
+use config::{Args, Command, Config, StorageBackend};
+use auth::{Authorized, Authorizer};
+use crypto::Vault;
+use admin::AdminServiceProvider;
+use device::DeviceServiceProvider;
+use events::ServerEventQueues;
+use user::UserServiceProvider;
+use purge::{run_purge_scheduler, PURGE_CHECK_INTERVAL};
+use quota::{run_retention_enforcer, RETENTION_CHECK_INTERVAL};
+use ratelimit::{run_rate_limit_pruner, PRUNE_INTERVAL};
+use storage::memory::{MemoryStorage, run_snapshotter};
+use storage::redb::RedbStorage;
+use storage::sqlite::SqliteStorage;
+use grpc::find_my_device::device_service_server::DeviceServiceServer;
+use grpc::find_my_device::user_service_server::UserServiceServer;
+use grpc::find_my_device::admin_service_server::AdminServiceServer;
 use warp::Filter;
 use warp::http::StatusCode;
+use gate::{AuthLayer, ClientAddress};
+use hyper::server::conn::Http;
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+use tonic::body::BoxBody;
+use tower::{Layer, Service, ServiceExt};
+use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::sync::Mutex;
-use log::{warn, debug, error, trace};
-use chrono::prelude::*;
 use web::{LocationsPage, Props};
 use std::convert::Infallible;
 use std::rc::Rc;
-use utils::grpc_timestamp_to_chrono;
-
-#[derive(Clone)]
-pub struct DeviceServiceProvider <S: Storage> {
-    pub storage: Arc<Mutex<S>>,
-    pub config: Arc<Config>,
-}
-
-#[tonic::async_trait]
-impl <S: Storage + Send + Sync + 'static> DeviceService for DeviceServiceProvider <S> {
-
-    async fn submit_location (
-        &self,
-        request: Request<SubmitLocationArg>,
-    ) -> Result<Response<SubmitLocationResult>, Status> {
-        let maybe_remote_addr = request.remote_addr();
-        let req = request.into_inner();
-        let mut storage = self.storage.lock().await;
-        let maybe_token_info = storage.get_token_info(&req.token).await
-            .map_err(|e| {
-                error!("Database failure: {:?}", e);
-                Status::internal("Database failure.")
-            })?;
-        let using_test_token = (req.token.len() > 0) && (req.token == self.config.testing_token);
-        if !using_test_token && maybe_token_info.is_none() {
-            debug!("Unauthenticated request from {:?}", maybe_remote_addr);
-            return Err(Status::unauthenticated("Unauthenticated"));
-        }
-        let token_info = if using_test_token {
-            maybe_token_info.unwrap()
-        } else {
-            TokenEntry{
-                not_before: DateTime::<Utc>::MIN_UTC,
-                not_after: None,
-                secret_key: self.config.testing_token.clone(),
-                permissions: Permissions{
-                    write_locations: true,
-                    ..Default::default()
-                }
-            }
-        };
-
-        if req.emergency {
-            warn!("Emergency announced by {:?}", token_info.secret_key);
-        }
-        let insertion = LocationInsertion{
-            emergency: req.emergency,
-            update_time: Utc::now(),
-            expected_next_update_time: req.expected_next_update_time
-                .map(|t| grpc_timestamp_to_chrono(&t).unwrap_or(Utc::now())),
-            location: req.location,
-            notes: req.notes,
-            velocity: req.velocity,
-            nearby_bluetooth_devices: req.nearby_bluetooth_devices,
-            nearby_wifi_network: req.nearby_wifi_network,
-            remote_addr: maybe_remote_addr,
-        };
-        let ret = match storage.write_location(&token_info.secret_key, &insertion).await {
-            Ok(_) => Ok(Response::new(SubmitLocationResult {
-                recorded: true,
-                excommunicated: false,
-                remote_wipe: false,
-            })),
-            Err(_) => return Err(Status::internal("Database failure.")),
-        };
-        trace!("Inserted location submitted by {:?}", maybe_remote_addr);
-        ret
-    }
-
-    async fn introduce_myself (
-        &self,
-        request: Request<IntroduceMyselfArg>,
-    ) -> Result<Response<IntroduceMyselfResult>, Status> {
-        let maybe_remote_addr = request.remote_addr();
-        let req = request.into_inner();
-        if !self.config.open_registration {
-            // TODO: Verify registration key.
-            // return Err(Status::invalid_argument("Secret key must be 16 bytes"));
-        }
-        let random_bytes = rand::random::<[u8; 32]>();
-        let secret_key = Vec::from(&random_bytes[0..16]);
-        let token = Vec::from(&random_bytes[16..]);
-        let insertion = IntroInsertion{
-            remote_addr: maybe_remote_addr,
-            secret_key: &secret_key,
-            token: &token,
-            arg: &req,
-        };
-        let mut storage = self.storage.lock().await;
-        match storage.write_intro(&insertion).await {
-            Ok(_) => Ok(Response::new(IntroduceMyselfResult {
-                nice_to_meet_you: true,
-                your_token: token,
-            })),
-            Err(_) => Err(Status::internal("Database failure.")),
-        }
-    }
-
-    // findmydevice.rs(955, 9): `StreamServerEventsStream` from trait
-    // findmydevice.rs(960, 9): `stream_server_events` from trait
-
-}
-
-// fn render_locations_path (token: String) -> String {
-
-// }
+use utils::redact_nearby;
+use clap::Parser;
+use log::{debug, warn};
 
 async fn render_locations_path <S: Storage> (
-    token_str: String,
-    storage: Arc<Mutex<S>>,
+    authorized: Authorized,
+    storage: Arc<S>,
+    vault: Vault,
 ) -> Result<Box<dyn warp::Reply>, Infallible> {
-    // tokio::time::sleep(Duration::from_secs(seconds)).await;
-    // Ok(format!("I waited {} seconds!", seconds))
-    let token: Token = match hex::decode(token_str) {
-        Ok(h) => h,
-        Err(_) => return Ok(Box::new(warp::reply::with_status(String::from("Malformed token"), StatusCode::BAD_REQUEST))),
-    };
-    let store = storage.lock().await;
-    let maybe_token_info = match store.get_token_info(&token).await {
-        Ok(t) => t,
-        Err(e) => return Ok(Box::new(warp::reply::with_status(e.to_string(), StatusCode::INTERNAL_SERVER_ERROR))),
+    let token_info = match authorized {
+        Authorized::Token(t) => t,
+        // The `AuthLayer` only lets requests with a token through to this
+        // route, but anything else is refused all the same.
+        _ => return Ok(Box::new(warp::reply::with_status(String::from("Forbidden"), StatusCode::FORBIDDEN))),
     };
-    if maybe_token_info.is_none() {
-        return Ok(Box::new(warp::reply::with_status(String::from("Unauthorized"), StatusCode::UNAUTHORIZED)));
-    }
-    let token_info = maybe_token_info.unwrap();
-    if !token_info.permissions.read_locations {
-        return Ok(Box::new(warp::reply::with_status(String::from("Forbidden"), StatusCode::FORBIDDEN)));
-    }
     let filter = LocationsFilter {
         limit: 100,
         since: None,
         until: None,
     };
-    let locs = match store.list_locations(&token_info.secret_key, &filter).await {
+    let mut locs = match vault.list_locations(storage.as_ref(), &token_info.device_id, &filter).await {
         Ok(l) => l,
         Err(e) => return Ok(Box::new(warp::reply::with_status(e.to_string(), StatusCode::INTERNAL_SERVER_ERROR))),
     };
+    if !token_info.permissions.nearby {
+        locs.iter_mut().for_each(redact_nearby);
+    }
     let renderer = yew::ServerRenderer::<LocationsPage>::with_props(move || Props {
-        locations: locs.locations.into_iter().map(|l| Rc::new(l)).collect(),
+        locations: locs.into_iter().map(Rc::new).collect(),
     });
     // .hydratable(false) gets rid of the HTML comments.
     let rendered = renderer.hydratable(false).render().await;
@@ -182,37 +91,210 @@ async fn render_locations_path <S: Storage> (
 }
 
 fn with_storage <S: Storage + Sync + Send> (
-    storage: Arc<Mutex<S>>,
-) -> impl Filter<Extract = (Arc<Mutex<S>>,), Error = std::convert::Infallible> + Clone {
+    storage: Arc<S>,
+) -> impl Filter<Extract = (Arc<S>,), Error = std::convert::Infallible> + Clone {
     warp::any().map(move || storage.clone())
 }
 
+fn with_vault (
+    vault: Vault,
+) -> impl Filter<Extract = (Vault,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || vault.clone())
+}
+
+/// The routes of the web UI, which expect to be wrapped in an `AuthLayer`.
+pub fn web_routes <S: Storage + Send + Sync + 'static> (
+    storage: Arc<S>,
+    vault: Vault,
+) -> impl Filter<Extract = (Box<dyn warp::Reply>,), Error = warp::Rejection> + Clone {
+    warp::path!("locations" / String)
+        .and(warp::ext::get::<Authorized>())
+        .and(with_storage(storage))
+        .and(with_vault(vault))
+        .and_then(|_token, authorized, storage, vault| render_locations_path(authorized, storage, vault))
+}
+
+/// Serves the web UI, with TLS if `tls` is given. This is done with hyper
+/// rather than `warp::serve`, so that the routes can be wrapped in the same
+/// `AuthLayer` as the gRPC services.
+async fn serve_web <W> (
+    web: W,
+    address: SocketAddr,
+    tls: Option<TlsAcceptor>,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    W: Service<hyper::Request<hyper::Body>, Response = hyper::Response<BoxBody>, Error = Infallible> + Clone + Send + 'static,
+    W::Future: Send + 'static,
+{
+    let listener = TcpListener::bind(address).await?;
+    loop {
+        let (stream, remote_addr) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                warn!("Could not accept a web connection: {}", e);
+                continue;
+            },
+        };
+        let web = web.clone().map_request(move |mut request: hyper::Request<hyper::Body>| {
+            request.extensions_mut().insert(ClientAddress(remote_addr));
+            request
+        });
+        let tls = tls.clone();
+        tokio::spawn(async move {
+            let served = match tls {
+                Some(acceptor) => match acceptor.accept(stream).await {
+                    Ok(stream) => Http::new().serve_connection(stream, web).await,
+                    Err(e) => {
+                        debug!("TLS handshake with {} failed: {}", remote_addr, e);
+                        return;
+                    },
+                },
+                None => Http::new().serve_connection(stream, web).await,
+            };
+            if let Err(e) = served {
+                debug!("Web connection with {} failed: {}", remote_addr, e);
+            }
+        });
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    log4rs::init_config(get_default_log4rs_config()).unwrap();
-    let addr = "127.0.0.1:50051".parse()?;
-    let storage = Arc::new(Mutex::new(MemoryStorage::new()));
+    let mut args = Args::parse();
+    let command = args.command.take();
+    let mut config = Config::load(args)?;
+    log4rs::init_config(get_log4rs_config(&config.log)?)?;
+    if !config.testing_token.is_empty() {
+        warn!("The testing token is enabled. It should be disabled in production.");
+    }
+    let persistent = config.storage.backend != StorageBackend::Memory || config.storage.path.is_some();
+    // Archives hold everything just as it is stored, so exporting and
+    // importing need neither key.
+    if command.is_none() && persistent && (config.digest_key.is_empty() || config.master_key.is_empty()) {
+        return Err("Persistent storage requires a digest key and a master key, without which nothing stored could be used after a restart.".into());
+    }
+    match config.storage.backend {
+        StorageBackend::Memory => match config.storage.path.clone() {
+            Some(dir) => {
+                let storage = Arc::new(MemoryStorage::open(&dir).await?);
+                if let Some(command) = command {
+                    run_command(storage.as_ref(), command).await?;
+                    // Whatever was imported is compacted into a snapshot.
+                    storage.take_snapshot().await?;
+                    return Ok(());
+                }
+                tokio::spawn(run_snapshotter(storage.clone(), config.storage.snapshot_interval));
+                serve(storage, config).await
+            },
+            None => {
+                if command.is_some() {
+                    return Err("Archives can only be exported from and imported into persistent storage.".into());
+                }
+                // Nothing outlives the server with this backend, so the digest
+                // key need not either.
+                if config.digest_key.is_empty() {
+                    config.digest_key = Vec::from(rand::random::<[u8; 32]>());
+                }
+                if config.master_key.is_empty() {
+                    config.master_key = Vec::from(rand::random::<[u8; crypto::KEY_LENGTH]>());
+                }
+                serve(Arc::new(MemoryStorage::new()), config).await
+            },
+        },
+        backend @ (StorageBackend::Redb | StorageBackend::Sqlite) => {
+            let path = match &config.storage.path {
+                Some(p) => p.clone(),
+                None => return Err("File storage backends require a storage path.".into()),
+            };
+            if backend == StorageBackend::Redb {
+                run_or_serve(RedbStorage::open(&path)?, command, config).await
+            } else {
+                run_or_serve(SqliteStorage::open(&path)?, command, config).await
+            }
+        },
+    }
+}
+
+async fn run_command <S: Storage> (storage: &S, command: Command) -> anyhow::Result<()> {
+    match command {
+        Command::Export { archive } => {
+            let summary = archive::export(storage, &archive).await?;
+            println!("Exported {} to {}.", summary, archive.display());
+        },
+        Command::Import { archive } => {
+            let summary = archive::import(storage, &archive).await?;
+            println!("Imported and verified {} from {}.", summary, archive.display());
+        },
+    };
+    Ok(())
+}
+
+async fn run_or_serve <S: Storage + Send + Sync + 'static> (
+    storage: S,
+    command: Option<Command>,
+    config: Config,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match command {
+        Some(command) => Ok(run_command(&storage, command).await?),
+        None => serve(Arc::new(storage), config).await,
+    }
+}
+
+async fn serve <S: Storage + Send + Sync + 'static> (
+    storage: Arc<S>,
+    config: Config,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let config = Arc::new(config);
+    let auth = Authorizer::new(config.clone());
+    let vault = Vault::new(&config.master_key)?;
+    let locations = Arc::new(LocationBroadcaster::new(config.limits.location_channel_capacity));
+    let events = Arc::new(ServerEventQueues::new(config.limits.event_queue_capacity));
     let device_service = DeviceServiceProvider {
         storage: storage.clone(),
-        config: Arc::new(Config{
-            open_registration: true,
-            testing_token: Vec::from([ 0x01, 0x02, 0x03, 0x04 ]),
-        }),
+        config: config.clone(),
+        locations: locations.clone(),
+        events: events.clone(),
+        auth: auth.clone(),
+        vault: vault.clone(),
+    };
+    let user_service = UserServiceProvider {
+        storage: storage.clone(),
+        config: config.clone(),
+        locations,
+        events: events.clone(),
+        auth: auth.clone(),
+        vault: vault.clone(),
+    };
+    let admin_service = AdminServiceProvider {
+        storage: storage.clone(),
+        events,
+        auth: auth.clone(),
     };
 
-    tokio::spawn(Server::builder()
-        .add_service(DeviceServiceServer::new(device_service))
-        .serve(addr));
+    tokio::spawn(run_purge_scheduler(storage.clone(), PURGE_CHECK_INTERVAL));
+    if let Some(retention) = config.quotas.retention {
+        tokio::spawn(run_retention_enforcer(storage.clone(), retention, RETENTION_CHECK_INTERVAL));
+    }
+    tokio::spawn(run_rate_limit_pruner(auth.limiter.clone(), PRUNE_INTERVAL));
 
-    let locations_path = warp::path!("locations" / String)
-        .and(with_storage(storage))
-        .and_then(|token, storage| {
-            render_locations_path(token, storage)
-        });
+    let layer = AuthLayer::new(storage.clone(), auth);
+    let mut grpc_server = Server::builder();
+    if let Some(tls) = &config.tls {
+        grpc_server = grpc_server.tls_config(tls.grpc_server_config()?)?;
+    }
+    tokio::spawn(grpc_server
+        .layer(layer.clone())
+        .add_service(DeviceServiceServer::new(device_service))
+        .add_service(UserServiceServer::new(user_service))
+        .add_service(AdminServiceServer::new(admin_service))
+        .serve(config.grpc_address));
 
-    warp::serve(locations_path)
-        .run(([127, 0, 0, 1], 3030))
-        .await;
+    let web = layer.layer(warp::service(web_routes(storage, vault)));
+    let tls = match &config.tls {
+        Some(tls) => Some(TlsAcceptor::from(Arc::new(tls.web_server_config()?))),
+        None => None,
+    };
+    serve_web(web, config.web_address, tls).await?;
 
     Ok(())
 }
\ No newline at end of file
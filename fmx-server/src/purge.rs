@@ -0,0 +1,143 @@
+use crate::storage::{Storage, AuditRecord, PurgeOrder};
+use std::sync::Arc;
+use std::time::Duration;
+use log::{error, info};
+use chrono::prelude::*;
+
+/// How often the purge scheduler looks for purge orders that have come due.
+pub const PURGE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Carries out every purge order that is due, returning how many were carried
+/// out. An order that fails is logged and left for the next run, so that it
+/// does not hold up the others.
+pub async fn execute_due_purges <S: Storage> (storage: &S) -> anyhow::Result<usize> {
+    let due = storage.list_due_purge_orders(Utc::now()).await?;
+    let mut executed = 0;
+    for order in due.iter() {
+        match carry_out_purge(storage, order).await {
+            Ok(true) => {},
+            // It was cancelled since it was listed.
+            Ok(false) => continue,
+            Err(e) => {
+                error!("Failed to carry out purge {}: {:?}", order.id, e);
+                continue;
+            },
+        };
+        executed += 1;
+        info!(
+            "Carried out purge {} of the location history of {:?}, requested at {}.",
+            order.id,
+            order.device_id,
+            order.requested.to_rfc3339(),
+        );
+        if let Err(e) = close_emergency_purges(storage, order.id, "Carried out after the usual delay").await {
+            error!("Failed to close the emergency purge requests for purge {}: {:?}", order.id, e);
+        }
+    }
+    Ok(executed)
+}
+
+/// Claims the purge order `order` by deleting it, and then purges what it
+/// orders. Whoever deletes an order first decides its fate, so once this has
+/// claimed it, `CancelPurge` can no longer report it as cancelled. Returns
+/// `false` if the order had already been cancelled or carried out.
+pub async fn carry_out_purge <S: Storage> (storage: &S, order: &PurgeOrder) -> anyhow::Result<bool> {
+    if !storage.delete_purge_order(order.id).await? {
+        return Ok(false);
+    }
+    if let Err(e) = storage.purge_location(&order.device_id, order.since).await {
+        // The order is put back, so that the purge is tried again rather than
+        // forgotten.
+        storage.write_purge_order(order).await?;
+        return Err(e);
+    }
+    Ok(true)
+}
+
+/// Marks any undecided emergency purge request for the purge order `purge_id`
+/// as decided, because that order has been cancelled or carried out, and there
+/// is nothing left for an administrator to approve.
+pub async fn close_emergency_purges <S: Storage> (storage: &S, purge_id: u64, reason: &str) -> anyhow::Result<()> {
+    let pending = storage.list_emergency_purges(false).await?;
+    for mut emergency in pending.into_iter().filter(|e| e.purge_order_id == purge_id) {
+        let now = Utc::now();
+        emergency.decided = Some(now);
+        emergency.approved = false;
+        storage.write_emergency_purge(&emergency).await?;
+        storage.write_audit_record(&AuditRecord {
+            time: now,
+            action: String::from("close-emergency-purge"),
+            device_id: Some(emergency.device_id.clone()),
+            detail: format!("Request {}: {}", emergency.id, reason),
+        }).await?;
+        info!("Emergency purge request {} closed: {}", emergency.id, reason);
+    }
+    Ok(())
+}
+
+/// Periodically carries out purge orders as they come due. This never returns.
+pub async fn run_purge_scheduler <S: Storage> (storage: Arc<S>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        if let Err(e) = execute_due_purges(storage.as_ref()).await {
+            error!("Failed to carry out due purges: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grpc::find_my_device::CancelPurgeArg;
+    use crate::storage::{StoredLocation, LocationsFilter};
+    use crate::testing::{Harness, all};
+    use tonic::Request;
+
+    #[tokio::test]
+    async fn only_due_uncancelled_purges_are_carried_out () {
+        let h = Harness::new().await;
+        let device_id = h.device_id();
+        let now = Utc::now();
+        let hours_ago = |hours: i64| now - chrono::Duration::hours(hours);
+        for hours in [ 3, 2, 1 ] {
+            h.storage.write_location(&device_id, &StoredLocation {
+                update_time: hours_ago(hours),
+                ciphertext: vec![ 0; 16 ],
+            }).await.unwrap();
+        }
+        let order = |id: u64, since: Option<DateTime<Utc>>, execute_at: DateTime<Utc>| PurgeOrder {
+            id,
+            device_id: device_id.clone(),
+            requested: hours_ago(4),
+            since,
+            execute_at,
+        };
+        let due = order(1, Some(hours_ago(2) + chrono::Duration::minutes(30)), hours_ago(1));
+        let not_due = order(2, None, now + chrono::Duration::hours(1));
+        let cancelled = order(3, None, hours_ago(1));
+        for o in [ &due, &not_due, &cancelled ] {
+            h.storage.write_purge_order(o).await.unwrap();
+        }
+        let result = h.user().cancel_purge(Request::new(CancelPurgeArg {
+            token: h.valid_token(all()).await,
+            purge_id: cancelled.id,
+        })).await.unwrap().into_inner();
+        assert_eq!(result.cancelled, 1);
+
+        assert_eq!(execute_due_purges(h.storage.as_ref()).await.unwrap(), 1);
+        let filter = LocationsFilter { limit: 100, since: None, until: None };
+        let left: Vec<_> = h.storage.list_locations(&device_id, &filter).await.unwrap()
+            .into_iter()
+            .map(|l| l.update_time)
+            .collect();
+        assert_eq!(left, [ hours_ago(3), hours_ago(2) ]);
+        let pending: Vec<_> = h.storage.list_purge_orders(&device_id).await.unwrap()
+            .into_iter()
+            .map(|o| o.id)
+            .collect();
+        assert_eq!(pending, [ not_due.id ]);
+        assert_eq!(execute_due_purges(h.storage.as_ref()).await.unwrap(), 0);
+    }
+
+}
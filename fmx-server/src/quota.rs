@@ -0,0 +1,254 @@
+use crate::config::{QuotaPolicy, Quotas};
+use crate::storage::{Storage, DeviceId, LocationsFilter, StoredLocation};
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+use log::{debug, error, info};
+use chrono::prelude::*;
+
+/// How often locations older than the retention period are deleted.
+pub const RETENTION_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+fn within (quotas: &Quotas, locations: u64, bytes: u64) -> bool {
+    (quotas.max_locations_per_device == 0 || locations <= quotas.max_locations_per_device)
+        && (quotas.max_bytes_per_device == 0 || bytes <= quotas.max_bytes_per_device)
+}
+
+/// Chooses which of `locs`, oldest first, to delete so that `incoming` fits
+/// within the quotas. The choice falls back to the oldest locations if
+/// downsampling alone cannot make enough room.
+fn choose_deletions (quotas: &Quotas, locs: &[StoredLocation], incoming: u64) -> Vec<DateTime<Utc>> {
+    let mut kept: Vec<&StoredLocation> = locs.iter().collect();
+    let fits = |kept: &[&StoredLocation]| within(
+        quotas,
+        kept.len() as u64 + 1,
+        kept.iter().map(|loc| loc.size()).sum::<u64>() + incoming,
+    );
+    if quotas.policy == QuotaPolicy::Downsample {
+        while !fits(&kept) && kept.len() >= 4 {
+            let older_half = kept.len() / 2;
+            let mut i = 0;
+            kept.retain(|_| {
+                i += 1;
+                i > older_half || i % 2 == 1
+            });
+        }
+    }
+    let mut oldest_kept = 0;
+    while oldest_kept < kept.len() && !fits(&kept[oldest_kept..]) {
+        oldest_kept += 1;
+    }
+    kept.drain(..oldest_kept);
+    let kept_times: HashSet<DateTime<Utc>> = kept.iter().map(|loc| loc.update_time).collect();
+    locs.iter()
+        .map(|loc| loc.update_time)
+        .filter(|t| !kept_times.contains(t))
+        .collect()
+}
+
+/// How many of the oldest locations are read at a time while looking for
+/// enough to drop to fit within a byte quota.
+const DROP_OLDEST_PAGE: u32 = 64;
+
+/// Chooses the oldest locations to delete so that `incoming` fits within the
+/// quotas, reading no more of the device's history than it must.
+async fn choose_oldest <S: Storage> (
+    storage: &S,
+    quotas: &Quotas,
+    device_id: &DeviceId,
+    mut locations: u64,
+    mut bytes: u64,
+    incoming: u64,
+) -> anyhow::Result<Vec<DateTime<Utc>>> {
+    let mut deletions = Vec::new();
+    let mut since = None;
+    while !within(quotas, locations + 1, bytes + incoming) {
+        let excess = match quotas.max_locations_per_device {
+            0 => 0,
+            max => (locations + 1).saturating_sub(max),
+        };
+        let filter = LocationsFilter {
+            limit: if excess > 0 { excess.min(u32::MAX as u64) as u32 } else { DROP_OLDEST_PAGE },
+            since,
+            until: None,
+        };
+        let locs = storage.list_locations(device_id, &filter).await?;
+        let Some(last) = locs.last() else {
+            break;
+        };
+        since = Some(last.update_time + chrono::Duration::nanoseconds(1));
+        for loc in locs.iter() {
+            if within(quotas, locations + 1, bytes + incoming) {
+                break;
+            }
+            deletions.push(loc.update_time);
+            locations = locations.saturating_sub(1);
+            bytes = bytes.saturating_sub(loc.size());
+        }
+    }
+    Ok(deletions)
+}
+
+/// Makes room for a location of `incoming` bytes in a device's history,
+/// according to the quota policy. Returns `false` if the location must not
+/// be recorded.
+///
+/// Checking the usage, deleting and the caller's write are separate storage
+/// operations, so concurrent submissions for the same device may leave it a
+/// few locations over its quota. Under every policy but `Reject`, the next
+/// submission trims it back.
+pub async fn make_room <S: Storage> (
+    storage: &S,
+    quotas: &Quotas,
+    device_id: &DeviceId,
+    incoming: u64,
+) -> anyhow::Result<bool> {
+    if !within(quotas, 1, incoming) {
+        return Ok(false);
+    }
+    let usage = storage.get_storage_usage(device_id).await?;
+    if within(quotas, usage.locations + 1, usage.bytes + incoming) {
+        return Ok(true);
+    }
+    let deletions = match quotas.policy {
+        QuotaPolicy::Reject => return Ok(false),
+        QuotaPolicy::DropOldest => {
+            choose_oldest(storage, quotas, device_id, usage.locations, usage.bytes, incoming).await?
+        },
+        QuotaPolicy::Downsample => {
+            let filter = LocationsFilter {
+                limit: u32::MAX,
+                since: None,
+                until: None,
+            };
+            let locs = storage.list_locations(device_id, &filter).await?;
+            choose_deletions(quotas, &locs, incoming)
+        },
+    };
+    let deleted = storage.delete_locations(device_id, &deletions).await?;
+    debug!("Deleted {} locations of {:?} to stay within its quota.", deleted, device_id);
+    Ok(true)
+}
+
+/// Periodically deletes locations older than `retention`. This never returns.
+pub async fn run_retention_enforcer <S: Storage> (storage: Arc<S>, retention: Duration, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let cutoff = match chrono::Duration::from_std(retention) {
+            Ok(r) => Utc::now() - r,
+            Err(_) => continue,
+        };
+        match storage.delete_locations_before(cutoff).await {
+            Ok(0) => {},
+            Ok(deleted) => info!("Deleted {} locations older than {}.", deleted, cutoff.to_rfc3339()),
+            Err(e) => error!("Failed to delete expired locations: {:?}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::grpc::find_my_device::{SubmitLocationArg, GetStorageInfoArg};
+    use crate::storage::memory::MemoryStorage;
+    use crate::testing::{Harness, all};
+    use tonic::Request;
+
+    fn history (n: i64) -> Vec<StoredLocation> {
+        let start = Utc::now();
+        (0..n).map(|i| StoredLocation {
+            update_time: start + chrono::Duration::seconds(i),
+            ciphertext: vec![ 0; 88 ],
+        }).collect()
+    }
+
+    fn quotas (policy: QuotaPolicy, max_locations_per_device: u64) -> Quotas {
+        Quotas {
+            max_locations_per_device,
+            max_bytes_per_device: 0,
+            policy,
+            retention: None,
+        }
+    }
+
+    #[test]
+    fn dropping_the_oldest_makes_just_enough_room () {
+        let locs = history(10);
+        let deletions = choose_deletions(&quotas(QuotaPolicy::DropOldest, 10), &locs, 100);
+        assert_eq!(deletions, vec![ locs[0].update_time ]);
+
+        // Byte quotas count the time as well as the ciphertext.
+        let by_bytes = Quotas {
+            max_bytes_per_device: 850,
+            ..quotas(QuotaPolicy::DropOldest, 0)
+        };
+        let deletions = choose_deletions(&by_bytes, &locs, 100);
+        assert_eq!(deletions, locs[..3].iter().map(|l| l.update_time).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn dropping_the_oldest_deletes_only_the_oldest () {
+        let storage = MemoryStorage::new();
+        let device_id = vec![ 1; 32 ];
+        let locs = history(10);
+        for loc in locs.iter() {
+            storage.write_location(&device_id, loc).await.unwrap();
+        }
+        let remaining = async || storage.list_locations(&device_id, &LocationsFilter {
+            limit: u32::MAX,
+            since: None,
+            until: None,
+        }).await.unwrap().iter().map(|l| l.update_time).collect::<Vec<_>>();
+        let times = |locs: &[StoredLocation]| locs.iter().map(|l| l.update_time).collect::<Vec<_>>();
+
+        let by_count = quotas(QuotaPolicy::DropOldest, 8);
+        assert!(make_room(&storage, &by_count, &device_id, 100).await.unwrap());
+        assert_eq!(remaining().await, times(&locs[3..]));
+
+        let by_bytes = Quotas {
+            max_bytes_per_device: 200,
+            ..quotas(QuotaPolicy::DropOldest, 0)
+        };
+        assert!(make_room(&storage, &by_bytes, &device_id, 100).await.unwrap());
+        assert_eq!(remaining().await, times(&locs[9..]));
+    }
+
+    #[test]
+    fn downsampling_thins_out_the_older_half () {
+        let locs = history(10);
+        let deletions = choose_deletions(&quotas(QuotaPolicy::Downsample, 10), &locs, 100);
+        let expected: Vec<DateTime<Utc>> = [ 1, 3 ].iter().map(|&i| locs[i].update_time).collect();
+        assert_eq!(deletions, expected);
+    }
+
+    #[tokio::test]
+    async fn full_quotas_reject_locations_under_the_reject_policy () {
+        let h = Harness::with_config(Config {
+            quotas: Quotas {
+                max_locations_per_device: 2,
+                policy: QuotaPolicy::Reject,
+                ..Default::default()
+            },
+            ..Default::default()
+        }).await;
+        let token = h.valid_token(all()).await;
+        let submit = async || h.device().submit_location(Request::new(SubmitLocationArg {
+            token: token.clone(),
+            ..Default::default()
+        })).await;
+        assert!(submit().await.unwrap().into_inner().recorded);
+        assert!(submit().await.unwrap().into_inner().recorded);
+        assert!(!submit().await.unwrap().into_inner().recorded);
+
+        let info = h.user().get_storage_info(Request::new(GetStorageInfoArg {
+            token: token.clone(),
+        })).await.unwrap().into_inner();
+        assert_eq!(info.locations_count, 2);
+        assert_eq!(info.locations_limit, 2);
+        assert!(info.bytes_storage_consumed > 0);
+        assert_eq!(info.bytes_storage_limit, Quotas::default().max_bytes_per_device);
+    }
+
+}
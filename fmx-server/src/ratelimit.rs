@@ -0,0 +1,259 @@
+use crate::config::{Rate, RateLimits};
+use crate::storage::{DeviceId, TokenDigest};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use log::warn;
+
+/// How often idle rate limiting state is forgotten.
+pub const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Whatever a rate limit is counted against.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Subject {
+    Ip(IpAddr),
+    Token(TokenDigest),
+    SecretKey(DeviceId),
+    Introductions(IpAddr),
+}
+
+/// A token bucket, which holds up to `rate.requests` requests, and refills at
+/// `rate.requests` per `rate.per`.
+struct Bucket {
+    available: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+
+    fn full (rate: &Rate, now: Instant) -> Self {
+        Bucket { available: rate.requests as f64, updated: now }
+    }
+
+    fn refill (&mut self, rate: &Rate, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        let per = rate.per.as_secs_f64().max(f64::EPSILON);
+        self.available = (self.available + elapsed * rate.requests as f64 / per).min(rate.requests as f64);
+        self.updated = now;
+    }
+
+}
+
+struct InvalidAttempts {
+    count: u32,
+    last: Instant,
+    locked_until: Option<Instant>,
+}
+
+/// Tracks how many requests each client, token and secret key has made, and
+/// which IP addresses are locked out for presenting too many invalid
+/// credentials. This is shared by the gRPC services and the web interface.
+pub struct RateLimiter {
+    limits: RateLimits,
+    buckets: Mutex<HashMap<Subject, Bucket>>,
+    invalid_attempts: Mutex<HashMap<IpAddr, InvalidAttempts>>,
+}
+
+impl RateLimiter {
+
+    pub fn new (limits: RateLimits) -> Self {
+        RateLimiter {
+            limits,
+            buckets: Mutex::new(HashMap::new()),
+            invalid_attempts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn rate (&self, subject: &Subject) -> &Rate {
+        match subject {
+            Subject::Ip(_) => &self.limits.per_ip,
+            Subject::Token(_) => &self.limits.per_token,
+            Subject::SecretKey(_) => &self.limits.per_secret_key,
+            Subject::Introductions(_) => &self.limits.introductions_per_ip,
+        }
+    }
+
+    /// Counts a request against `subject`, returning `false` if it has already
+    /// used up its allowance.
+    pub fn allow (&self, subject: Subject) -> bool {
+        self.allow_at(subject, Instant::now())
+    }
+
+    fn allow_at (&self, subject: Subject, now: Instant) -> bool {
+        let rate = *self.rate(&subject);
+        if rate.requests == 0 {
+            return true;
+        }
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(subject).or_insert_with(|| Bucket::full(&rate, now));
+        bucket.refill(&rate, now);
+        if bucket.available < 1.0 {
+            return false;
+        }
+        bucket.available -= 1.0;
+        true
+    }
+
+    pub fn is_locked_out (&self, ip: &IpAddr) -> bool {
+        self.is_locked_out_at(ip, Instant::now())
+    }
+
+    fn is_locked_out_at (&self, ip: &IpAddr, now: Instant) -> bool {
+        self.invalid_attempts.lock().unwrap()
+            .get(ip)
+            .and_then(|a| a.locked_until)
+            .map(|until| now < until)
+            .unwrap_or(false)
+    }
+
+    /// Records that `ip` presented an invalid token, secret key or
+    /// registration key, and locks it out if it has done so too often.
+    pub fn record_invalid_attempt (&self, ip: IpAddr) {
+        self.record_invalid_attempt_at(ip, Instant::now())
+    }
+
+    fn record_invalid_attempt_at (&self, ip: IpAddr, now: Instant) {
+        if self.limits.max_invalid_attempts == 0 {
+            return;
+        }
+        let mut attempts = self.invalid_attempts.lock().unwrap();
+        let a = attempts.entry(ip).or_insert(InvalidAttempts { count: 0, last: now, locked_until: None });
+        if now.saturating_duration_since(a.last) >= self.limits.lockout {
+            a.count = 0;
+        }
+        a.count += 1;
+        a.last = now;
+        if a.count >= self.limits.max_invalid_attempts {
+            warn!("Locking out {} after {} invalid attempts.", ip, a.count);
+            a.count = 0;
+            a.locked_until = Some(now + self.limits.lockout);
+        }
+    }
+
+    /// Forgets buckets that have refilled completely and invalid attempts that
+    /// no longer count, neither of which would make any difference.
+    pub fn prune (&self) {
+        self.prune_at(Instant::now())
+    }
+
+    fn prune_at (&self, now: Instant) {
+        self.buckets.lock().unwrap().retain(|subject, bucket| {
+            let rate = self.rate(subject);
+            bucket.refill(rate, now);
+            bucket.available < rate.requests as f64
+        });
+        let lockout = self.limits.lockout;
+        self.invalid_attempts.lock().unwrap().retain(|_, a| {
+            a.locked_until.map(|until| now < until).unwrap_or(false)
+                || now.saturating_duration_since(a.last) < lockout
+        });
+    }
+
+}
+
+/// Periodically forgets idle rate limiting state. This never returns.
+pub async fn run_rate_limit_pruner (limiter: Arc<RateLimiter>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        limiter.prune();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{Authorizer, AuthError, Operation};
+    use crate::config::Config;
+    use crate::storage::{Storage, TokenEntry};
+    use crate::storage::memory::MemoryStorage;
+    use crate::testing::all;
+    use chrono::Utc;
+    use tonic::{Code, Status};
+    use warp::http::StatusCode;
+
+    fn limiter () -> RateLimiter {
+        RateLimiter::new(RateLimits {
+            per_ip: Rate::per_minute(2),
+            max_invalid_attempts: 3,
+            lockout: Duration::from_secs(60),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn buckets_refill_over_time () {
+        let limiter = limiter();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let start = Instant::now();
+        assert!(limiter.allow_at(Subject::Ip(ip), start));
+        assert!(limiter.allow_at(Subject::Ip(ip), start));
+        assert!(!limiter.allow_at(Subject::Ip(ip), start));
+        assert!(limiter.allow_at(Subject::Ip("192.0.2.2".parse().unwrap()), start));
+        assert!(limiter.allow_at(Subject::Ip(ip), start + Duration::from_secs(30)));
+        assert!(!limiter.allow_at(Subject::Ip(ip), start + Duration::from_secs(30)));
+
+        limiter.prune_at(start + Duration::from_secs(120));
+        assert!(limiter.buckets.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn repeated_invalid_attempts_lock_out () {
+        let limiter = limiter();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let start = Instant::now();
+        limiter.record_invalid_attempt_at(ip, start);
+        limiter.record_invalid_attempt_at(ip, start);
+        assert!(!limiter.is_locked_out_at(&ip, start));
+
+        // Attempts are forgotten once the lockout period has passed.
+        let later = start + Duration::from_secs(61);
+        limiter.record_invalid_attempt_at(ip, later);
+        limiter.record_invalid_attempt_at(ip, later);
+        assert!(!limiter.is_locked_out_at(&ip, later));
+        limiter.record_invalid_attempt_at(ip, later);
+        assert!(limiter.is_locked_out_at(&ip, later));
+        assert!(limiter.is_locked_out_at(&ip, later + Duration::from_secs(59)));
+        assert!(!limiter.is_locked_out_at(&ip, later + Duration::from_secs(60)));
+    }
+
+    #[tokio::test]
+    async fn clients_are_rate_limited_and_locked_out () {
+        let auth = Authorizer::new(Arc::new(Config {
+            rate_limits: RateLimits {
+                per_token: Rate::per_minute(2),
+                max_invalid_attempts: 2,
+                ..Default::default()
+            },
+            ..Default::default()
+        }));
+        let storage = MemoryStorage::new();
+        let (token, other_token) = (vec![ 1; 16 ], vec![ 2; 16 ]);
+        for token in [ &token, &other_token ] {
+            storage.write_token(&auth.digests.token(token), &TokenEntry {
+                device_id: vec![ 3; 32 ],
+                permissions: all(),
+                not_before: Utc::now(),
+                not_after: None,
+            }).await.unwrap();
+        }
+        let attacker = Some("192.0.2.1".parse().unwrap());
+        let bystander = Some("192.0.2.2".parse().unwrap());
+        let unknown_token = vec![ 0xFF; 16 ];
+        let authorize = |token, client| auth.authorize(&storage, Operation::ListLocations, token, client);
+
+        assert!(authorize(&token, bystander).await.is_ok());
+        assert!(authorize(&token, bystander).await.is_ok());
+        let limited = authorize(&token, bystander).await.unwrap_err();
+        assert_eq!(limited.http_status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(Status::from(limited).code(), Code::ResourceExhausted);
+        assert!(authorize(&other_token, bystander).await.is_ok());
+
+        assert!(matches!(authorize(&unknown_token, attacker).await, Err(AuthError::Unauthenticated)));
+        assert!(matches!(authorize(&unknown_token, attacker).await, Err(AuthError::Unauthenticated)));
+        assert!(matches!(authorize(&other_token, attacker).await, Err(AuthError::LockedOut)));
+        assert!(authorize(&other_token, bystander).await.is_ok());
+    }
+
+}
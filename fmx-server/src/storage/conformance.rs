@@ -0,0 +1,575 @@
+//! The behavior that every `Storage` backend must share. Each backend runs
+//! these checks on a fresh, empty storage with `storage_conformance_tests!`.
+use crate::grpc::find_my_device::{IntroduceMyselfArg, Permissions};
+use crate::storage::{
+    Storage,
+    Introduction,
+    WipeOrder,
+    DeviceId,
+    TokenDigest,
+    StoredLocation,
+    IntroInsertion,
+    TokenEntry,
+    LocationsFilter,
+    Excommunication,
+    PurgeOrder,
+    StorageUsage,
+    EmergencyPurgeRequest,
+    AuditRecord,
+    RegistrationKey,
+};
+use chrono::prelude::*;
+use chrono::Duration;
+
+/// Generates a test for each check below, given an expression that makes a
+/// fresh, empty storage.
+macro_rules! storage_conformance_tests {
+    ($storage:expr) => {
+        mod conformance {
+            use super::*;
+            use crate::storage::conformance;
+
+            #[tokio::test]
+            async fn token_lifecycle () {
+                conformance::token_lifecycle(&$storage).await;
+            }
+
+            #[tokio::test]
+            async fn location_ordering () {
+                conformance::location_ordering(&$storage).await;
+            }
+
+            #[tokio::test]
+            async fn locations_filter () {
+                conformance::locations_filter(&$storage).await;
+            }
+
+            #[tokio::test]
+            async fn purging () {
+                conformance::purging(&$storage).await;
+            }
+
+            #[tokio::test]
+            async fn purge_orders () {
+                conformance::purge_orders(&$storage).await;
+            }
+
+            #[tokio::test]
+            async fn storage_usage () {
+                conformance::storage_usage(&$storage).await;
+            }
+
+            #[tokio::test]
+            async fn devices () {
+                conformance::devices(&$storage).await;
+            }
+
+            #[tokio::test]
+            async fn emergency_purges () {
+                conformance::emergency_purges(&$storage).await;
+            }
+
+            #[tokio::test]
+            async fn audit_log () {
+                conformance::audit_log(&$storage).await;
+            }
+
+            #[tokio::test]
+            async fn registration_keys () {
+                conformance::registration_keys(&$storage).await;
+            }
+        }
+    };
+}
+
+pub(crate) use storage_conformance_tests;
+
+/// A time with nanoseconds, which every backend must keep.
+fn base_time () -> DateTime<Utc> {
+    Utc.timestamp_opt(1_700_000_000, 123_456_789).unwrap()
+}
+
+fn at (seconds: i64) -> DateTime<Utc> {
+    base_time() + Duration::seconds(seconds)
+}
+
+fn location (seconds: i64, ciphertext: &[u8]) -> StoredLocation {
+    StoredLocation {
+        update_time: at(seconds),
+        ciphertext: ciphertext.to_vec(),
+    }
+}
+
+fn device (n: u8) -> DeviceId {
+    vec![ n; 32 ]
+}
+
+fn digest (n: u8) -> TokenDigest {
+    vec![ n; 32 ]
+}
+
+fn filter (since: Option<DateTime<Utc>>, until: Option<DateTime<Utc>>, limit: u32) -> LocationsFilter {
+    LocationsFilter { limit, since, until }
+}
+
+fn everything () -> LocationsFilter {
+    filter(None, None, u32::MAX)
+}
+
+fn times (locs: &[StoredLocation]) -> Vec<DateTime<Utc>> {
+    locs.iter().map(|loc| loc.update_time).collect()
+}
+
+fn seconds (secs: &[i64]) -> Vec<DateTime<Utc>> {
+    secs.iter().map(|s| at(*s)).collect()
+}
+
+async fn write_seconds <S: Storage> (storage: &S, device_id: &DeviceId, secs: &[i64]) {
+    for s in secs {
+        storage.write_location(device_id, &location(*s, &[ *s as u8; 10 ])).await.unwrap();
+    }
+}
+
+async fn listed_digests <S: Storage> (storage: &S, device_id: &DeviceId) -> Vec<TokenDigest> {
+    let mut digests: Vec<TokenDigest> = storage.list_tokens(device_id).await.unwrap()
+        .into_iter()
+        .map(|(digest, _)| digest)
+        .collect();
+    digests.sort();
+    digests
+}
+
+/// Tokens are stored, listed per device, replaced, and revoked one at a time
+/// or all at once, without disturbing any other tokens.
+pub async fn token_lifecycle <S: Storage> (storage: &S) {
+    let (a, b, c) = (device(1), device(2), device(3));
+    let entry = |device_id: &DeviceId, read_locations: bool| TokenEntry {
+        device_id: device_id.clone(),
+        permissions: Permissions { write_locations: true, read_locations, ..Default::default() },
+        not_before: at(0),
+        not_after: Some(at(60)),
+    };
+    storage.write_token(&digest(1), &entry(&a, false)).await.unwrap();
+    storage.write_token(&digest(2), &entry(&a, false)).await.unwrap();
+    storage.write_token(&digest(3), &entry(&b, false)).await.unwrap();
+
+    let info = storage.get_token_info(&digest(1)).await.unwrap().unwrap();
+    assert_eq!(info.device_id, a);
+    assert!(info.permissions.write_locations);
+    assert!(!info.permissions.read_locations);
+    assert_eq!(info.not_before, at(0));
+    assert_eq!(info.not_after, Some(at(60)));
+    assert!(storage.get_token_info(&digest(9)).await.unwrap().is_none());
+    assert_eq!(listed_digests(storage, &a).await, vec![ digest(1), digest(2) ]);
+    assert_eq!(listed_digests(storage, &b).await, vec![ digest(3) ]);
+    assert!(listed_digests(storage, &device(9)).await.is_empty());
+
+    // Writing a token again replaces it.
+    storage.write_token(&digest(1), &entry(&a, true)).await.unwrap();
+    assert!(storage.get_token_info(&digest(1)).await.unwrap().unwrap().permissions.read_locations);
+    assert_eq!(listed_digests(storage, &a).await, vec![ digest(1), digest(2) ]);
+
+    // A device cannot revoke another device's tokens.
+    assert_eq!(storage.revoke_token(&b, Some(&digest(1))).await.unwrap(), 0);
+    assert!(storage.get_token_info(&digest(1)).await.unwrap().is_some());
+
+    // Revoking one token leaves the device's others alone.
+    assert_eq!(storage.revoke_token(&a, Some(&digest(1))).await.unwrap(), 1);
+    assert!(storage.get_token_info(&digest(1)).await.unwrap().is_none());
+    assert!(storage.get_token_info(&digest(2)).await.unwrap().is_some());
+    assert_eq!(listed_digests(storage, &a).await, vec![ digest(2) ]);
+    assert_eq!(storage.revoke_token(&a, Some(&digest(1))).await.unwrap(), 0);
+
+    // Revoking every token of a device leaves other devices alone.
+    storage.write_token(&digest(4), &entry(&a, false)).await.unwrap();
+    assert_eq!(storage.revoke_token(&a, None).await.unwrap(), 2);
+    assert!(listed_digests(storage, &a).await.is_empty());
+    assert!(storage.get_token_info(&digest(4)).await.unwrap().is_none());
+    assert_eq!(storage.revoke_token(&a, None).await.unwrap(), 0);
+    assert_eq!(listed_digests(storage, &b).await, vec![ digest(3) ]);
+
+    // Introducing a device gives it its first token.
+    let permissions = Permissions { write_locations: true, nearby: true, ..Default::default() };
+    storage.write_intro(&IntroInsertion {
+        device_id: &c,
+        token_digest: &digest(5),
+        permissions: &permissions,
+        remote_addr: Some("192.0.2.1:1234".parse().unwrap()),
+        registration_key_digest: &digest(7),
+        arg: &IntroduceMyselfArg { remote_wipe_enabled: true, ..Default::default() },
+    }).await.unwrap();
+    let intro = storage.get_intro(&c).await.unwrap().unwrap();
+    assert!(intro.remote_wipe_enabled);
+    assert_eq!(intro.registration_key_digest, digest(7));
+    assert_eq!(intro.remote_addr, Some("192.0.2.1:1234".parse().unwrap()));
+    assert!(storage.get_intro(&a).await.unwrap().is_none());
+    assert_eq!(listed_digests(storage, &c).await, vec![ digest(5) ]);
+    assert_eq!(storage.get_token_info(&digest(5)).await.unwrap().unwrap().permissions, permissions);
+
+    // Excommunication leaves the device's tokens as they were.
+    storage.excommunicate(&c, &Excommunication {
+        time: at(1),
+        reason: String::from("abuse"),
+        by_administrator: true,
+    }).await.unwrap();
+    assert_eq!(storage.get_token_info(&digest(5)).await.unwrap().unwrap().permissions, permissions);
+    assert_eq!(storage.get_excommunication(&c).await.unwrap().unwrap().reason, "abuse");
+    assert!(storage.get_excommunication(&b).await.unwrap().is_none());
+    assert!(storage.get_token_info(&digest(3)).await.unwrap().unwrap().permissions.write_locations);
+}
+
+/// Locations are listed oldest first, whatever order they were written in,
+/// and a location written at the same time as another replaces it.
+pub async fn location_ordering <S: Storage> (storage: &S) {
+    let (a, b) = (device(1), device(2));
+    write_seconds(storage, &a, &[ 3, 1, 2 ]).await;
+    write_seconds(storage, &b, &[ 0 ]).await;
+    let locs = storage.list_locations(&a, &everything()).await.unwrap();
+    assert_eq!(times(&locs), seconds(&[ 1, 2, 3 ]));
+    assert_eq!(locs[0].ciphertext, vec![ 1; 10 ]);
+
+    storage.write_location(&a, &location(2, b"replaced")).await.unwrap();
+    let locs = storage.list_locations(&a, &everything()).await.unwrap();
+    assert_eq!(times(&locs), seconds(&[ 1, 2, 3 ]));
+    assert_eq!(locs[1].ciphertext, b"replaced");
+
+    // Times a nanosecond apart are kept apart.
+    let nanosecond = StoredLocation {
+        update_time: at(3) + Duration::nanoseconds(1),
+        ciphertext: vec![ 4 ],
+    };
+    storage.write_location(&a, &nanosecond).await.unwrap();
+    let locs = storage.list_locations(&a, &everything()).await.unwrap();
+    assert_eq!(times(&locs)[2..], [ at(3), nanosecond.update_time ]);
+
+    assert_eq!(times(&storage.list_locations(&b, &everything()).await.unwrap()), seconds(&[ 0 ]));
+    assert!(storage.list_locations(&device(9), &everything()).await.unwrap().is_empty());
+}
+
+/// `since` and `until` are both inclusive, and `limit` keeps the oldest of
+/// the locations between them.
+pub async fn locations_filter <S: Storage> (storage: &S) {
+    let a = &device(1);
+    write_seconds(storage, a, &[ 0, 1, 2, 3, 4 ]).await;
+    write_seconds(storage, &device(2), &[ 0, 1, 2, 3, 4 ]).await;
+    let list = |since, until, limit| async move {
+        times(&storage.list_locations(a, &filter(since, until, limit)).await.unwrap())
+    };
+    let nanosecond = Duration::nanoseconds(1);
+
+    assert_eq!(list(None, None, u32::MAX).await, seconds(&[ 0, 1, 2, 3, 4 ]));
+    assert_eq!(list(Some(at(1)), None, u32::MAX).await, seconds(&[ 1, 2, 3, 4 ]));
+    assert_eq!(list(Some(at(1) + nanosecond), None, u32::MAX).await, seconds(&[ 2, 3, 4 ]));
+    assert_eq!(list(None, Some(at(3)), u32::MAX).await, seconds(&[ 0, 1, 2, 3 ]));
+    assert_eq!(list(None, Some(at(3) - nanosecond), u32::MAX).await, seconds(&[ 0, 1, 2 ]));
+    assert_eq!(list(Some(at(1)), Some(at(3)), u32::MAX).await, seconds(&[ 1, 2, 3 ]));
+    assert_eq!(list(Some(at(2)), Some(at(2)), u32::MAX).await, seconds(&[ 2 ]));
+    assert_eq!(list(Some(at(3)), Some(at(1)), u32::MAX).await, seconds(&[]));
+    assert_eq!(list(Some(at(5)), None, u32::MAX).await, seconds(&[]));
+    assert_eq!(list(None, Some(at(-1)), u32::MAX).await, seconds(&[]));
+
+    assert_eq!(list(None, None, 2).await, seconds(&[ 0, 1 ]));
+    assert_eq!(list(Some(at(2)), None, 2).await, seconds(&[ 2, 3 ]));
+    assert_eq!(list(Some(at(1)), Some(at(2)), 5).await, seconds(&[ 1, 2 ]));
+    assert_eq!(list(None, None, 1).await, seconds(&[ 0 ]));
+    assert_eq!(list(None, None, 0).await, seconds(&[]));
+}
+
+/// Purging deletes a device's locations from a time onwards, or all of them
+/// along with its data key, and deletions never reach other devices.
+pub async fn purging <S: Storage> (storage: &S) {
+    let (a, b) = (device(1), device(2));
+    write_seconds(storage, &a, &[ 0, 1, 2, 3, 4 ]).await;
+    write_seconds(storage, &b, &[ 0, 1, 2, 3, 4 ]).await;
+    assert_eq!(storage.insert_device_key(&a, b"first").await.unwrap(), b"first");
+    assert_eq!(storage.insert_device_key(&a, b"second").await.unwrap(), b"first");
+    storage.insert_device_key(&b, b"other").await.unwrap();
+
+    storage.purge_location(&a, Some(at(3))).await.unwrap();
+    assert_eq!(times(&storage.list_locations(&a, &everything()).await.unwrap()), seconds(&[ 0, 1, 2 ]));
+    assert_eq!(storage.get_device_key(&a).await.unwrap().unwrap(), b"first");
+
+    assert_eq!(storage.delete_locations(&a, &[ at(1), at(3), at(9) ]).await.unwrap(), 1);
+    assert_eq!(times(&storage.list_locations(&a, &everything()).await.unwrap()), seconds(&[ 0, 2 ]));
+    assert_eq!(storage.delete_locations(&device(9), &[ at(0) ]).await.unwrap(), 0);
+
+    storage.purge_location(&a, None).await.unwrap();
+    assert!(storage.list_locations(&a, &everything()).await.unwrap().is_empty());
+    assert!(storage.get_device_key(&a).await.unwrap().is_none());
+    assert_eq!(storage.insert_device_key(&a, b"new").await.unwrap(), b"new");
+    storage.purge_location(&device(9), None).await.unwrap();
+
+    assert_eq!(times(&storage.list_locations(&b, &everything()).await.unwrap()), seconds(&[ 0, 1, 2, 3, 4 ]));
+    assert_eq!(storage.get_device_key(&b).await.unwrap().unwrap(), b"other");
+
+    // Deleting old locations reaches every device, and keeps those at the
+    // cutoff itself.
+    write_seconds(storage, &a, &[ 1, 5 ]).await;
+    assert_eq!(storage.delete_locations_before(at(2)).await.unwrap(), 3);
+    assert_eq!(times(&storage.list_locations(&a, &everything()).await.unwrap()), seconds(&[ 5 ]));
+    assert_eq!(times(&storage.list_locations(&b, &everything()).await.unwrap()), seconds(&[ 2, 3, 4 ]));
+    assert_eq!(storage.delete_locations_before(at(2)).await.unwrap(), 0);
+}
+
+/// Purge orders are listed per device and as they come due, soonest first.
+pub async fn purge_orders <S: Storage> (storage: &S) {
+    let (a, b) = (device(1), device(2));
+    let order = |id: u64, device_id: &DeviceId, since, execute_at| PurgeOrder {
+        id,
+        device_id: device_id.clone(),
+        requested: at(0),
+        since,
+        execute_at,
+    };
+    // IDs are random, so they may use every bit.
+    let big_id = u64::MAX - 1;
+    storage.write_purge_order(&order(big_id, &a, None, at(20))).await.unwrap();
+    storage.write_purge_order(&order(2, &a, Some(at(5)), at(10))).await.unwrap();
+    storage.write_purge_order(&order(3, &b, None, at(15))).await.unwrap();
+
+    let ids = |orders: Vec<PurgeOrder>| orders.iter().map(|o| o.id).collect::<Vec<_>>();
+    let listed = storage.list_purge_orders(&a).await.unwrap();
+    assert_eq!(ids(listed.clone()), vec![ 2, big_id ]);
+    assert_eq!(listed[0].since, Some(at(5)));
+    assert_eq!(listed[0].execute_at, at(10));
+    assert!(storage.list_purge_orders(&device(9)).await.unwrap().is_empty());
+
+    assert!(storage.list_due_purge_orders(at(10) - Duration::nanoseconds(1)).await.unwrap().is_empty());
+    assert_eq!(ids(storage.list_due_purge_orders(at(10)).await.unwrap()), vec![ 2 ]);
+    assert_eq!(ids(storage.list_due_purge_orders(at(30)).await.unwrap()), vec![ 2, 3, big_id ]);
+
+    assert!(storage.delete_purge_order(2).await.unwrap());
+    assert!(!storage.delete_purge_order(2).await.unwrap());
+    assert!(storage.delete_purge_order(big_id).await.unwrap());
+    assert!(storage.list_purge_orders(&a).await.unwrap().is_empty());
+    assert_eq!(ids(storage.list_due_purge_orders(at(30)).await.unwrap()), vec![ 3 ]);
+}
+
+/// Storage usage counts a device's locations and their sizes, and notes the
+/// oldest of them, however the locations were written or deleted.
+pub async fn storage_usage <S: Storage> (storage: &S) {
+    let (a, b) = (device(1), device(2));
+    assert_eq!(storage.get_storage_usage(&a).await.unwrap(), StorageUsage::default());
+
+    storage.write_location(&a, &location(2, &[ 0; 100 ])).await.unwrap();
+    storage.write_location(&a, &location(1, &[ 0; 50 ])).await.unwrap();
+    storage.write_location(&b, &location(0, &[ 0; 10 ])).await.unwrap();
+    assert_eq!(storage.get_storage_usage(&a).await.unwrap(), StorageUsage {
+        locations: 2,
+        bytes: 100 + 50 + 2 * 12,
+        oldest: Some(at(1)),
+    });
+
+    // Replacing a location counts only its replacement, whether it is
+    // smaller or larger.
+    storage.write_location(&a, &location(1, &[ 0; 70 ])).await.unwrap();
+    storage.write_location(&a, &location(1, &[ 0; 20 ])).await.unwrap();
+    assert_eq!(storage.get_storage_usage(&a).await.unwrap(), StorageUsage {
+        locations: 2,
+        bytes: 100 + 20 + 2 * 12,
+        oldest: Some(at(1)),
+    });
+
+    storage.delete_locations(&a, &[ at(1) ]).await.unwrap();
+    assert_eq!(storage.get_storage_usage(&a).await.unwrap(), StorageUsage {
+        locations: 1,
+        bytes: 112,
+        oldest: Some(at(2)),
+    });
+
+    storage.write_location(&a, &location(3, &[ 0; 30 ])).await.unwrap();
+    storage.write_location(&a, &location(4, &[ 0; 40 ])).await.unwrap();
+    storage.purge_location(&a, Some(at(4))).await.unwrap();
+    assert_eq!(storage.get_storage_usage(&a).await.unwrap(), StorageUsage {
+        locations: 2,
+        bytes: 100 + 30 + 2 * 12,
+        oldest: Some(at(2)),
+    });
+
+    storage.delete_locations_before(at(3)).await.unwrap();
+    assert_eq!(storage.get_storage_usage(&a).await.unwrap(), StorageUsage {
+        locations: 1,
+        bytes: 42,
+        oldest: Some(at(3)),
+    });
+    assert_eq!(storage.get_storage_usage(&b).await.unwrap(), StorageUsage::default());
+
+    storage.write_location(&b, &location(5, &[ 0; 10 ])).await.unwrap();
+    storage.purge_location(&a, None).await.unwrap();
+    assert_eq!(storage.get_storage_usage(&a).await.unwrap(), StorageUsage::default());
+    assert_eq!(storage.get_storage_usage(&b).await.unwrap().locations, 1);
+}
+
+/// Every device that anything is stored for is listed, once, in order of ID,
+/// and an introduction can be restored without giving the device a token.
+pub async fn devices <S: Storage> (storage: &S) {
+    assert!(storage.list_devices().await.unwrap().is_empty());
+    let intro = Introduction {
+        remote_addr: None,
+        registration_key_digest: vec![ 7 ],
+        remote_wipe_enabled: false,
+        can_read_nearby_devices: true,
+    };
+    storage.restore_intro(&device(6), &intro).await.unwrap();
+    let restored = storage.get_intro(&device(6)).await.unwrap().unwrap();
+    assert_eq!(restored.registration_key_digest, vec![ 7 ]);
+    assert!(restored.can_read_nearby_devices);
+    assert!(storage.list_tokens(&device(6)).await.unwrap().is_empty());
+
+    write_seconds(storage, &device(5), &[ 0, 1, 2 ]).await;
+    write_seconds(storage, &vec![ 5; 31 ], &[ 0 ]).await;
+    storage.insert_device_key(&device(4), b"key").await.unwrap();
+    storage.write_token(&digest(1), &TokenEntry {
+        device_id: device(3),
+        permissions: Permissions::default(),
+        not_before: at(0),
+        not_after: None,
+    }).await.unwrap();
+    storage.write_wipe_order(&device(2), &WipeOrder {
+        requested: at(0),
+        delivered: None,
+        acknowledged: None,
+    }).await.unwrap();
+    storage.excommunicate(&device(1), &Excommunication {
+        time: at(0),
+        reason: String::new(),
+        by_administrator: false,
+    }).await.unwrap();
+    storage.write_purge_order(&PurgeOrder {
+        id: 1,
+        device_id: device(0),
+        requested: at(0),
+        since: None,
+        execute_at: at(1),
+    }).await.unwrap();
+    storage.write_location(&device(6), &location(0, &[ 0 ])).await.unwrap();
+
+    let mut expected = vec![ device(0), device(1), device(2), device(3), device(4), vec![ 5; 31 ], device(5), device(6) ];
+    expected.sort();
+    assert_eq!(storage.list_devices().await.unwrap(), expected);
+}
+
+/// Emergency purge requests are kept whole, replaced when written again, and
+/// listed oldest first, with or without those that have been decided.
+pub async fn emergency_purges <S: Storage> (storage: &S) {
+    let request = |id: u64, requested: DateTime<Utc>| EmergencyPurgeRequest {
+        id,
+        device_id: device(1),
+        purge_order_id: u64::MAX - id,
+        since: Some(at(-10) + Duration::nanoseconds(1)),
+        requested,
+        decided: None,
+        approved: false,
+    };
+    let ids = |requests: Vec<EmergencyPurgeRequest>| requests.iter().map(|r| r.id).collect::<Vec<_>>();
+    assert!(storage.list_emergency_purges(true).await.unwrap().is_empty());
+    // IDs are random, so they may use every bit.
+    let big_id = u64::MAX - 1;
+    storage.write_emergency_purge(&request(big_id, at(2))).await.unwrap();
+    storage.write_emergency_purge(&request(2, at(1))).await.unwrap();
+    storage.write_emergency_purge(&EmergencyPurgeRequest {
+        device_id: device(2),
+        since: None,
+        ..request(3, at(3))
+    }).await.unwrap();
+
+    let stored = storage.get_emergency_purge(big_id).await.unwrap().unwrap();
+    assert_eq!(stored.device_id, device(1));
+    assert_eq!(stored.purge_order_id, 1);
+    assert_eq!(stored.since, Some(at(-10) + Duration::nanoseconds(1)));
+    assert_eq!(stored.requested, at(2));
+    assert!(stored.decided.is_none());
+    assert!(!stored.approved);
+    assert!(storage.get_emergency_purge(3).await.unwrap().unwrap().since.is_none());
+    assert!(storage.get_emergency_purge(9).await.unwrap().is_none());
+    assert_eq!(ids(storage.list_emergency_purges(false).await.unwrap()), vec![ 2, big_id, 3 ]);
+
+    // Deciding a request replaces it, and takes it off the undecided list.
+    storage.write_emergency_purge(&EmergencyPurgeRequest {
+        decided: Some(at(4)),
+        approved: true,
+        ..request(big_id, at(2))
+    }).await.unwrap();
+    let decided = storage.get_emergency_purge(big_id).await.unwrap().unwrap();
+    assert_eq!(decided.decided, Some(at(4)));
+    assert!(decided.approved);
+    assert_eq!(ids(storage.list_emergency_purges(false).await.unwrap()), vec![ 2, 3 ]);
+    assert_eq!(ids(storage.list_emergency_purges(true).await.unwrap()), vec![ 2, big_id, 3 ]);
+}
+
+/// Audit records are kept whole, and listed most recent first, up to a limit.
+pub async fn audit_log <S: Storage> (storage: &S) {
+    assert!(storage.list_audit_records(10).await.unwrap().is_empty());
+    let record = |seconds: i64, device_id: Option<DeviceId>| AuditRecord {
+        time: at(seconds),
+        action: format!("action-{}", seconds),
+        device_id,
+        detail: format!("detail-{}", seconds),
+    };
+    for seconds in 0..5 {
+        let device_id = if seconds % 2 == 0 { Some(device(seconds as u8)) } else { None };
+        storage.write_audit_record(&record(seconds, device_id)).await.unwrap();
+    }
+    let actions = |records: Vec<AuditRecord>| records.into_iter().map(|r| r.action).collect::<Vec<_>>();
+    let listed = storage.list_audit_records(10).await.unwrap();
+    assert_eq!(actions(listed.clone()), [ "action-4", "action-3", "action-2", "action-1", "action-0" ]);
+    assert_eq!(listed[0].time, at(4));
+    assert_eq!(listed[0].device_id, Some(device(4)));
+    assert_eq!(listed[0].detail, "detail-4");
+    assert!(listed[1].device_id.is_none());
+    assert_eq!(actions(storage.list_audit_records(2).await.unwrap()), [ "action-4", "action-3" ]);
+    assert!(storage.list_audit_records(0).await.unwrap().is_empty());
+}
+
+/// Registration keys are kept whole and listed oldest first, until they are
+/// deleted. Each use counts one down, and a key that has expired or has no
+/// uses left is listed, but cannot be used.
+pub async fn registration_keys <S: Storage> (storage: &S) {
+    let key = |n: u8, uses_remaining: u32, created: DateTime<Utc>, not_after: Option<DateTime<Utc>>| RegistrationKey {
+        digest: digest(n),
+        uses_remaining,
+        created,
+        not_after,
+        device_permissions: Permissions { write_locations: true, stats: true, ..Default::default() },
+        note: format!("key {}", n),
+    };
+    let digests = |keys: Vec<RegistrationKey>| keys.into_iter().map(|k| k.digest).collect::<Vec<_>>();
+    assert!(storage.list_registration_keys().await.unwrap().is_empty());
+    storage.write_registration_key(&key(1, 2, at(1), None)).await.unwrap();
+    storage.write_registration_key(&key(2, 5, at(0), Some(at(10)))).await.unwrap();
+    storage.write_registration_key(&key(3, 0, at(2), None)).await.unwrap();
+
+    let listed = storage.list_registration_keys().await.unwrap();
+    assert_eq!(digests(listed.clone()), vec![ digest(2), digest(1), digest(3) ]);
+    assert_eq!(listed[0].uses_remaining, 5);
+    assert_eq!(listed[0].created, at(0));
+    assert_eq!(listed[0].not_after, Some(at(10)));
+    assert_eq!(listed[0].device_permissions, key(2, 5, at(0), None).device_permissions);
+    assert_eq!(listed[0].note, "key 2");
+    assert!(listed[1].not_after.is_none());
+
+    // Each use returns the key as it was before, and counts one use down.
+    let used = storage.use_registration_key(&digest(1), at(5)).await.unwrap().unwrap();
+    assert_eq!(used.uses_remaining, 2);
+    assert_eq!(used.note, "key 1");
+    let used = storage.use_registration_key(&digest(1), at(5)).await.unwrap().unwrap();
+    assert_eq!(used.uses_remaining, 1);
+    assert!(storage.use_registration_key(&digest(1), at(5)).await.unwrap().is_none());
+    assert!(storage.use_registration_key(&digest(3), at(5)).await.unwrap().is_none());
+    assert!(storage.use_registration_key(&digest(9), at(5)).await.unwrap().is_none());
+
+    // A key can be used until just before it expires.
+    let almost = at(10) - Duration::nanoseconds(1);
+    assert_eq!(storage.use_registration_key(&digest(2), almost).await.unwrap().unwrap().uses_remaining, 5);
+    assert!(storage.use_registration_key(&digest(2), at(10)).await.unwrap().is_none());
+
+    let listed = storage.list_registration_keys().await.unwrap();
+    let uses = listed.iter().map(|k| k.uses_remaining).collect::<Vec<_>>();
+    assert_eq!(uses, vec![ 4, 0, 0 ]);
+
+    assert!(storage.delete_registration_key(&digest(1)).await.unwrap());
+    assert!(!storage.delete_registration_key(&digest(1)).await.unwrap());
+    assert!(!storage.delete_registration_key(&digest(9)).await.unwrap());
+    assert!(storage.use_registration_key(&digest(1), at(5)).await.unwrap().is_none());
+    assert_eq!(digests(storage.list_registration_keys().await.unwrap()), vec![ digest(2), digest(3) ]);
+}
@@ -0,0 +1,699 @@
+//! The append-only journal and snapshots with which `MemoryStorage` can keep
+//! its contents across restarts.
+//!
+//! A directory holds two files: `snapshot`, the whole contents of the storage
+//! as of some point, and `journal`, every change made since. Both consist of
+//! records framed as a little-endian `u32` length, a little-endian `u32`
+//! CRC-32 of the payload, and then the payload, which is a Protocol Buffers
+//! message. A crash while appending to the journal can leave its final record
+//! torn, which is detected by its length or checksum and discarded.
+use crate::storage::records::{
+    LocationRecord,
+    TokenRecord,
+    IntroRecord,
+    WipeOrderRecord,
+    ExcommunicationRecord,
+    PurgeOrderRecord,
+    EmergencyPurgeRecord,
+    AuditRecordRecord,
+    RegistrationKeyRecord,
+};
+use log::{error, warn};
+use prost::{Message, Oneof};
+use prost_types::Timestamp;
+use std::fs::{self, File, OpenOptions};
+use std::future::Future;
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::sync::{mpsc, oneshot};
+
+pub const HEADER_LENGTH: usize = 8;
+
+const SNAPSHOT_FILE: &str = "snapshot";
+const SNAPSHOT_TEMP_FILE: &str = "snapshot.tmp";
+const JOURNAL_FILE: &str = "journal";
+const JOURNAL_TEMP_FILE: &str = "journal.tmp";
+
+#[derive(Clone, PartialEq, Message)]
+pub struct DeviceLocation {
+    #[prost(bytes = "vec", tag = "1")]
+    pub device_id: Vec<u8>,
+    #[prost(message, optional, tag = "2")]
+    pub location: Option<LocationRecord>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct DeviceLocations {
+    #[prost(bytes = "vec", tag = "1")]
+    pub device_id: Vec<u8>,
+    #[prost(message, repeated, tag = "2")]
+    pub locations: Vec<LocationRecord>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct DeviceKey {
+    #[prost(bytes = "vec", tag = "1")]
+    pub device_id: Vec<u8>,
+    #[prost(bytes = "vec", tag = "2")]
+    pub wrapped_key: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct DeviceIntro {
+    #[prost(bytes = "vec", tag = "1")]
+    pub device_id: Vec<u8>,
+    #[prost(message, optional, tag = "2")]
+    pub intro: Option<IntroRecord>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Token {
+    #[prost(bytes = "vec", tag = "1")]
+    pub digest: Vec<u8>,
+    #[prost(message, optional, tag = "2")]
+    pub entry: Option<TokenRecord>,
+}
+
+/// An introduction, along with the token that the device was given.
+#[derive(Clone, PartialEq, Message)]
+pub struct Registration {
+    #[prost(message, optional, tag = "1")]
+    pub intro: Option<DeviceIntro>,
+    #[prost(message, optional, tag = "2")]
+    pub token: Option<Token>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct TokenRevocation {
+    #[prost(bytes = "vec", tag = "1")]
+    pub device_id: Vec<u8>,
+    #[prost(bytes = "vec", optional, tag = "2")]
+    pub digest: Option<Vec<u8>>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct LocationPurge {
+    #[prost(bytes = "vec", tag = "1")]
+    pub device_id: Vec<u8>,
+    #[prost(message, optional, tag = "2")]
+    pub since: Option<Timestamp>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct LocationDeletion {
+    #[prost(bytes = "vec", tag = "1")]
+    pub device_id: Vec<u8>,
+    #[prost(message, repeated, tag = "2")]
+    pub update_times: Vec<Timestamp>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct DeviceWipeOrder {
+    #[prost(bytes = "vec", tag = "1")]
+    pub device_id: Vec<u8>,
+    #[prost(message, optional, tag = "2")]
+    pub order: Option<WipeOrderRecord>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct DeviceExcommunication {
+    #[prost(bytes = "vec", tag = "1")]
+    pub device_id: Vec<u8>,
+    #[prost(message, optional, tag = "2")]
+    pub record: Option<ExcommunicationRecord>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct RegistrationKeyUse {
+    #[prost(bytes = "vec", tag = "1")]
+    pub key: Vec<u8>,
+    #[prost(message, optional, tag = "2")]
+    pub now: Option<Timestamp>,
+}
+
+/// A change to the storage, with everything needed to make it again exactly
+/// as it was made the first time.
+#[derive(Clone, PartialEq, Oneof)]
+pub enum Mutation {
+    #[prost(message, tag = "2")]
+    WriteLocation(DeviceLocation),
+    #[prost(message, tag = "3")]
+    WriteIntro(Registration),
+    #[prost(message, tag = "4")]
+    WriteToken(Token),
+    #[prost(message, tag = "5")]
+    RevokeToken(TokenRevocation),
+    #[prost(message, tag = "6")]
+    PurgeLocation(LocationPurge),
+    #[prost(message, tag = "7")]
+    DeleteLocations(LocationDeletion),
+    #[prost(message, tag = "8")]
+    DeleteLocationsBefore(Timestamp),
+    #[prost(message, tag = "9")]
+    InsertDeviceKey(DeviceKey),
+    #[prost(message, tag = "10")]
+    WriteWipeOrder(DeviceWipeOrder),
+    #[prost(message, tag = "11")]
+    Excommunicate(DeviceExcommunication),
+    #[prost(message, tag = "12")]
+    WritePurgeOrder(PurgeOrderRecord),
+    #[prost(uint64, tag = "13")]
+    DeletePurgeOrder(u64),
+    #[prost(message, tag = "14")]
+    WriteEmergencyPurge(EmergencyPurgeRecord),
+    #[prost(message, tag = "15")]
+    WriteAuditRecord(AuditRecordRecord),
+    #[prost(message, tag = "16")]
+    WriteRegistrationKey(RegistrationKeyRecord),
+    #[prost(bytes, tag = "17")]
+    DeleteRegistrationKey(Vec<u8>),
+    #[prost(message, tag = "18")]
+    UseRegistrationKey(RegistrationKeyUse),
+    #[prost(message, tag = "19")]
+    RestoreIntro(DeviceIntro),
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct JournalEntry {
+    #[prost(uint64, tag = "1")]
+    sequence: u64,
+    #[prost(oneof = "Mutation", tags = "2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19")]
+    mutation: Option<Mutation>,
+}
+
+/// The whole contents of a `MemoryStorage`.
+#[derive(Clone, PartialEq, Message)]
+pub struct Snapshot {
+    /// The sequence number of the last journal entry reflected in this
+    /// snapshot. Entries up to this one are skipped when replaying.
+    #[prost(uint64, tag = "1")]
+    pub last_sequence: u64,
+    #[prost(message, repeated, tag = "2")]
+    pub locations: Vec<DeviceLocations>,
+    #[prost(message, repeated, tag = "3")]
+    pub device_keys: Vec<DeviceKey>,
+    #[prost(message, repeated, tag = "4")]
+    pub intros: Vec<DeviceIntro>,
+
+    /// Every token, grouped by device in the order they were created.
+    #[prost(message, repeated, tag = "5")]
+    pub tokens: Vec<Token>,
+    #[prost(message, repeated, tag = "6")]
+    pub wipe_orders: Vec<DeviceWipeOrder>,
+    #[prost(message, repeated, tag = "7")]
+    pub excommunications: Vec<DeviceExcommunication>,
+    #[prost(message, repeated, tag = "8")]
+    pub purge_orders: Vec<PurgeOrderRecord>,
+    #[prost(message, repeated, tag = "9")]
+    pub emergency_purges: Vec<EmergencyPurgeRecord>,
+
+    /// The audit log, oldest first.
+    #[prost(message, repeated, tag = "10")]
+    pub audit_log: Vec<AuditRecordRecord>,
+    #[prost(message, repeated, tag = "11")]
+    pub registration_keys: Vec<RegistrationKeyRecord>,
+}
+
+/// What was found in a journal's directory when it was opened.
+pub struct Recovered {
+    pub snapshot: Option<Snapshot>,
+
+    /// The changes made since the snapshot, in order.
+    pub mutations: Vec<Mutation>,
+}
+
+/// Frames a record as described above.
+pub fn frame (payload: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let len: u32 = payload.len().try_into()
+        .map_err(|_| anyhow::anyhow!("A record may not be larger than 4 GiB."))?;
+    let mut framed = Vec::with_capacity(HEADER_LENGTH + payload.len());
+    framed.extend_from_slice(&len.to_le_bytes());
+    framed.extend_from_slice(&crc32fast::hash(payload).to_le_bytes());
+    framed.extend_from_slice(payload);
+    Ok(framed)
+}
+
+/// Splits the framed record at the start of `bytes` from what follows it.
+/// Returns `None` if the record is cut short or fails its checksum.
+fn unframe (bytes: &[u8]) -> Option<(&[u8], &[u8])> {
+    if bytes.len() < HEADER_LENGTH {
+        return None;
+    }
+    let len = u32::from_le_bytes(bytes[0..4].try_into().ok()?) as usize;
+    let crc = u32::from_le_bytes(bytes[4..8].try_into().ok()?);
+    let rest = &bytes[HEADER_LENGTH..];
+    if rest.len() < len || crc32fast::hash(&rest[..len]) != crc {
+        return None;
+    }
+    Some(rest.split_at(len))
+}
+
+/// Whether a whole record later than `sequence` starts anywhere in `bytes`,
+/// in which case whatever precedes it cannot be a torn write. This is only
+/// ever done over what follows a broken record, and most offsets are passed
+/// over without a checksum, since their lengths run past the end.
+fn record_follows (bytes: &[u8], sequence: u64) -> bool {
+    (1..bytes.len()).any(|start| {
+        unframe(&bytes[start..])
+            .and_then(|(payload, _)| JournalEntry::decode(payload).ok())
+            .is_some_and(|entry| entry.sequence > sequence)
+    })
+}
+
+fn read_if_exists (path: &Path) -> anyhow::Result<Option<Vec<u8>>> {
+    match fs::read(path) {
+        Ok(bytes) => Ok(Some(bytes)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(anyhow::anyhow!("Could not read {}: {}", path.display(), e)),
+    }
+}
+
+fn sync_dir (dir: &Path) -> anyhow::Result<()> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// A point in the journal, up to which a snapshot reflects every change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mark {
+    pub sequence: u64,
+    offset: u64,
+}
+
+/// An open journal, to which every change is appended and synced to disk.
+pub struct Journal {
+    dir: PathBuf,
+    file: File,
+
+    /// The length of the journal file, up to the end of its last whole record.
+    len: u64,
+    next_sequence: u64,
+}
+
+impl Journal {
+
+    /// Opens the journal in `dir`, creating the directory if it does not
+    /// exist, and reads what was recorded there before. A torn final record
+    /// is discarded, but any other damage is an error.
+    pub fn open (dir: &Path) -> anyhow::Result<(Journal, Recovered)> {
+        fs::create_dir_all(dir)
+            .map_err(|e| anyhow::anyhow!("Could not create {}: {}", dir.display(), e))?;
+        let snapshot = match read_if_exists(&dir.join(SNAPSHOT_FILE))? {
+            Some(bytes) => {
+                let (payload, _) = unframe(&bytes)
+                    .ok_or_else(|| anyhow::anyhow!("The snapshot in {} is corrupt.", dir.display()))?;
+                Some(Snapshot::decode(payload)?)
+            },
+            None => None,
+        };
+        let last_snapshotted = snapshot.as_ref().map(|s| s.last_sequence).unwrap_or(0);
+        let mut next_sequence = last_snapshotted + 1;
+
+        let journal_path = dir.join(JOURNAL_FILE);
+        let bytes = read_if_exists(&journal_path)?.unwrap_or_default();
+        let mut mutations = vec![];
+        let mut rest = bytes.as_slice();
+        let mut last_read = 0;
+        while !rest.is_empty() {
+            let (payload, after) = match unframe(rest) {
+                Some(r) => r,
+                None => break,
+            };
+            let entry = JournalEntry::decode(payload)?;
+            last_read = last_read.max(entry.sequence);
+            if entry.sequence >= next_sequence {
+                let mutation = entry.mutation
+                    .ok_or_else(|| anyhow::anyhow!("Journal entry {} is empty.", entry.sequence))?;
+                mutations.push(mutation);
+                next_sequence = entry.sequence + 1;
+            }
+            rest = after;
+        }
+        let len = (bytes.len() - rest.len()) as u64;
+        if !rest.is_empty() {
+            // Only the last record can be torn, since nothing is appended
+            // after a record until it has been synced. A record whose length
+            // runs past the end is torn only if no whole record follows it:
+            // otherwise, its length is what is damaged.
+            let framed_len = rest.get(..4)
+                .map(|l| u32::from_le_bytes(l.try_into().unwrap()) as usize + HEADER_LENGTH);
+            if framed_len.map(|l| l < rest.len()).unwrap_or(false) || record_follows(rest, last_read) {
+                anyhow::bail!("The journal in {} is corrupt at byte {}.", dir.display(), len);
+            }
+            warn!("Discarding a torn record of {} bytes at the end of the journal in {}.", rest.len(), dir.display());
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&journal_path)
+            .map_err(|e| anyhow::anyhow!("Could not open {}: {}", journal_path.display(), e))?;
+        file.set_len(len)?;
+        file.sync_all()?;
+        let journal = Journal {
+            dir: dir.to_path_buf(),
+            file,
+            len,
+            next_sequence,
+        };
+        Ok((journal, Recovered { snapshot, mutations }))
+    }
+
+    /// Appends changes to the journal, returning once they are on disk. They
+    /// are synced together, so this is much cheaper than appending each of
+    /// them on its own.
+    pub fn append (&mut self, mutations: Vec<Mutation>) -> anyhow::Result<()> {
+        let mut framed = vec![];
+        let mut next_sequence = self.next_sequence;
+        for mutation in mutations {
+            let entry = JournalEntry {
+                sequence: next_sequence,
+                mutation: Some(mutation),
+            };
+            framed.extend(frame(&entry.encode_to_vec())?);
+            next_sequence += 1;
+        }
+        let written = self.file.write_all(&framed).and_then(|_| self.file.sync_data());
+        if let Err(e) = written {
+            // Leave no partial record behind for later ones to follow.
+            let _ = self.file.set_len(self.len);
+            return Err(anyhow::anyhow!("Could not write to the journal in {}: {}", self.dir.display(), e));
+        }
+        self.len += framed.len() as u64;
+        self.next_sequence = next_sequence;
+        Ok(())
+    }
+
+    /// Whether anything has been appended since the last snapshot.
+    pub fn is_empty (&self) -> bool {
+        self.len == 0
+    }
+
+    /// Marks the end of what has been appended so far.
+    pub fn mark (&self) -> Mark {
+        Mark {
+            sequence: self.next_sequence - 1,
+            offset: self.len,
+        }
+    }
+
+    /// Removes the records up to `mark` from the journal, once a snapshot
+    /// reflecting them is on disk. Records appended since are kept.
+    pub fn discard_through (&mut self, mark: Mark) -> anyhow::Result<()> {
+        if mark.offset > self.len {
+            anyhow::bail!("The journal in {} is shorter than the mark.", self.dir.display());
+        }
+        let path = self.dir.join(JOURNAL_FILE);
+        if mark.offset == self.len {
+            self.file.set_len(0)?;
+            self.file.sync_all()?;
+            self.len = 0;
+            return Ok(());
+        }
+        let mut rest = vec![];
+        let mut file = File::open(&path)?;
+        file.seek(SeekFrom::Start(mark.offset))?;
+        file.take(self.len - mark.offset).read_to_end(&mut rest)?;
+        // If the server stops before the rename, the records already in the
+        // snapshot are skipped by their sequence numbers.
+        let temp_path = self.dir.join(JOURNAL_TEMP_FILE);
+        let mut temp = File::create(&temp_path)?;
+        temp.write_all(&rest)?;
+        temp.sync_all()?;
+        drop(temp);
+        fs::rename(&temp_path, &path)?;
+        sync_dir(&self.dir)?;
+        self.file = OpenOptions::new().append(true).open(&path)?;
+        self.len = rest.len() as u64;
+        Ok(())
+    }
+
+}
+
+/// Replaces the snapshot in `dir` with `snapshot`.
+pub fn write_snapshot (dir: &Path, snapshot: &Snapshot) -> anyhow::Result<()> {
+    let temp_path = dir.join(SNAPSHOT_TEMP_FILE);
+    let mut temp = File::create(&temp_path)?;
+    temp.write_all(&frame(&snapshot.encode_to_vec())?)?;
+    temp.sync_all()?;
+    drop(temp);
+    fs::rename(&temp_path, dir.join(SNAPSHOT_FILE))?;
+    sync_dir(dir)
+}
+
+enum Request {
+    Append(Mutation, oneshot::Sender<anyhow::Result<()>>),
+    Mark(oneshot::Sender<Option<Mark>>),
+    Discard(Mark, oneshot::Sender<anyhow::Result<()>>),
+}
+
+/// A change that has been queued to be written to the journal.
+#[derive(Default)]
+pub struct Written (Option<oneshot::Receiver<anyhow::Result<()>>>);
+
+impl Written {
+
+    /// Waits for the change to be on disk.
+    pub async fn wait (self) -> anyhow::Result<()> {
+        match self.0 {
+            Some(written) => written.await.map_err(|_| stopped())?,
+            None => Ok(()),
+        }
+    }
+
+}
+
+fn stopped () -> anyhow::Error {
+    anyhow::anyhow!("The journal writer has stopped.")
+}
+
+/// Writes to a journal from a thread of its own, so that nothing waits on
+/// the disk while holding a lock or occupying the async runtime. Changes
+/// queued while one write is being synced are all written and synced
+/// together next.
+///
+/// Once a write fails, every later one is refused, because whatever the
+/// failed write was for may already have been made in memory.
+pub struct JournalWriter {
+    dir: PathBuf,
+    requests: mpsc::UnboundedSender<Request>,
+    failed: Arc<AtomicBool>,
+}
+
+impl JournalWriter {
+
+    pub fn start (journal: Journal) -> Self {
+        let (requests, receiver) = mpsc::unbounded_channel();
+        let failed = Arc::new(AtomicBool::new(false));
+        let dir = journal.dir.clone();
+        let thread_failed = failed.clone();
+        std::thread::Builder::new()
+            .name(String::from("journal"))
+            .spawn(move || run_writer(journal, receiver, thread_failed))
+            .expect("Could not start the journal writer.");
+        JournalWriter { dir, requests, failed }
+    }
+
+    /// Queues a change to be written. Changes are written in the order they
+    /// are queued.
+    pub fn append (&self, mutation: Mutation) -> anyhow::Result<Written> {
+        if self.failed.load(Ordering::Acquire) {
+            anyhow::bail!("An earlier write to the journal in {} failed.", self.dir.display());
+        }
+        let (written, receiver) = oneshot::channel();
+        self.requests.send(Request::Append(mutation, written)).map_err(|_| stopped())?;
+        Ok(Written(Some(receiver)))
+    }
+
+    /// Queues a mark after every change queued so far. The mark is `None` if
+    /// nothing has been written since the last snapshot.
+    pub fn mark (&self) -> impl Future<Output = anyhow::Result<Option<Mark>>> {
+        let (mark, receiver) = oneshot::channel();
+        let sent = self.requests.send(Request::Mark(mark)).is_ok();
+        async move {
+            if !sent {
+                return Err(stopped());
+            }
+            receiver.await.map_err(|_| stopped())
+        }
+    }
+
+    /// Replaces the snapshot with `snapshot`, which must reflect every change
+    /// up to `mark` and no more, and discards those changes from the journal.
+    pub async fn compact (&self, mark: Mark, mut snapshot: Snapshot) -> anyhow::Result<()> {
+        snapshot.last_sequence = mark.sequence;
+        let dir = self.dir.clone();
+        tokio::task::spawn_blocking(move || write_snapshot(&dir, &snapshot)).await??;
+        let (discarded, receiver) = oneshot::channel();
+        self.requests.send(Request::Discard(mark, discarded)).map_err(|_| stopped())?;
+        receiver.await.map_err(|_| stopped())?
+    }
+
+}
+
+fn run_writer (mut journal: Journal, mut requests: mpsc::UnboundedReceiver<Request>, failed: Arc<AtomicBool>) {
+    let mut next = None;
+    while let Some(request) = next.take().or_else(|| requests.blocking_recv()) {
+        match request {
+            Request::Append(mutation, written) => {
+                let mut mutations = vec![ mutation ];
+                let mut waiting = vec![ written ];
+                loop {
+                    match requests.try_recv() {
+                        Ok(Request::Append(mutation, written)) => {
+                            mutations.push(mutation);
+                            waiting.push(written);
+                        },
+                        Ok(other) => {
+                            next = Some(other);
+                            break;
+                        },
+                        Err(_) => break,
+                    };
+                }
+                let result = if failed.load(Ordering::Acquire) {
+                    Err(anyhow::anyhow!("An earlier write to the journal in {} failed.", journal.dir.display()))
+                } else {
+                    journal.append(mutations)
+                };
+                if let Err(e) = &result {
+                    error!("{:#}", e);
+                    failed.store(true, Ordering::Release);
+                }
+                for written in waiting {
+                    let _ = written.send(result.as_ref().map(|_| ()).map_err(|e| anyhow::anyhow!("{:#}", e)));
+                }
+            },
+            Request::Mark(mark) => {
+                let _ = mark.send(if journal.is_empty() { None } else { Some(journal.mark()) });
+            },
+            Request::Discard(mark, discarded) => {
+                let _ = discarded.send(journal.discard_through(mark));
+            },
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir () -> PathBuf {
+        std::env::temp_dir().join(format!("fmx-journal-{}", rand::random::<u64>()))
+    }
+
+    #[test]
+    fn torn_final_records_are_discarded () {
+        let dir = temp_dir();
+        {
+            let (mut journal, _) = Journal::open(&dir).unwrap();
+            journal.append(vec![ Mutation::DeletePurgeOrder(1) ]).unwrap();
+            journal.append(vec![ Mutation::DeletePurgeOrder(2) ]).unwrap();
+        }
+        let path = dir.join(JOURNAL_FILE);
+        let whole = fs::read(&path).unwrap();
+        let record_len = whole.len() / 2;
+
+        // A record cut short, and one whose end never made it to disk.
+        let torn_tails: [&[u8]; 2] = [ &whole[..record_len + 3], &whole[..whole.len() - 1] ];
+        for torn in torn_tails {
+            fs::write(&path, torn).unwrap();
+            let (mut journal, recovered) = Journal::open(&dir).unwrap();
+            assert_eq!(recovered.mutations, vec![ Mutation::DeletePurgeOrder(1) ]);
+            journal.append(vec![ Mutation::DeletePurgeOrder(3) ]).unwrap();
+            drop(journal);
+            let (_, recovered) = Journal::open(&dir).unwrap();
+            assert_eq!(recovered.mutations, vec![ Mutation::DeletePurgeOrder(1), Mutation::DeletePurgeOrder(3) ]);
+        }
+
+        // Damage anywhere else is not mistaken for a torn write.
+        let mut corrupt = whole.clone();
+        corrupt[HEADER_LENGTH] ^= 1;
+        fs::write(&path, corrupt).unwrap();
+        assert!(Journal::open(&dir).is_err());
+
+        // Nor is a length that runs past the end, if whole records follow.
+        let mut overlong = whole.clone();
+        overlong[..4].copy_from_slice(&u32::MAX.to_le_bytes());
+        fs::write(&path, overlong).unwrap();
+        assert!(Journal::open(&dir).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn snapshots_supersede_the_journal () {
+        let dir = temp_dir();
+        let (mut journal, _) = Journal::open(&dir).unwrap();
+        journal.append(vec![ Mutation::DeletePurgeOrder(1) ]).unwrap();
+        let journal_before_compaction = fs::read(dir.join(JOURNAL_FILE)).unwrap();
+        let mark = journal.mark();
+        write_snapshot(&dir, &Snapshot {
+            last_sequence: mark.sequence,
+            audit_log: vec![ Default::default() ],
+            ..Default::default()
+        }).unwrap();
+        journal.discard_through(mark).unwrap();
+        assert!(journal.is_empty());
+        journal.append(vec![ Mutation::DeletePurgeOrder(2) ]).unwrap();
+        drop(journal);
+
+        let (_, recovered) = Journal::open(&dir).unwrap();
+        let snapshot = recovered.snapshot.unwrap();
+        assert_eq!(snapshot.last_sequence, 1);
+        assert_eq!(snapshot.audit_log.len(), 1);
+        assert_eq!(recovered.mutations, vec![ Mutation::DeletePurgeOrder(2) ]);
+
+        // As if the server had stopped before emptying the journal.
+        let mut unemptied = journal_before_compaction;
+        unemptied.extend(fs::read(dir.join(JOURNAL_FILE)).unwrap());
+        fs::write(dir.join(JOURNAL_FILE), unemptied).unwrap();
+        let (_, recovered) = Journal::open(&dir).unwrap();
+        assert_eq!(recovered.mutations, vec![ Mutation::DeletePurgeOrder(2) ]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn changes_after_the_mark_survive_compaction () {
+        let dir = temp_dir();
+        let (mut journal, _) = Journal::open(&dir).unwrap();
+        journal.append(vec![ Mutation::DeletePurgeOrder(1), Mutation::DeletePurgeOrder(2) ]).unwrap();
+        let mark = journal.mark();
+        journal.append(vec![ Mutation::DeletePurgeOrder(3) ]).unwrap();
+        write_snapshot(&dir, &Snapshot { last_sequence: mark.sequence, ..Default::default() }).unwrap();
+        journal.discard_through(mark).unwrap();
+        assert!(!journal.is_empty());
+        journal.append(vec![ Mutation::DeletePurgeOrder(4) ]).unwrap();
+        drop(journal);
+
+        let (_, recovered) = Journal::open(&dir).unwrap();
+        assert_eq!(recovered.snapshot.unwrap().last_sequence, 2);
+        assert_eq!(recovered.mutations, vec![ Mutation::DeletePurgeOrder(3), Mutation::DeletePurgeOrder(4) ]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn queued_changes_are_written_in_order () {
+        let dir = temp_dir();
+        let (journal, _) = Journal::open(&dir).unwrap();
+        let writer = JournalWriter::start(journal);
+        let written: Vec<Written> = (1..=100)
+            .map(|id| writer.append(Mutation::DeletePurgeOrder(id)).unwrap())
+            .collect();
+        for w in written {
+            w.wait().await.unwrap();
+        }
+        let mark = writer.mark().await.unwrap().unwrap();
+        assert_eq!(mark.sequence, 100);
+        writer.compact(mark, Snapshot::default()).await.unwrap();
+        assert_eq!(writer.mark().await.unwrap(), None);
+        drop(writer);
+
+        let (_, recovered) = Journal::open(&dir).unwrap();
+        assert_eq!(recovered.snapshot.unwrap().last_sequence, 100);
+        assert!(recovered.mutations.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+}
@@ -1,143 +1,630 @@
-use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::BuildHasher;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use crate::digest::constant_time_eq;
+use crate::storage::journal::{
+    Journal,
+    JournalWriter,
+    Mark,
+    Written,
+    Mutation,
+    Snapshot,
+    DeviceLocation,
+    DeviceLocations,
+    DeviceKey,
+    DeviceIntro,
+    Token,
+    Registration,
+    TokenRevocation,
+    LocationPurge,
+    LocationDeletion,
+    DeviceWipeOrder,
+    DeviceExcommunication,
+    RegistrationKeyUse,
+};
+use crate::storage::records::{
+    time,
+    optional_time,
+    LocationRecord,
+    TokenRecord,
+    IntroRecord,
+    WipeOrderRecord,
+    ExcommunicationRecord,
+    PurgeOrderRecord,
+    EmergencyPurgeRecord,
+    AuditRecordRecord,
+    RegistrationKeyRecord,
+};
 use crate::storage::{
     Storage,
-    SecretKey,
-    Token,
-    LocationInsertion,
+    DeviceId,
+    TokenDigest,
+    StoredLocation,
     IntroInsertion,
     TokenEntry,
     LocationsFilter,
+    Introduction,
+    WipeOrder,
+    Excommunication,
+    PurgeOrder,
+    EmergencyPurgeRequest,
+    AuditRecord,
+    RegistrationKey,
+    RegistrationKeyDigest,
+    StorageUsage,
 };
-use crate::grpc::find_my_device::{
-    RevokeTokenArg,
-    ListLocationsResult,
-    GetStorageInfoResult,
-    LocationSnapshot,
-    Permissions,
-};
+use crate::utils::chrono_to_grpc_timestamp;
 use chrono::prelude::*;
+use log::{error, info};
+
+/// The number of shards that devices are spread across.
+const SHARDS: usize = 64;
+
+/// A device's locations, oldest first, along with their total size, so that
+/// its storage usage can be told without going through all of them.
+#[derive(Default)]
+struct History {
+    locations: Vec<StoredLocation>,
+    bytes: u64,
+}
+
+impl History {
+
+    fn new (mut locations: Vec<StoredLocation>) -> Self {
+        locations.sort_by_key(|loc| loc.update_time);
+        let bytes = locations.iter().map(StoredLocation::size).sum();
+        History{ locations, bytes }
+    }
+
+    /// Adds a location, replacing any at the same time.
+    fn insert (&mut self, location: &StoredLocation) {
+        self.bytes += location.size();
+        match self.locations.binary_search_by_key(&location.update_time, |loc| loc.update_time) {
+            Ok(i) => {
+                self.bytes -= self.locations[i].size();
+                self.locations[i] = location.clone();
+            },
+            Err(i) => self.locations.insert(i, location.clone()),
+        };
+    }
+
+    /// Keeps only the locations that `keep` returns `true` for, returning how
+    /// many were removed.
+    fn retain (&mut self, mut keep: impl FnMut(&StoredLocation) -> bool) -> u64 {
+        let before = self.locations.len();
+        let mut removed_bytes = 0;
+        self.locations.retain(|loc| {
+            let kept = keep(loc);
+            if !kept {
+                removed_bytes += loc.size();
+            }
+            kept
+        });
+        self.bytes -= removed_bytes;
+        (before - self.locations.len()) as u64
+    }
 
-#[derive(Clone)]
-pub struct Introduction {
-    pub remote_addr: Option<SocketAddr>,
-    pub registration_key: Vec<u8>,
-    pub remote_wipe_enabled: bool,
-    pub can_read_nearby_devices: bool,
 }
 
-#[derive(Clone)]
+/// Everything kept about the devices in one shard.
+#[derive(Default)]
+struct Shard {
+    locations: HashMap<DeviceId, History>,
+    device_keys: HashMap<DeviceId, Vec<u8>>,
+    intros: HashMap<DeviceId, Introduction>,
+    tokens_by_device: HashMap<DeviceId, Vec<TokenDigest>>,
+    wipe_orders: HashMap<DeviceId, WipeOrder>,
+    excommunications: HashMap<DeviceId, Excommunication>,
+}
+
+/// Storage in memory. Devices are spread across shards, each behind its own
+/// lock, so that requests about different devices do not wait on each other,
+/// and requests that only read do not wait on each other at all.
+///
+/// If it is opened with a journal, every change is also written to the
+/// journal, so that the contents can be restored when the server starts
+/// again. A change is queued for the journal while the locks on whatever it
+/// changes are held, but it is only waited for once they are released, so
+/// that no lock is held while waiting on the disk. Nothing that changes the
+/// storage returns until its change is on disk.
+///
+/// Locks are always taken in this order, to avoid deadlocks: shards, in
+/// order, then `tokens`, `purge_orders`, `emergency_purges`, `audit_log`,
+/// `registration_keys`, and lastly the journal.
 pub struct MemoryStorage {
-    pub locations: HashMap<SecretKey, Vec<LocationInsertion>>,
-    pub intros: HashMap<SecretKey, Introduction>,
-    pub tokens_by_secret: HashMap<SecretKey, Vec<Token>>,
-    pub tokens: HashMap<Token, TokenEntry>,
+    shards: Vec<RwLock<Shard>>,
+    hasher: RandomState,
+    tokens: RwLock<HashMap<TokenDigest, TokenEntry>>,
+    purge_orders: RwLock<HashMap<u64, PurgeOrder>>,
+    emergency_purges: RwLock<HashMap<u64, EmergencyPurgeRequest>>,
+    audit_log: RwLock<Vec<AuditRecord>>,
+    registration_keys: RwLock<HashMap<Vec<u8>, RegistrationKey>>,
+    journal: Option<JournalWriter>,
+
+    /// Held while a snapshot is taken, so that only one is taken at a time.
+    snapshotting: tokio::sync::Mutex<()>,
 }
 
 impl MemoryStorage {
 
     pub fn new () -> Self {
         MemoryStorage{
-            locations: HashMap::new(),
-            intros: HashMap::new(),
-            tokens_by_secret: HashMap::new(),
-            tokens: HashMap::new(),
+            shards: (0..SHARDS).map(|_| RwLock::new(Shard::default())).collect(),
+            hasher: RandomState::new(),
+            tokens: RwLock::new(HashMap::new()),
+            purge_orders: RwLock::new(HashMap::new()),
+            emergency_purges: RwLock::new(HashMap::new()),
+            audit_log: RwLock::new(Vec::new()),
+            registration_keys: RwLock::new(HashMap::new()),
+            journal: None,
+            snapshotting: tokio::sync::Mutex::new(()),
         }
     }
 
-}
+    /// Opens storage journaled in `dir`, restoring whatever was journaled
+    /// there before.
+    pub async fn open (dir: &Path) -> anyhow::Result<Self> {
+        let (journal, recovered) = Journal::open(dir)?;
+        let mut storage = match recovered.snapshot {
+            Some(snapshot) => MemoryStorage::from_snapshot(snapshot)?,
+            None => MemoryStorage::new(),
+        };
+        let replayed = recovered.mutations.len();
+        for mutation in recovered.mutations {
+            storage.apply(mutation).await?;
+        }
+        info!("Restored storage from {}, replaying {} journal entries.", dir.display(), replayed);
+        storage.journal = Some(JournalWriter::start(journal));
+        Ok(storage)
+    }
 
-#[tonic::async_trait]
-impl Storage for MemoryStorage {
+    fn shard_index (&self, device_id: &DeviceId) -> usize {
+        (self.hasher.hash_one(device_id) % SHARDS as u64) as usize
+    }
 
-    async fn get_token_info (&self, token: &Token) -> anyhow::Result<Option<TokenEntry>> {
-        Ok(self.tokens.get(token).cloned())
+    fn shard (&self, device_id: &DeviceId) -> &RwLock<Shard> {
+        &self.shards[self.shard_index(device_id)]
     }
 
-    async fn write_location (&mut self, secret_key: &SecretKey, arg: &LocationInsertion) -> anyhow::Result<()> {
-        match self.locations.get_mut(secret_key.as_slice()) {
-            Some(locs) => {
-                locs.push(arg.clone());
-                Ok(())
+    /// Queues a change to be written to the journal, if there is one.
+    /// `mutation` is only called if there is. The locks on whatever is changed
+    /// must be held, so that changes to the same things are journaled in the
+    /// order they are made, but must be released before waiting for the
+    /// change to be written.
+    fn journal (&self, mutation: impl FnOnce() -> Mutation) -> anyhow::Result<Written> {
+        match &self.journal {
+            Some(journal) => journal.append(mutation()),
+            None => Ok(Written::default()),
+        }
+    }
+
+    /// Makes a change read back from the journal.
+    async fn apply (&self, mutation: Mutation) -> anyhow::Result<()> {
+        let missing = || anyhow::anyhow!("Journal entry is missing a field.");
+        match mutation {
+            Mutation::WriteLocation(m) => {
+                let location = m.location.ok_or_else(missing)?.try_into()?;
+                self.write_location(&m.device_id, &location).await?;
+            },
+            Mutation::WriteIntro(m) => {
+                let intro = m.intro.ok_or_else(missing)?;
+                let token = m.token.ok_or_else(missing)?;
+                let entry = token.entry.ok_or_else(missing)?.try_into()?;
+                self.register(&intro.device_id, intro.intro.ok_or_else(missing)?.into(), &token.digest, entry)?.wait().await?;
+            },
+            Mutation::WriteToken(m) => {
+                self.write_token(&m.digest, &m.entry.ok_or_else(missing)?.try_into()?).await?;
+            },
+            Mutation::RevokeToken(m) => {
+                self.revoke_token(&m.device_id, m.digest.as_ref()).await?;
+            },
+            Mutation::PurgeLocation(m) => {
+                self.purge_location(&m.device_id, optional_time(m.since)).await?;
+            },
+            Mutation::DeleteLocations(m) => {
+                let update_times = m.update_times
+                    .into_iter()
+                    .map(|t| time(Some(t)))
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                self.delete_locations(&m.device_id, &update_times).await?;
+            },
+            Mutation::DeleteLocationsBefore(t) => {
+                self.delete_locations_before(time(Some(t))?).await?;
+            },
+            Mutation::InsertDeviceKey(m) => {
+                self.insert_device_key(&m.device_id, &m.wrapped_key).await?;
+            },
+            Mutation::WriteWipeOrder(m) => {
+                self.write_wipe_order(&m.device_id, &m.order.ok_or_else(missing)?.try_into()?).await?;
+            },
+            Mutation::Excommunicate(m) => {
+                self.excommunicate(&m.device_id, &m.record.ok_or_else(missing)?.try_into()?).await?;
+            },
+            Mutation::WritePurgeOrder(m) => {
+                self.write_purge_order(&m.try_into()?).await?;
+            },
+            Mutation::DeletePurgeOrder(id) => {
+                self.delete_purge_order(id).await?;
+            },
+            Mutation::WriteEmergencyPurge(m) => {
+                self.write_emergency_purge(&m.try_into()?).await?;
+            },
+            Mutation::WriteAuditRecord(m) => {
+                self.write_audit_record(&m.try_into()?).await?;
+            },
+            Mutation::WriteRegistrationKey(m) => {
+                self.write_registration_key(&m.try_into()?).await?;
             },
-            None => {
-                self.locations.insert(secret_key.clone(), Vec::from([ arg.clone() ]));
-                Ok(())
+            Mutation::DeleteRegistrationKey(key) => {
+                self.delete_registration_key(&key).await?;
             },
+            Mutation::UseRegistrationKey(m) => {
+                self.use_registration_key(&m.key, time(m.now)?).await?;
+            },
+            Mutation::RestoreIntro(m) => {
+                self.restore_intro(&m.device_id, &m.intro.ok_or_else(missing)?.into()).await?;
+            },
+        };
+        Ok(())
+    }
+
+    /// Records an introduction and the token given to the device.
+    fn register (&self, device_id: &DeviceId, intro: Introduction, token_digest: &TokenDigest, entry: TokenEntry) -> anyhow::Result<Written> {
+        let mut shard = self.shard(device_id).write().unwrap();
+        let mut tokens = self.tokens.write().unwrap();
+        let written = self.journal(|| Mutation::WriteIntro(Registration {
+            intro: Some(DeviceIntro {
+                device_id: device_id.clone(),
+                intro: Some(IntroRecord::from(&intro)),
+            }),
+            token: Some(Token {
+                digest: token_digest.clone(),
+                entry: Some(TokenRecord::from(&entry)),
+            }),
+        }))?;
+        shard.intros.insert(device_id.clone(), intro);
+        if tokens.insert(token_digest.clone(), entry).is_none() {
+            shard.tokens_by_device.entry(device_id.clone()).or_default().push(token_digest.clone());
+        }
+        Ok(written)
+    }
+
+    fn from_snapshot (snapshot: Snapshot) -> anyhow::Result<Self> {
+        let missing = || anyhow::anyhow!("Snapshot is missing a field.");
+        let mut storage = MemoryStorage::new();
+        let mut shards: Vec<Shard> = (0..SHARDS).map(|_| Shard::default()).collect();
+        for device in snapshot.locations {
+            let locs = device.locations
+                .into_iter()
+                .map(StoredLocation::try_from)
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            shards[storage.shard_index(&device.device_id)].locations.insert(device.device_id, History::new(locs));
+        }
+        for key in snapshot.device_keys {
+            shards[storage.shard_index(&key.device_id)].device_keys.insert(key.device_id, key.wrapped_key);
+        }
+        for intro in snapshot.intros {
+            shards[storage.shard_index(&intro.device_id)].intros.insert(intro.device_id, intro.intro.ok_or_else(missing)?.into());
+        }
+        let mut tokens = HashMap::new();
+        for token in snapshot.tokens {
+            let entry = TokenEntry::try_from(token.entry.ok_or_else(missing)?)?;
+            shards[storage.shard_index(&entry.device_id)].tokens_by_device
+                .entry(entry.device_id.clone())
+                .or_default()
+                .push(token.digest.clone());
+            tokens.insert(token.digest, entry);
+        }
+        for order in snapshot.wipe_orders {
+            shards[storage.shard_index(&order.device_id)].wipe_orders.insert(order.device_id, order.order.ok_or_else(missing)?.try_into()?);
+        }
+        for record in snapshot.excommunications {
+            shards[storage.shard_index(&record.device_id)].excommunications.insert(record.device_id, record.record.ok_or_else(missing)?.try_into()?);
+        }
+        for order in snapshot.purge_orders {
+            let order = PurgeOrder::try_from(order)?;
+            storage.purge_orders.get_mut().unwrap().insert(order.id, order);
+        }
+        for request in snapshot.emergency_purges {
+            let request = EmergencyPurgeRequest::try_from(request)?;
+            storage.emergency_purges.get_mut().unwrap().insert(request.id, request);
+        }
+        for record in snapshot.audit_log {
+            storage.audit_log.get_mut().unwrap().push(record.try_into()?);
+        }
+        for key in snapshot.registration_keys {
+            let key = RegistrationKey::try_from(key)?;
+            storage.registration_keys.get_mut().unwrap().insert(key.digest.clone(), key);
+        }
+        storage.shards = shards.into_iter().map(RwLock::new).collect();
+        storage.tokens = RwLock::new(tokens);
+        Ok(storage)
+    }
+
+    /// Replaces the journal's snapshot with the current contents, emptying
+    /// the journal, if there is a journal and anything has been written to it
+    /// since the last snapshot. Nothing can be changed while the contents are
+    /// copied, but the locks are released before the copy is written out.
+    pub async fn take_snapshot (&self) -> anyhow::Result<()> {
+        let journal = match &self.journal {
+            Some(j) => j,
+            None => return Ok(()),
+        };
+        let _snapshotting = self.snapshotting.lock().await;
+        // Copying the contents is not cheap, so it is skipped if nothing has
+        // changed.
+        if journal.mark().await?.is_none() {
+            return Ok(());
+        }
+        let (mark, snapshot) = self.copy_contents(journal);
+        match mark.await? {
+            Some(mark) => journal.compact(mark, snapshot).await,
+            None => Ok(()),
         }
     }
 
-    async fn write_intro <'a> (&mut self, arg: &'a IntroInsertion) -> anyhow::Result<()> {
-        self.intros.insert(arg.secret_key.clone(), Introduction{
-            remote_addr: arg.remote_addr.clone(),
-            registration_key: arg.arg.registration_key.clone(),
+    /// Copies the contents into a snapshot, and queues a mark in the journal
+    /// after every change the copy reflects.
+    fn copy_contents (&self, journal: &JournalWriter) -> (impl Future<Output = anyhow::Result<Option<Mark>>>, Snapshot) {
+        let shards: Vec<_> = self.shards.iter().map(|s| s.read().unwrap()).collect();
+        let tokens = self.tokens.read().unwrap();
+        let purge_orders = self.purge_orders.read().unwrap();
+        let emergency_purges = self.emergency_purges.read().unwrap();
+        let audit_log = self.audit_log.read().unwrap();
+        let registration_keys = self.registration_keys.read().unwrap();
+        let mark = journal.mark();
+        let snapshot = Snapshot {
+            last_sequence: 0,
+            locations: shards.iter()
+                .flat_map(|s| s.locations.iter())
+                .map(|(device_id, history)| DeviceLocations {
+                    device_id: device_id.clone(),
+                    locations: history.locations.iter().map(LocationRecord::from).collect(),
+                })
+                .collect(),
+            device_keys: shards.iter()
+                .flat_map(|s| s.device_keys.iter())
+                .map(|(device_id, wrapped_key)| DeviceKey {
+                    device_id: device_id.clone(),
+                    wrapped_key: wrapped_key.clone(),
+                })
+                .collect(),
+            intros: shards.iter()
+                .flat_map(|s| s.intros.iter())
+                .map(|(device_id, intro)| DeviceIntro {
+                    device_id: device_id.clone(),
+                    intro: Some(IntroRecord::from(intro)),
+                })
+                .collect(),
+            tokens: shards.iter()
+                .flat_map(|s| s.tokens_by_device.values())
+                .flatten()
+                .filter_map(|digest| tokens.get(digest).map(|entry| Token {
+                    digest: digest.clone(),
+                    entry: Some(TokenRecord::from(entry)),
+                }))
+                .collect(),
+            wipe_orders: shards.iter()
+                .flat_map(|s| s.wipe_orders.iter())
+                .map(|(device_id, order)| DeviceWipeOrder {
+                    device_id: device_id.clone(),
+                    order: Some(WipeOrderRecord::from(order)),
+                })
+                .collect(),
+            excommunications: shards.iter()
+                .flat_map(|s| s.excommunications.iter())
+                .map(|(device_id, record)| DeviceExcommunication {
+                    device_id: device_id.clone(),
+                    record: Some(ExcommunicationRecord::from(record)),
+                })
+                .collect(),
+            purge_orders: purge_orders.values().map(PurgeOrderRecord::from).collect(),
+            emergency_purges: emergency_purges.values().map(EmergencyPurgeRecord::from).collect(),
+            audit_log: audit_log.iter().map(AuditRecordRecord::from).collect(),
+            registration_keys: registration_keys.values().map(RegistrationKeyRecord::from).collect(),
+        };
+        (mark, snapshot)
+    }
+
+}
+
+/// Periodically snapshots journaled storage. This never returns.
+pub async fn run_snapshotter (storage: Arc<MemoryStorage>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    // The first tick completes immediately, just after the storage has been
+    // restored.
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        if let Err(e) = storage.take_snapshot().await {
+            error!("Failed to snapshot storage: {:?}", e);
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Storage for MemoryStorage {
+
+    async fn get_token_info (&self, token: &TokenDigest) -> anyhow::Result<Option<TokenEntry>> {
+        Ok(self.tokens.read().unwrap().get(token).cloned())
+    }
+
+    async fn write_location (&self, device_id: &DeviceId, arg: &StoredLocation) -> anyhow::Result<()> {
+        let written = {
+            let mut shard = self.shard(device_id).write().unwrap();
+            let written = self.journal(|| Mutation::WriteLocation(DeviceLocation {
+                device_id: device_id.clone(),
+                location: Some(LocationRecord::from(arg)),
+            }))?;
+            shard.locations.entry(device_id.clone()).or_default().insert(arg);
+            written
+        };
+        written.wait().await
+    }
+
+    async fn write_intro <'a> (&self, arg: &'a IntroInsertion) -> anyhow::Result<()> {
+        let intro = Introduction{
+            remote_addr: arg.remote_addr,
+            registration_key_digest: arg.registration_key_digest.clone(),
             remote_wipe_enabled: arg.arg.remote_wipe_enabled,
             can_read_nearby_devices: arg.arg.can_read_nearby_devices,
-        });
-        self.tokens.insert(arg.token.clone(), TokenEntry {
-            secret_key: arg.secret_key.clone(),
-            permissions: Permissions{
-                list_tokens: false,
-                write_locations: true,
-                read_locations: true, // TODO: Set this to false after testing is done.
-                nearby: true,
-                stats: false,
-                wipe: false,
-            },
+        };
+        let entry = TokenEntry {
+            device_id: arg.device_id.clone(),
+            permissions: arg.permissions.clone(),
             not_before: Utc::now(),
             not_after: None,
-        });
-        match self.tokens_by_secret.get_mut(arg.secret_key.as_slice()) {
-            Some(tokens) => {
-                tokens.push(arg.token.clone());
-            },
-            None => {
-                self.tokens_by_secret.insert(arg.secret_key.clone(), Vec::from([ arg.token.clone() ]));
-            },
-        }
-        Ok(())
+        };
+        self.register(arg.device_id, intro, arg.token_digest, entry)?.wait().await
     }
 
-    async fn write_token (&mut self, token: &Token, arg: &TokenEntry) -> anyhow::Result<()> {
-        self.tokens.insert(token.clone(), arg.clone());
-        match self.tokens_by_secret.get_mut(arg.secret_key.as_slice()) {
-            Some(tokens) => {
-                tokens.push(token.clone());
-                Ok(())
-            },
-            None => {
-                self.tokens_by_secret.insert(arg.secret_key.clone(), Vec::from([ token.clone() ]));
-                Ok(())
-            },
+    async fn get_intro (&self, device_id: &DeviceId) -> anyhow::Result<Option<Introduction>> {
+        Ok(self.shard(device_id).read().unwrap().intros.get(device_id.as_slice()).cloned())
+    }
+
+    async fn restore_intro (&self, device_id: &DeviceId, intro: &Introduction) -> anyhow::Result<()> {
+        let written = {
+            let mut shard = self.shard(device_id).write().unwrap();
+            let written = self.journal(|| Mutation::RestoreIntro(DeviceIntro {
+                device_id: device_id.clone(),
+                intro: Some(IntroRecord::from(intro)),
+            }))?;
+            shard.intros.insert(device_id.clone(), intro.clone());
+            written
+        };
+        written.wait().await
+    }
+
+    async fn list_devices (&self) -> anyhow::Result<Vec<DeviceId>> {
+        let mut devices = BTreeSet::new();
+        for shard in self.shards.iter() {
+            let shard = shard.read().unwrap();
+            devices.extend(shard.locations.keys().cloned());
+            devices.extend(shard.device_keys.keys().cloned());
+            devices.extend(shard.intros.keys().cloned());
+            devices.extend(shard.tokens_by_device.keys().cloned());
+            devices.extend(shard.wipe_orders.keys().cloned());
+            devices.extend(shard.excommunications.keys().cloned());
         }
+        devices.extend(self.purge_orders.read().unwrap().values().map(|o| o.device_id.clone()));
+        Ok(devices.into_iter().collect())
     }
 
-    async fn revoke_token (&mut self, arg: &RevokeTokenArg) -> anyhow::Result<()> {
-        self.tokens.remove(&arg.token.to_owned());
-        self.tokens_by_secret.remove(&arg.secret_key.to_owned());
-        Ok(())
+    async fn insert_device_key (&self, device_id: &DeviceId, wrapped_key: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let written = {
+            let mut shard = self.shard(device_id).write().unwrap();
+            if let Some(existing) = shard.device_keys.get(device_id.as_slice()) {
+                return Ok(existing.clone());
+            }
+            let written = self.journal(|| Mutation::InsertDeviceKey(DeviceKey {
+                device_id: device_id.clone(),
+                wrapped_key: wrapped_key.to_vec(),
+            }))?;
+            shard.device_keys.insert(device_id.clone(), wrapped_key.to_vec());
+            written
+        };
+        written.wait().await?;
+        Ok(wrapped_key.to_vec())
     }
 
-    async fn list_tokens (&self, secret_key: &SecretKey) -> anyhow::Result<Vec<TokenEntry>> {
-        let tokens = self.tokens_by_secret.get(secret_key.as_slice())
-            .cloned()
-            .unwrap_or(Vec::new());
-        let token_infos = tokens
-            .iter()
-            .filter_map(|t| self.tokens.get(t).cloned())
-            .collect();
+    async fn get_device_key (&self, device_id: &DeviceId) -> anyhow::Result<Option<Vec<u8>>> {
+        Ok(self.shard(device_id).read().unwrap().device_keys.get(device_id.as_slice()).cloned())
+    }
+
+    async fn write_token (&self, token: &TokenDigest, arg: &TokenEntry) -> anyhow::Result<()> {
+        let written = {
+            let mut shard = self.shard(&arg.device_id).write().unwrap();
+            let mut tokens = self.tokens.write().unwrap();
+            let written = self.journal(|| Mutation::WriteToken(Token {
+                digest: token.clone(),
+                entry: Some(TokenRecord::from(arg)),
+            }))?;
+            if tokens.insert(token.clone(), arg.clone()).is_none() {
+                shard.tokens_by_device.entry(arg.device_id.clone()).or_default().push(token.clone());
+            }
+            written
+        };
+        written.wait().await
+    }
+
+    async fn revoke_token (&self, device_id: &DeviceId, token: Option<&TokenDigest>) -> anyhow::Result<u32> {
+        let (revoked, written) = {
+            let mut shard = self.shard(device_id).write().unwrap();
+            let mut all_tokens = self.tokens.write().unwrap();
+            let written = self.journal(|| Mutation::RevokeToken(TokenRevocation {
+                device_id: device_id.clone(),
+                digest: token.cloned(),
+            }))?;
+            let revoked: Vec<TokenDigest> = match shard.tokens_by_device.get_mut(device_id.as_slice()) {
+                Some(tokens) => {
+                    let revoked = match token {
+                        Some(token) => {
+                            match tokens.iter().position(|t| constant_time_eq(t, token)) {
+                                Some(i) => vec![ tokens.swap_remove(i) ],
+                                None => vec![],
+                            }
+                        },
+                        None => std::mem::take(tokens),
+                    };
+                    if tokens.is_empty() {
+                        shard.tokens_by_device.remove(device_id.as_slice());
+                    }
+                    revoked
+                },
+                None => vec![],
+            };
+            for t in revoked.iter() {
+                all_tokens.remove(t);
+            }
+            (revoked.len() as u32, written)
+        };
+        written.wait().await?;
+        Ok(revoked)
+    }
+
+    async fn list_tokens (&self, device_id: &DeviceId) -> anyhow::Result<Vec<(TokenDigest, TokenEntry)>> {
+        let shard = self.shard(device_id).read().unwrap();
+        let all_tokens = self.tokens.read().unwrap();
+        let token_infos = shard.tokens_by_device.get(device_id.as_slice())
+            .map(|tokens| tokens
+                .iter()
+                .filter_map(|t| all_tokens.get(t).cloned().map(|entry| (t.clone(), entry)))
+                .collect())
+            .unwrap_or_default();
         Ok(token_infos)
     }
 
-    async fn purge_location (&mut self, secret_key: &SecretKey) -> anyhow::Result<()> {
-        self.locations.remove(secret_key);
-        Ok(())
+    async fn purge_location (&self, device_id: &DeviceId, since: Option<DateTime<Utc>>) -> anyhow::Result<()> {
+        let written = {
+            let mut shard = self.shard(device_id).write().unwrap();
+            let written = self.journal(|| Mutation::PurgeLocation(LocationPurge {
+                device_id: device_id.clone(),
+                since: since.as_ref().map(chrono_to_grpc_timestamp),
+            }))?;
+            match since {
+                Some(since) => {
+                    if let Some(history) = shard.locations.get_mut(device_id.as_slice()) {
+                        history.retain(|loc| loc.update_time < since);
+                    }
+                },
+                None => {
+                    shard.locations.remove(device_id);
+                    shard.device_keys.remove(device_id);
+                },
+            };
+            written
+        };
+        written.wait().await
     }
 
-    async fn list_locations (&self, secret_key: &SecretKey, filter: &LocationsFilter) -> anyhow::Result<ListLocationsResult> {
-        Ok(ListLocationsResult {
-            locations: self.locations.get(secret_key.as_slice())
-                .unwrap_or(&vec![])
+    async fn list_locations (&self, device_id: &DeviceId, filter: &LocationsFilter) -> anyhow::Result<Vec<StoredLocation>> {
+        Ok(self.shard(device_id).read().unwrap().locations.get(device_id.as_slice())
+                .map(|history| history.locations.as_slice())
+                .unwrap_or_default()
                 .iter()
                 .filter_map(|loc| {
                     if let Some(since) = filter.since {
@@ -150,51 +637,311 @@ impl Storage for MemoryStorage {
                             return None;
                         }
                     }
-                    Some(LocationSnapshot{
-                        emergency: loc.emergency,
-                        // TODO: Refactor into a function
-                        update_time: Some(prost_types::Timestamp{
-                            seconds: loc.update_time.timestamp(),
-                            nanos: loc.update_time.nanosecond() as i32,
-                        }),
-                        expected_next_update_time: loc.expected_next_update_time.map(|t| prost_types::Timestamp {
-                            seconds: t.timestamp(),
-                            nanos: t.nanosecond() as i32,
-                        }),
-                        nearby_bluetooth_devices: loc.nearby_bluetooth_devices.to_owned(),
-                        nearby_wifi_network: loc.nearby_wifi_network.to_owned(),
-                        location: loc.location.to_owned(),
-                        notes: loc.notes.to_owned(),
-                        velocity: loc.velocity.to_owned(),
-                    })
+                    Some(loc.clone())
                 })
                 .take(filter.limit as usize)
-                .collect()
-        })
+                .collect())
+    }
+
+    async fn delete_locations (&self, device_id: &DeviceId, update_times: &[DateTime<Utc>]) -> anyhow::Result<u64> {
+        let (deleted, written) = {
+            let mut shard = self.shard(device_id).write().unwrap();
+            let written = self.journal(|| Mutation::DeleteLocations(LocationDeletion {
+                device_id: device_id.clone(),
+                update_times: update_times.iter().map(chrono_to_grpc_timestamp).collect(),
+            }))?;
+            let deleted = match shard.locations.get_mut(device_id.as_slice()) {
+                Some(history) => {
+                    let update_times: HashSet<&DateTime<Utc>> = update_times.iter().collect();
+                    history.retain(|loc| !update_times.contains(&loc.update_time))
+                },
+                None => 0,
+            };
+            (deleted, written)
+        };
+        written.wait().await?;
+        Ok(deleted)
+    }
+
+    async fn delete_locations_before (&self, before: DateTime<Utc>) -> anyhow::Result<u64> {
+        let (deleted, written) = {
+            let mut shards: Vec<_> = self.shards.iter().map(|s| s.write().unwrap()).collect();
+            let written = self.journal(|| Mutation::DeleteLocationsBefore(chrono_to_grpc_timestamp(&before)))?;
+            let mut deleted = 0;
+            for history in shards.iter_mut().flat_map(|s| s.locations.values_mut()) {
+                deleted += history.retain(|loc| loc.update_time >= before);
+            }
+            (deleted, written)
+        };
+        written.wait().await?;
+        Ok(deleted)
+    }
+
+    async fn get_storage_usage (&self, device_id: &DeviceId) -> anyhow::Result<StorageUsage> {
+        let shard = self.shard(device_id).read().unwrap();
+        Ok(shard.locations.get(device_id)
+            .map(|history| StorageUsage {
+                locations: history.locations.len() as u64,
+                bytes: history.bytes,
+                oldest: history.locations.first().map(|loc| loc.update_time),
+            })
+            .unwrap_or_default())
+    }
+
+    async fn write_wipe_order (&self, device_id: &DeviceId, order: &WipeOrder) -> anyhow::Result<()> {
+        let written = {
+            let mut shard = self.shard(device_id).write().unwrap();
+            let written = self.journal(|| Mutation::WriteWipeOrder(DeviceWipeOrder {
+                device_id: device_id.clone(),
+                order: Some(WipeOrderRecord::from(order)),
+            }))?;
+            shard.wipe_orders.insert(device_id.clone(), order.clone());
+            written
+        };
+        written.wait().await
+    }
+
+    async fn get_wipe_order (&self, device_id: &DeviceId) -> anyhow::Result<Option<WipeOrder>> {
+        Ok(self.shard(device_id).read().unwrap().wipe_orders.get(device_id.as_slice()).cloned())
+    }
+
+    async fn excommunicate (&self, device_id: &DeviceId, record: &Excommunication) -> anyhow::Result<()> {
+        let written = {
+            let mut shard = self.shard(device_id).write().unwrap();
+            let written = self.journal(|| Mutation::Excommunicate(DeviceExcommunication {
+                device_id: device_id.clone(),
+                record: Some(ExcommunicationRecord::from(record)),
+            }))?;
+            shard.excommunications.insert(device_id.clone(), record.clone());
+            written
+        };
+        written.wait().await
+    }
+
+    async fn get_excommunication (&self, device_id: &DeviceId) -> anyhow::Result<Option<Excommunication>> {
+        Ok(self.shard(device_id).read().unwrap().excommunications.get(device_id.as_slice()).cloned())
+    }
 
+    async fn write_purge_order (&self, order: &PurgeOrder) -> anyhow::Result<()> {
+        let written = {
+            let mut purge_orders = self.purge_orders.write().unwrap();
+            let written = self.journal(|| Mutation::WritePurgeOrder(PurgeOrderRecord::from(order)))?;
+            purge_orders.insert(order.id, order.clone());
+            written
+        };
+        written.wait().await
     }
 
-    async fn get_storage_info (&self, secret_key: &SecretKey) -> anyhow::Result<GetStorageInfoResult> {
-        let empty = vec![];
-        let locs = self.locations.get(secret_key).unwrap_or(&empty);
-        let since = locs
+    async fn list_purge_orders (&self, device_id: &DeviceId) -> anyhow::Result<Vec<PurgeOrder>> {
+        let mut orders: Vec<PurgeOrder> = self.purge_orders.read().unwrap()
+            .values()
+            .filter(|o| o.device_id == *device_id)
+            .cloned()
+            .collect();
+        orders.sort_by_key(|o| o.execute_at);
+        Ok(orders)
+    }
+
+    async fn list_due_purge_orders (&self, now: DateTime<Utc>) -> anyhow::Result<Vec<PurgeOrder>> {
+        let mut orders: Vec<PurgeOrder> = self.purge_orders.read().unwrap()
+            .values()
+            .filter(|o| o.execute_at <= now)
+            .cloned()
+            .collect();
+        orders.sort_by_key(|o| o.execute_at);
+        Ok(orders)
+    }
+
+    async fn delete_purge_order (&self, id: u64) -> anyhow::Result<bool> {
+        let (deleted, written) = {
+            let mut purge_orders = self.purge_orders.write().unwrap();
+            let written = self.journal(|| Mutation::DeletePurgeOrder(id))?;
+            (purge_orders.remove(&id).is_some(), written)
+        };
+        written.wait().await?;
+        Ok(deleted)
+    }
+
+    async fn write_emergency_purge (&self, request: &EmergencyPurgeRequest) -> anyhow::Result<()> {
+        let written = {
+            let mut emergency_purges = self.emergency_purges.write().unwrap();
+            let written = self.journal(|| Mutation::WriteEmergencyPurge(EmergencyPurgeRecord::from(request)))?;
+            emergency_purges.insert(request.id, request.clone());
+            written
+        };
+        written.wait().await
+    }
+
+    async fn get_emergency_purge (&self, id: u64) -> anyhow::Result<Option<EmergencyPurgeRequest>> {
+        Ok(self.emergency_purges.read().unwrap().get(&id).cloned())
+    }
+
+    async fn list_emergency_purges (&self, include_decided: bool) -> anyhow::Result<Vec<EmergencyPurgeRequest>> {
+        let mut requests: Vec<EmergencyPurgeRequest> = self.emergency_purges.read().unwrap()
+            .values()
+            .filter(|r| include_decided || r.decided.is_none())
+            .cloned()
+            .collect();
+        requests.sort_by_key(|r| r.requested);
+        Ok(requests)
+    }
+
+    async fn write_audit_record (&self, record: &AuditRecord) -> anyhow::Result<()> {
+        let written = {
+            let mut audit_log = self.audit_log.write().unwrap();
+            let written = self.journal(|| Mutation::WriteAuditRecord(AuditRecordRecord::from(record)))?;
+            audit_log.push(record.clone());
+            written
+        };
+        written.wait().await
+    }
+
+    async fn list_audit_records (&self, limit: u32) -> anyhow::Result<Vec<AuditRecord>> {
+        Ok(self.audit_log.read().unwrap()
             .iter()
-            .filter_map(|loc| Some(loc.update_time))
-            .reduce(|acc, loc| {
-                // We don't bother comparing nanoseconds. It's just not worth it.
-                if loc.timestamp() < acc.timestamp() { loc } else { acc }
-            });
-        let locs_len = locs.len();
-        Ok(GetStorageInfoResult {
-            locations_count: locs_len as u32,
-            since: since.map(|t| prost_types::Timestamp {
-                seconds: t.timestamp(),
-                nanos: t.nanosecond() as i32,
-            }),
-            bytes_storage_consumed: 0,
-            bytes_storage_limit: 0,
-            locations_limit: 100,
-        })
+            .rev()
+            .take(limit as usize)
+            .cloned()
+            .collect())
+    }
+
+    async fn write_registration_key (&self, key: &RegistrationKey) -> anyhow::Result<()> {
+        let written = {
+            let mut registration_keys = self.registration_keys.write().unwrap();
+            let written = self.journal(|| Mutation::WriteRegistrationKey(RegistrationKeyRecord::from(key)))?;
+            registration_keys.insert(key.digest.clone(), key.clone());
+            written
+        };
+        written.wait().await
     }
 
-}
\ No newline at end of file
+    async fn list_registration_keys (&self) -> anyhow::Result<Vec<RegistrationKey>> {
+        let mut keys: Vec<RegistrationKey> = self.registration_keys.read().unwrap().values().cloned().collect();
+        keys.sort_by_key(|k| k.created);
+        Ok(keys)
+    }
+
+    async fn delete_registration_key (&self, key: &RegistrationKeyDigest) -> anyhow::Result<bool> {
+        let (deleted, written) = {
+            let mut registration_keys = self.registration_keys.write().unwrap();
+            let written = self.journal(|| Mutation::DeleteRegistrationKey(key.to_vec()))?;
+            (registration_keys.remove(key).is_some(), written)
+        };
+        written.wait().await?;
+        Ok(deleted)
+    }
+
+    async fn use_registration_key (&self, key: &RegistrationKeyDigest, now: DateTime<Utc>) -> anyhow::Result<Option<RegistrationKey>> {
+        let (used, written) = {
+            let mut registration_keys = self.registration_keys.write().unwrap();
+            let written = self.journal(|| Mutation::UseRegistrationKey(RegistrationKeyUse {
+                key: key.to_vec(),
+                now: Some(chrono_to_grpc_timestamp(&now)),
+            }))?;
+            let used = match registration_keys.get_mut(key) {
+                Some(entry) if entry.uses_remaining > 0 && entry.not_after.map(|t| t > now).unwrap_or(true) => {
+                    let before = entry.clone();
+                    entry.uses_remaining -= 1;
+                    Some(before)
+                },
+                _ => None,
+            };
+            (used, written)
+        };
+        written.wait().await?;
+        Ok(used)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grpc::find_my_device::{IntroduceMyselfArg, Permissions};
+    use crate::storage::conformance::storage_conformance_tests;
+
+    storage_conformance_tests!(MemoryStorage::new());
+
+    #[tokio::test]
+    async fn journaled_storage_survives_reopening () {
+        let dir = std::env::temp_dir().join(format!("fmx-memory-{}", rand::random::<u64>()));
+        let device_id: DeviceId = vec![ 1; 32 ];
+        let token: TokenDigest = vec![ 2; 32 ];
+        let now = Utc::now();
+        let location = |i: i64| StoredLocation {
+            update_time: now + chrono::Duration::seconds(i),
+            ciphertext: vec![ i as u8; 40 ],
+        };
+        {
+            let storage = MemoryStorage::open(&dir).await.unwrap();
+            storage.write_registration_key(&RegistrationKey {
+                digest: vec![ 3 ],
+                uses_remaining: 2,
+                created: now,
+                not_after: None,
+                device_permissions: Permissions::default(),
+                note: String::new(),
+            }).await.unwrap();
+            storage.use_registration_key(&vec![ 3 ], now).await.unwrap();
+            storage.write_intro(&IntroInsertion {
+                device_id: &device_id,
+                token_digest: &token,
+                permissions: &Permissions { write_locations: true, ..Default::default() },
+                remote_addr: None,
+                registration_key_digest: &vec![],
+                arg: &IntroduceMyselfArg::default(),
+            }).await.unwrap();
+            storage.write_location(&device_id, &location(0)).await.unwrap();
+            storage.write_location(&device_id, &location(1)).await.unwrap();
+            storage.take_snapshot().await.unwrap();
+
+            // Changes after the snapshot are replayed from the journal.
+            storage.write_location(&device_id, &location(2)).await.unwrap();
+            storage.delete_locations(&device_id, &[ location(0).update_time ]).await.unwrap();
+            storage.write_audit_record(&AuditRecord {
+                time: now,
+                action: String::from("test"),
+                device_id: None,
+                detail: String::new(),
+            }).await.unwrap();
+        }
+        let storage = MemoryStorage::open(&dir).await.unwrap();
+        let filter = LocationsFilter { limit: 10, since: None, until: None };
+        let locs = storage.list_locations(&device_id, &filter).await.unwrap();
+        assert_eq!(locs.iter().map(|l| l.update_time).collect::<Vec<_>>(), vec![ location(1).update_time, location(2).update_time ]);
+        let entry = storage.get_token_info(&token).await.unwrap().unwrap();
+        assert!(entry.permissions.write_locations);
+        assert_eq!(storage.list_tokens(&device_id).await.unwrap().len(), 1);
+        assert!(storage.get_intro(&device_id).await.unwrap().is_some());
+        assert_eq!(storage.list_registration_keys().await.unwrap()[0].uses_remaining, 1);
+        assert_eq!(storage.list_audit_records(10).await.unwrap().len(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn devices_are_written_concurrently () {
+        let storage = std::sync::Arc::new(MemoryStorage::new());
+        let now = Utc::now();
+        let tasks: Vec<_> = (0..32u8).map(|d| {
+            let storage = storage.clone();
+            tokio::spawn(async move {
+                let device_id: DeviceId = vec![ d; 32 ];
+                for i in 0..50 {
+                    storage.write_location(&device_id, &StoredLocation {
+                        update_time: now + chrono::Duration::seconds(i),
+                        ciphertext: vec![ d; 8 ],
+                    }).await.unwrap();
+                }
+                // Only the first key inserted for a device is kept.
+                storage.insert_device_key(&device_id, &[ d, 1 ]).await.unwrap()
+            })
+        }).collect();
+        for (d, task) in tasks.into_iter().enumerate() {
+            assert_eq!(task.await.unwrap(), vec![ d as u8, 1 ]);
+            let device_id: DeviceId = vec![ d as u8; 32 ];
+            assert_eq!(storage.get_storage_usage(&device_id).await.unwrap().locations, 50);
+            assert_eq!(storage.insert_device_key(&device_id, &[ 0 ]).await.unwrap(), vec![ d as u8, 1 ]);
+        }
+    }
+
+}
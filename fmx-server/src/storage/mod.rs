@@ -1,25 +1,42 @@
+#[cfg(test)]
+pub mod conformance;
+pub mod journal;
 pub mod memory;
+pub mod records;
+pub mod redb;
+pub mod sqlite;
 use std::net::SocketAddr;
 
 use crate::grpc::find_my_device::{
     IntroduceMyselfArg,
-    RevokeTokenArg,
-    ListLocationsResult,
-    GetStorageInfoResult,
     NearbyWifiNetwork,
     NearbyBluetoothDevice,
     Location,
     Velocity,
     Permissions,
+    LocationSnapshot,
 };
+use crate::utils::chrono_to_grpc_timestamp;
 use chrono::prelude::*;
 
 pub type Token = Vec<u8>;
 pub type SecretKey = Vec<u8>;
 
+/// A keyed digest of a token (see `Digester`). Storage only ever sees tokens
+/// in this form.
+pub type TokenDigest = Vec<u8>;
+
+/// A keyed digest of a registration key, which is likewise the only form
+/// storage sees them in.
+pub type RegistrationKeyDigest = Vec<u8>;
+
+/// A keyed digest of a device's secret key (see `Digester`), which identifies
+/// the device in storage in place of the secret key itself.
+pub type DeviceId = Vec<u8>;
+
 #[derive(Debug, Clone)]
 pub struct TokenEntry {
-    pub secret_key: SecretKey,
+    pub device_id: DeviceId,
     pub permissions: Permissions,
     pub not_before: DateTime<Utc>,
     pub not_after: Option<DateTime<Utc>>,
@@ -35,17 +52,147 @@ pub struct LocationInsertion {
     pub notes: String,
     pub nearby_wifi_network: Vec<NearbyWifiNetwork>,
     pub nearby_bluetooth_devices: Vec<NearbyBluetoothDevice>,
+    pub encrypted_snapshot: Vec<u8>,
     pub remote_addr: Option<SocketAddr>,
 }
 
+impl LocationInsertion {
+
+    pub fn to_snapshot (&self) -> LocationSnapshot {
+        LocationSnapshot{
+            emergency: self.emergency,
+            update_time: Some(chrono_to_grpc_timestamp(&self.update_time)),
+            expected_next_update_time: self.expected_next_update_time
+                .as_ref()
+                .map(chrono_to_grpc_timestamp),
+            nearby_bluetooth_devices: self.nearby_bluetooth_devices.to_owned(),
+            nearby_wifi_network: self.nearby_wifi_network.to_owned(),
+            location: self.location.to_owned(),
+            notes: self.notes.to_owned(),
+            velocity: self.velocity.to_owned(),
+            encrypted_snapshot: self.encrypted_snapshot.to_owned(),
+        }
+    }
+
+}
+
+/// A location as it is kept in storage: everything but its time is encrypted
+/// with the device's data key (see `Vault`).
+#[derive(Debug, Clone)]
+pub struct StoredLocation {
+    pub update_time: DateTime<Utc>,
+    pub ciphertext: Vec<u8>,
+}
+
+impl StoredLocation {
+
+    /// The number of bytes this counts for against quotas: the ciphertext,
+    /// and the time, as seconds and nanoseconds.
+    pub fn size (&self) -> u64 {
+        (self.ciphertext.len() + 12) as u64
+    }
+
+}
+
+/// How much storage a device's location history takes up.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StorageUsage {
+    pub locations: u64,
+
+    /// The total `StoredLocation::size` of the locations.
+    pub bytes: u64,
+    pub oldest: Option<DateTime<Utc>>,
+}
+
 #[derive(Debug, Clone)]
 pub struct IntroInsertion <'a> {
-    pub secret_key: &'a SecretKey,
-    pub token: &'a Token,
+    pub device_id: &'a DeviceId,
+    pub token_digest: &'a TokenDigest,
+    pub permissions: &'a Permissions,
     pub remote_addr: Option<SocketAddr>,
+    /// Empty if the device registered without a key.
+    pub registration_key_digest: &'a RegistrationKeyDigest,
     pub arg: &'a IntroduceMyselfArg,
 }
 
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
+pub struct Introduction {
+    pub remote_addr: Option<SocketAddr>,
+    pub registration_key_digest: RegistrationKeyDigest,
+    pub remote_wipe_enabled: bool,
+    pub can_read_nearby_devices: bool,
+}
+
+/// A request from the owner of a device for the device to wipe itself.
+#[derive(Debug, Clone)]
+pub struct WipeOrder {
+    pub requested: DateTime<Utc>,
+
+    /// When the order was first handed to the device.
+    pub delivered: Option<DateTime<Utc>>,
+
+    /// When the device confirmed that it is wiping itself.
+    pub acknowledged: Option<DateTime<Utc>>,
+}
+
+/// A record of a device being cut off from the server.
+#[derive(Debug, Clone)]
+pub struct Excommunication {
+    pub time: DateTime<Utc>,
+    pub reason: String,
+    pub by_administrator: bool,
+}
+
+/// A deletion of location history that will be carried out at `execute_at`,
+/// unless it is cancelled before then.
+#[derive(Debug, Clone)]
+pub struct PurgeOrder {
+    pub id: u64,
+    pub device_id: DeviceId,
+    pub requested: DateTime<Utc>,
+
+    /// If set, only locations from this time onwards are purged.
+    pub since: Option<DateTime<Utc>>,
+    pub execute_at: DateTime<Utc>,
+}
+
+/// A request for an administrator to carry out a purge immediately.
+#[derive(Debug, Clone)]
+pub struct EmergencyPurgeRequest {
+    pub id: u64,
+    pub device_id: DeviceId,
+
+    /// The ordinary, delayed purge that was scheduled alongside this request.
+    pub purge_order_id: u64,
+    pub since: Option<DateTime<Utc>>,
+    pub requested: DateTime<Utc>,
+    pub decided: Option<DateTime<Utc>>,
+    pub approved: bool,
+}
+
+/// A record of an action taken by an administrator.
+#[derive(Debug, Clone)]
+pub struct AuditRecord {
+    pub time: DateTime<Utc>,
+    pub action: String,
+    pub device_id: Option<DeviceId>,
+    pub detail: String,
+}
+
+/// A key that permits devices to introduce themselves while registration is
+/// closed.
+#[derive(Debug, Clone)]
+pub struct RegistrationKey {
+    pub digest: RegistrationKeyDigest,
+    pub uses_remaining: u32,
+    pub created: DateTime<Utc>,
+    pub not_after: Option<DateTime<Utc>>,
+    pub device_permissions: Permissions,
+    pub note: String,
+}
+
+#[derive(Debug, Clone)]
 pub struct LocationsFilter {
     pub limit: u32,
     pub since: Option<DateTime<Utc>>,
@@ -53,23 +200,104 @@ pub struct LocationsFilter {
 }
 
 #[tonic::async_trait]
-pub trait Storage {
+pub trait Storage: Send + Sync {
+
+    async fn get_token_info (&self, token: &TokenDigest) -> anyhow::Result<Option<TokenEntry>>;
+
+    async fn write_location (&self, device_id: &DeviceId, location: &StoredLocation) -> anyhow::Result<()>;
+
+    async fn write_intro <'a> (&self, arg: &'a IntroInsertion) -> anyhow::Result<()>;
+
+    async fn write_token (&self, token: &TokenDigest, arg: &TokenEntry) -> anyhow::Result<()>;
+
+    /// Revokes `token`, if it belongs to the device identified by `device_id`,
+    /// or every token for that device, if `token` is `None`. Returns the number
+    /// of tokens revoked.
+    async fn revoke_token (&self, device_id: &DeviceId, token: Option<&TokenDigest>) -> anyhow::Result<u32>;
+
+    async fn list_tokens (&self, device_id: &DeviceId) -> anyhow::Result<Vec<(TokenDigest, TokenEntry)>>;
+
+    /// Deletes the locations recorded for a device from `since` onwards, or
+    /// all of them, if `since` is `None`. Deleting all of them also destroys
+    /// the device's data key, so that any copies of them that remain
+    /// elsewhere, such as in backups, can no longer be read.
+    async fn purge_location (&self, device_id: &DeviceId, since: Option<DateTime<Utc>>) -> anyhow::Result<()>;
+
+    /// Lists the locations recorded for a device, oldest first.
+    async fn list_locations (&self, device_id: &DeviceId, filter: &LocationsFilter) -> anyhow::Result<Vec<StoredLocation>>;
+
+    /// Deletes the locations recorded for a device at exactly the times given,
+    /// returning how many were deleted.
+    async fn delete_locations (&self, device_id: &DeviceId, update_times: &[DateTime<Utc>]) -> anyhow::Result<u64>;
+
+    /// Deletes the locations recorded for any device before `before`,
+    /// returning how many were deleted.
+    async fn delete_locations_before (&self, before: DateTime<Utc>) -> anyhow::Result<u64>;
+
+    /// Tells how much storage a device's locations take up. This is called
+    /// for every location submitted, so it should not have to read through
+    /// the device's whole history.
+    async fn get_storage_usage (&self, device_id: &DeviceId) -> anyhow::Result<StorageUsage>;
+
+    async fn get_intro (&self, device_id: &DeviceId) -> anyhow::Result<Option<Introduction>>;
+
+    /// Stores an introduction as it was read back from another storage,
+    /// without giving the device a token.
+    async fn restore_intro (&self, device_id: &DeviceId, intro: &Introduction) -> anyhow::Result<()>;
+
+    /// Lists every device that anything is stored for, in order of ID.
+    async fn list_devices (&self) -> anyhow::Result<Vec<DeviceId>>;
+
+    /// Stores a device's data key, wrapped by the master key, unless the
+    /// device already has one. Returns the wrapped key that the device has
+    /// afterwards.
+    async fn insert_device_key (&self, device_id: &DeviceId, wrapped_key: &[u8]) -> anyhow::Result<Vec<u8>>;
+
+    async fn get_device_key (&self, device_id: &DeviceId) -> anyhow::Result<Option<Vec<u8>>>;
+
+    async fn write_wipe_order (&self, device_id: &DeviceId, order: &WipeOrder) -> anyhow::Result<()>;
+
+    async fn get_wipe_order (&self, device_id: &DeviceId) -> anyhow::Result<Option<WipeOrder>>;
+
+    /// Records the excommunication of a device. Its tokens keep their
+    /// permissions, so that the `Authorizer` can still tell which of them may
+    /// be told of it, but none of them may be used to write.
+    async fn excommunicate (&self, device_id: &DeviceId, record: &Excommunication) -> anyhow::Result<()>;
+
+    async fn get_excommunication (&self, device_id: &DeviceId) -> anyhow::Result<Option<Excommunication>>;
+
+    async fn write_purge_order (&self, order: &PurgeOrder) -> anyhow::Result<()>;
+
+    async fn list_purge_orders (&self, device_id: &DeviceId) -> anyhow::Result<Vec<PurgeOrder>>;
+
+    /// Lists the purge orders, for any device, that are due at `now`.
+    async fn list_due_purge_orders (&self, now: DateTime<Utc>) -> anyhow::Result<Vec<PurgeOrder>>;
+
+    /// Deletes a purge order, returning `true` if it existed.
+    async fn delete_purge_order (&self, id: u64) -> anyhow::Result<bool>;
 
-    async fn get_token_info (&self, arg: &Token) -> anyhow::Result<Option<TokenEntry>>;
+    async fn write_emergency_purge (&self, request: &EmergencyPurgeRequest) -> anyhow::Result<()>;
 
-    async fn write_location (&mut self, secret_key: &SecretKey, arg: &LocationInsertion) -> anyhow::Result<()>;
+    async fn get_emergency_purge (&self, id: u64) -> anyhow::Result<Option<EmergencyPurgeRequest>>;
 
-    async fn write_intro <'a> (&mut self, arg: &'a IntroInsertion) -> anyhow::Result<()>;
+    /// Lists emergency purge requests, oldest first.
+    async fn list_emergency_purges (&self, include_decided: bool) -> anyhow::Result<Vec<EmergencyPurgeRequest>>;
 
-    async fn write_token (&mut self, secret_key: &SecretKey, arg: &TokenEntry) -> anyhow::Result<()>;
+    async fn write_audit_record (&self, record: &AuditRecord) -> anyhow::Result<()>;
 
-    async fn revoke_token (&mut self, arg: &RevokeTokenArg) -> anyhow::Result<()>;
+    /// Lists up to `limit` audit records, most recent first.
+    async fn list_audit_records (&self, limit: u32) -> anyhow::Result<Vec<AuditRecord>>;
 
-    async fn list_tokens (&self, secret_key: &SecretKey) -> anyhow::Result<Vec<TokenEntry>>;
+    async fn write_registration_key (&self, key: &RegistrationKey) -> anyhow::Result<()>;
 
-    async fn purge_location (&mut self, secret_key: &SecretKey) -> anyhow::Result<()>;
+    /// Lists every registration key, including used up and expired ones.
+    async fn list_registration_keys (&self) -> anyhow::Result<Vec<RegistrationKey>>;
 
-    async fn list_locations (&self, secret_key: &SecretKey, filter: &LocationsFilter) -> anyhow::Result<ListLocationsResult>;
+    /// Deletes a registration key, returning `true` if it existed.
+    async fn delete_registration_key (&self, key: &RegistrationKeyDigest) -> anyhow::Result<bool>;
 
-    async fn get_storage_info (&self, secret_key: &SecretKey) -> anyhow::Result<GetStorageInfoResult>;
+    /// Uses up one use of a registration key, if it is valid at `now`, and
+    /// returns the key as it was before. Returns `None` if the key does not
+    /// exist, has expired, or has no uses left.
+    async fn use_registration_key (&self, key: &RegistrationKeyDigest, now: DateTime<Utc>) -> anyhow::Result<Option<RegistrationKey>>;
 }
\ No newline at end of file
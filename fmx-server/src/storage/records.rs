@@ -0,0 +1,390 @@
+//! Protocol Buffers encodings of what is kept in storage, for backends that
+//! keep records as bytes.
+use crate::grpc::find_my_device::Permissions;
+use crate::storage::{
+    StoredLocation,
+    TokenEntry,
+    Introduction,
+    WipeOrder,
+    Excommunication,
+    PurgeOrder,
+    EmergencyPurgeRequest,
+    AuditRecord,
+    RegistrationKey,
+};
+use crate::utils::{chrono_to_grpc_timestamp, grpc_timestamp_to_chrono};
+use chrono::prelude::*;
+use prost::Message;
+use prost_types::Timestamp;
+
+pub fn time (t: Option<Timestamp>) -> anyhow::Result<DateTime<Utc>> {
+    t.as_ref()
+        .and_then(grpc_timestamp_to_chrono)
+        .ok_or_else(|| anyhow::anyhow!("Stored record lacks a valid time."))
+}
+
+pub fn optional_time (t: Option<Timestamp>) -> Option<DateTime<Utc>> {
+    t.as_ref().and_then(grpc_timestamp_to_chrono)
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct LocationRecord {
+    #[prost(message, optional, tag = "1")]
+    pub update_time: Option<Timestamp>,
+    #[prost(bytes = "vec", tag = "2")]
+    pub ciphertext: Vec<u8>,
+}
+
+impl From<&StoredLocation> for LocationRecord {
+
+    fn from (location: &StoredLocation) -> Self {
+        LocationRecord {
+            update_time: Some(chrono_to_grpc_timestamp(&location.update_time)),
+            ciphertext: location.ciphertext.clone(),
+        }
+    }
+
+}
+
+impl TryFrom<LocationRecord> for StoredLocation {
+    type Error = anyhow::Error;
+
+    fn try_from (record: LocationRecord) -> anyhow::Result<Self> {
+        Ok(StoredLocation {
+            update_time: time(record.update_time)?,
+            ciphertext: record.ciphertext,
+        })
+    }
+
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct TokenRecord {
+    #[prost(bytes = "vec", tag = "1")]
+    pub device_id: Vec<u8>,
+    #[prost(message, optional, tag = "2")]
+    pub permissions: Option<Permissions>,
+    #[prost(message, optional, tag = "3")]
+    pub not_before: Option<Timestamp>,
+    #[prost(message, optional, tag = "4")]
+    pub not_after: Option<Timestamp>,
+}
+
+impl From<&TokenEntry> for TokenRecord {
+
+    fn from (entry: &TokenEntry) -> Self {
+        TokenRecord {
+            device_id: entry.device_id.clone(),
+            permissions: Some(entry.permissions.clone()),
+            not_before: Some(chrono_to_grpc_timestamp(&entry.not_before)),
+            not_after: entry.not_after.as_ref().map(chrono_to_grpc_timestamp),
+        }
+    }
+
+}
+
+impl TryFrom<TokenRecord> for TokenEntry {
+    type Error = anyhow::Error;
+
+    fn try_from (record: TokenRecord) -> anyhow::Result<Self> {
+        Ok(TokenEntry {
+            device_id: record.device_id,
+            permissions: record.permissions.unwrap_or_default(),
+            not_before: time(record.not_before)?,
+            not_after: optional_time(record.not_after),
+        })
+    }
+
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct IntroRecord {
+    #[prost(string, tag = "1")]
+    pub remote_addr: String,
+    #[prost(bytes = "vec", tag = "2")]
+    pub registration_key: Vec<u8>,
+    #[prost(bool, tag = "3")]
+    pub remote_wipe_enabled: bool,
+    #[prost(bool, tag = "4")]
+    pub can_read_nearby_devices: bool,
+}
+
+impl From<&Introduction> for IntroRecord {
+
+    fn from (intro: &Introduction) -> Self {
+        IntroRecord {
+            remote_addr: intro.remote_addr.map(|a| a.to_string()).unwrap_or_default(),
+            registration_key: intro.registration_key_digest.clone(),
+            remote_wipe_enabled: intro.remote_wipe_enabled,
+            can_read_nearby_devices: intro.can_read_nearby_devices,
+        }
+    }
+
+}
+
+impl From<IntroRecord> for Introduction {
+
+    fn from (record: IntroRecord) -> Self {
+        Introduction {
+            remote_addr: record.remote_addr.parse().ok(),
+            registration_key_digest: record.registration_key,
+            remote_wipe_enabled: record.remote_wipe_enabled,
+            can_read_nearby_devices: record.can_read_nearby_devices,
+        }
+    }
+
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct WipeOrderRecord {
+    #[prost(message, optional, tag = "1")]
+    pub requested: Option<Timestamp>,
+    #[prost(message, optional, tag = "2")]
+    pub delivered: Option<Timestamp>,
+    #[prost(message, optional, tag = "3")]
+    pub acknowledged: Option<Timestamp>,
+}
+
+impl From<&WipeOrder> for WipeOrderRecord {
+
+    fn from (order: &WipeOrder) -> Self {
+        WipeOrderRecord {
+            requested: Some(chrono_to_grpc_timestamp(&order.requested)),
+            delivered: order.delivered.as_ref().map(chrono_to_grpc_timestamp),
+            acknowledged: order.acknowledged.as_ref().map(chrono_to_grpc_timestamp),
+        }
+    }
+
+}
+
+impl TryFrom<WipeOrderRecord> for WipeOrder {
+    type Error = anyhow::Error;
+
+    fn try_from (record: WipeOrderRecord) -> anyhow::Result<Self> {
+        Ok(WipeOrder {
+            requested: time(record.requested)?,
+            delivered: optional_time(record.delivered),
+            acknowledged: optional_time(record.acknowledged),
+        })
+    }
+
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ExcommunicationRecord {
+    #[prost(message, optional, tag = "1")]
+    pub time: Option<Timestamp>,
+    #[prost(string, tag = "2")]
+    pub reason: String,
+    #[prost(bool, tag = "3")]
+    pub by_administrator: bool,
+}
+
+impl From<&Excommunication> for ExcommunicationRecord {
+
+    fn from (record: &Excommunication) -> Self {
+        ExcommunicationRecord {
+            time: Some(chrono_to_grpc_timestamp(&record.time)),
+            reason: record.reason.clone(),
+            by_administrator: record.by_administrator,
+        }
+    }
+
+}
+
+impl TryFrom<ExcommunicationRecord> for Excommunication {
+    type Error = anyhow::Error;
+
+    fn try_from (record: ExcommunicationRecord) -> anyhow::Result<Self> {
+        Ok(Excommunication {
+            time: time(record.time)?,
+            reason: record.reason,
+            by_administrator: record.by_administrator,
+        })
+    }
+
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct PurgeOrderRecord {
+    #[prost(uint64, tag = "1")]
+    pub id: u64,
+    #[prost(bytes = "vec", tag = "2")]
+    pub device_id: Vec<u8>,
+    #[prost(message, optional, tag = "3")]
+    pub requested: Option<Timestamp>,
+    #[prost(message, optional, tag = "4")]
+    pub since: Option<Timestamp>,
+    #[prost(message, optional, tag = "5")]
+    pub execute_at: Option<Timestamp>,
+}
+
+impl From<&PurgeOrder> for PurgeOrderRecord {
+
+    fn from (order: &PurgeOrder) -> Self {
+        PurgeOrderRecord {
+            id: order.id,
+            device_id: order.device_id.clone(),
+            requested: Some(chrono_to_grpc_timestamp(&order.requested)),
+            since: order.since.as_ref().map(chrono_to_grpc_timestamp),
+            execute_at: Some(chrono_to_grpc_timestamp(&order.execute_at)),
+        }
+    }
+
+}
+
+impl TryFrom<PurgeOrderRecord> for PurgeOrder {
+    type Error = anyhow::Error;
+
+    fn try_from (record: PurgeOrderRecord) -> anyhow::Result<Self> {
+        Ok(PurgeOrder {
+            id: record.id,
+            device_id: record.device_id,
+            requested: time(record.requested)?,
+            since: optional_time(record.since),
+            execute_at: time(record.execute_at)?,
+        })
+    }
+
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct EmergencyPurgeRecord {
+    #[prost(uint64, tag = "1")]
+    pub id: u64,
+    #[prost(bytes = "vec", tag = "2")]
+    pub device_id: Vec<u8>,
+    #[prost(uint64, tag = "3")]
+    pub purge_order_id: u64,
+    #[prost(message, optional, tag = "4")]
+    pub since: Option<Timestamp>,
+    #[prost(message, optional, tag = "5")]
+    pub requested: Option<Timestamp>,
+    #[prost(message, optional, tag = "6")]
+    pub decided: Option<Timestamp>,
+    #[prost(bool, tag = "7")]
+    pub approved: bool,
+}
+
+impl From<&EmergencyPurgeRequest> for EmergencyPurgeRecord {
+
+    fn from (request: &EmergencyPurgeRequest) -> Self {
+        EmergencyPurgeRecord {
+            id: request.id,
+            device_id: request.device_id.clone(),
+            purge_order_id: request.purge_order_id,
+            since: request.since.as_ref().map(chrono_to_grpc_timestamp),
+            requested: Some(chrono_to_grpc_timestamp(&request.requested)),
+            decided: request.decided.as_ref().map(chrono_to_grpc_timestamp),
+            approved: request.approved,
+        }
+    }
+
+}
+
+impl TryFrom<EmergencyPurgeRecord> for EmergencyPurgeRequest {
+    type Error = anyhow::Error;
+
+    fn try_from (record: EmergencyPurgeRecord) -> anyhow::Result<Self> {
+        Ok(EmergencyPurgeRequest {
+            id: record.id,
+            device_id: record.device_id,
+            purge_order_id: record.purge_order_id,
+            since: optional_time(record.since),
+            requested: time(record.requested)?,
+            decided: optional_time(record.decided),
+            approved: record.approved,
+        })
+    }
+
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct AuditRecordRecord {
+    #[prost(message, optional, tag = "1")]
+    pub time: Option<Timestamp>,
+    #[prost(string, tag = "2")]
+    pub action: String,
+    #[prost(bytes = "vec", optional, tag = "3")]
+    pub device_id: Option<Vec<u8>>,
+    #[prost(string, tag = "4")]
+    pub detail: String,
+}
+
+impl From<&AuditRecord> for AuditRecordRecord {
+
+    fn from (record: &AuditRecord) -> Self {
+        AuditRecordRecord {
+            time: Some(chrono_to_grpc_timestamp(&record.time)),
+            action: record.action.clone(),
+            device_id: record.device_id.clone(),
+            detail: record.detail.clone(),
+        }
+    }
+
+}
+
+impl TryFrom<AuditRecordRecord> for AuditRecord {
+    type Error = anyhow::Error;
+
+    fn try_from (record: AuditRecordRecord) -> anyhow::Result<Self> {
+        Ok(AuditRecord {
+            time: time(record.time)?,
+            action: record.action,
+            device_id: record.device_id,
+            detail: record.detail,
+        })
+    }
+
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct RegistrationKeyRecord {
+    #[prost(bytes = "vec", tag = "1")]
+    pub key: Vec<u8>,
+    #[prost(uint32, tag = "2")]
+    pub uses_remaining: u32,
+    #[prost(message, optional, tag = "3")]
+    pub created: Option<Timestamp>,
+    #[prost(message, optional, tag = "4")]
+    pub not_after: Option<Timestamp>,
+    #[prost(message, optional, tag = "5")]
+    pub device_permissions: Option<Permissions>,
+    #[prost(string, tag = "6")]
+    pub note: String,
+}
+
+impl From<&RegistrationKey> for RegistrationKeyRecord {
+
+    fn from (key: &RegistrationKey) -> Self {
+        RegistrationKeyRecord {
+            key: key.digest.clone(),
+            uses_remaining: key.uses_remaining,
+            created: Some(chrono_to_grpc_timestamp(&key.created)),
+            not_after: key.not_after.as_ref().map(chrono_to_grpc_timestamp),
+            device_permissions: Some(key.device_permissions.clone()),
+            note: key.note.clone(),
+        }
+    }
+
+}
+
+impl TryFrom<RegistrationKeyRecord> for RegistrationKey {
+    type Error = anyhow::Error;
+
+    fn try_from (record: RegistrationKeyRecord) -> anyhow::Result<Self> {
+        Ok(RegistrationKey {
+            digest: record.key,
+            uses_remaining: record.uses_remaining,
+            created: time(record.created)?,
+            not_after: optional_time(record.not_after),
+            device_permissions: record.device_permissions.unwrap_or_default(),
+            note: record.note,
+        })
+    }
+
+}
+
+pub fn decode <R: Message + Default, T: TryFrom<R, Error = anyhow::Error>> (bytes: &[u8]) -> anyhow::Result<T> {
+    T::try_from(R::decode(bytes)?)
+}
@@ -0,0 +1,841 @@
+use crate::digest::constant_time_eq;
+use crate::storage::{
+    Storage,
+    DeviceId,
+    TokenDigest,
+    StoredLocation,
+    IntroInsertion,
+    TokenEntry,
+    LocationsFilter,
+    Introduction,
+    WipeOrder,
+    Excommunication,
+    PurgeOrder,
+    EmergencyPurgeRequest,
+    AuditRecord,
+    RegistrationKey,
+    RegistrationKeyDigest,
+    StorageUsage,
+};
+use crate::storage::records::{
+    decode,
+    TokenRecord,
+    IntroRecord,
+    WipeOrderRecord,
+    ExcommunicationRecord,
+    PurgeOrderRecord,
+    EmergencyPurgeRecord,
+    AuditRecordRecord,
+    RegistrationKeyRecord,
+};
+use ::redb::{
+    Database,
+    MultimapTableDefinition,
+    ReadableMultimapTable,
+    ReadableTable,
+    TableDefinition,
+};
+use chrono::prelude::*;
+use prost::Message;
+use std::collections::{BTreeSet, HashMap};
+use std::path::Path;
+use std::sync::Arc;
+
+/// The version of the layout of the tables below. This must be incremented,
+/// and a migration added to `RedbStorage::open`, whenever it changes.
+pub const SCHEMA_VERSION: u64 = 2;
+
+const META: TableDefinition<&str, u64> = TableDefinition::new("meta");
+
+/// Locations, keyed by `location_key`, so that each device's locations are
+/// together and in order of time.
+const LOCATIONS: TableDefinition<&[u8], &[u8]> = TableDefinition::new("locations");
+
+/// The number of locations each device has and their total size, keyed by
+/// device ID, so that storage usage can be told without reading them all. It
+/// is changed in the same transaction as the locations.
+const USAGE: TableDefinition<&[u8], (u64, u64)> = TableDefinition::new("usage");
+const DEVICE_KEYS: TableDefinition<&[u8], &[u8]> = TableDefinition::new("device_keys");
+const INTROS: TableDefinition<&[u8], &[u8]> = TableDefinition::new("intros");
+const TOKENS: TableDefinition<&[u8], &[u8]> = TableDefinition::new("tokens");
+
+/// The digests of each device's tokens, keyed by device ID.
+const TOKENS_BY_DEVICE: MultimapTableDefinition<&[u8], &[u8]> = MultimapTableDefinition::new("tokens_by_device");
+const WIPE_ORDERS: TableDefinition<&[u8], &[u8]> = TableDefinition::new("wipe_orders");
+const EXCOMMUNICATIONS: TableDefinition<&[u8], &[u8]> = TableDefinition::new("excommunications");
+const PURGE_ORDERS: TableDefinition<u64, &[u8]> = TableDefinition::new("purge_orders");
+const EMERGENCY_PURGES: TableDefinition<u64, &[u8]> = TableDefinition::new("emergency_purges");
+
+/// Audit records, keyed by a sequence number.
+const AUDIT_LOG: TableDefinition<u64, &[u8]> = TableDefinition::new("audit_log");
+const REGISTRATION_KEYS: TableDefinition<&[u8], &[u8]> = TableDefinition::new("registration_keys");
+
+const TIME_LENGTH: usize = 12;
+
+/// Encodes a time so that the encodings sort in the same order as the times.
+fn encode_time (time: &DateTime<Utc>) -> [u8; TIME_LENGTH] {
+    let mut encoded = [ 0; TIME_LENGTH ];
+    encoded[..8].copy_from_slice(&((time.timestamp() as u64) ^ (1 << 63)).to_be_bytes());
+    encoded[8..].copy_from_slice(&time.timestamp_subsec_nanos().to_be_bytes());
+    encoded
+}
+
+fn decode_time (encoded: &[u8]) -> anyhow::Result<DateTime<Utc>> {
+    let (secs, nanos) = encoded.split_at(8);
+    let secs = (u64::from_be_bytes(secs.try_into()?) ^ (1 << 63)) as i64;
+    let nanos = u32::from_be_bytes(nanos.try_into()?);
+    Utc.timestamp_opt(secs, nanos)
+        .single()
+        .ok_or_else(|| anyhow::anyhow!("Stored time is out of range."))
+}
+
+/// The key of a location: the length of the device ID, the device ID, and
+/// then the time.
+fn location_key (device_id: &DeviceId, time: &DateTime<Utc>) -> anyhow::Result<Vec<u8>> {
+    let len: u8 = device_id.len().try_into()
+        .map_err(|_| anyhow::anyhow!("Device IDs may not be longer than 255 bytes."))?;
+    let mut key = Vec::with_capacity(1 + device_id.len() + TIME_LENGTH);
+    key.push(len);
+    key.extend_from_slice(device_id);
+    key.extend_from_slice(&encode_time(time));
+    Ok(key)
+}
+
+/// The `StoredLocation::size` of a location with this ciphertext.
+fn location_size (ciphertext: &[u8]) -> u64 {
+    (ciphertext.len() + TIME_LENGTH) as u64
+}
+
+/// The device ID that a location key starts with.
+fn device_of_key (key: &[u8]) -> anyhow::Result<&[u8]> {
+    key.get(1..1 + *key.first().unwrap_or(&0) as usize)
+        .ok_or_else(|| anyhow::anyhow!("Location key is truncated."))
+}
+
+/// Changes a device's entry in `USAGE` by `added` and `removed`, each a
+/// number of locations and their total size.
+fn change_usage (
+    usage: &mut ::redb::Table<&[u8], (u64, u64)>,
+    device_id: &[u8],
+    added: (u64, u64),
+    removed: (u64, u64),
+) -> anyhow::Result<()> {
+    let (locations, bytes) = usage.get(device_id)?.map(|v| v.value()).unwrap_or_default();
+    let locations = (locations + added.0).saturating_sub(removed.0);
+    let bytes = (bytes + added.1).saturating_sub(removed.1);
+    if locations == 0 {
+        usage.remove(device_id)?;
+    } else {
+        usage.insert(device_id, (locations, bytes))?;
+    }
+    Ok(())
+}
+
+/// Fills `USAGE` in from the locations, for databases from before it existed.
+fn count_usage (txn: &::redb::WriteTransaction) -> anyhow::Result<()> {
+    let locations = txn.open_table(LOCATIONS)?;
+    let mut usage = txn.open_table(USAGE)?;
+    for entry in locations.iter()? {
+        let (key, value) = entry?;
+        change_usage(&mut usage, device_of_key(key.value())?, (1, location_size(value.value())), (0, 0))?;
+    }
+    Ok(())
+}
+
+fn location_from_entry (key: &[u8], ciphertext: &[u8]) -> anyhow::Result<StoredLocation> {
+    if key.len() < TIME_LENGTH {
+        anyhow::bail!("Location key is truncated.");
+    }
+    Ok(StoredLocation {
+        update_time: decode_time(&key[key.len() - TIME_LENGTH..])?,
+        ciphertext: ciphertext.to_vec(),
+    })
+}
+
+/// The range of keys of a device's locations from `since` to `until`.
+fn location_range (
+    device_id: &DeviceId,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+    Ok((
+        location_key(device_id, &since.unwrap_or(DateTime::<Utc>::MIN_UTC))?,
+        location_key(device_id, &until.unwrap_or(DateTime::<Utc>::MAX_UTC))?,
+    ))
+}
+
+/// Storage in a single redb database file. Every method that writes does so
+/// in one transaction, which is durable once the method returns. Transactions
+/// are run on threads where they may block, since committing waits for the
+/// disk.
+pub struct RedbStorage {
+    db: Arc<Database>,
+}
+
+impl RedbStorage {
+
+    /// Opens the database at `path`, creating it if it does not exist.
+    pub fn open (path: &Path) -> anyhow::Result<Self> {
+        let db = Database::create(path)
+            .map_err(|e| anyhow::anyhow!("Could not open {}: {}", path.display(), e))?;
+        RedbStorage::from_database(db, &path.display().to_string())
+    }
+
+    /// Checks the schema version of `db`, which is called `name` in errors,
+    /// and creates its tables.
+    fn from_database (db: Database, name: &str) -> anyhow::Result<Self> {
+        let txn = db.begin_write()?;
+        {
+            let mut meta = txn.open_table(META)?;
+            let version = meta.get("schema_version")?.map(|v| v.value());
+            match version {
+                None => {
+                    meta.insert("schema_version", SCHEMA_VERSION)?;
+                },
+                Some(1) => {
+                    count_usage(&txn)?;
+                    meta.insert("schema_version", SCHEMA_VERSION)?;
+                },
+                Some(SCHEMA_VERSION) => {},
+                Some(v) => anyhow::bail!(
+                    "{} has schema version {}, but this server only supports version {}.",
+                    name,
+                    v,
+                    SCHEMA_VERSION,
+                ),
+            };
+            // Tables must exist before they can be opened for reading.
+            txn.open_table(LOCATIONS)?;
+            txn.open_table(USAGE)?;
+            txn.open_table(DEVICE_KEYS)?;
+            txn.open_table(INTROS)?;
+            txn.open_table(TOKENS)?;
+            txn.open_multimap_table(TOKENS_BY_DEVICE)?;
+            txn.open_table(WIPE_ORDERS)?;
+            txn.open_table(EXCOMMUNICATIONS)?;
+            txn.open_table(PURGE_ORDERS)?;
+            txn.open_table(EMERGENCY_PURGES)?;
+            txn.open_table(AUDIT_LOG)?;
+            txn.open_table(REGISTRATION_KEYS)?;
+        }
+        txn.commit()?;
+        Ok(RedbStorage { db: Arc::new(db) })
+    }
+
+    /// Runs `f` with the database.
+    async fn run <T: Send + 'static> (
+        &self,
+        f: impl FnOnce(&Database) -> anyhow::Result<T> + Send + 'static,
+    ) -> anyhow::Result<T> {
+        let db = self.db.clone();
+        tokio::task::spawn_blocking(move || f(&db)).await?
+    }
+
+    async fn get (&self, table: TableDefinition<'static, &'static [u8], &'static [u8]>, key: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+        let key = key.to_vec();
+        self.run(move |db| {
+            let txn = db.begin_read()?;
+            let table = txn.open_table(table)?;
+            let value = table.get(key.as_slice())?.map(|v| v.value().to_vec());
+            Ok(value)
+        }).await
+    }
+
+    async fn put (&self, table: TableDefinition<'static, &'static [u8], &'static [u8]>, key: &[u8], value: Vec<u8>) -> anyhow::Result<()> {
+        let key = key.to_vec();
+        self.run(move |db| {
+            let txn = db.begin_write()?;
+            txn.open_table(table)?.insert(key.as_slice(), value.as_slice())?;
+            txn.commit()?;
+            Ok(())
+        }).await
+    }
+
+    async fn list_purge_orders_where (
+        &self,
+        predicate: impl Fn(&PurgeOrder) -> bool + Send + 'static,
+    ) -> anyhow::Result<Vec<PurgeOrder>> {
+        self.run(move |db| {
+            let txn = db.begin_read()?;
+            let table = txn.open_table(PURGE_ORDERS)?;
+            let mut orders = vec![];
+            for entry in table.iter()? {
+                let (_, value) = entry?;
+                let order: PurgeOrder = decode::<PurgeOrderRecord, _>(value.value())?;
+                if predicate(&order) {
+                    orders.push(order);
+                }
+            }
+            orders.sort_by_key(|o| o.execute_at);
+            Ok(orders)
+        }).await
+    }
+
+}
+
+#[tonic::async_trait]
+impl Storage for RedbStorage {
+
+    async fn get_token_info (&self, token: &TokenDigest) -> anyhow::Result<Option<TokenEntry>> {
+        self.get(TOKENS, token).await?
+            .map(|v| decode::<TokenRecord, _>(&v))
+            .transpose()
+    }
+
+    async fn write_location (&self, device_id: &DeviceId, location: &StoredLocation) -> anyhow::Result<()> {
+        let key = location_key(device_id, &location.update_time)?;
+        let (device_id, ciphertext) = (device_id.clone(), location.ciphertext.clone());
+        self.run(move |db| {
+            let txn = db.begin_write()?;
+            {
+                let replaced = txn.open_table(LOCATIONS)?
+                    .insert(key.as_slice(), ciphertext.as_slice())?
+                    .map(|v| (1, location_size(v.value())))
+                    .unwrap_or_default();
+                let added = (1, location_size(&ciphertext));
+                change_usage(&mut txn.open_table(USAGE)?, &device_id, added, replaced)?;
+            }
+            txn.commit()?;
+            Ok(())
+        }).await
+    }
+
+    async fn write_intro <'a> (&self, arg: &'a IntroInsertion) -> anyhow::Result<()> {
+        let intro = IntroRecord {
+            remote_addr: arg.remote_addr.map(|a| a.to_string()).unwrap_or_default(),
+            registration_key: arg.registration_key_digest.clone(),
+            remote_wipe_enabled: arg.arg.remote_wipe_enabled,
+            can_read_nearby_devices: arg.arg.can_read_nearby_devices,
+        };
+        let token = TokenRecord::from(&TokenEntry {
+            device_id: arg.device_id.clone(),
+            permissions: arg.permissions.clone(),
+            not_before: Utc::now(),
+            not_after: None,
+        });
+        let (device_id, token_digest) = (arg.device_id.clone(), arg.token_digest.clone());
+        self.run(move |db| {
+            let txn = db.begin_write()?;
+            {
+                txn.open_table(INTROS)?.insert(device_id.as_slice(), intro.encode_to_vec().as_slice())?;
+                txn.open_table(TOKENS)?.insert(token_digest.as_slice(), token.encode_to_vec().as_slice())?;
+                txn.open_multimap_table(TOKENS_BY_DEVICE)?.insert(device_id.as_slice(), token_digest.as_slice())?;
+            }
+            txn.commit()?;
+            Ok(())
+        }).await
+    }
+
+    async fn write_token (&self, token: &TokenDigest, arg: &TokenEntry) -> anyhow::Result<()> {
+        let (token, device_id, record) = (token.clone(), arg.device_id.clone(), TokenRecord::from(arg));
+        self.run(move |db| {
+            let txn = db.begin_write()?;
+            {
+                txn.open_table(TOKENS)?.insert(token.as_slice(), record.encode_to_vec().as_slice())?;
+                txn.open_multimap_table(TOKENS_BY_DEVICE)?.insert(device_id.as_slice(), token.as_slice())?;
+            }
+            txn.commit()?;
+            Ok(())
+        }).await
+    }
+
+    async fn revoke_token (&self, device_id: &DeviceId, token: Option<&TokenDigest>) -> anyhow::Result<u32> {
+        let (device_id, token) = (device_id.clone(), token.cloned());
+        self.run(move |db| {
+            let txn = db.begin_write()?;
+            let revoked = {
+                let mut index = txn.open_multimap_table(TOKENS_BY_DEVICE)?;
+                let revoked: Vec<Vec<u8>> = match token {
+                    Some(token) => {
+                        let mut found = None;
+                        for t in index.get(device_id.as_slice())? {
+                            let t = t?.value().to_vec();
+                            if constant_time_eq(&t, &token) {
+                                found = Some(t);
+                            }
+                        }
+                        if let Some(t) = &found {
+                            index.remove(device_id.as_slice(), t.as_slice())?;
+                        }
+                        found.into_iter().collect()
+                    },
+                    None => {
+                        let mut all = vec![];
+                        for t in index.remove_all(device_id.as_slice())? {
+                            all.push(t?.value().to_vec());
+                        }
+                        all
+                    },
+                };
+                let mut tokens = txn.open_table(TOKENS)?;
+                for t in revoked.iter() {
+                    tokens.remove(t.as_slice())?;
+                }
+                revoked.len() as u32
+            };
+            txn.commit()?;
+            Ok(revoked)
+        }).await
+    }
+
+    async fn list_tokens (&self, device_id: &DeviceId) -> anyhow::Result<Vec<(TokenDigest, TokenEntry)>> {
+        let device_id = device_id.clone();
+        self.run(move |db| {
+            let txn = db.begin_read()?;
+            let index = txn.open_multimap_table(TOKENS_BY_DEVICE)?;
+            let tokens = txn.open_table(TOKENS)?;
+            let mut listed = vec![];
+            for t in index.get(device_id.as_slice())? {
+                let t = t?;
+                if let Some(entry) = tokens.get(t.value())? {
+                    listed.push((t.value().to_vec(), decode::<TokenRecord, _>(entry.value())?));
+                }
+            }
+            Ok(listed)
+        }).await
+    }
+
+    async fn purge_location (&self, device_id: &DeviceId, since: Option<DateTime<Utc>>) -> anyhow::Result<()> {
+        let (start, end) = location_range(device_id, since, None)?;
+        let device_id = device_id.clone();
+        self.run(move |db| {
+            let txn = db.begin_write()?;
+            {
+                let mut removed = (0, 0);
+                txn.open_table(LOCATIONS)?.retain_in(start.as_slice()..=end.as_slice(), |_, ciphertext| {
+                    removed.0 += 1;
+                    removed.1 += location_size(ciphertext);
+                    false
+                })?;
+                change_usage(&mut txn.open_table(USAGE)?, &device_id, (0, 0), removed)?;
+                if since.is_none() {
+                    txn.open_table(DEVICE_KEYS)?.remove(device_id.as_slice())?;
+                }
+            }
+            txn.commit()?;
+            Ok(())
+        }).await
+    }
+
+    async fn list_locations (&self, device_id: &DeviceId, filter: &LocationsFilter) -> anyhow::Result<Vec<StoredLocation>> {
+        let (start, end) = location_range(device_id, filter.since, filter.until)?;
+        let limit = filter.limit as usize;
+        self.run(move |db| {
+            let txn = db.begin_read()?;
+            let table = txn.open_table(LOCATIONS)?;
+            let mut locs = vec![];
+            for entry in table.range(start.as_slice()..=end.as_slice())?.take(limit) {
+                let (key, value) = entry?;
+                locs.push(location_from_entry(key.value(), value.value())?);
+            }
+            Ok(locs)
+        }).await
+    }
+
+    async fn delete_locations (&self, device_id: &DeviceId, update_times: &[DateTime<Utc>]) -> anyhow::Result<u64> {
+        let keys = update_times.iter()
+            .map(|t| location_key(device_id, t))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let device_id = device_id.clone();
+        self.run(move |db| {
+            let txn = db.begin_write()?;
+            let mut removed = (0, 0);
+            {
+                let mut table = txn.open_table(LOCATIONS)?;
+                for key in keys.iter() {
+                    if let Some(ciphertext) = table.remove(key.as_slice())? {
+                        removed.0 += 1;
+                        removed.1 += location_size(ciphertext.value());
+                    }
+                }
+                change_usage(&mut txn.open_table(USAGE)?, &device_id, (0, 0), removed)?;
+            }
+            txn.commit()?;
+            Ok(removed.0)
+        }).await
+    }
+
+    async fn delete_locations_before (&self, before: DateTime<Utc>) -> anyhow::Result<u64> {
+        self.run(move |db| {
+            let txn = db.begin_write()?;
+            let mut removed: HashMap<Vec<u8>, (u64, u64)> = HashMap::new();
+            {
+                let mut table = txn.open_table(LOCATIONS)?;
+                table.retain(|key, ciphertext| {
+                    let expired = key.len() >= TIME_LENGTH
+                        && decode_time(&key[key.len() - TIME_LENGTH..]).map(|t| t < before).unwrap_or(false);
+                    if let (true, Ok(device_id)) = (expired, device_of_key(key)) {
+                        let removed = removed.entry(device_id.to_vec()).or_default();
+                        removed.0 += 1;
+                        removed.1 += location_size(ciphertext);
+                    }
+                    !expired
+                })?;
+                let mut usage = txn.open_table(USAGE)?;
+                for (device_id, removed) in removed.iter() {
+                    change_usage(&mut usage, device_id, (0, 0), *removed)?;
+                }
+            }
+            txn.commit()?;
+            Ok(removed.values().map(|r| r.0).sum())
+        }).await
+    }
+
+    async fn get_storage_usage (&self, device_id: &DeviceId) -> anyhow::Result<StorageUsage> {
+        let (start, end) = location_range(device_id, None, None)?;
+        let device_id = device_id.clone();
+        self.run(move |db| {
+            let txn = db.begin_read()?;
+            let (locations, bytes) = txn.open_table(USAGE)?
+                .get(device_id.as_slice())?
+                .map(|v| v.value())
+                .unwrap_or_default();
+            // Only the first of the device's locations is read.
+            let oldest = match txn.open_table(LOCATIONS)?.range(start.as_slice()..=end.as_slice())?.next() {
+                Some(entry) => Some(location_from_entry(entry?.0.value(), &[])?.update_time),
+                None => None,
+            };
+            Ok(StorageUsage { locations, bytes, oldest })
+        }).await
+    }
+
+    async fn get_intro (&self, device_id: &DeviceId) -> anyhow::Result<Option<Introduction>> {
+        Ok(self.get(INTROS, device_id).await?
+            .map(|v| IntroRecord::decode(v.as_slice()))
+            .transpose()?
+            .map(Introduction::from))
+    }
+
+    async fn restore_intro (&self, device_id: &DeviceId, intro: &Introduction) -> anyhow::Result<()> {
+        self.put(INTROS, device_id, IntroRecord::from(intro).encode_to_vec()).await
+    }
+
+    async fn list_devices (&self) -> anyhow::Result<Vec<DeviceId>> {
+        self.run(|db| {
+            let txn = db.begin_read()?;
+            let mut devices = BTreeSet::new();
+            for table in [ DEVICE_KEYS, INTROS, WIPE_ORDERS, EXCOMMUNICATIONS ] {
+                for entry in txn.open_table(table)?.iter()? {
+                    devices.insert(entry?.0.value().to_vec());
+                }
+            }
+            for entry in txn.open_multimap_table(TOKENS_BY_DEVICE)?.iter()? {
+                devices.insert(entry?.0.value().to_vec());
+            }
+            for entry in txn.open_table(PURGE_ORDERS)?.iter()? {
+                devices.insert(PurgeOrderRecord::decode(entry?.1.value())?.device_id);
+            }
+            // Rather than reading every location, skip past each device's
+            // locations as soon as one of them is found.
+            let locations = txn.open_table(LOCATIONS)?;
+            let mut start = vec![];
+            while let Some(entry) = locations.range(start.as_slice()..)?.next() {
+                let (key, _) = entry?;
+                let device_id = device_of_key(key.value())?.to_vec();
+                start = location_key(&device_id, &DateTime::<Utc>::MAX_UTC)?;
+                start.push(0);
+                devices.insert(device_id);
+            }
+            Ok(devices.into_iter().collect())
+        }).await
+    }
+
+    async fn insert_device_key (&self, device_id: &DeviceId, wrapped_key: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let (device_id, wrapped_key) = (device_id.clone(), wrapped_key.to_vec());
+        self.run(move |db| {
+            let txn = db.begin_write()?;
+            let key = {
+                let mut table = txn.open_table(DEVICE_KEYS)?;
+                let existing = table.get(device_id.as_slice())?.map(|v| v.value().to_vec());
+                match existing {
+                    Some(existing) => existing,
+                    None => {
+                        table.insert(device_id.as_slice(), wrapped_key.as_slice())?;
+                        wrapped_key
+                    },
+                }
+            };
+            txn.commit()?;
+            Ok(key)
+        }).await
+    }
+
+    async fn get_device_key (&self, device_id: &DeviceId) -> anyhow::Result<Option<Vec<u8>>> {
+        self.get(DEVICE_KEYS, device_id).await
+    }
+
+    async fn write_wipe_order (&self, device_id: &DeviceId, order: &WipeOrder) -> anyhow::Result<()> {
+        self.put(WIPE_ORDERS, device_id, WipeOrderRecord::from(order).encode_to_vec()).await
+    }
+
+    async fn get_wipe_order (&self, device_id: &DeviceId) -> anyhow::Result<Option<WipeOrder>> {
+        self.get(WIPE_ORDERS, device_id).await?
+            .map(|v| decode::<WipeOrderRecord, _>(&v))
+            .transpose()
+    }
+
+    async fn excommunicate (&self, device_id: &DeviceId, record: &Excommunication) -> anyhow::Result<()> {
+        self.put(EXCOMMUNICATIONS, device_id, ExcommunicationRecord::from(record).encode_to_vec()).await
+    }
+
+    async fn get_excommunication (&self, device_id: &DeviceId) -> anyhow::Result<Option<Excommunication>> {
+        self.get(EXCOMMUNICATIONS, device_id).await?
+            .map(|v| decode::<ExcommunicationRecord, _>(&v))
+            .transpose()
+    }
+
+    async fn write_purge_order (&self, order: &PurgeOrder) -> anyhow::Result<()> {
+        let (id, record) = (order.id, PurgeOrderRecord::from(order));
+        self.run(move |db| {
+            let txn = db.begin_write()?;
+            txn.open_table(PURGE_ORDERS)?.insert(id, record.encode_to_vec().as_slice())?;
+            txn.commit()?;
+            Ok(())
+        }).await
+    }
+
+    async fn list_purge_orders (&self, device_id: &DeviceId) -> anyhow::Result<Vec<PurgeOrder>> {
+        let device_id = device_id.clone();
+        self.list_purge_orders_where(move |o| o.device_id == device_id).await
+    }
+
+    async fn list_due_purge_orders (&self, now: DateTime<Utc>) -> anyhow::Result<Vec<PurgeOrder>> {
+        self.list_purge_orders_where(move |o| o.execute_at <= now).await
+    }
+
+    async fn delete_purge_order (&self, id: u64) -> anyhow::Result<bool> {
+        self.run(move |db| {
+            let txn = db.begin_write()?;
+            let existed = txn.open_table(PURGE_ORDERS)?.remove(id)?.is_some();
+            txn.commit()?;
+            Ok(existed)
+        }).await
+    }
+
+    async fn write_emergency_purge (&self, request: &EmergencyPurgeRequest) -> anyhow::Result<()> {
+        let (id, record) = (request.id, EmergencyPurgeRecord::from(request));
+        self.run(move |db| {
+            let txn = db.begin_write()?;
+            txn.open_table(EMERGENCY_PURGES)?.insert(id, record.encode_to_vec().as_slice())?;
+            txn.commit()?;
+            Ok(())
+        }).await
+    }
+
+    async fn get_emergency_purge (&self, id: u64) -> anyhow::Result<Option<EmergencyPurgeRequest>> {
+        self.run(move |db| {
+            let txn = db.begin_read()?;
+            let table = txn.open_table(EMERGENCY_PURGES)?;
+            let request = table.get(id)?;
+            request.map(|v| decode::<EmergencyPurgeRecord, _>(v.value())).transpose()
+        }).await
+    }
+
+    async fn list_emergency_purges (&self, include_decided: bool) -> anyhow::Result<Vec<EmergencyPurgeRequest>> {
+        self.run(move |db| {
+            let txn = db.begin_read()?;
+            let table = txn.open_table(EMERGENCY_PURGES)?;
+            let mut requests = vec![];
+            for entry in table.iter()? {
+                let (_, value) = entry?;
+                let request: EmergencyPurgeRequest = decode::<EmergencyPurgeRecord, _>(value.value())?;
+                if include_decided || request.decided.is_none() {
+                    requests.push(request);
+                }
+            }
+            requests.sort_by_key(|r| r.requested);
+            Ok(requests)
+        }).await
+    }
+
+    async fn write_audit_record (&self, record: &AuditRecord) -> anyhow::Result<()> {
+        let record = AuditRecordRecord::from(record);
+        self.run(move |db| {
+            let txn = db.begin_write()?;
+            {
+                let mut table = txn.open_table(AUDIT_LOG)?;
+                let next = match table.last()? {
+                    Some((key, _)) => key.value() + 1,
+                    None => 0,
+                };
+                table.insert(next, record.encode_to_vec().as_slice())?;
+            }
+            txn.commit()?;
+            Ok(())
+        }).await
+    }
+
+    async fn list_audit_records (&self, limit: u32) -> anyhow::Result<Vec<AuditRecord>> {
+        self.run(move |db| {
+            let txn = db.begin_read()?;
+            let table = txn.open_table(AUDIT_LOG)?;
+            let mut records = vec![];
+            for entry in table.iter()?.rev().take(limit as usize) {
+                let (_, value) = entry?;
+                records.push(decode::<AuditRecordRecord, _>(value.value())?);
+            }
+            Ok(records)
+        }).await
+    }
+
+    async fn write_registration_key (&self, key: &RegistrationKey) -> anyhow::Result<()> {
+        self.put(REGISTRATION_KEYS, &key.digest, RegistrationKeyRecord::from(key).encode_to_vec()).await
+    }
+
+    async fn list_registration_keys (&self) -> anyhow::Result<Vec<RegistrationKey>> {
+        self.run(|db| {
+            let txn = db.begin_read()?;
+            let table = txn.open_table(REGISTRATION_KEYS)?;
+            let mut keys = vec![];
+            for entry in table.iter()? {
+                let (_, value) = entry?;
+                keys.push(decode::<RegistrationKeyRecord, RegistrationKey>(value.value())?);
+            }
+            keys.sort_by_key(|k| k.created);
+            Ok(keys)
+        }).await
+    }
+
+    async fn delete_registration_key (&self, key: &RegistrationKeyDigest) -> anyhow::Result<bool> {
+        let key = key.to_vec();
+        self.run(move |db| {
+            let txn = db.begin_write()?;
+            let existed = txn.open_table(REGISTRATION_KEYS)?.remove(key.as_slice())?.is_some();
+            txn.commit()?;
+            Ok(existed)
+        }).await
+    }
+
+    async fn use_registration_key (&self, key: &RegistrationKeyDigest, now: DateTime<Utc>) -> anyhow::Result<Option<RegistrationKey>> {
+        let key = key.to_vec();
+        self.run(move |db| {
+            let txn = db.begin_write()?;
+            let before = {
+                let mut table = txn.open_table(REGISTRATION_KEYS)?;
+                let entry = table.get(key.as_slice())?.map(|v| decode::<RegistrationKeyRecord, RegistrationKey>(v.value()));
+                let before = match entry.transpose()? {
+                    Some(entry) => entry,
+                    None => return Ok(None),
+                };
+                if before.uses_remaining == 0 || before.not_after.map(|t| t <= now).unwrap_or(false) {
+                    return Ok(None);
+                }
+                let mut after = before.clone();
+                after.uses_remaining -= 1;
+                table.insert(key.as_slice(), RegistrationKeyRecord::from(&after).encode_to_vec().as_slice())?;
+                before
+            };
+            txn.commit()?;
+            Ok(Some(before))
+        }).await
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::conformance::storage_conformance_tests;
+    use crate::grpc::find_my_device::Permissions;
+
+    fn temp_path () -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("fmx-redb-{}.redb", rand::random::<u64>()))
+    }
+
+    fn in_memory () -> RedbStorage {
+        let db = Database::builder().create_with_backend(::redb::backends::InMemoryBackend::new()).unwrap();
+        RedbStorage::from_database(db, "an in-memory database").unwrap()
+    }
+
+    storage_conformance_tests!(in_memory());
+
+    #[test]
+    fn encoded_times_sort_like_times () {
+        let times = [
+            DateTime::<Utc>::MIN_UTC,
+            Utc.timestamp_opt(-1, 999_999_999).unwrap(),
+            Utc.timestamp_opt(0, 0).unwrap(),
+            Utc.timestamp_opt(0, 1).unwrap(),
+            Utc::now(),
+            DateTime::<Utc>::MAX_UTC,
+        ];
+        for pair in times.windows(2) {
+            assert!(encode_time(&pair[0]) < encode_time(&pair[1]));
+        }
+        for t in times.iter() {
+            assert_eq!(decode_time(&encode_time(t)).unwrap(), *t);
+        }
+    }
+
+    #[tokio::test]
+    async fn data_survives_reopening () {
+        let path = temp_path();
+        let device_id: DeviceId = vec![ 1; 32 ];
+        let token: TokenDigest = vec![ 2; 32 ];
+        let now = Utc::now();
+        {
+            let storage = RedbStorage::open(&path).unwrap();
+            storage.write_token(&token, &TokenEntry {
+                device_id: device_id.clone(),
+                permissions: Permissions { read_locations: true, ..Default::default() },
+                not_before: now,
+                not_after: None,
+            }).await.unwrap();
+            for i in 0..3 {
+                storage.write_location(&device_id, &StoredLocation {
+                    update_time: now + chrono::Duration::seconds(i),
+                    ciphertext: vec![ i as u8; 40 ],
+                }).await.unwrap();
+            }
+            // Another device's locations must not be mixed in.
+            storage.write_location(&vec![ 1; 31 ], &StoredLocation {
+                update_time: now,
+                ciphertext: vec![],
+            }).await.unwrap();
+        }
+        let storage = RedbStorage::open(&path).unwrap();
+        let entry = storage.get_token_info(&token).await.unwrap().unwrap();
+        assert_eq!(entry.device_id, device_id);
+        assert!(entry.permissions.read_locations);
+        assert_eq!(storage.list_tokens(&device_id).await.unwrap().len(), 1);
+
+        let filter = LocationsFilter { limit: 10, since: Some(now + chrono::Duration::seconds(1)), until: None };
+        let locs = storage.list_locations(&device_id, &filter).await.unwrap();
+        assert_eq!(locs.iter().map(|l| l.ciphertext[0]).collect::<Vec<_>>(), vec![ 1, 2 ]);
+        assert_eq!(locs[0].update_time, now + chrono::Duration::seconds(1));
+        let usage = storage.get_storage_usage(&device_id).await.unwrap();
+        assert_eq!(usage, StorageUsage { locations: 3, bytes: 3 * 52, oldest: Some(now) });
+
+        assert_eq!(storage.revoke_token(&device_id, None).await.unwrap(), 1);
+        assert!(storage.get_token_info(&token).await.unwrap().is_none());
+        drop(storage);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn usage_is_counted_when_upgrading_from_version_1 () {
+        let path = temp_path();
+        let device_id: DeviceId = vec![ 1; 32 ];
+        let now = Utc::now();
+        {
+            let storage = RedbStorage::open(&path).unwrap();
+            for i in 0..3 {
+                storage.write_location(&device_id, &StoredLocation {
+                    update_time: now + chrono::Duration::seconds(i),
+                    ciphertext: vec![ 0; 40 ],
+                }).await.unwrap();
+            }
+            // Put the database back as it was before usage was kept.
+            let txn = storage.db.begin_write().unwrap();
+            txn.delete_table(USAGE).unwrap();
+            txn.open_table(META).unwrap().insert("schema_version", 1).unwrap();
+            txn.commit().unwrap();
+        }
+        let storage = RedbStorage::open(&path).unwrap();
+        let usage = storage.get_storage_usage(&device_id).await.unwrap();
+        assert_eq!(usage, StorageUsage { locations: 3, bytes: 3 * 52, oldest: Some(now) });
+        drop(storage);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+}
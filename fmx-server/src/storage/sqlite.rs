@@ -0,0 +1,950 @@
+use crate::digest::constant_time_eq;
+use crate::grpc::find_my_device::Permissions;
+use crate::storage::{
+    Storage,
+    DeviceId,
+    TokenDigest,
+    StoredLocation,
+    IntroInsertion,
+    TokenEntry,
+    LocationsFilter,
+    Introduction,
+    WipeOrder,
+    Excommunication,
+    PurgeOrder,
+    EmergencyPurgeRequest,
+    AuditRecord,
+    RegistrationKey,
+    RegistrationKeyDigest,
+    StorageUsage,
+};
+use chrono::prelude::*;
+use log::info;
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension, Row};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::sync::Semaphore;
+
+/// The schema migrations, in order. The database's `user_version` is the
+/// number of them that have been applied. Migrations that have been released
+/// must never be changed: add another one instead.
+const MIGRATIONS: &[&str] = &[
+    include_str!("../../migrations/sqlite/0001_initial.sql"),
+    include_str!("../../migrations/sqlite/0002_location_usage.sql"),
+];
+
+/// Lists a device's locations between two times. The primary key of
+/// `locations` makes this a range scan of an index.
+const LIST_LOCATIONS: &str = "SELECT update_time, ciphertext FROM locations
+    WHERE device_id = ?1 AND update_time BETWEEN ?2 AND ?3
+    ORDER BY update_time
+    LIMIT ?4";
+
+/// Tells how much storage a device's locations take up, from the totals kept
+/// in `location_usage` and the first entry of the device's range of the
+/// primary key of `locations`, so that neither reads the device's history.
+const STORAGE_USAGE: &str = "SELECT locations, bytes,
+        (SELECT update_time FROM locations WHERE device_id = ?1 ORDER BY update_time LIMIT 1)
+    FROM location_usage
+    WHERE device_id = ?1";
+
+const LIST_DEVICES: &str = "SELECT device_id FROM intros
+    UNION SELECT device_id FROM device_keys
+    UNION SELECT device_id FROM tokens
+    UNION SELECT device_id FROM wipe_orders
+    UNION SELECT device_id FROM excommunications
+    UNION SELECT device_id FROM purge_orders
+    UNION SELECT device_id FROM locations
+    ORDER BY device_id";
+
+const TOKEN_COLUMNS: &str = "device_id, write_locations, read_locations, nearby, wipe, list_tokens, stats, cancel_purge, not_before, not_after";
+
+const PURGE_ORDER_COLUMNS: &str = "id, device_id, requested, since, execute_at";
+
+const EMERGENCY_PURGE_COLUMNS: &str = "id, device_id, purge_order_id, since, requested, decided, approved";
+
+const REGISTRATION_KEY_COLUMNS: &str = "key, uses_remaining, created, not_after, write_locations, read_locations, nearby, wipe, list_tokens, stats, cancel_purge, note";
+
+const NANOS_PER_SEC: i64 = 1_000_000_000;
+
+fn to_nanos (t: &DateTime<Utc>) -> anyhow::Result<i64> {
+    t.timestamp()
+        .checked_mul(NANOS_PER_SEC)
+        .and_then(|n| n.checked_add(t.timestamp_subsec_nanos() as i64))
+        .ok_or_else(|| anyhow::anyhow!("{} is too far from 1970 to be stored.", t.to_rfc3339()))
+}
+
+fn optional_nanos (t: &Option<DateTime<Utc>>) -> anyhow::Result<Option<i64>> {
+    t.as_ref().map(to_nanos).transpose()
+}
+
+/// Like `to_nanos`, but saturating, for the bounds of queries.
+fn bound_nanos (t: &DateTime<Utc>) -> i64 {
+    to_nanos(t).unwrap_or(if t.timestamp() < 0 { i64::MIN } else { i64::MAX })
+}
+
+fn from_nanos (n: i64) -> DateTime<Utc> {
+    Utc.timestamp_nanos(n)
+}
+
+/// Reads the seven permissions columns starting at `start`.
+fn permissions_from_row (row: &Row, start: usize) -> rusqlite::Result<Permissions> {
+    Ok(Permissions {
+        write_locations: row.get(start)?,
+        read_locations: row.get(start + 1)?,
+        nearby: row.get(start + 2)?,
+        wipe: row.get(start + 3)?,
+        list_tokens: row.get(start + 4)?,
+        stats: row.get(start + 5)?,
+        cancel_purge: row.get(start + 6)?,
+    })
+}
+
+/// Reads the `TOKEN_COLUMNS`.
+fn token_from_row (row: &Row) -> rusqlite::Result<TokenEntry> {
+    Ok(TokenEntry {
+        device_id: row.get(0)?,
+        permissions: permissions_from_row(row, 1)?,
+        not_before: from_nanos(row.get(8)?),
+        not_after: row.get::<_, Option<i64>>(9)?.map(from_nanos),
+    })
+}
+
+fn purge_order_from_row (row: &Row) -> rusqlite::Result<PurgeOrder> {
+    Ok(PurgeOrder {
+        id: row.get::<_, i64>(0)? as u64,
+        device_id: row.get(1)?,
+        requested: from_nanos(row.get(2)?),
+        since: row.get::<_, Option<i64>>(3)?.map(from_nanos),
+        execute_at: from_nanos(row.get(4)?),
+    })
+}
+
+fn emergency_purge_from_row (row: &Row) -> rusqlite::Result<EmergencyPurgeRequest> {
+    Ok(EmergencyPurgeRequest {
+        id: row.get::<_, i64>(0)? as u64,
+        device_id: row.get(1)?,
+        purge_order_id: row.get::<_, i64>(2)? as u64,
+        since: row.get::<_, Option<i64>>(3)?.map(from_nanos),
+        requested: from_nanos(row.get(4)?),
+        decided: row.get::<_, Option<i64>>(5)?.map(from_nanos),
+        approved: row.get(6)?,
+    })
+}
+
+fn registration_key_from_row (row: &Row) -> rusqlite::Result<RegistrationKey> {
+    Ok(RegistrationKey {
+        digest: row.get(0)?,
+        uses_remaining: row.get(1)?,
+        created: from_nanos(row.get(2)?),
+        not_after: row.get::<_, Option<i64>>(3)?.map(from_nanos),
+        device_permissions: permissions_from_row(row, 4)?,
+        note: row.get(11)?,
+    })
+}
+
+fn write_token (conn: &Connection, token: &TokenDigest, entry: &TokenEntry) -> anyhow::Result<()> {
+    let p = &entry.permissions;
+    conn.execute(
+        &format!("INSERT OR REPLACE INTO tokens (digest, {}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)", TOKEN_COLUMNS),
+        params![
+            token,
+            entry.device_id,
+            p.write_locations,
+            p.read_locations,
+            p.nearby,
+            p.wipe,
+            p.list_tokens,
+            p.stats,
+            p.cancel_purge,
+            to_nanos(&entry.not_before)?,
+            optional_nanos(&entry.not_after)?,
+        ],
+    )?;
+    Ok(())
+}
+
+fn write_intro (conn: &Connection, device_id: &DeviceId, intro: &Introduction) -> anyhow::Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO intros (device_id, remote_addr, registration_key, remote_wipe_enabled, can_read_nearby_devices)
+            VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+            device_id,
+            intro.remote_addr.map(|a| a.to_string()),
+            intro.registration_key_digest,
+            intro.remote_wipe_enabled,
+            intro.can_read_nearby_devices,
+        ],
+    )?;
+    Ok(())
+}
+
+fn write_registration_key (conn: &Connection, key: &RegistrationKey) -> anyhow::Result<()> {
+    let p = &key.device_permissions;
+    conn.execute(
+        &format!("INSERT OR REPLACE INTO registration_keys ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)", REGISTRATION_KEY_COLUMNS),
+        params![
+            key.digest,
+            key.uses_remaining,
+            to_nanos(&key.created)?,
+            optional_nanos(&key.not_after)?,
+            p.write_locations,
+            p.read_locations,
+            p.nearby,
+            p.wipe,
+            p.list_tokens,
+            p.stats,
+            p.cancel_purge,
+            key.note,
+        ],
+    )?;
+    Ok(())
+}
+
+/// Applies whichever of `MIGRATIONS` have not been applied yet, each in its
+/// own transaction.
+fn migrate (conn: &mut Connection) -> anyhow::Result<()> {
+    let applied = conn.query_row("PRAGMA user_version", [], |row| row.get::<_, i64>(0))? as usize;
+    if applied > MIGRATIONS.len() {
+        anyhow::bail!(
+            "The database has schema version {}, but this server only supports up to version {}.",
+            applied,
+            MIGRATIONS.len(),
+        );
+    }
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(applied) {
+        let txn = conn.transaction()?;
+        txn.execute_batch(migration)?;
+        txn.pragma_update(None, "user_version", (i + 1) as i64)?;
+        txn.commit()?;
+        info!("Migrated the database to schema version {}.", i + 1);
+    }
+    Ok(())
+}
+
+/// The most connections that read at once. Reads do not wait for writes, or
+/// for each other, because the database is in WAL mode.
+const READERS: usize = 4;
+
+/// The connections to one database: one that writes, since SQLite only lets
+/// one connection write at a time anyway, and a few that only read, which are
+/// opened as they are needed.
+struct Pool {
+    /// Where the database is, or `None` if it is in memory, in which case
+    /// there is only one connection, and reads use it too.
+    path: Option<PathBuf>,
+    writer: Mutex<Connection>,
+    readers: Mutex<Vec<Connection>>,
+}
+
+impl Pool {
+
+    fn writer (&self) -> MutexGuard<'_, Connection> {
+        // A panic while the lock was held cannot leave a transaction open,
+        // since uncommitted transactions are rolled back when dropped.
+        self.writer.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Runs `f` with a connection that only reads, opening one if none is
+    /// idle. The caller must ensure no more than `READERS` are in use.
+    fn read <T> (&self, f: impl FnOnce(&Connection) -> anyhow::Result<T>) -> anyhow::Result<T> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return f(&self.writer()),
+        };
+        let idle = self.readers.lock().unwrap_or_else(|e| e.into_inner()).pop();
+        let conn = match idle {
+            Some(conn) => conn,
+            None => Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX)
+                .map_err(|e| anyhow::anyhow!("Could not open {}: {}", path.display(), e))?,
+        };
+        let result = f(&conn);
+        self.readers.lock().unwrap_or_else(|e| e.into_inner()).push(conn);
+        result
+    }
+
+}
+
+/// Storage in a SQLite database, which can be inspected with the usual SQLite
+/// tools. Every method that writes does so in one transaction, which is
+/// durable once the method returns. Statements are run on threads where they
+/// may block, so that no async task waits on the disk.
+pub struct SqliteStorage {
+    pool: Arc<Pool>,
+    readers_available: Semaphore,
+}
+
+impl SqliteStorage {
+
+    /// Opens the database at `path`, creating it if it does not exist, and
+    /// brings its schema up to date.
+    pub fn open (path: &Path) -> anyhow::Result<Self> {
+        let conn = Connection::open(path)
+            .map_err(|e| anyhow::anyhow!("Could not open {}: {}", path.display(), e))?;
+        SqliteStorage::from_connection(conn, Some(path.to_path_buf()))
+    }
+
+    fn from_connection (mut conn: Connection, path: Option<PathBuf>) -> anyhow::Result<Self> {
+        conn.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+        conn.pragma_update(None, "synchronous", "FULL")?;
+        migrate(&mut conn)?;
+        Ok(SqliteStorage {
+            pool: Arc::new(Pool {
+                path,
+                writer: Mutex::new(conn),
+                readers: Mutex::new(Vec::new()),
+            }),
+            readers_available: Semaphore::new(READERS),
+        })
+    }
+
+    /// Runs `f` with the connection that writes.
+    async fn write <T: Send + 'static> (
+        &self,
+        f: impl FnOnce(&mut Connection) -> anyhow::Result<T> + Send + 'static,
+    ) -> anyhow::Result<T> {
+        let pool = self.pool.clone();
+        tokio::task::spawn_blocking(move || f(&mut pool.writer())).await?
+    }
+
+    /// Runs `f` with a connection that only reads.
+    async fn read <T: Send + 'static> (
+        &self,
+        f: impl FnOnce(&Connection) -> anyhow::Result<T> + Send + 'static,
+    ) -> anyhow::Result<T> {
+        let _permit = self.readers_available.acquire().await?;
+        let pool = self.pool.clone();
+        tokio::task::spawn_blocking(move || pool.read(f)).await?
+    }
+
+    async fn list_purge_orders_where (
+        &self,
+        condition: &'static str,
+        param: impl rusqlite::ToSql + Send + 'static,
+    ) -> anyhow::Result<Vec<PurgeOrder>> {
+        self.read(move |conn| {
+            let mut stmt = conn.prepare(&format!(
+                "SELECT {} FROM purge_orders WHERE {} ORDER BY execute_at",
+                PURGE_ORDER_COLUMNS,
+                condition,
+            ))?;
+            let orders = stmt.query_map([ param ], purge_order_from_row)?.collect::<Result<_, _>>()?;
+            Ok(orders)
+        }).await
+    }
+
+}
+
+#[tonic::async_trait]
+impl Storage for SqliteStorage {
+
+    async fn get_token_info (&self, token: &TokenDigest) -> anyhow::Result<Option<TokenEntry>> {
+        let token = token.clone();
+        self.read(move |conn| {
+            let entry = conn
+                .query_row(
+                    &format!("SELECT {} FROM tokens WHERE digest = ?1", TOKEN_COLUMNS),
+                    [ token ],
+                    token_from_row,
+                )
+                .optional()?;
+            Ok(entry)
+        }).await
+    }
+
+    async fn write_location (&self, device_id: &DeviceId, location: &StoredLocation) -> anyhow::Result<()> {
+        let (device_id, location) = (device_id.clone(), location.clone());
+        self.write(move |conn| {
+            // This replaces a location at the same time with an update, rather
+            // than INSERT OR REPLACE, so that the triggers keeping
+            // `location_usage` up to date see the location it replaces.
+            conn.execute(
+                "INSERT INTO locations (device_id, update_time, ciphertext) VALUES (?1, ?2, ?3)
+                    ON CONFLICT (device_id, update_time) DO UPDATE SET ciphertext = excluded.ciphertext",
+                params![ device_id, to_nanos(&location.update_time)?, location.ciphertext ],
+            )?;
+            Ok(())
+        }).await
+    }
+
+    async fn write_intro <'a> (&self, arg: &'a IntroInsertion) -> anyhow::Result<()> {
+        let device_id = arg.device_id.clone();
+        let token_digest = arg.token_digest.clone();
+        let intro = Introduction {
+            remote_addr: arg.remote_addr,
+            registration_key_digest: arg.registration_key_digest.clone(),
+            remote_wipe_enabled: arg.arg.remote_wipe_enabled,
+            can_read_nearby_devices: arg.arg.can_read_nearby_devices,
+        };
+        let entry = TokenEntry {
+            device_id: arg.device_id.clone(),
+            permissions: arg.permissions.clone(),
+            not_before: Utc::now(),
+            not_after: None,
+        };
+        self.write(move |conn| {
+            let txn = conn.transaction()?;
+            write_intro(&txn, &device_id, &intro)?;
+            write_token(&txn, &token_digest, &entry)?;
+            txn.commit()?;
+            Ok(())
+        }).await
+    }
+
+    async fn write_token (&self, token: &TokenDigest, arg: &TokenEntry) -> anyhow::Result<()> {
+        let (token, arg) = (token.clone(), arg.clone());
+        self.write(move |conn| write_token(conn, &token, &arg)).await
+    }
+
+    async fn revoke_token (&self, device_id: &DeviceId, token: Option<&TokenDigest>) -> anyhow::Result<u32> {
+        let (device_id, token) = (device_id.clone(), token.cloned());
+        self.write(move |conn| {
+            let txn = conn.transaction()?;
+            let revoked = match token {
+                Some(token) => {
+                    let digests: Vec<Vec<u8>> = txn
+                        .prepare("SELECT digest FROM tokens WHERE device_id = ?1")?
+                        .query_map([ &device_id ], |row| row.get(0))?
+                        .collect::<Result<_, _>>()?;
+                    match digests.iter().find(|d| constant_time_eq(d, &token)) {
+                        Some(d) => txn.execute("DELETE FROM tokens WHERE digest = ?1", [ d ])?,
+                        None => 0,
+                    }
+                },
+                None => txn.execute("DELETE FROM tokens WHERE device_id = ?1", [ &device_id ])?,
+            };
+            txn.commit()?;
+            Ok(revoked as u32)
+        }).await
+    }
+
+    async fn list_tokens (&self, device_id: &DeviceId) -> anyhow::Result<Vec<(TokenDigest, TokenEntry)>> {
+        let device_id = device_id.clone();
+        self.read(move |conn| {
+            let mut stmt = conn.prepare(&format!("SELECT digest, {} FROM tokens WHERE device_id = ?1", TOKEN_COLUMNS))?;
+            let tokens = stmt
+                .query_map([ device_id ], |row| {
+                    let digest: Vec<u8> = row.get(0)?;
+                    let entry = TokenEntry {
+                        device_id: row.get(1)?,
+                        permissions: permissions_from_row(row, 2)?,
+                        not_before: from_nanos(row.get(9)?),
+                        not_after: row.get::<_, Option<i64>>(10)?.map(from_nanos),
+                    };
+                    Ok((digest, entry))
+                })?
+                .collect::<Result<_, _>>()?;
+            Ok(tokens)
+        }).await
+    }
+
+    async fn purge_location (&self, device_id: &DeviceId, since: Option<DateTime<Utc>>) -> anyhow::Result<()> {
+        let device_id = device_id.clone();
+        self.write(move |conn| {
+            let txn = conn.transaction()?;
+            match since {
+                Some(since) => {
+                    txn.execute(
+                        "DELETE FROM locations WHERE device_id = ?1 AND update_time >= ?2",
+                        params![ device_id, bound_nanos(&since) ],
+                    )?;
+                },
+                None => {
+                    txn.execute("DELETE FROM locations WHERE device_id = ?1", [ &device_id ])?;
+                    txn.execute("DELETE FROM device_keys WHERE device_id = ?1", [ &device_id ])?;
+                },
+            };
+            txn.commit()?;
+            Ok(())
+        }).await
+    }
+
+    async fn list_locations (&self, device_id: &DeviceId, filter: &LocationsFilter) -> anyhow::Result<Vec<StoredLocation>> {
+        let (device_id, filter) = (device_id.clone(), filter.clone());
+        self.read(move |conn| {
+            let mut stmt = conn.prepare_cached(LIST_LOCATIONS)?;
+            let locs = stmt
+                .query_map(
+                    params![
+                        device_id,
+                        filter.since.as_ref().map(bound_nanos).unwrap_or(i64::MIN),
+                        filter.until.as_ref().map(bound_nanos).unwrap_or(i64::MAX),
+                        filter.limit,
+                    ],
+                    |row| Ok(StoredLocation {
+                        update_time: from_nanos(row.get(0)?),
+                        ciphertext: row.get(1)?,
+                    }),
+                )?
+                .collect::<Result<_, _>>()?;
+            Ok(locs)
+        }).await
+    }
+
+    async fn delete_locations (&self, device_id: &DeviceId, update_times: &[DateTime<Utc>]) -> anyhow::Result<u64> {
+        let (device_id, update_times) = (device_id.clone(), update_times.to_vec());
+        self.write(move |conn| {
+            let txn = conn.transaction()?;
+            let mut deleted = 0;
+            {
+                let mut stmt = txn.prepare("DELETE FROM locations WHERE device_id = ?1 AND update_time = ?2")?;
+                for t in update_times.iter() {
+                    deleted += stmt.execute(params![ device_id, bound_nanos(t) ])? as u64;
+                }
+            }
+            txn.commit()?;
+            Ok(deleted)
+        }).await
+    }
+
+    async fn delete_locations_before (&self, before: DateTime<Utc>) -> anyhow::Result<u64> {
+        self.write(move |conn| {
+            let deleted = conn.execute("DELETE FROM locations WHERE update_time < ?1", [ bound_nanos(&before) ])?;
+            Ok(deleted as u64)
+        }).await
+    }
+
+    async fn get_storage_usage (&self, device_id: &DeviceId) -> anyhow::Result<StorageUsage> {
+        let device_id = device_id.clone();
+        self.read(move |conn| {
+            let usage = conn
+                .prepare_cached(STORAGE_USAGE)?
+                .query_row(
+                    [ device_id ],
+                    |row| Ok((row.get::<_, i64>(0)? as u64, row.get::<_, i64>(1)? as u64, row.get::<_, Option<i64>>(2)?)),
+                )
+                .optional()?;
+            // A device without locations has no row in `location_usage`.
+            let (locations, ciphertext_bytes, oldest) = usage.unwrap_or((0, 0, None));
+            Ok(StorageUsage {
+                locations,
+                // See `StoredLocation::size`.
+                bytes: ciphertext_bytes + 12 * locations,
+                oldest: oldest.map(from_nanos),
+            })
+        }).await
+    }
+
+    async fn get_intro (&self, device_id: &DeviceId) -> anyhow::Result<Option<Introduction>> {
+        let device_id = device_id.clone();
+        self.read(move |conn| {
+            let intro = conn
+                .query_row(
+                    "SELECT remote_addr, registration_key, remote_wipe_enabled, can_read_nearby_devices FROM intros WHERE device_id = ?1",
+                    [ device_id ],
+                    |row| Ok(Introduction {
+                        remote_addr: row.get::<_, Option<String>>(0)?.and_then(|a| a.parse().ok()),
+                        registration_key_digest: row.get(1)?,
+                        remote_wipe_enabled: row.get(2)?,
+                        can_read_nearby_devices: row.get(3)?,
+                    }),
+                )
+                .optional()?;
+            Ok(intro)
+        }).await
+    }
+
+    async fn restore_intro (&self, device_id: &DeviceId, intro: &Introduction) -> anyhow::Result<()> {
+        let (device_id, intro) = (device_id.clone(), intro.clone());
+        self.write(move |conn| write_intro(conn, &device_id, &intro)).await
+    }
+
+    async fn list_devices (&self) -> anyhow::Result<Vec<DeviceId>> {
+        self.read(|conn| {
+            let mut stmt = conn.prepare_cached(LIST_DEVICES)?;
+            let devices = stmt.query_map([], |row| row.get(0))?.collect::<Result<_, _>>()?;
+            Ok(devices)
+        }).await
+    }
+
+    async fn insert_device_key (&self, device_id: &DeviceId, wrapped_key: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let (device_id, wrapped_key) = (device_id.clone(), wrapped_key.to_vec());
+        self.write(move |conn| {
+            let txn = conn.transaction()?;
+            txn.execute(
+                "INSERT OR IGNORE INTO device_keys (device_id, wrapped_key) VALUES (?1, ?2)",
+                params![ device_id, wrapped_key ],
+            )?;
+            let key = txn.query_row("SELECT wrapped_key FROM device_keys WHERE device_id = ?1", [ &device_id ], |row| row.get(0))?;
+            txn.commit()?;
+            Ok(key)
+        }).await
+    }
+
+    async fn get_device_key (&self, device_id: &DeviceId) -> anyhow::Result<Option<Vec<u8>>> {
+        let device_id = device_id.clone();
+        self.read(move |conn| {
+            let key = conn
+                .query_row("SELECT wrapped_key FROM device_keys WHERE device_id = ?1", [ device_id ], |row| row.get(0))
+                .optional()?;
+            Ok(key)
+        }).await
+    }
+
+    async fn write_wipe_order (&self, device_id: &DeviceId, order: &WipeOrder) -> anyhow::Result<()> {
+        let (device_id, order) = (device_id.clone(), order.clone());
+        self.write(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO wipe_orders (device_id, requested, delivered, acknowledged) VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    device_id,
+                    to_nanos(&order.requested)?,
+                    optional_nanos(&order.delivered)?,
+                    optional_nanos(&order.acknowledged)?,
+                ],
+            )?;
+            Ok(())
+        }).await
+    }
+
+    async fn get_wipe_order (&self, device_id: &DeviceId) -> anyhow::Result<Option<WipeOrder>> {
+        let device_id = device_id.clone();
+        self.read(move |conn| {
+            let order = conn
+                .query_row(
+                    "SELECT requested, delivered, acknowledged FROM wipe_orders WHERE device_id = ?1",
+                    [ device_id ],
+                    |row| Ok(WipeOrder {
+                        requested: from_nanos(row.get(0)?),
+                        delivered: row.get::<_, Option<i64>>(1)?.map(from_nanos),
+                        acknowledged: row.get::<_, Option<i64>>(2)?.map(from_nanos),
+                    }),
+                )
+                .optional()?;
+            Ok(order)
+        }).await
+    }
+
+    async fn excommunicate (&self, device_id: &DeviceId, record: &Excommunication) -> anyhow::Result<()> {
+        let (device_id, record) = (device_id.clone(), record.clone());
+        self.write(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO excommunications (device_id, time, reason, by_administrator) VALUES (?1, ?2, ?3, ?4)",
+                params![ device_id, to_nanos(&record.time)?, record.reason, record.by_administrator ],
+            )?;
+            Ok(())
+        }).await
+    }
+
+    async fn get_excommunication (&self, device_id: &DeviceId) -> anyhow::Result<Option<Excommunication>> {
+        let device_id = device_id.clone();
+        self.read(move |conn| {
+            let record = conn
+                .query_row(
+                    "SELECT time, reason, by_administrator FROM excommunications WHERE device_id = ?1",
+                    [ device_id ],
+                    |row| Ok(Excommunication {
+                        time: from_nanos(row.get(0)?),
+                        reason: row.get(1)?,
+                        by_administrator: row.get(2)?,
+                    }),
+                )
+                .optional()?;
+            Ok(record)
+        }).await
+    }
+
+    async fn write_purge_order (&self, order: &PurgeOrder) -> anyhow::Result<()> {
+        let order = order.clone();
+        self.write(move |conn| {
+            conn.execute(
+                &format!("INSERT OR REPLACE INTO purge_orders ({}) VALUES (?1, ?2, ?3, ?4, ?5)", PURGE_ORDER_COLUMNS),
+                params![
+                    order.id as i64,
+                    order.device_id,
+                    to_nanos(&order.requested)?,
+                    optional_nanos(&order.since)?,
+                    to_nanos(&order.execute_at)?,
+                ],
+            )?;
+            Ok(())
+        }).await
+    }
+
+    async fn list_purge_orders (&self, device_id: &DeviceId) -> anyhow::Result<Vec<PurgeOrder>> {
+        self.list_purge_orders_where("device_id = ?1", device_id.clone()).await
+    }
+
+    async fn list_due_purge_orders (&self, now: DateTime<Utc>) -> anyhow::Result<Vec<PurgeOrder>> {
+        self.list_purge_orders_where("execute_at <= ?1", bound_nanos(&now)).await
+    }
+
+    async fn delete_purge_order (&self, id: u64) -> anyhow::Result<bool> {
+        self.write(move |conn| {
+            let deleted = conn.execute("DELETE FROM purge_orders WHERE id = ?1", [ id as i64 ])?;
+            Ok(deleted > 0)
+        }).await
+    }
+
+    async fn write_emergency_purge (&self, request: &EmergencyPurgeRequest) -> anyhow::Result<()> {
+        let request = request.clone();
+        self.write(move |conn| {
+            conn.execute(
+                &format!("INSERT OR REPLACE INTO emergency_purges ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)", EMERGENCY_PURGE_COLUMNS),
+                params![
+                    request.id as i64,
+                    request.device_id,
+                    request.purge_order_id as i64,
+                    optional_nanos(&request.since)?,
+                    to_nanos(&request.requested)?,
+                    optional_nanos(&request.decided)?,
+                    request.approved,
+                ],
+            )?;
+            Ok(())
+        }).await
+    }
+
+    async fn get_emergency_purge (&self, id: u64) -> anyhow::Result<Option<EmergencyPurgeRequest>> {
+        self.read(move |conn| {
+            let request = conn
+                .query_row(
+                    &format!("SELECT {} FROM emergency_purges WHERE id = ?1", EMERGENCY_PURGE_COLUMNS),
+                    [ id as i64 ],
+                    emergency_purge_from_row,
+                )
+                .optional()?;
+            Ok(request)
+        }).await
+    }
+
+    async fn list_emergency_purges (&self, include_decided: bool) -> anyhow::Result<Vec<EmergencyPurgeRequest>> {
+        self.read(move |conn| {
+            let mut stmt = conn.prepare(&format!(
+                "SELECT {} FROM emergency_purges WHERE ?1 OR decided IS NULL ORDER BY requested",
+                EMERGENCY_PURGE_COLUMNS,
+            ))?;
+            let requests = stmt.query_map([ include_decided ], emergency_purge_from_row)?.collect::<Result<_, _>>()?;
+            Ok(requests)
+        }).await
+    }
+
+    async fn write_audit_record (&self, record: &AuditRecord) -> anyhow::Result<()> {
+        let record = record.clone();
+        self.write(move |conn| {
+            conn.execute(
+                "INSERT INTO audit_log (time, action, device_id, detail) VALUES (?1, ?2, ?3, ?4)",
+                params![ to_nanos(&record.time)?, record.action, record.device_id, record.detail ],
+            )?;
+            Ok(())
+        }).await
+    }
+
+    async fn list_audit_records (&self, limit: u32) -> anyhow::Result<Vec<AuditRecord>> {
+        self.read(move |conn| {
+            let mut stmt = conn.prepare("SELECT time, action, device_id, detail FROM audit_log ORDER BY id DESC LIMIT ?1")?;
+            let records = stmt
+                .query_map([ limit ], |row| Ok(AuditRecord {
+                    time: from_nanos(row.get(0)?),
+                    action: row.get(1)?,
+                    device_id: row.get(2)?,
+                    detail: row.get(3)?,
+                }))?
+                .collect::<Result<_, _>>()?;
+            Ok(records)
+        }).await
+    }
+
+    async fn write_registration_key (&self, key: &RegistrationKey) -> anyhow::Result<()> {
+        let key = key.clone();
+        self.write(move |conn| write_registration_key(conn, &key)).await
+    }
+
+    async fn list_registration_keys (&self) -> anyhow::Result<Vec<RegistrationKey>> {
+        self.read(|conn| {
+            let mut stmt = conn.prepare(&format!("SELECT {} FROM registration_keys ORDER BY created", REGISTRATION_KEY_COLUMNS))?;
+            let keys = stmt.query_map([], registration_key_from_row)?.collect::<Result<_, _>>()?;
+            Ok(keys)
+        }).await
+    }
+
+    async fn delete_registration_key (&self, key: &RegistrationKeyDigest) -> anyhow::Result<bool> {
+        let key = key.to_vec();
+        self.write(move |conn| {
+            let deleted = conn.execute("DELETE FROM registration_keys WHERE key = ?1", [ key ])?;
+            Ok(deleted > 0)
+        }).await
+    }
+
+    async fn use_registration_key (&self, key: &RegistrationKeyDigest, now: DateTime<Utc>) -> anyhow::Result<Option<RegistrationKey>> {
+        let key = key.to_vec();
+        self.write(move |conn| {
+            let txn = conn.transaction()?;
+            let before = txn
+                .query_row(
+                    &format!("SELECT {} FROM registration_keys WHERE key = ?1", REGISTRATION_KEY_COLUMNS),
+                    [ &key ],
+                    registration_key_from_row,
+                )
+                .optional()?;
+            let before = match before {
+                Some(before) => before,
+                None => return Ok(None),
+            };
+            if before.uses_remaining == 0 || before.not_after.map(|t| t <= now).unwrap_or(false) {
+                return Ok(None);
+            }
+            txn.execute("UPDATE registration_keys SET uses_remaining = uses_remaining - 1 WHERE key = ?1", [ &key ])?;
+            txn.commit()?;
+            Ok(Some(before))
+        }).await
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::conformance::storage_conformance_tests;
+
+    fn temp_path () -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("fmx-sqlite-{}.sqlite3", rand::random::<u64>()))
+    }
+
+    fn in_memory () -> SqliteStorage {
+        SqliteStorage::from_connection(Connection::open_in_memory().unwrap(), None).unwrap()
+    }
+
+    storage_conformance_tests!(in_memory());
+
+    fn remove_database (path: &Path) {
+        for suffix in [ "", "-wal", "-shm" ] {
+            let mut p = path.as_os_str().to_owned();
+            p.push(suffix);
+            let _ = std::fs::remove_file(p);
+        }
+    }
+
+    #[tokio::test]
+    async fn data_survives_reopening () {
+        let path = temp_path();
+        let device_id: DeviceId = vec![ 1; 32 ];
+        let token: TokenDigest = vec![ 2; 32 ];
+        let now = Utc::now();
+        {
+            let storage = SqliteStorage::open(&path).unwrap();
+            storage.write_token(&token, &TokenEntry {
+                device_id: device_id.clone(),
+                permissions: Permissions { read_locations: true, ..Default::default() },
+                not_before: now,
+                not_after: None,
+            }).await.unwrap();
+            for i in 0..3 {
+                storage.write_location(&device_id, &StoredLocation {
+                    update_time: now + chrono::Duration::seconds(i),
+                    ciphertext: vec![ i as u8; 40 ],
+                }).await.unwrap();
+            }
+            // Another device's locations must not be mixed in.
+            storage.write_location(&vec![ 1; 31 ], &StoredLocation {
+                update_time: now,
+                ciphertext: vec![],
+            }).await.unwrap();
+        }
+        let storage = SqliteStorage::open(&path).unwrap();
+        let entry = storage.get_token_info(&token).await.unwrap().unwrap();
+        assert_eq!(entry.device_id, device_id);
+        assert_eq!(entry.not_before, now);
+        assert!(entry.permissions.read_locations);
+        assert_eq!(storage.list_tokens(&device_id).await.unwrap().len(), 1);
+
+        let filter = LocationsFilter { limit: 1, since: Some(now + chrono::Duration::seconds(1)), until: None };
+        let locs = storage.list_locations(&device_id, &filter).await.unwrap();
+        assert_eq!(locs.iter().map(|l| l.ciphertext[0]).collect::<Vec<_>>(), vec![ 1 ]);
+        assert_eq!(locs[0].update_time, now + chrono::Duration::seconds(1));
+        let usage = storage.get_storage_usage(&device_id).await.unwrap();
+        assert_eq!(usage, StorageUsage { locations: 3, bytes: 3 * 52, oldest: Some(now) });
+
+        assert_eq!(storage.revoke_token(&device_id, None).await.unwrap(), 1);
+        assert!(storage.get_token_info(&token).await.unwrap().is_none());
+        drop(storage);
+        remove_database(&path);
+    }
+
+    #[tokio::test]
+    async fn reads_do_not_wait_for_the_writer () {
+        let path = temp_path();
+        let storage = SqliteStorage::open(&path).unwrap();
+        let device_id: DeviceId = vec![ 1; 32 ];
+        storage.insert_device_key(&device_id, &[ 1 ]).await.unwrap();
+        // A write that is in the middle of a transaction.
+        let (started, has_started) = std::sync::mpsc::channel();
+        let (finish, finished) = std::sync::mpsc::channel::<()>();
+        let pool = storage.pool.clone();
+        let writer = std::thread::spawn(move || {
+            let mut conn = pool.writer();
+            let txn = conn.transaction().unwrap();
+            txn.execute("DELETE FROM device_keys", []).unwrap();
+            started.send(()).unwrap();
+            finished.recv().unwrap();
+        });
+        has_started.recv().unwrap();
+        for _ in 0..READERS * 2 {
+            let read = tokio::time::timeout(std::time::Duration::from_secs(10), storage.get_device_key(&device_id)).await;
+            assert_eq!(read.unwrap().unwrap(), Some(vec![ 1 ]));
+        }
+        assert!(storage.pool.readers.lock().unwrap().len() <= READERS);
+        finish.send(()).unwrap();
+        writer.join().unwrap();
+        drop(storage);
+        remove_database(&path);
+    }
+
+    #[test]
+    fn listing_locations_scans_an_index_range () {
+        let storage = in_memory();
+        let conn = storage.pool.writer();
+        let plan: Vec<String> = conn
+            .prepare(&format!("EXPLAIN QUERY PLAN {}", LIST_LOCATIONS)).unwrap()
+            .query_map(params![ vec![ 1u8 ], 0, 1, 10 ], |row| row.get(3)).unwrap()
+            .collect::<Result<_, _>>().unwrap();
+        assert_eq!(plan, vec![ "SEARCH locations USING PRIMARY KEY (device_id=? AND update_time>? AND update_time<?)" ]);
+    }
+
+    #[test]
+    fn storage_usage_does_not_scan_locations () {
+        let storage = in_memory();
+        let conn = storage.pool.writer();
+        let plan: Vec<String> = conn
+            .prepare(&format!("EXPLAIN QUERY PLAN {}", STORAGE_USAGE)).unwrap()
+            .query_map(params![ vec![ 1u8 ] ], |row| row.get(3)).unwrap()
+            .collect::<Result<_, _>>().unwrap();
+        assert!(!plan.iter().any(|step| step.starts_with("SCAN")), "{:?}", plan);
+        assert!(!plan.iter().any(|step| step.contains("TEMP B-TREE")), "{:?}", plan);
+    }
+
+    #[tokio::test]
+    async fn location_usage_is_counted_for_existing_locations () {
+        let path = temp_path();
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(MIGRATIONS[0]).unwrap();
+        conn.pragma_update(None, "user_version", 1).unwrap();
+        for (nanos, length) in [ (2, 100), (1, 50) ] {
+            conn.execute(
+                "INSERT INTO locations (device_id, update_time, ciphertext) VALUES (?1, ?2, ?3)",
+                params![ vec![ 1u8; 32 ], nanos, vec![ 0u8; length ] ],
+            ).unwrap();
+        }
+        drop(conn);
+        let storage = SqliteStorage::open(&path).unwrap();
+        assert_eq!(storage.get_storage_usage(&vec![ 1; 32 ]).await.unwrap(), StorageUsage {
+            locations: 2,
+            bytes: 100 + 50 + 2 * 12,
+            oldest: Some(from_nanos(1)),
+        });
+        remove_database(&path);
+    }
+
+    #[test]
+    fn newer_schemas_are_refused () {
+        let path = temp_path();
+        SqliteStorage::open(&path).unwrap();
+        let conn = Connection::open(&path).unwrap();
+        assert_eq!(conn.query_row("PRAGMA user_version", [], |row| row.get::<_, i64>(0)).unwrap(), MIGRATIONS.len() as i64);
+        conn.pragma_update(None, "user_version", MIGRATIONS.len() as i64 + 1).unwrap();
+        drop(conn);
+        assert!(SqliteStorage::open(&path).is_err());
+        remove_database(&path);
+    }
+
+}
@@ -0,0 +1,555 @@
+//! A harness shared by the tests of the gRPC services and the web UI, which
+//! serves them in-process, wrapped in the `AuthLayer` just as `serve` does.
+use crate::admin::AdminServiceProvider;
+use crate::auth::{Authorizer, Credentials, Operation};
+use crate::broadcast::LocationBroadcaster;
+use crate::config::Config;
+use crate::crypto::{Vault, KEY_LENGTH};
+use crate::device::DeviceServiceProvider;
+use crate::events::ServerEventQueues;
+use crate::gate::{AuthLayer, LOCATIONS_PATH};
+use crate::user::UserServiceProvider;
+use crate::storage::memory::MemoryStorage;
+use crate::storage::{
+    Storage,
+    Token,
+    TokenDigest,
+    TokenEntry,
+    SecretKey,
+    DeviceId,
+    StoredLocation,
+    IntroInsertion,
+    LocationsFilter,
+    StorageUsage,
+    Introduction,
+    WipeOrder,
+    Excommunication,
+    PurgeOrder,
+    EmergencyPurgeRequest,
+    AuditRecord,
+    RegistrationKey,
+    RegistrationKeyDigest,
+};
+use crate::grpc::find_my_device::admin_service_client::AdminServiceClient;
+use crate::grpc::find_my_device::admin_service_server::AdminServiceServer;
+use crate::grpc::find_my_device::device_service_client::DeviceServiceClient;
+use crate::grpc::find_my_device::device_service_server::DeviceServiceServer;
+use crate::grpc::find_my_device::user_service_client::UserServiceClient;
+use crate::grpc::find_my_device::user_service_server::UserServiceServer;
+use crate::grpc::find_my_device::*;
+use tonic::{Code, Response, Status};
+use tonic::body::BoxBody;
+use tonic::transport::{Channel, Endpoint, Server, Uri};
+use tower::{Layer, ServiceExt};
+use tower::util::BoxCloneService;
+use warp::http::StatusCode;
+use tokio::io::DuplexStream;
+use tokio::sync::{mpsc, Notify, Semaphore};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use std::convert::Infallible;
+use std::sync::Arc;
+use chrono::prelude::*;
+
+pub const TESTING_TOKEN: [u8; 4] = [ 0x01, 0x02, 0x03, 0x04 ];
+
+pub const ADMIN_TOKEN: [u8; 4] = [ 0x05, 0x06, 0x07, 0x08 ];
+
+pub const ALL_PERMISSIONS: [&str; 7] = [
+    "write_locations",
+    "read_locations",
+    "nearby",
+    "wipe",
+    "list_tokens",
+    "stats",
+    "cancel_purge",
+];
+
+pub fn only (permission: &str) -> Permissions {
+    let mut p = Permissions::default();
+    match permission {
+        "write_locations" => p.write_locations = true,
+        "read_locations" => p.read_locations = true,
+        "nearby" => p.nearby = true,
+        "wipe" => p.wipe = true,
+        "list_tokens" => p.list_tokens = true,
+        "stats" => p.stats = true,
+        "cancel_purge" => p.cancel_purge = true,
+        _ => panic!("unknown permission {}", permission),
+    };
+    p
+}
+
+pub fn all () -> Permissions {
+    Permissions {
+        write_locations: true,
+        read_locations: true,
+        nearby: true,
+        wipe: true,
+        list_tokens: true,
+        stats: true,
+        cancel_purge: true,
+    }
+}
+
+pub fn code <T> (result: Result<Response<T>, Status>) -> Code {
+    match result {
+        Ok(_) => Code::Ok,
+        Err(status) => status.code(),
+    }
+}
+
+/// Lets the server act on whatever the client has done, such as closing a
+/// stream. This is for tests with time paused, in which it only returns once
+/// nothing else can run.
+pub async fn settle () {
+    tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+}
+
+/// Returns only the `token` of a request's credentials.
+pub fn token (token: Token) -> Credentials {
+    Credentials { token, ..Default::default() }
+}
+
+/// The path of the RPC, or of the web UI, that performs `operation`.
+pub fn path (operation: Operation) -> &'static str {
+    match operation {
+        Operation::SubmitLocation => "/findmydevice.DeviceService/SubmitLocation",
+        Operation::StreamServerEvents => "/findmydevice.DeviceService/StreamServerEvents",
+        Operation::AcknowledgeWipe => "/findmydevice.DeviceService/AcknowledgeWipe",
+        Operation::ListTokens => "/findmydevice.UserService/ListTokens",
+        Operation::PurgeLocation => "/findmydevice.UserService/PurgeLocation",
+        Operation::CancelPurge => "/findmydevice.UserService/CancelPurge",
+        Operation::Wipe => "/findmydevice.UserService/Wipe",
+        Operation::ListLocations => "/findmydevice.UserService/ListLocations",
+        Operation::StreamLocation => "/findmydevice.UserService/StreamLocation",
+        Operation::RequestLocation => "/findmydevice.UserService/RequestLocation",
+        Operation::GetStorageInfo => "/findmydevice.UserService/GetStorageInfo",
+        Operation::GetDeviceStatus => "/findmydevice.UserService/GetDeviceStatus",
+        Operation::ViewLocations => LOCATIONS_PATH,
+    }
+}
+
+type WebService = BoxCloneService<warp::http::Request<hyper::Body>, warp::http::Response<BoxBody>, Infallible>;
+
+/// Every service, backed by the same `MemoryStorage` and served in-process,
+/// and, if registration is open, a device that has already introduced itself.
+pub struct Harness {
+    pub storage: Arc<MemoryStorage>,
+    pub auth: Authorizer,
+    pub events: Arc<ServerEventQueues>,
+    pub secret_key: SecretKey,
+    channel: Channel,
+    web: WebService,
+}
+
+impl Harness {
+
+    pub async fn new () -> Self {
+        Harness::with_config(Config {
+            admin_token: Some(Vec::from(ADMIN_TOKEN)),
+            ..Default::default()
+        }).await
+    }
+
+    pub async fn with_config (config: Config) -> Self {
+        let storage = Arc::new(MemoryStorage::new());
+        let config = Arc::new(Config {
+            testing_token: Vec::from(TESTING_TOKEN),
+            ..config
+        });
+        let auth = Authorizer::new(config.clone());
+        let vault = Vault::new(&[ 0; KEY_LENGTH ]).unwrap();
+        let locations = Arc::new(LocationBroadcaster::new(config.limits.location_channel_capacity));
+        let events = Arc::new(ServerEventQueues::new(config.limits.event_queue_capacity));
+        let device = DeviceServiceProvider {
+            storage: storage.clone(),
+            config: config.clone(),
+            locations: locations.clone(),
+            events: events.clone(),
+            auth: auth.clone(),
+            vault: vault.clone(),
+        };
+        let user = UserServiceProvider {
+            storage: storage.clone(),
+            config: config.clone(),
+            locations,
+            events: events.clone(),
+            auth: auth.clone(),
+            vault: vault.clone(),
+        };
+        let admin = AdminServiceProvider {
+            storage: storage.clone(),
+            events: events.clone(),
+            auth: auth.clone(),
+        };
+        let layer = AuthLayer::new(storage.clone(), auth.clone());
+
+        // Each connection the channel makes is a new in-memory stream, which
+        // is handed to the server through `connections`.
+        let (connections, incoming) = mpsc::unbounded_channel::<std::io::Result<DuplexStream>>();
+        tokio::spawn(Server::builder()
+            .layer(layer.clone())
+            .add_service(DeviceServiceServer::new(device))
+            .add_service(UserServiceServer::new(user))
+            .add_service(AdminServiceServer::new(admin))
+            .serve_with_incoming(UnboundedReceiverStream::new(incoming)));
+        let channel = Endpoint::from_static("http://localhost")
+            .connect_with_connector_lazy(tower::service_fn(move |_: Uri| {
+                let (client, server) = tokio::io::duplex(64 * 1024);
+                let connected = connections.send(Ok(server))
+                    .map(|_| client)
+                    .map_err(|_| std::io::Error::new(std::io::ErrorKind::BrokenPipe, "The server has stopped."));
+                async move { connected }
+            }));
+        let web = BoxCloneService::new(layer.layer(warp::service(crate::web_routes(storage.clone(), vault))));
+
+        let mut h = Harness {
+            storage,
+            auth,
+            events,
+            secret_key: vec![],
+            channel,
+            web,
+        };
+        if config.open_registration {
+            let intro = h.device().introduce_myself(IntroduceMyselfArg {
+                remote_wipe_enabled: true,
+                ..Default::default()
+            }).await.unwrap().into_inner();
+            h.secret_key = intro.your_secret_key;
+        }
+        h
+    }
+
+    pub fn device (&self) -> DeviceServiceClient<Channel> {
+        DeviceServiceClient::new(self.channel.clone())
+    }
+
+    pub fn user (&self) -> UserServiceClient<Channel> {
+        UserServiceClient::new(self.channel.clone())
+    }
+
+    pub fn admin (&self) -> AdminServiceClient<Channel> {
+        AdminServiceClient::new(self.channel.clone())
+    }
+
+    pub async fn token (
+        &self,
+        permissions: Permissions,
+        not_before: DateTime<Utc>,
+        not_after: Option<DateTime<Utc>>,
+    ) -> Token {
+        let token = Vec::from(rand::random::<[u8; 16]>());
+        let entry = TokenEntry {
+            device_id: self.device_id(),
+            permissions,
+            not_before,
+            not_after,
+        };
+        self.storage.write_token(&self.auth.digests.token(&token), &entry).await.unwrap();
+        token
+    }
+
+    pub fn device_id (&self) -> DeviceId {
+        self.auth.digests.device_id(&self.secret_key)
+    }
+
+    pub async fn valid_token (&self, permissions: Permissions) -> Token {
+        self.token(permissions, Utc::now() - chrono::Duration::minutes(1), None).await
+    }
+
+    /// Performs `operation` with `token`, and returns the resulting code.
+    pub async fn call (&self, operation: Operation, token: Token) -> Code {
+        self.request(path(operation), crate::testing::token(token)).await
+    }
+
+    /// Makes the request at `path`, which is either an RPC or the web UI, with
+    /// `credentials` in whichever fields of its message they belong, and
+    /// returns the resulting code.
+    pub async fn request (&self, path: &str, credentials: Credentials) -> Code {
+        let Credentials { token, secret_key, admin_token } = credentials;
+        match path {
+            "/findmydevice.DeviceService/SubmitLocation" => code(self.device().submit_location(SubmitLocationArg {
+                token,
+                ..Default::default()
+            }).await),
+            "/findmydevice.DeviceService/StreamServerEvents" => code(self.device().stream_server_events(StreamServerEventsArg {
+                token,
+            }).await),
+            "/findmydevice.DeviceService/IntroduceMyself" => code(self.device().introduce_myself(IntroduceMyselfArg {
+                ..Default::default()
+            }).await),
+            "/findmydevice.DeviceService/AcknowledgeWipe" => code(self.device().acknowledge_wipe(AcknowledgeWipeArg {
+                token,
+            }).await),
+            "/findmydevice.UserService/CreateToken" => code(self.user().create_token(CreateTokenArg {
+                secret_key,
+                permissions: Some(Permissions::default()),
+                ..Default::default()
+            }).await),
+            "/findmydevice.UserService/RevokeToken" => code(self.user().revoke_token(RevokeTokenArg {
+                secret_key,
+                token: vec![ 0; 16 ],
+                ..Default::default()
+            }).await),
+            "/findmydevice.UserService/ListTokens" => code(self.user().list_tokens(ListTokensArg {
+                secret_key,
+                token,
+            }).await),
+            "/findmydevice.UserService/PurgeLocation" => code(self.user().purge_location(PurgeLocationArg {
+                token,
+                ..Default::default()
+            }).await),
+            "/findmydevice.UserService/CancelPurge" => code(self.user().cancel_purge(CancelPurgeArg {
+                token,
+                ..Default::default()
+            }).await),
+            "/findmydevice.UserService/Wipe" => code(self.user().wipe(WipeArg {
+                token,
+            }).await),
+            "/findmydevice.UserService/ListLocations" => code(self.user().list_locations(ListLocationsArg {
+                token,
+                ..Default::default()
+            }).await),
+            "/findmydevice.UserService/StreamLocation" => code(self.user().stream_location(StreamLocationArg {
+                token,
+            }).await),
+            "/findmydevice.UserService/RequestLocation" => code(self.user().request_location(RequestLocationArg {
+                token,
+                timeout_seconds: 1,
+            }).await),
+            "/findmydevice.UserService/GetServerInfo" => code(self.user().get_server_info(()).await),
+            "/findmydevice.UserService/GetStorageInfo" => code(self.user().get_storage_info(GetStorageInfoArg {
+                token,
+            }).await),
+            "/findmydevice.UserService/GetDeviceStatus" => code(self.user().get_device_status(GetDeviceStatusArg {
+                secret_key,
+                token,
+            }).await),
+            "/findmydevice.UserService/Excommunicate" => code(self.user().excommunicate(ExcommunicateArg {
+                secret_key,
+                ..Default::default()
+            }).await),
+            "/findmydevice.AdminService/Excommunicate" => code(self.admin().excommunicate(AdminExcommunicateArg {
+                admin_token,
+                ..Default::default()
+            }).await),
+            "/findmydevice.AdminService/ListEmergencyPurges" => code(self.admin().list_emergency_purges(ListEmergencyPurgesArg {
+                admin_token,
+                ..Default::default()
+            }).await),
+            "/findmydevice.AdminService/DecideEmergencyPurge" => code(self.admin().decide_emergency_purge(DecideEmergencyPurgeArg {
+                admin_token,
+                ..Default::default()
+            }).await),
+            "/findmydevice.AdminService/ListAuditLog" => code(self.admin().list_audit_log(ListAuditLogArg {
+                admin_token,
+                ..Default::default()
+            }).await),
+            "/findmydevice.AdminService/CreateRegistrationKey" => code(self.admin().create_registration_key(CreateRegistrationKeyArg {
+                admin_token,
+                ..Default::default()
+            }).await),
+            "/findmydevice.AdminService/ListRegistrationKeys" => code(self.admin().list_registration_keys(ListRegistrationKeysArg {
+                admin_token,
+            }).await),
+            "/findmydevice.AdminService/RevokeRegistrationKey" => code(self.admin().revoke_registration_key(RevokeRegistrationKeyArg {
+                admin_token,
+                ..Default::default()
+            }).await),
+            LOCATIONS_PATH => {
+                let request = warp::http::Request::get(format!("{}{}", LOCATIONS_PATH, hex::encode(token)))
+                    .body(hyper::Body::empty())
+                    .unwrap();
+                match self.web.clone().oneshot(request).await.unwrap().status() {
+                    StatusCode::OK => Code::Ok,
+                    StatusCode::UNAUTHORIZED => Code::Unauthenticated,
+                    StatusCode::FORBIDDEN => Code::PermissionDenied,
+                    _ => Code::Unknown,
+                }
+            },
+            _ => panic!("unknown path {}", path),
+        }
+    }
+
+}
+
+/// A storage that shares a `MemoryStorage` with a `Harness`, but stops just
+/// before `operation` until the test lets it go on. This is for acting out
+/// what happens when another request arrives at that moment. Only
+/// `write_location` and `purge_location` can be paused.
+pub struct PausingStorage {
+    inner: Arc<MemoryStorage>,
+    operation: &'static str,
+    reached: Notify,
+    resume: Semaphore,
+}
+
+impl PausingStorage {
+
+    pub fn new (inner: Arc<MemoryStorage>, operation: &'static str) -> Self {
+        PausingStorage {
+            inner,
+            operation,
+            reached: Notify::new(),
+            resume: Semaphore::new(0),
+        }
+    }
+
+    /// Completes once the operation has been paused.
+    pub async fn reached (&self) {
+        self.reached.notified().await
+    }
+
+    /// Lets the paused operation go on.
+    pub fn resume (&self) {
+        self.resume.add_permits(1);
+    }
+
+    async fn pause_at (&self, operation: &str) {
+        if operation == self.operation {
+            self.reached.notify_one();
+            self.resume.acquire().await.unwrap().forget();
+        }
+    }
+
+}
+
+#[tonic::async_trait]
+impl Storage for PausingStorage {
+
+    async fn get_token_info (&self, token: &TokenDigest) -> anyhow::Result<Option<TokenEntry>> {
+        self.inner.get_token_info(token).await
+    }
+
+    async fn write_location (&self, device_id: &DeviceId, location: &StoredLocation) -> anyhow::Result<()> {
+        self.pause_at("write_location").await;
+        self.inner.write_location(device_id, location).await
+    }
+
+    async fn write_intro <'a> (&self, arg: &'a IntroInsertion) -> anyhow::Result<()> {
+        self.inner.write_intro(arg).await
+    }
+
+    async fn write_token (&self, token: &TokenDigest, arg: &TokenEntry) -> anyhow::Result<()> {
+        self.inner.write_token(token, arg).await
+    }
+
+    async fn revoke_token (&self, device_id: &DeviceId, token: Option<&TokenDigest>) -> anyhow::Result<u32> {
+        self.inner.revoke_token(device_id, token).await
+    }
+
+    async fn list_tokens (&self, device_id: &DeviceId) -> anyhow::Result<Vec<(TokenDigest, TokenEntry)>> {
+        self.inner.list_tokens(device_id).await
+    }
+
+    async fn purge_location (&self, device_id: &DeviceId, since: Option<DateTime<Utc>>) -> anyhow::Result<()> {
+        self.pause_at("purge_location").await;
+        self.inner.purge_location(device_id, since).await
+    }
+
+    async fn list_locations (&self, device_id: &DeviceId, filter: &LocationsFilter) -> anyhow::Result<Vec<StoredLocation>> {
+        self.inner.list_locations(device_id, filter).await
+    }
+
+    async fn delete_locations (&self, device_id: &DeviceId, update_times: &[DateTime<Utc>]) -> anyhow::Result<u64> {
+        self.inner.delete_locations(device_id, update_times).await
+    }
+
+    async fn delete_locations_before (&self, before: DateTime<Utc>) -> anyhow::Result<u64> {
+        self.inner.delete_locations_before(before).await
+    }
+
+    async fn get_storage_usage (&self, device_id: &DeviceId) -> anyhow::Result<StorageUsage> {
+        self.inner.get_storage_usage(device_id).await
+    }
+
+    async fn get_intro (&self, device_id: &DeviceId) -> anyhow::Result<Option<Introduction>> {
+        self.inner.get_intro(device_id).await
+    }
+
+    async fn restore_intro (&self, device_id: &DeviceId, intro: &Introduction) -> anyhow::Result<()> {
+        self.inner.restore_intro(device_id, intro).await
+    }
+
+    async fn list_devices (&self) -> anyhow::Result<Vec<DeviceId>> {
+        self.inner.list_devices().await
+    }
+
+    async fn insert_device_key (&self, device_id: &DeviceId, wrapped_key: &[u8]) -> anyhow::Result<Vec<u8>> {
+        self.inner.insert_device_key(device_id, wrapped_key).await
+    }
+
+    async fn get_device_key (&self, device_id: &DeviceId) -> anyhow::Result<Option<Vec<u8>>> {
+        self.inner.get_device_key(device_id).await
+    }
+
+    async fn write_wipe_order (&self, device_id: &DeviceId, order: &WipeOrder) -> anyhow::Result<()> {
+        self.inner.write_wipe_order(device_id, order).await
+    }
+
+    async fn get_wipe_order (&self, device_id: &DeviceId) -> anyhow::Result<Option<WipeOrder>> {
+        self.inner.get_wipe_order(device_id).await
+    }
+
+    async fn excommunicate (&self, device_id: &DeviceId, record: &Excommunication) -> anyhow::Result<()> {
+        self.inner.excommunicate(device_id, record).await
+    }
+
+    async fn get_excommunication (&self, device_id: &DeviceId) -> anyhow::Result<Option<Excommunication>> {
+        self.inner.get_excommunication(device_id).await
+    }
+
+    async fn write_purge_order (&self, order: &PurgeOrder) -> anyhow::Result<()> {
+        self.inner.write_purge_order(order).await
+    }
+
+    async fn list_purge_orders (&self, device_id: &DeviceId) -> anyhow::Result<Vec<PurgeOrder>> {
+        self.inner.list_purge_orders(device_id).await
+    }
+
+    async fn list_due_purge_orders (&self, now: DateTime<Utc>) -> anyhow::Result<Vec<PurgeOrder>> {
+        self.inner.list_due_purge_orders(now).await
+    }
+
+    async fn delete_purge_order (&self, id: u64) -> anyhow::Result<bool> {
+        self.inner.delete_purge_order(id).await
+    }
+
+    async fn write_emergency_purge (&self, request: &EmergencyPurgeRequest) -> anyhow::Result<()> {
+        self.inner.write_emergency_purge(request).await
+    }
+
+    async fn get_emergency_purge (&self, id: u64) -> anyhow::Result<Option<EmergencyPurgeRequest>> {
+        self.inner.get_emergency_purge(id).await
+    }
+
+    async fn list_emergency_purges (&self, include_decided: bool) -> anyhow::Result<Vec<EmergencyPurgeRequest>> {
+        self.inner.list_emergency_purges(include_decided).await
+    }
+
+    async fn write_audit_record (&self, record: &AuditRecord) -> anyhow::Result<()> {
+        self.inner.write_audit_record(record).await
+    }
+
+    async fn list_audit_records (&self, limit: u32) -> anyhow::Result<Vec<AuditRecord>> {
+        self.inner.list_audit_records(limit).await
+    }
+
+    async fn write_registration_key (&self, key: &RegistrationKey) -> anyhow::Result<()> {
+        self.inner.write_registration_key(key).await
+    }
+
+    async fn list_registration_keys (&self) -> anyhow::Result<Vec<RegistrationKey>> {
+        self.inner.list_registration_keys().await
+    }
+
+    async fn delete_registration_key (&self, key: &RegistrationKeyDigest) -> anyhow::Result<bool> {
+        self.inner.delete_registration_key(key).await
+    }
+
+    async fn use_registration_key (&self, key: &RegistrationKeyDigest, now: DateTime<Utc>) -> anyhow::Result<Option<RegistrationKey>> {
+        self.inner.use_registration_key(key, now).await
+    }
+
+}
@@ -0,0 +1,777 @@
+use crate::broadcast::LocationBroadcaster;
+use crate::config::Config;
+use crate::crypto::Vault;
+use crate::events::ServerEventQueues;
+use crate::grpc::find_my_device::user_service_server::UserService;
+use crate::grpc::find_my_device::{
+    CreateTokenArg,
+    CreateTokenResult,
+    RevokeTokenArg,
+    RevokeTokenResult,
+    ListTokensArg,
+    ListTokensResult,
+    PurgeLocationArg,
+    PurgeLocationResult,
+    CancelPurgeArg,
+    CancelPurgeResult,
+    PendingPurge,
+    WipeArg,
+    WipeResult,
+    ListLocationsArg,
+    ListLocationsResult,
+    StreamLocationArg,
+    RequestLocationArg,
+    LocationSnapshot,
+    ServerInfo,
+    GetStorageInfoArg,
+    GetStorageInfoResult,
+    TokenInfo,
+    ServerEventType,
+    GetDeviceStatusArg,
+    DeviceStatus,
+    ExcommunicateArg,
+    ExcommunicateResult,
+};
+use crate::storage::{
+    Storage,
+    Token,
+    TokenDigest,
+    TokenEntry,
+    LocationsFilter,
+    WipeOrder,
+    Excommunication,
+    PurgeOrder,
+    EmergencyPurgeRequest,
+};
+use crate::auth::{Authorized, Authorizer, database_failure, expiry, is_expired, is_revoked};
+use crate::purge::close_emergency_purges;
+use crate::utils::{grpc_timestamp_to_chrono, chrono_to_grpc_timestamp, redact_nearby};
+use tonic::{Request, Response, Status};
+use tokio_stream::wrappers::ReceiverStream;
+use std::num::NonZeroU64;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::sync::broadcast::error::RecvError;
+use log::{debug, error, info, warn};
+use chrono::prelude::*;
+use rand::RngCore;
+use rand::rngs::OsRng;
+
+/// The length, in bytes, of tokens created with `CreateToken`.
+const TOKEN_LENGTH: usize = 16;
+
+/// The number of locations returned by `ListLocations` if no limit is given.
+const DEFAULT_LOCATIONS_LIMIT: u32 = 100;
+
+/// How long `RequestLocation` waits for the device if no timeout is given.
+const DEFAULT_REQUEST_LOCATION_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The number of snapshots buffered for a `StreamLocation` client that is not
+/// reading them as fast as they are produced.
+const STREAM_LOCATION_BUFFER: usize = 16;
+
+#[derive(Clone)]
+pub struct UserServiceProvider <S: Storage> {
+    pub storage: Arc<S>,
+    pub config: Arc<Config>,
+    pub locations: Arc<LocationBroadcaster>,
+    pub events: Arc<ServerEventQueues>,
+    pub auth: Authorizer,
+    pub vault: Vault,
+}
+
+/// `token` is only known when the token has just been created: after that,
+/// only its digest is kept.
+fn token_entry_to_info (token: Option<Token>, digest: TokenDigest, entry: &TokenEntry) -> TokenInfo {
+    TokenInfo {
+        token: token.unwrap_or_default(),
+        token_digest: digest,
+        permissions: Some(entry.permissions.clone()),
+        not_before: Some(chrono_to_grpc_timestamp(&entry.not_before)),
+        not_after: entry.not_after.as_ref().map(chrono_to_grpc_timestamp),
+    }
+}
+
+#[tonic::async_trait]
+impl <S: Storage + Send + Sync + 'static> UserService for UserServiceProvider <S> {
+
+    async fn create_token (
+        &self,
+        request: Request<CreateTokenArg>,
+    ) -> Result<Response<CreateTokenResult>, Status> {
+        let device_id = Authorized::device(&request)?;
+        let req = request.into_inner();
+        let storage = self.storage.as_ref();
+        if storage.get_excommunication(&device_id).await.map_err(database_failure)?.is_some() {
+            return Err(Status::permission_denied("Excommunicated"));
+        }
+        let permissions = req.permissions
+            .ok_or_else(|| Status::invalid_argument("Permissions must be supplied."))?;
+        let not_before = match req.not_before.as_ref() {
+            Some(t) => grpc_timestamp_to_chrono(t)
+                .ok_or_else(|| Status::invalid_argument("Invalid notBefore."))?,
+            None => Utc::now(),
+        };
+        let not_after = match req.not_after.as_ref() {
+            Some(t) => grpc_timestamp_to_chrono(t)
+                .ok_or_else(|| Status::invalid_argument("Invalid notAfter."))?,
+            None => not_before + chrono::Duration::from_std(self.config.default_token_lifetime)
+                .map_err(|_| Status::internal("Invalid default token lifetime."))?,
+        };
+        if not_after <= not_before {
+            return Err(Status::invalid_argument("notAfter must be after notBefore."));
+        }
+        let mut token: Token = vec![0; TOKEN_LENGTH];
+        OsRng.fill_bytes(&mut token);
+        let digest = self.auth.digests.token(&token);
+        let entry = TokenEntry {
+            device_id,
+            permissions,
+            not_before,
+            not_after: Some(not_after),
+        };
+        storage.write_token(&digest, &entry).await.map_err(database_failure)?;
+        self.events.raise(&entry.device_id, ServerEventType::TokenIssued);
+        info!("Token issued for {:?}, valid until {}.", entry.device_id, not_after.to_rfc3339());
+        Ok(Response::new(CreateTokenResult {
+            token_info: Some(token_entry_to_info(Some(token), digest, &entry)),
+        }))
+    }
+
+    async fn revoke_token (
+        &self,
+        request: Request<RevokeTokenArg>,
+    ) -> Result<Response<RevokeTokenResult>, Status> {
+        let device_id = Authorized::device(&request)?;
+        let req = request.into_inner();
+        let storage = self.storage.as_ref();
+        let digest = if !req.token.is_empty() {
+            Some(self.auth.digests.token(&req.token))
+        } else if !req.token_digest.is_empty() {
+            Some(req.token_digest)
+        } else {
+            None
+        };
+        let revoked = storage.revoke_token(&device_id, digest.as_ref()).await.map_err(database_failure)?;
+        if revoked > 0 {
+            self.auth.notify_revoked();
+            info!("{} tokens revoked by the holder of the secret key.", revoked);
+        }
+        Ok(Response::new(RevokeTokenResult { revoked: revoked > 0 }))
+    }
+
+    async fn list_tokens (
+        &self,
+        request: Request<ListTokensArg>,
+    ) -> Result<Response<ListTokensResult>, Status> {
+        let device_id = Authorized::device(&request)?;
+        let storage = self.storage.as_ref();
+        let tokens = storage.list_tokens(&device_id).await.map_err(database_failure)?;
+        Ok(Response::new(ListTokensResult {
+            tokens: tokens
+                .into_iter()
+                .map(|(digest, entry)| token_entry_to_info(None, digest, &entry))
+                .collect(),
+        }))
+    }
+
+    async fn purge_location (
+        &self,
+        request: Request<PurgeLocationArg>,
+    ) -> Result<Response<PurgeLocationResult>, Status> {
+        let token_info = Authorized::token(&request)?;
+        let req = request.into_inner();
+        let storage = self.storage.as_ref();
+        let since = match req.since.as_ref() {
+            Some(t) => Some(grpc_timestamp_to_chrono(t)
+                .ok_or_else(|| Status::invalid_argument("Invalid since."))?),
+            None => None,
+        };
+        let now = Utc::now();
+        let delay = chrono::Duration::from_std(self.config.purge_delay)
+            .map_err(|_| Status::internal("Invalid purge delay."))?;
+        let order = PurgeOrder {
+            // `CancelPurge` takes an ID of 0 to mean every pending purge.
+            id: rand::random::<NonZeroU64>().get(),
+            device_id: token_info.device_id,
+            requested: now,
+            since,
+            execute_at: now + delay,
+        };
+        storage.write_purge_order(&order).await.map_err(database_failure)?;
+        info!(
+            "Purge {} of the location history of {:?} scheduled for {}.",
+            order.id,
+            order.device_id,
+            order.execute_at.to_rfc3339(),
+        );
+        if req.emergency {
+            let emergency = EmergencyPurgeRequest {
+                id: rand::random::<NonZeroU64>().get(),
+                device_id: order.device_id.clone(),
+                purge_order_id: order.id,
+                since: order.since,
+                requested: now,
+                decided: None,
+                approved: false,
+            };
+            storage.write_emergency_purge(&emergency).await.map_err(database_failure)?;
+            error!(
+                "EMERGENCY: immediate purge requested for {:?}. An administrator must review request {}.",
+                emergency.device_id,
+                emergency.id,
+            );
+        }
+        Ok(Response::new(PurgeLocationResult {
+            will_be_purged: Some(chrono_to_grpc_timestamp(&order.execute_at)),
+            purge_id: order.id,
+        }))
+    }
+
+    async fn cancel_purge (
+        &self,
+        request: Request<CancelPurgeArg>,
+    ) -> Result<Response<CancelPurgeResult>, Status> {
+        let token_info = Authorized::token(&request)?;
+        let req = request.into_inner();
+        let storage = self.storage.as_ref();
+        let pending = storage.list_purge_orders(&token_info.device_id).await
+            .map_err(database_failure)?;
+        let mut cancelled: u32 = 0;
+        for order in pending.iter().filter(|o| req.purge_id == 0 || o.id == req.purge_id) {
+            if storage.delete_purge_order(order.id).await.map_err(database_failure)? {
+                info!("Purge {} of the location history of {:?} cancelled.", order.id, order.device_id);
+                close_emergency_purges(storage, order.id, "Cancelled by the owner").await
+                    .map_err(database_failure)?;
+                cancelled += 1;
+            }
+        }
+        Ok(Response::new(CancelPurgeResult { cancelled }))
+    }
+
+    async fn wipe (
+        &self,
+        request: Request<WipeArg>,
+    ) -> Result<Response<WipeResult>, Status> {
+        let token_info = Authorized::token(&request)?;
+        let storage = self.storage.as_ref();
+        let device_id = token_info.device_id;
+        let remote_wipe_enabled = storage.get_intro(&device_id).await
+            .map_err(database_failure)?
+            .map(|intro| intro.remote_wipe_enabled)
+            .unwrap_or(false);
+        if !remote_wipe_enabled {
+            return Err(Status::failed_precondition("This device does not permit remote wipes."));
+        }
+        if let Some(order) = storage.get_wipe_order(&device_id).await.map_err(database_failure)? {
+            return Ok(Response::new(WipeResult { wiped: order.acknowledged.is_some() }));
+        }
+        let order = WipeOrder {
+            requested: Utc::now(),
+            delivered: None,
+            acknowledged: None,
+        };
+        storage.write_wipe_order(&device_id, &order).await.map_err(database_failure)?;
+        self.events.raise(&device_id, ServerEventType::Wipe);
+        warn!("Remote wipe ordered for {:?}", device_id);
+        Ok(Response::new(WipeResult { wiped: false }))
+    }
+
+    async fn list_locations (
+        &self,
+        request: Request<ListLocationsArg>,
+    ) -> Result<Response<ListLocationsResult>, Status> {
+        let token_info = Authorized::token(&request)?;
+        let req = request.into_inner();
+        let storage = self.storage.as_ref();
+        let since = match req.since.as_ref() {
+            Some(t) => Some(grpc_timestamp_to_chrono(t)
+                .ok_or_else(|| Status::invalid_argument("Invalid since."))?),
+            None => None,
+        };
+        let until = match req.until.as_ref() {
+            Some(t) => Some(grpc_timestamp_to_chrono(t)
+                .ok_or_else(|| Status::invalid_argument("Invalid until."))?),
+            None => None,
+        };
+        let filter = LocationsFilter {
+            limit: match req.limit {
+                0 => DEFAULT_LOCATIONS_LIMIT,
+                l => l,
+            }.min(self.config.limits.max_locations_per_request),
+            since,
+            until,
+        };
+        let mut locations = self.vault.list_locations(storage, &token_info.device_id, &filter).await
+            .map_err(database_failure)?;
+        if !token_info.permissions.nearby {
+            locations.iter_mut().for_each(redact_nearby);
+        }
+        Ok(Response::new(ListLocationsResult { locations }))
+    }
+
+    type StreamLocationStream = ReceiverStream<Result<LocationSnapshot, Status>>;
+
+    async fn stream_location (
+        &self,
+        request: Request<StreamLocationArg>,
+    ) -> Result<Response<Self::StreamLocationStream>, Status> {
+        let token_info = Authorized::token(&request)?;
+        let req = request.into_inner();
+        let mut updates = self.locations.subscribe(&token_info.device_id);
+        let mut revocations = self.auth.revocations();
+        let storage = self.storage.clone();
+        let token = self.auth.digests.token(&req.token);
+        let (tx, rx) = mpsc::channel(STREAM_LOCATION_BUFFER);
+        tokio::spawn(async move {
+            let expired = expiry(&token_info);
+            tokio::pin!(expired);
+            let end = loop {
+                let mut snapshot = tokio::select! {
+                    _ = &mut expired => break Status::unauthenticated("Token expired"),
+                    _ = tx.closed() => return,
+                    _ = revocations.changed() => {
+                        if is_revoked(storage.as_ref(), &token).await {
+                            break Status::unauthenticated("Token revoked");
+                        }
+                        continue;
+                    },
+                    update = updates.recv() => match update {
+                        Ok(snapshot) => snapshot,
+                        Err(RecvError::Lagged(skipped)) => {
+                            warn!("Location stream fell behind and skipped {} snapshots.", skipped);
+                            continue;
+                        },
+                        Err(RecvError::Closed) => return,
+                    },
+                };
+                // The client may have been slow enough for the token to expire.
+                if is_expired(&token_info) {
+                    break Status::unauthenticated("Token expired");
+                }
+                if !token_info.permissions.nearby {
+                    redact_nearby(&mut snapshot);
+                }
+                if tx.send(Ok(snapshot)).await.is_err() {
+                    return;
+                }
+            };
+            debug!("Closing location stream: {}", end.message());
+            let _ = tx.send(Err(end)).await;
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn request_location (
+        &self,
+        request: Request<RequestLocationArg>,
+    ) -> Result<Response<LocationSnapshot>, Status> {
+        let token_info = Authorized::token(&request)?;
+        let req = request.into_inner();
+        let timeout = match req.timeout_seconds {
+            0 => DEFAULT_REQUEST_LOCATION_TIMEOUT,
+            t => Duration::from_secs(t.into()),
+        }.min(self.config.limits.max_request_location_timeout);
+        let answer = self.events.request_location(&token_info.device_id);
+        debug!("Asked {:?} what it sees.", token_info.device_id);
+        let answer = tokio::time::timeout(timeout, answer).await;
+        // If this was the last one waiting, the request is dropped.
+        self.events.tidy(&token_info.device_id);
+        let mut snapshot = match answer {
+            Ok(Ok(snapshot)) => snapshot,
+            Ok(Err(_)) => return Err(Status::unavailable("The location request was abandoned.")),
+            Err(_) => return Err(Status::deadline_exceeded("The device did not respond in time.")),
+        };
+        if !token_info.permissions.nearby {
+            redact_nearby(&mut snapshot);
+        }
+        Ok(Response::new(snapshot))
+    }
+
+    async fn get_server_info (
+        &self,
+        _request: Request<()>,
+    ) -> Result<Response<ServerInfo>, Status> {
+        Ok(Response::new(ServerInfo {
+            registration_required: !self.config.open_registration,
+            ..Default::default()
+        }))
+    }
+
+    async fn get_storage_info (
+        &self,
+        request: Request<GetStorageInfoArg>,
+    ) -> Result<Response<GetStorageInfoResult>, Status> {
+        let token_info = Authorized::token(&request)?;
+        let storage = self.storage.as_ref();
+        let usage = storage.get_storage_usage(&token_info.device_id).await
+            .map_err(database_failure)?;
+        Ok(Response::new(GetStorageInfoResult {
+            locations_count: usage.locations.min(u32::MAX as u64) as u32,
+            since: usage.oldest.as_ref().map(chrono_to_grpc_timestamp),
+            bytes_storage_consumed: usage.bytes,
+            bytes_storage_limit: self.config.quotas.max_bytes_per_device,
+            locations_limit: self.config.quotas.max_locations_per_device.min(u32::MAX as u64) as u32,
+        }))
+    }
+
+    async fn get_device_status (
+        &self,
+        request: Request<GetDeviceStatusArg>,
+    ) -> Result<Response<DeviceStatus>, Status> {
+        let device_id = Authorized::device(&request)?;
+        let storage = self.storage.as_ref();
+        let intro = match storage.get_intro(&device_id).await.map_err(database_failure)? {
+            Some(intro) => intro,
+            None => return Err(Status::not_found("No such device")),
+        };
+        let wipe_order = storage.get_wipe_order(&device_id).await.map_err(database_failure)?;
+        let excommunication = storage.get_excommunication(&device_id).await.map_err(database_failure)?;
+        let purge_orders = storage.list_purge_orders(&device_id).await.map_err(database_failure)?;
+        Ok(Response::new(DeviceStatus {
+            remote_wipe_enabled: intro.remote_wipe_enabled,
+            wipe_requested: wipe_order.as_ref().map(|o| chrono_to_grpc_timestamp(&o.requested)),
+            wipe_acknowledged: wipe_order
+                .as_ref()
+                .and_then(|o| o.acknowledged.as_ref())
+                .map(chrono_to_grpc_timestamp),
+            excommunicated: excommunication.as_ref().map(|e| chrono_to_grpc_timestamp(&e.time)),
+            excommunication_reason: excommunication
+                .as_ref()
+                .map(|e| e.reason.clone())
+                .unwrap_or_default(),
+            excommunicated_by_administrator: excommunication
+                .map(|e| e.by_administrator)
+                .unwrap_or(false),
+            pending_purges: purge_orders
+                .iter()
+                .map(|o| PendingPurge {
+                    purge_id: o.id,
+                    requested: Some(chrono_to_grpc_timestamp(&o.requested)),
+                    since: o.since.as_ref().map(chrono_to_grpc_timestamp),
+                    will_be_purged: Some(chrono_to_grpc_timestamp(&o.execute_at)),
+                })
+                .collect(),
+        }))
+    }
+
+    async fn excommunicate (
+        &self,
+        request: Request<ExcommunicateArg>,
+    ) -> Result<Response<ExcommunicateResult>, Status> {
+        let device_id = Authorized::device(&request)?;
+        let req = request.into_inner();
+        let storage = self.storage.as_ref();
+        if storage.get_excommunication(&device_id).await.map_err(database_failure)?.is_none() {
+            let record = Excommunication {
+                time: Utc::now(),
+                reason: req.reason,
+                by_administrator: false,
+            };
+            storage.excommunicate(&device_id, &record).await.map_err(database_failure)?;
+            self.events.raise(&device_id, ServerEventType::Excommunicated);
+            warn!("Device {:?} excommunicated by its owner: {}", device_id, record.reason);
+        }
+        Ok(Response::new(ExcommunicateResult { excommunicated: true }))
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Operation;
+    use crate::testing::{Harness, all, only, code, settle};
+    use crate::grpc::find_my_device::{
+        SubmitLocationArg,
+        AcknowledgeWipeArg,
+        IntroduceMyselfArg,
+        StreamServerEventsArg,
+        NearbyWifiNetwork,
+    };
+    use tonic::Code;
+    use tokio_stream::StreamExt;
+
+    #[tokio::test]
+    async fn purges_with_an_invalid_since_are_refused () {
+        let h = Harness::new().await;
+        let token = h.valid_token(all()).await;
+        let invalid = h.user().purge_location(Request::new(PurgeLocationArg {
+            token,
+            since: Some(prost_types::Timestamp { seconds: i64::MAX, nanos: 0 }),
+            ..Default::default()
+        })).await;
+        assert_eq!(invalid.unwrap_err().code(), Code::InvalidArgument);
+        assert!(h.storage.list_purge_orders(&h.device_id()).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn location_listings_with_an_invalid_bound_are_refused () {
+        let h = Harness::new().await;
+        let token = h.valid_token(all()).await;
+        let invalid = Some(prost_types::Timestamp { seconds: i64::MAX, nanos: 0 });
+        let since = h.user().list_locations(Request::new(ListLocationsArg {
+            token: token.clone(),
+            since: invalid.clone(),
+            ..Default::default()
+        })).await;
+        assert_eq!(code(since), Code::InvalidArgument);
+        let until = h.user().list_locations(Request::new(ListLocationsArg {
+            token,
+            until: invalid,
+            ..Default::default()
+        })).await;
+        assert_eq!(code(until), Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn wipes_are_refused_unless_the_device_permits_them () {
+        let h = Harness::new().await;
+        let intro = h.device().introduce_myself(Request::new(IntroduceMyselfArg {
+            remote_wipe_enabled: false,
+            ..Default::default()
+        })).await.unwrap().into_inner();
+        let token = h.user().create_token(Request::new(CreateTokenArg {
+            secret_key: intro.your_secret_key.clone(),
+            permissions: Some(all()),
+            ..Default::default()
+        })).await.unwrap().into_inner().token_info.unwrap().token;
+        let refused = h.user().wipe(Request::new(WipeArg { token })).await;
+        assert_eq!(code(refused), Code::FailedPrecondition);
+        let device_id = h.auth.digests.device_id(&intro.your_secret_key);
+        assert!(h.storage.get_wipe_order(&device_id).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn wipes_are_delivered_with_the_next_location_and_acknowledged () {
+        let h = Harness::new().await;
+        let token = h.valid_token(all()).await;
+        let wipe = async || h.user().wipe(Request::new(WipeArg {
+            token: token.clone(),
+        })).await.unwrap().into_inner();
+        let submit = async || h.device().submit_location(Request::new(SubmitLocationArg {
+            token: token.clone(),
+            ..Default::default()
+        })).await.unwrap().into_inner();
+
+        assert!(!submit().await.remote_wipe);
+        assert!(!wipe().await.wiped);
+        let order = h.storage.get_wipe_order(&h.device_id()).await.unwrap().unwrap();
+        assert!(order.delivered.is_none());
+
+        assert!(submit().await.remote_wipe);
+        let order = h.storage.get_wipe_order(&h.device_id()).await.unwrap().unwrap();
+        assert!(order.delivered.is_some());
+        // Until the device acknowledges it, the order is repeated, and the
+        // device is not taken to have been wiped.
+        assert!(submit().await.remote_wipe);
+        assert!(!wipe().await.wiped);
+
+        let acknowledged = h.device().acknowledge_wipe(Request::new(AcknowledgeWipeArg {
+            token: token.clone(),
+        })).await.unwrap().into_inner();
+        assert!(acknowledged.acknowledged);
+        assert!(wipe().await.wiped);
+        assert!(!submit().await.remote_wipe);
+    }
+
+    /// Asks the device what it sees, with a request that is left waiting for
+    /// the answer.
+    async fn request_location (h: &Harness, token: &Token) -> tokio::task::JoinHandle<Result<Response<LocationSnapshot>, Status>> {
+        let mut user = h.user();
+        let token = token.clone();
+        let request = tokio::spawn(async move {
+            user.request_location(Request::new(RequestLocationArg {
+                token,
+                timeout_seconds: 60,
+            })).await
+        });
+        settle().await;
+        request
+    }
+
+    fn notes (notes: &str, token: &Token) -> SubmitLocationArg {
+        SubmitLocationArg {
+            token: token.clone(),
+            notes: String::from(notes),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn location_requests_are_answered_after_delivery_through_the_event_stream () {
+        let h = Harness::new().await;
+        let token = h.valid_token(all()).await;
+        let request = request_location(&h, &token).await;
+        // This was submitted before the device knew of the request.
+        h.device().submit_location(Request::new(notes("Before", &token))).await.unwrap();
+
+        let mut events = h.device().stream_server_events(Request::new(StreamServerEventsArg {
+            token: token.clone(),
+        })).await.unwrap().into_inner();
+        let event = events.next().await.unwrap().unwrap();
+        assert_eq!(event.event_type(), ServerEventType::TellMeWhatYouSee);
+        settle().await;
+        assert!(!request.is_finished());
+        let submitted = h.device().submit_location(Request::new(notes("After", &token))).await.unwrap().into_inner();
+        assert!(!submitted.tell_me_what_you_see);
+        assert_eq!(request.await.unwrap().unwrap().into_inner().notes, "After");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn location_requests_are_answered_after_delivery_through_submit_location () {
+        let h = Harness::new().await;
+        let token = h.valid_token(all()).await;
+        let request = request_location(&h, &token).await;
+        let submitted = h.device().submit_location(Request::new(notes("Before", &token))).await.unwrap().into_inner();
+        assert!(submitted.tell_me_what_you_see);
+        settle().await;
+        assert!(!request.is_finished());
+        let submitted = h.device().submit_location(Request::new(notes("After", &token))).await.unwrap().into_inner();
+        assert!(!submitted.tell_me_what_you_see);
+        assert_eq!(request.await.unwrap().unwrap().into_inner().notes, "After");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn location_requests_time_out () {
+        let h = Harness::new().await;
+        let token = h.valid_token(all()).await;
+        let unanswered = h.user().request_location(Request::new(RequestLocationArg {
+            token,
+            timeout_seconds: 5,
+        })).await;
+        assert_eq!(code(unanswered), Code::DeadlineExceeded);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn submitted_locations_are_streamed_to_subscribers () {
+        let h = Harness::new().await;
+        let device = h.valid_token(all()).await;
+        let subscribe = async |token: Token| h.user().stream_location(Request::new(StreamLocationArg {
+            token,
+        })).await.unwrap().into_inner();
+        let mut full = subscribe(h.valid_token(all()).await).await;
+        let mut redacted = subscribe(h.valid_token(only("read_locations")).await).await;
+        settle().await;
+
+        h.device().submit_location(Request::new(SubmitLocationArg {
+            nearby_wifi_network: vec![ NearbyWifiNetwork {
+                ssid: b"Lighthouse".to_vec(),
+                mac_address: vec![ 0x02; 6 ],
+            } ],
+            ..notes("Home", &device)
+        })).await.unwrap();
+        let snapshot = full.next().await.unwrap().unwrap();
+        assert_eq!(snapshot.notes, "Home");
+        assert_eq!(snapshot.nearby_wifi_network.len(), 1);
+        let snapshot = redacted.next().await.unwrap().unwrap();
+        assert_eq!(snapshot.notes, "Home");
+        assert!(snapshot.nearby_wifi_network.is_empty());
+
+        // Each subscriber gets every snapshot, in the order submitted.
+        h.device().submit_location(Request::new(notes("Away", &device))).await.unwrap();
+        assert_eq!(full.next().await.unwrap().unwrap().notes, "Away");
+        assert_eq!(redacted.next().await.unwrap().unwrap().notes, "Away");
+    }
+
+    #[tokio::test]
+    async fn created_tokens_are_usable_and_expire_by_default () {
+        let h = Harness::new().await;
+        let created = h.user().create_token(Request::new(CreateTokenArg {
+            secret_key: h.secret_key.clone(),
+            permissions: Some(only("read_locations")),
+            ..Default::default()
+        })).await.unwrap().into_inner().token_info.unwrap();
+        assert!(created.not_after.is_some());
+        assert_eq!(h.call(Operation::ListLocations, created.token.clone()).await, Code::Ok);
+        assert_eq!(h.call(Operation::SubmitLocation, created.token).await, Code::PermissionDenied);
+
+        let now = Utc::now();
+        let backwards = h.user().create_token(Request::new(CreateTokenArg {
+            secret_key: h.secret_key.clone(),
+            permissions: Some(all()),
+            not_before: Some(chrono_to_grpc_timestamp(&now)),
+            not_after: Some(chrono_to_grpc_timestamp(&(now - chrono::Duration::hours(1)))),
+        })).await;
+        assert_eq!(code(backwards), Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn revoking_one_token_leaves_the_others_usable () {
+        let h = Harness::new().await;
+        let revoked = h.valid_token(all()).await;
+        let kept = h.valid_token(all()).await;
+        let result = h.user().revoke_token(Request::new(RevokeTokenArg {
+            secret_key: h.secret_key.clone(),
+            token: revoked.clone(),
+            ..Default::default()
+        })).await.unwrap().into_inner();
+        assert!(result.revoked);
+        assert_eq!(h.call(Operation::ListLocations, revoked.clone()).await, Code::Unauthenticated);
+        assert_eq!(h.call(Operation::ListLocations, kept).await, Code::Ok);
+
+        // Revoking it again, or revoking a token of another device, does nothing.
+        let again = h.user().revoke_token(Request::new(RevokeTokenArg {
+            secret_key: h.secret_key.clone(),
+            token: revoked,
+            ..Default::default()
+        })).await.unwrap().into_inner();
+        assert!(!again.revoked);
+        let other = Harness::new().await;
+        let foreign = other.valid_token(all()).await;
+        let result = h.user().revoke_token(Request::new(RevokeTokenArg {
+            secret_key: h.secret_key.clone(),
+            token: foreign.clone(),
+            ..Default::default()
+        })).await.unwrap().into_inner();
+        assert!(!result.revoked);
+        assert_eq!(other.call(Operation::ListLocations, foreign).await, Code::Ok);
+    }
+
+    #[tokio::test]
+    async fn an_empty_token_revokes_all_tokens () {
+        let h = Harness::new().await;
+        let tokens = [ h.valid_token(all()).await, h.valid_token(all()).await ];
+        let result = h.user().revoke_token(Request::new(RevokeTokenArg {
+            secret_key: h.secret_key.clone(),
+            token: vec![],
+            ..Default::default()
+        })).await.unwrap().into_inner();
+        assert!(result.revoked);
+        for token in tokens {
+            assert_eq!(h.call(Operation::ListLocations, token).await, Code::Unauthenticated);
+        }
+        let listed = h.user().list_tokens(Request::new(ListTokensArg {
+            secret_key: h.secret_key.clone(),
+            ..Default::default()
+        })).await.unwrap().into_inner();
+        assert!(listed.tokens.is_empty());
+    }
+
+    #[tokio::test]
+    async fn streams_end_when_the_token_is_revoked () {
+        let h = Harness::new().await;
+        let token = h.valid_token(all()).await;
+        let mut locations = h.user().stream_location(Request::new(StreamLocationArg {
+            token: token.clone(),
+        })).await.unwrap().into_inner();
+        let mut events = h.device().stream_server_events(Request::new(StreamServerEventsArg {
+            token: token.clone(),
+        })).await.unwrap().into_inner();
+        // Skip the keepalive that is sent as soon as the stream opens.
+        events.next().await.unwrap().unwrap();
+        h.user().revoke_token(Request::new(RevokeTokenArg {
+            secret_key: h.secret_key.clone(),
+            token,
+            ..Default::default()
+        })).await.unwrap();
+        let end = locations.next().await.unwrap();
+        assert_eq!(end.unwrap_err().code(), Code::Unauthenticated);
+        assert!(locations.next().await.is_none());
+        let end = events.next().await.unwrap();
+        assert_eq!(end.unwrap_err().code(), Code::Unauthenticated);
+        assert!(events.next().await.is_none());
+    }
+
+}
@@ -1,3 +1,4 @@
+use crate::grpc::find_my_device::LocationSnapshot;
 use chrono::prelude::*;
 
 pub fn grpc_timestamp_to_chrono (grpc_time: &prost_types::Timestamp) -> Option<DateTime<Utc>> {
@@ -5,4 +6,18 @@ pub fn grpc_timestamp_to_chrono (grpc_time: &prost_types::Timestamp) -> Option<D
         chrono::LocalResult::Single(dt) => Some(dt),
         _ => None,
     }
-}
\ No newline at end of file
+}
+
+pub fn chrono_to_grpc_timestamp (time: &DateTime<Utc>) -> prost_types::Timestamp {
+    prost_types::Timestamp {
+        seconds: time.timestamp(),
+        nanos: time.nanosecond() as i32,
+    }
+}
+
+/// Removes the nearby Wi-Fi networks and Bluetooth devices from a snapshot, for
+/// tokens that lack the `nearby` permission.
+pub fn redact_nearby (snapshot: &mut LocationSnapshot) {
+    snapshot.nearby_wifi_network.clear();
+    snapshot.nearby_bluetooth_devices.clear();
+}
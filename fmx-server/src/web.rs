@@ -2,6 +2,7 @@ use yew::prelude::*;
 use crate::grpc::find_my_device::LocationSnapshot;
 use std::rc::Rc;
 use crate::utils::grpc_timestamp_to_chrono;
+use base64::Engine;
 
 #[derive(Properties, PartialEq)]
 pub struct LocationHistoryItemProps {
@@ -10,6 +11,7 @@ pub struct LocationHistoryItemProps {
 }
 
 const UNSUPPLIED_FIELD: &str = "-";
+const ENCRYPTED_FIELD: &str = "encrypted";
 
 #[function_component]
 fn LocationHistoryItem (props: &LocationHistoryItemProps) -> Html {
@@ -17,53 +19,45 @@ fn LocationHistoryItem (props: &LocationHistoryItemProps) -> Html {
         .snapshot
         .update_time
         .as_ref()
-        .and_then(|t| grpc_timestamp_to_chrono(&t))
+        .and_then(grpc_timestamp_to_chrono)
         .map(|t| t.to_rfc2822())
         .unwrap_or(String::from(UNSUPPLIED_FIELD));
     let (lat, long, elevation) = props
         .snapshot
         .location
         .as_ref()
-        .and_then(|loc| Some((
+        .map(|loc| (
             loc.degrees_latitude.to_string(),
             loc.degress_longitude.to_string(),
             loc.meters_elevation.to_string()
-        )))
+        ))
         .unwrap_or((String::from(UNSUPPLIED_FIELD), String::from(UNSUPPLIED_FIELD), String::from(UNSUPPLIED_FIELD)));
     let (speed, bearing) = props
         .snapshot
         .velocity
         .as_ref()
-        .and_then(|vel| Some((
+        .map(|vel| (
             vel.meters_per_second_speed.to_string(),
             vel.bearing.to_string(),
-        )))
+        ))
         .unwrap_or((String::from(UNSUPPLIED_FIELD), String::from(UNSUPPLIED_FIELD)));
     let next_update = props
         .snapshot
         .expected_next_update_time
         .as_ref()
-        .and_then(|t| grpc_timestamp_to_chrono(&t))
+        .and_then(grpc_timestamp_to_chrono)
         .map(|t| t.to_rfc2822())
         .unwrap_or(String::from(UNSUPPLIED_FIELD));
     let emergency = if props.snapshot.emergency { "emergency" } else { "safe" };
-    let notes = if props.snapshot.notes.len() > 0 {
+    let notes = if !props.snapshot.notes.is_empty() {
         props.snapshot.notes.clone()
     } else {
         String::from(UNSUPPLIED_FIELD)
     };
 
-    let wifi = if props.snapshot.nearby_wifi_network.len() > 0 {
-        String::from(UNSUPPLIED_FIELD)
-    } else {
-        String::from(UNSUPPLIED_FIELD)
-    };
+    let wifi = String::from(UNSUPPLIED_FIELD);
 
-    let bluetooth = if props.snapshot.nearby_bluetooth_devices.len() > 0 {
-        String::from(UNSUPPLIED_FIELD)
-    } else {
-        String::from(UNSUPPLIED_FIELD)
-    };
+    let bluetooth = String::from(UNSUPPLIED_FIELD);
 
     let url_cell = if lat.len() > 1 && long.len() > 1 {
         let url = format!("https://www.openstreetmap.org/?mlat={}&mlon={}", lat, long);
@@ -71,7 +65,28 @@ fn LocationHistoryItem (props: &LocationHistoryItemProps) -> Html {
     } else {
         html!{<>{String::from(UNSUPPLIED_FIELD)}</>}
     };
-    
+
+    // The server cannot read encrypted snapshots: OPEN_SNAPSHOTS_SCRIPT fills
+    // these rows in, in the browser, if the page's URL carries the key.
+    if !props.snapshot.encrypted_snapshot.is_empty() {
+        let sealed = base64::engine::general_purpose::STANDARD.encode(&props.snapshot.encrypted_snapshot);
+        return html! {
+            <tr class={classes!(["loc-item", emergency].as_ref())} data-sealed={sealed}>
+                <td>{update_time}</td>
+                <td class="lat">{ENCRYPTED_FIELD}</td>
+                <td class="long">{ENCRYPTED_FIELD}</td>
+                <td class="elevation">{ENCRYPTED_FIELD}</td>
+                <td class="speed">{ENCRYPTED_FIELD}</td>
+                <td class="bearing">{ENCRYPTED_FIELD}</td>
+                <td>{next_update}</td>
+                <td class="wifi">{ENCRYPTED_FIELD}</td>
+                <td class="bluetooth">{ENCRYPTED_FIELD}</td>
+                <td class="notes">{ENCRYPTED_FIELD}</td>
+                <td class="link">{UNSUPPLIED_FIELD}</td>
+            </tr>
+        };
+    }
+
     return html! {
         <tr class={classes!(["loc-item", emergency].as_ref())}>
             <td>{update_time}</td>
@@ -122,9 +137,108 @@ a:visited {
 }
 "#;
 
+/// Decrypts the encrypted snapshots on the page with the key given in the
+/// URL's fragment (`#key=<hex>`), which browsers never send to the server.
+/// See `SubmitLocationArg.encryptedSnapshot` for the format.
+const OPEN_SNAPSHOTS_SCRIPT: &str = r#"
+const SNAPSHOT_AAD = new TextEncoder().encode("fmx location snapshot");
+
+const fromHex = (hex) => Uint8Array.from(hex.match(/../g) || [], (b) => parseInt(b, 16));
+const fromBase64 = (b64) => Uint8Array.from(atob(b64), (c) => c.charCodeAt(0));
+
+// Decodes a protobuf message into a map from field numbers to lists of values.
+function decode (bytes) {
+    const fields = {};
+    let i = 0;
+    const varint = () => {
+        let n = 0, shift = 0, b;
+        do {
+            b = bytes[i++];
+            n += (b & 0x7F) * 2 ** shift;
+            shift += 7;
+        } while (b & 0x80);
+        return n;
+    };
+    while (i < bytes.length) {
+        const key = varint();
+        let value;
+        switch (key & 7) {
+            case 0: value = varint(); break;
+            case 1: value = bytes.subarray(i, i + 8); i += 8; break;
+            case 2: { const len = varint(); value = bytes.subarray(i, i + len); i += len; break; }
+            case 5: value = bytes.subarray(i, i + 4); i += 4; break;
+            default: throw new Error("Unsupported wire type");
+        }
+        const field = Math.floor(key / 8);
+        (fields[field] = fields[field] || []).push(value);
+    }
+    return fields;
+}
+
+const all = (fields, n) => fields[n] || [];
+const first = (fields, n) => all(fields, n)[0];
+// Proto floats are 32 bits, so only about seven of their digits mean anything.
+const float = (b) => b ? Number(new DataView(b.buffer, b.byteOffset, 4).getFloat32(0, true).toPrecision(7)) : 0;
+const text = (b) => b ? new TextDecoder().decode(b) : "";
+const list = (items) => items.length > 0 ? items.join(", ") : "-";
+
+async function openSnapshots () {
+    const hex = new URLSearchParams(window.location.hash.slice(1)).get("key");
+    if (!hex) {
+        return;
+    }
+    const key = await crypto.subtle.importKey("raw", fromHex(hex), "AES-GCM", false, ["decrypt"]);
+    for (const row of document.querySelectorAll("tr[data-sealed]")) {
+        const set = (cls, value) => { row.querySelector("." + cls).textContent = value; };
+        const sealed = fromBase64(row.dataset.sealed);
+        let snapshot;
+        try {
+            const plaintext = await crypto.subtle.decrypt(
+                { name: "AES-GCM", iv: sealed.subarray(0, 12), additionalData: SNAPSHOT_AAD },
+                key,
+                sealed.subarray(12),
+            );
+            snapshot = decode(new Uint8Array(plaintext));
+        } catch (e) {
+            set("notes", "Could not be decrypted");
+            continue;
+        }
+        const location = first(snapshot, 4);
+        if (location) {
+            const loc = decode(location);
+            const lat = float(first(loc, 1));
+            const long = float(first(loc, 2));
+            set("lat", lat);
+            set("long", long);
+            set("elevation", float(first(loc, 3)));
+            const link = document.createElement("a");
+            link.href = `https://www.openstreetmap.org/?mlat=${lat}&mlon=${long}`;
+            link.textContent = "Link";
+            row.querySelector(".link").replaceChildren(link);
+        } else {
+            ["lat", "long", "elevation"].forEach((cls) => set(cls, "-"));
+        }
+        const velocity = first(snapshot, 5);
+        if (velocity) {
+            const vel = decode(velocity);
+            set("speed", float(first(vel, 1)));
+            set("bearing", float(first(vel, 2)));
+        } else {
+            ["speed", "bearing"].forEach((cls) => set(cls, "-"));
+        }
+        set("notes", text(first(snapshot, 7)) || "-");
+        set("wifi", list(all(snapshot, 8).map((n) => text(first(decode(n), 1)))));
+        set("bluetooth", list(all(snapshot, 9).map((d) => text(first(decode(d), 1)))));
+    }
+}
+
+openSnapshots();
+"#;
+
 #[function_component]
 pub fn LocationsPage (props: &Props) -> Html {
     let css = Html::from_html_unchecked(LOCATIONS_STYLE.into());
+    let script = Html::from_html_unchecked(OPEN_SNAPSHOTS_SCRIPT.into());
     html! {
         <html>
             <head>
@@ -163,6 +277,7 @@ pub fn LocationsPage (props: &Props) -> Html {
                     }
                     </tbody>
                 </table>
+                <script>{script}</script>
             </body>
         </html>
     }